@@ -16,6 +16,8 @@ use chrono::offset::Utc;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::Serialize;
 
+pub const PERMISSION_ADMIN: i32 = 1;
+
 #[derive(Clone, Debug, Serialize)]
 pub struct Account {
     #[serde(rename = "_id")]
@@ -60,7 +62,7 @@ impl From<OrderedDocument> for Account {
                 .get_document("session")
                 .ok()
                 .map(|session| session.clone().into()),
-            permissions: None,
+            permissions: document.get_i32("permissions").ok(),
         }
     }
 }
@@ -96,6 +98,11 @@ impl Account {
         &self.apikey
     }
 
+    /// Admins have the lowest bit of their permissions set.
+    pub fn is_admin(&self) -> bool {
+        self.permissions.unwrap_or_default() & PERMISSION_ADMIN != 0
+    }
+
     pub fn is_expired(&self, expires_at: i64) -> bool {
         if let Some(session) = &self.session {
             let now = Utc::now().timestamp_millis();
@@ -18,10 +18,28 @@ pub struct Course2 {
     owner: ObjectId,
     last_modified: i64,
     uploaded: i64,
+    /// Starts at 1 and is incremented by every re-upload of the course data.
+    #[serde(default = "Course2::first_version")]
+    version: i32,
     #[serde(skip_serializing_if = "Option::is_none")]
     difficulty: Option<Difficulty>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// Written on SMMDB, in addition to the description stored in the course data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// Course id in Super Mario Maker 2, e.g. `1AB-2CD-3FG`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nintendo_id: Option<String>,
+    /// Unlisted courses are only found by their id.
+    #[serde(default)]
+    unlisted: bool,
     #[serde(default)]
     votes: i32,
+    #[serde(default)]
+    downloads: i64,
     course: SMM2Course,
     hash: MinHash,
 }
@@ -51,13 +69,34 @@ impl Course2 {
             owner,
             last_modified: uploaded,
             uploaded,
+            version: Course2::first_version(),
             difficulty,
+            video_url: None,
+            tags: vec![],
+            description: None,
+            nintendo_id: None,
+            unlisted: false,
             votes: 0,
+            downloads: 0,
             course: course.get_course().clone(),
             hash,
         }
     }
 
+    /// Replaces the course data of an existing course, which keeps its id, votes and downloads.
+    pub fn reupload(&mut self, course: &smmdb_lib::Course2, perm_gen: &PermGen) {
+        let mut hash = MinHash::new(&perm_gen);
+        hash.update(&perm_gen, course.get_course_data());
+        self.last_modified = Utc::now().timestamp_millis().max(self.last_modified + 1);
+        self.version += 1;
+        self.course = course.get_course().clone();
+        self.hash = hash;
+    }
+
+    fn first_version() -> i32 {
+        1
+    }
+
     pub fn set_id(&mut self, id: ObjectId) {
         self.id = Some(id);
     }
@@ -74,6 +113,26 @@ impl Course2 {
         &self.difficulty
     }
 
+    pub fn get_video_url(&self) -> &Option<String> {
+        &self.video_url
+    }
+
+    pub fn get_tags(&self) -> &Vec<String> {
+        &self.tags
+    }
+
+    pub fn get_description(&self) -> &Option<String> {
+        &self.description
+    }
+
+    pub fn get_nintendo_id(&self) -> &Option<String> {
+        &self.nintendo_id
+    }
+
+    pub fn is_unlisted(&self) -> bool {
+        self.unlisted
+    }
+
     pub fn get_last_modified(&self) -> i64 {
         self.last_modified
     }
@@ -82,15 +141,23 @@ impl Course2 {
         self.uploaded
     }
 
+    pub fn get_version(&self) -> i32 {
+        self.version
+    }
+
     pub fn get_votes(&self) -> i32 {
         self.votes
     }
 
+    pub fn get_downloads(&self) -> i64 {
+        self.downloads
+    }
+
     pub fn get_own_vote(
         &self,
         account_id: &ObjectId,
         course_id: &ObjectId,
-        database: &Database,
+        database: &dyn Database,
     ) -> Option<i32> {
         database.get_vote_for_account(account_id, course_id).ok()
     }
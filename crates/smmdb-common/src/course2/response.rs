@@ -14,9 +14,20 @@ pub struct Course2Response {
     uploader: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     difficulty: Option<Difficulty>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nintendo_id: Option<String>,
+    unlisted: bool,
     last_modified: i64,
     uploaded: i64,
+    version: i32,
     votes: i32,
+    downloads: i64,
     #[serde(skip_serializing_if = "Option::is_none")]
     own_vote: Option<i32>,
     course: SMM2CourseWrap,
@@ -40,16 +51,23 @@ impl Course2Response {
         course: Course2,
         account: &Account,
         own_account: Option<&Account>,
-        database: &Database,
+        database: &dyn Database,
     ) -> Course2Response {
         Course2Response {
             id: course.get_id().to_hex(),
             owner: course.owner.to_hex(),
             uploader: account.get_username().clone(),
             difficulty: course.get_difficulty().clone(),
+            video_url: course.get_video_url().clone(),
+            tags: course.get_tags().clone(),
+            description: course.get_description().clone(),
+            nintendo_id: course.get_nintendo_id().clone(),
+            unlisted: course.is_unlisted(),
             last_modified: course.get_last_modified(),
             uploaded: course.get_uploaded(),
+            version: course.get_version(),
             votes: course.get_votes(),
+            downloads: course.get_downloads(),
             own_vote: if let Some(own_account) = own_account {
                 course.get_own_vote(own_account.get_id(), course.get_id(), database)
             } else {
@@ -18,28 +18,33 @@ fn calculate_hash(t: &[u8]) -> u64 {
     s.finish()
 }
 
-static mut HASH_SEED: u32 = 1;
+/// Deterministic source of permutation values.
+///
+/// Permutations only depend on the seed, so that hashes are comparable across processes.
+struct PermRng {
+    seed: u32,
+}
 
-fn get_rand_int() -> u64 {
-    unsafe {
+impl PermRng {
+    fn next_value(&mut self) -> u64 {
         let max_hash = MAX_HASH as f64;
-        let x = f64::from(HASH_SEED).sin() * max_hash;
-        HASH_SEED += 1;
+        let x = f64::from(self.seed).sin() * max_hash;
+        self.seed = self.seed.wrapping_add(1);
 
         ((x - x.floor()) * max_hash).floor() as u64
     }
 }
 
-fn get_random_perm(size: usize) -> Vec<u64> {
+fn get_random_perm(rng: &mut PermRng, size: usize) -> Vec<u64> {
     let mut perm = Vec::with_capacity(size);
 
     let mut used: HashSet<u64> = HashSet::new();
 
     while perm.len() < size {
-        let mut val = get_rand_int();
+        let mut val = rng.next_value();
 
         while used.contains(&val) {
-            val = get_rand_int();
+            val = rng.next_value();
         }
 
         used.insert(val);
@@ -54,23 +59,30 @@ pub struct PermGen {
     perm_a: Vec<u64>,
     perm_b: Vec<u64>,
     num_perm: usize,
+    seed: u32,
 }
 
 impl PermGen {
-    pub fn new(num_perm: usize) -> PermGen {
-        let perm_a = get_random_perm(num_perm);
-        let perm_b = get_random_perm(num_perm);
+    pub fn new(num_perm: usize, seed: u32) -> PermGen {
+        let mut rng = PermRng { seed };
+        let perm_a = get_random_perm(&mut rng, num_perm);
+        let perm_b = get_random_perm(&mut rng, num_perm);
 
         PermGen {
             perm_a,
             perm_b,
             num_perm,
+            seed,
         }
     }
 
     pub fn num_perm(&self) -> usize {
         self.num_perm
     }
+
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
 }
 
 impl MinHash {
@@ -116,9 +128,13 @@ impl MinHash {
     }
 }
 
+/// Locality sensitive hashing index over the bands of `MinHash`es.
+///
+/// The bands of every key are kept as well, so that keys can be removed or updated.
 #[derive(Debug)]
 pub struct LshIndex {
     index: HashMap<u64, HashSet<String>>,
+    keys: HashMap<String, Vec<u64>>,
     band_size: u64,
 }
 
@@ -149,11 +165,48 @@ impl LshIndex {
     pub fn new(band_size: u64) -> LshIndex {
         LshIndex {
             index: HashMap::new(),
+            keys: HashMap::new(),
             band_size,
         }
     }
 
+    /// Rebuilds an index from the bands previously returned by `keys`.
+    pub fn from_keys(band_size: u64, keys: HashMap<String, Vec<u64>>) -> LshIndex {
+        let mut index: HashMap<u64, HashSet<String>> = HashMap::new();
+        for (key, bands) in keys.iter() {
+            for band in bands {
+                index
+                    .entry(*band)
+                    .or_insert_with(HashSet::new)
+                    .insert(key.to_owned());
+            }
+        }
+        LshIndex {
+            index,
+            keys,
+            band_size,
+        }
+    }
+
+    pub fn band_size(&self) -> u64 {
+        self.band_size
+    }
+
+    pub fn keys(&self) -> &HashMap<String, Vec<u64>> {
+        &self.keys
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Inserts the key or replaces its previous hash.
     pub fn insert(&mut self, key: String, h: &MinHash) {
+        self.remove(&key);
         let hash_bands = get_hash_bands(&h.hash, self.band_size);
 
         for band in &hash_bands {
@@ -162,6 +215,25 @@ impl LshIndex {
                 .or_insert_with(HashSet::new)
                 .insert(key.to_owned());
         }
+        self.keys.insert(key, hash_bands);
+    }
+
+    /// Returns whether the key has been part of the index.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let hash_bands = match self.keys.remove(key) {
+            Some(hash_bands) => hash_bands,
+            None => return false,
+        };
+
+        for band in &hash_bands {
+            if let Some(set) = self.index.get_mut(band) {
+                set.remove(key);
+                if set.is_empty() {
+                    self.index.remove(band);
+                }
+            }
+        }
+        true
     }
 
     pub fn query(&self, h: &MinHash) -> Vec<String> {
@@ -0,0 +1,61 @@
+use super::BlobStore;
+use crate::DatabaseError;
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Stores every blob as a file below `root`, keys being relative paths.
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self, DatabaseError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(FsBlobStore { root })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, DatabaseError> {
+        if key
+            .split('/')
+            .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+        {
+            return Err(DatabaseError::Blob(format!("invalid key {}", key)));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DatabaseError> {
+        match fs::read(self.path(key)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes to a temporary file first, so that readers never see a partially written blob.
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), DatabaseError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), DatabaseError> {
+        match fs::remove_file(self.path(key)?) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
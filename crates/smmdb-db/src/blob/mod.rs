@@ -0,0 +1,668 @@
+//! Storage of course binaries outside of the database.
+
+mod fs;
+mod s3;
+
+pub use fs::FsBlobStore;
+pub use s3::{S3BlobStore, S3Config};
+
+use crate::{
+    AccountStore, CourseStore, Database, DatabaseError, DownloadStore, JobStore, MetaStore,
+    VoteStore,
+};
+
+use bson::{oid::ObjectId, ordered::OrderedDocument, spec::BinarySubtype, Bson};
+use std::sync::Arc;
+
+pub trait BlobStore: Send + Sync {
+    /// Returns `None`, if there is no blob with the given key.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DatabaseError>;
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), DatabaseError>;
+
+    /// Deleting a missing blob succeeds.
+    fn delete(&self, key: &str) -> Result<(), DatabaseError>;
+}
+
+/// Field of the document, which replaces a binary moved into the blob store.
+const BLOB_KEY: &str = "blob";
+
+/// A database, which keeps the binaries of course data documents in a `BlobStore`.
+///
+/// Every top-level binary of a course data document is stored as blob
+/// `course2/<course id>/<field>` and replaced with a reference to it, binaries of previous
+/// versions as `course2/<course id>/v<version>/<field>`. References are resolved when course data
+/// is read, so that callers never see them.
+pub struct BlobDatabase {
+    database: Arc<dyn Database>,
+    blobs: Arc<dyn BlobStore>,
+}
+
+impl BlobDatabase {
+    pub fn new(database: Arc<dyn Database>, blobs: Arc<dyn BlobStore>) -> Self {
+        BlobDatabase { database, blobs }
+    }
+
+    fn prefix(course_id: &Bson) -> Result<String, DatabaseError> {
+        match course_id {
+            Bson::ObjectId(course_id) => Ok(format!("course2/{}", course_id.to_hex())),
+            _ => Err(DatabaseError::Blob(format!(
+                "course data id {} is not an object id",
+                course_id
+            ))),
+        }
+    }
+
+    fn history_prefix(doc: &OrderedDocument) -> Result<String, DatabaseError> {
+        let course_id = doc.get("course_id").cloned().unwrap_or(Bson::Null);
+        Ok(format!(
+            "{}/v{}",
+            BlobDatabase::prefix(&course_id)?,
+            doc.get_i32("version")?
+        ))
+    }
+
+    fn blob_ref(value: &Bson) -> Option<&str> {
+        match value {
+            Bson::Document(doc) => doc.get_str(BLOB_KEY).ok(),
+            _ => None,
+        }
+    }
+
+    /// Moves all binaries of `fields` into the blob store and replaces them with references.
+    fn externalize(
+        &self,
+        prefix: &str,
+        fields: &mut OrderedDocument,
+    ) -> Result<bool, DatabaseError> {
+        let binaries: Vec<String> = fields
+            .iter()
+            .filter(|(_, value)| matches!(value, Bson::Binary(BinarySubtype::Generic, _)))
+            .map(|(field, _)| field.clone())
+            .collect();
+        for field in binaries.iter() {
+            let key = format!("{}/{}", prefix, field);
+            let size = match fields.get(field) {
+                Some(Bson::Binary(_, data)) => {
+                    self.blobs.put(&key, data)?;
+                    data.len() as i64
+                }
+                _ => continue,
+            };
+            fields.insert(
+                field.clone(),
+                doc! {
+                    BLOB_KEY => key,
+                    "size" => size,
+                },
+            );
+        }
+        Ok(!binaries.is_empty())
+    }
+
+    /// Replaces all references with the referenced binaries.
+    ///
+    /// Fields of missing blobs are removed, as if they were never stored.
+    fn resolve(&self, doc: &mut OrderedDocument) -> Result<(), DatabaseError> {
+        let refs: Vec<(String, String)> = doc
+            .iter()
+            .filter_map(|(field, value)| {
+                BlobDatabase::blob_ref(value).map(|key| (field.clone(), key.to_string()))
+            })
+            .collect();
+        for (field, key) in refs {
+            match self.blobs.get(&key)? {
+                Some(data) => {
+                    doc.insert(field, Bson::Binary(BinarySubtype::Generic, data));
+                }
+                None => {
+                    doc.remove(&field);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_blobs(&self, doc: &OrderedDocument) -> Result<(), DatabaseError> {
+        for (_, value) in doc.iter() {
+            if let Some(key) = BlobDatabase::blob_ref(value) {
+                self.blobs.delete(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the binaries of the course data document, which are still stored in the database,
+    /// into the blob store. Returns whether there were any.
+    pub fn externalize_course2(&self, course_id: &ObjectId) -> Result<bool, DatabaseError> {
+        let filter = doc! {
+            "_id" => course_id.clone()
+        };
+        let mut doc = match self.database.get_course2(filter.clone(), doc! {})? {
+            Some(doc) => doc,
+            None => return Ok(false),
+        };
+        doc.remove("_id");
+        let prefix = BlobDatabase::prefix(&Bson::ObjectId(course_id.clone()))?;
+        if !self.externalize(&prefix, &mut doc)? {
+            return Ok(false);
+        }
+        let update = doc! {
+            "$set" => doc
+        };
+        self.database.update_course2_data(filter, update)?;
+        Ok(true)
+    }
+
+    /// Moves the binaries of the previous version, which are still stored in the database, into
+    /// the blob store. Returns whether there were any.
+    pub fn externalize_course2_history(&self, history_id: &Bson) -> Result<bool, DatabaseError> {
+        let filter = doc! {
+            "_id" => history_id.clone()
+        };
+        let mut doc = match self
+            .database
+            .find_course2_history(filter.clone(), doc! {})?
+            .into_iter()
+            .next()
+        {
+            Some(doc) => doc,
+            None => return Ok(false),
+        };
+        doc.remove("_id");
+        let prefix = BlobDatabase::history_prefix(&doc)?;
+        if !self.externalize(&prefix, &mut doc)? {
+            return Ok(false);
+        }
+        let update = doc! {
+            "$set" => doc
+        };
+        self.database.update_course2_history(filter, update)?;
+        Ok(true)
+    }
+}
+
+impl AccountStore for BlobDatabase {
+    fn find_account(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.database.find_account(filter)
+    }
+
+    fn find_accounts(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.database.find_accounts(filter)
+    }
+
+    fn insert_account(&self, account: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        self.database.insert_account(account)
+    }
+
+    fn update_account(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.database.update_account(filter, update)
+    }
+}
+
+impl CourseStore for BlobDatabase {
+    fn get_courses(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.database.get_courses(query)
+    }
+
+    fn get_courses2(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.database.get_courses2(query)
+    }
+
+    fn find_courses2(
+        &self,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.database.find_courses2(filter, projection)
+    }
+
+    fn count_courses2(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.database.count_courses2(filter)
+    }
+
+    fn insert_course2(&self, doc_meta: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        self.database.insert_course2(doc_meta)
+    }
+
+    fn update_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        self.database.update_course2(filter, update)
+    }
+
+    fn delete_course2_meta(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.database.delete_course2_meta(filter)
+    }
+
+    fn get_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        match self.database.get_course2(filter, projection)? {
+            Some(mut doc) => {
+                self.resolve(&mut doc)?;
+                Ok(Some(doc))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn find_course2_data(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let mut docs = self.database.find_course2_data(filter, projection)?;
+        for doc in docs.iter_mut() {
+            self.resolve(doc)?;
+        }
+        Ok(docs)
+    }
+
+    fn insert_course2_data(&self, mut doc: OrderedDocument) -> Result<(), DatabaseError> {
+        let course_id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+        self.externalize(&BlobDatabase::prefix(&course_id)?, &mut doc)?;
+        self.database.insert_course2_data(doc)
+    }
+
+    /// Only updates the first matched document, like all backends do.
+    fn update_course2_data(
+        &self,
+        filter: OrderedDocument,
+        mut update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        let course_id = match filter.get("_id") {
+            Some(Bson::ObjectId(course_id)) => Bson::ObjectId(course_id.clone()),
+            _ => {
+                let projection = doc! {
+                    "_id" => 1
+                };
+                match self
+                    .database
+                    .find_course2_data(filter, projection)?
+                    .into_iter()
+                    .next()
+                    .and_then(|doc| doc.get("_id").cloned())
+                {
+                    Some(course_id) => course_id,
+                    None => return Ok(()),
+                }
+            }
+        };
+        let prefix = BlobDatabase::prefix(&course_id)?;
+        if let Some(Bson::Document(set)) = update.get_mut("$set") {
+            self.externalize(&prefix, set)?;
+        }
+        let unset: Vec<String> = update
+            .get_document("$unset")
+            .map(|unset| unset.keys().cloned().collect())
+            .unwrap_or_default();
+
+        let filter = doc! {
+            "_id" => course_id.clone()
+        };
+        self.database.update_course2_data(filter, update)?;
+        for field in unset {
+            self.blobs.delete(&format!("{}/{}", prefix, field))?;
+        }
+        Ok(())
+    }
+
+    fn delete_course2_data(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        let doc = self.database.get_course2(filter.clone(), doc! {})?;
+        let deleted = self.database.delete_course2_data(filter)?;
+        if let Some(doc) = doc {
+            self.delete_blobs(&doc)?;
+        }
+        Ok(deleted)
+    }
+
+    fn find_course2_history(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let mut docs = self.database.find_course2_history(filter, projection)?;
+        for doc in docs.iter_mut() {
+            self.resolve(doc)?;
+        }
+        Ok(docs)
+    }
+
+    fn insert_course2_history(&self, mut doc: OrderedDocument) -> Result<(), DatabaseError> {
+        self.externalize(&BlobDatabase::history_prefix(&doc)?, &mut doc)?;
+        self.database.insert_course2_history(doc)
+    }
+
+    /// Only updates the first matched version, like all backends do.
+    fn update_course2_history(
+        &self,
+        filter: OrderedDocument,
+        mut update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        let projection = doc! {
+            "_id" => 1,
+            "course_id" => 1,
+            "version" => 1
+        };
+        let doc = match self
+            .database
+            .find_course2_history(filter, projection)?
+            .into_iter()
+            .next()
+        {
+            Some(doc) => doc,
+            None => return Ok(()),
+        };
+        let prefix = BlobDatabase::history_prefix(&doc)?;
+        if let Some(Bson::Document(set)) = update.get_mut("$set") {
+            self.externalize(&prefix, set)?;
+        }
+        let unset: Vec<String> = update
+            .get_document("$unset")
+            .map(|unset| unset.keys().cloned().collect())
+            .unwrap_or_default();
+
+        let filter = doc! {
+            "_id" => doc.get("_id").cloned().unwrap_or(Bson::Null)
+        };
+        self.database.update_course2_history(filter, update)?;
+        for field in unset {
+            self.blobs.delete(&format!("{}/{}", prefix, field))?;
+        }
+        Ok(())
+    }
+
+    fn delete_course2_history(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        let docs = self
+            .database
+            .find_course2_history(filter.clone(), doc! {})?;
+        let deleted = self.database.delete_course2_history(filter)?;
+        for doc in docs.iter() {
+            self.delete_blobs(doc)?;
+        }
+        Ok(deleted)
+    }
+}
+
+impl VoteStore for BlobDatabase {
+    fn vote_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.database.vote_course2(filter, update)
+    }
+
+    fn unvote_course2(&self, filter: OrderedDocument) -> Result<(), DatabaseError> {
+        self.database.unvote_course2(filter)
+    }
+
+    fn get_votes_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.database.get_votes_course2(filter, projection)
+    }
+}
+
+impl DownloadStore for BlobDatabase {
+    fn find_download_course2(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.database.find_download_course2(filter)
+    }
+
+    fn upsert_download_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.database.upsert_download_course2(filter, update)
+    }
+}
+
+impl JobStore for BlobDatabase {
+    fn find_jobs(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        limit: Option<i64>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.database.find_jobs(filter, sort, limit)
+    }
+
+    fn count_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.database.count_jobs(filter)
+    }
+
+    fn upsert_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.database.upsert_job(filter, update)
+    }
+
+    fn claim_job(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.database.claim_job(filter, sort, update)
+    }
+
+    fn update_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        self.database.update_job(filter, update)
+    }
+
+    fn delete_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.database.delete_jobs(filter)
+    }
+}
+
+impl MetaStore for BlobDatabase {
+    fn find_meta(&self, filter: OrderedDocument) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.database.find_meta(filter)
+    }
+
+    fn update_meta(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.database.update_meta(filter, update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryDatabase;
+    use std::{env, fs, path::PathBuf};
+
+    /// A blob database over a `MemoryDatabase`, whose blobs are stored in a temporary directory,
+    /// which is removed on drop.
+    struct TestDatabase {
+        database: BlobDatabase,
+        inner: Arc<MemoryDatabase>,
+        blobs: Arc<FsBlobStore>,
+        root: PathBuf,
+    }
+
+    impl TestDatabase {
+        fn new() -> Self {
+            let root = env::temp_dir().join(format!(
+                "smmdb-test-blobs-{}",
+                ObjectId::new().unwrap().to_hex()
+            ));
+            let inner = Arc::new(MemoryDatabase::new());
+            let blobs = Arc::new(FsBlobStore::new(&root).unwrap());
+            TestDatabase {
+                database: BlobDatabase::new(inner.clone(), blobs.clone()),
+                inner,
+                blobs,
+                root,
+            }
+        }
+    }
+
+    impl Drop for TestDatabase {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn binary(data: &[u8]) -> Bson {
+        Bson::Binary(BinarySubtype::Generic, data.to_vec())
+    }
+
+    #[test]
+    fn round_trips_course_data_through_the_blob_store() {
+        let db = TestDatabase::new();
+        let course_id = ObjectId::new().unwrap();
+        let filter = doc! {
+            "_id" => course_id.clone()
+        };
+        let prefix = format!("course2/{}", course_id.to_hex());
+        db.database
+            .insert_course2_data(doc! {
+                "_id" => course_id.clone(),
+                "data_encrypted" => binary(&[1, 2, 3]),
+                "data_br" => binary(&[4, 5])
+            })
+            .unwrap();
+
+        let stored = db
+            .inner
+            .get_course2(filter.clone(), doc! {})
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            stored
+                .get_document("data_encrypted")
+                .unwrap()
+                .get_str(BLOB_KEY)
+                .unwrap(),
+            format!("{}/data_encrypted", prefix)
+        );
+        assert_eq!(
+            db.blobs.get(&format!("{}/data_encrypted", prefix)).unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        let doc = db
+            .database
+            .get_course2(filter.clone(), doc! {})
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            doc.get_binary_generic("data_encrypted").unwrap(),
+            &[1, 2, 3]
+        );
+        assert_eq!(doc.get_binary_generic("data_br").unwrap(), &[4, 5]);
+
+        db.database
+            .update_course2_data(
+                filter.clone(),
+                doc! {
+                    "$set" => { "data_br" => binary(&[6]) },
+                    "$unset" => { "data_encrypted" => "" }
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            db.blobs.get(&format!("{}/data_encrypted", prefix)).unwrap(),
+            None
+        );
+        let doc = db
+            .database
+            .get_course2(filter.clone(), doc! {})
+            .unwrap()
+            .unwrap();
+        assert!(doc.get("data_encrypted").is_none());
+        assert_eq!(doc.get_binary_generic("data_br").unwrap(), &[6]);
+
+        db.database.delete_course2_data(filter.clone()).unwrap();
+        assert_eq!(db.blobs.get(&format!("{}/data_br", prefix)).unwrap(), None);
+    }
+
+    #[test]
+    fn round_trips_course_history_through_the_blob_store() {
+        let db = TestDatabase::new();
+        let course_id = ObjectId::new().unwrap();
+        let filter = doc! {
+            "course_id" => course_id.clone(),
+            "version" => 1
+        };
+        let key = format!("course2/{}/v1/data_encrypted", course_id.to_hex());
+        db.database
+            .insert_course2_history(doc! {
+                "course_id" => course_id.clone(),
+                "version" => 1,
+                "data_encrypted" => binary(&[1, 2, 3])
+            })
+            .unwrap();
+        assert_eq!(db.blobs.get(&key).unwrap(), Some(vec![1, 2, 3]));
+        let docs = db
+            .database
+            .find_course2_history(filter.clone(), doc! {})
+            .unwrap();
+        assert_eq!(
+            docs[0].get_binary_generic("data_encrypted").unwrap(),
+            &[1, 2, 3]
+        );
+
+        db.database
+            .update_course2_history(
+                filter.clone(),
+                doc! {
+                    "$unset" => { "data_encrypted" => "" }
+                },
+            )
+            .unwrap();
+        assert_eq!(db.blobs.get(&key).unwrap(), None);
+        let docs = db.database.find_course2_history(filter, doc! {}).unwrap();
+        assert!(docs[0].get("data_encrypted").is_none());
+    }
+
+    #[test]
+    fn rejects_keys_outside_of_the_root() {
+        let db = TestDatabase::new();
+        assert!(matches!(
+            db.blobs.get("course2/../../secret"),
+            Err(DatabaseError::Blob(_))
+        ));
+        assert!(matches!(
+            db.blobs.put("/absolute", &[]),
+            Err(DatabaseError::Blob(_))
+        ));
+        assert!(db.blobs.delete("course2/missing").is_ok());
+    }
+}
@@ -3,8 +3,11 @@ pub enum Collections {
     CourseData,
     Courses2,
     Course2Data,
+    Course2History,
     Accounts,
     Votes,
+    Downloads,
+    Jobs,
     Meta,
 }
 
@@ -15,8 +18,11 @@ impl Collections {
             Collections::CourseData => "courseData",
             Collections::Courses2 => "courses2",
             Collections::Course2Data => "course2Data",
+            Collections::Course2History => "course2History",
             Collections::Accounts => "accounts",
             Collections::Votes => "votes",
+            Collections::Downloads => "downloads",
+            Collections::Jobs => "jobs",
             Collections::Meta => "meta",
         }
     }
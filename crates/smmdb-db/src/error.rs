@@ -1,16 +1,44 @@
 use std::io;
 
-use bson::ordered::OrderedDocument;
+use bson::{ordered::OrderedDocument, ValueAccessError};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum DatabaseError {
     #[error("[Course2ConvertError]: {0}\n{1}")]
     Course2Convert(OrderedDocument, serde_json::Error),
+    #[error("[DatabaseError::NotFound]: {0}")]
+    NotFound(String),
+    #[error("[DatabaseError::Canceled]: database call has been canceled")]
+    Canceled,
+    #[error("[DatabaseError::UnsupportedQuery]: {0}")]
+    UnsupportedQuery(String),
+    #[error("[DatabaseError::Blob]: {0}")]
+    Blob(String),
     #[error(transparent)]
     Smmdb(#[from] smmdb_lib::Error),
     #[error(transparent)]
     Mongo(#[from] mongodb::Error),
     #[error(transparent)]
+    MongoWrite(#[from] mongodb::coll::error::WriteException),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Postgres(#[from] postgres::Error),
+    #[error(transparent)]
+    Pool(#[from] r2d2::Error),
+    #[error(transparent)]
+    BsonEncode(#[from] bson::EncoderError),
+    #[error(transparent)]
+    BsonDecode(#[from] bson::DecoderError),
+    #[error(transparent)]
+    ObjectId(#[from] bson::oid::Error),
+    #[error(transparent)]
+    ValueAccess(#[from] ValueAccessError),
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+    #[error(transparent)]
     Io(#[from] io::Error),
 }
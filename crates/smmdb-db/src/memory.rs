@@ -0,0 +1,427 @@
+use crate::{
+    query, AccountStore, CourseStore, DatabaseError, DownloadStore, JobStore, MetaStore, VoteStore,
+};
+
+use bson::{oid::ObjectId, ordered::OrderedDocument, Bson};
+use parking_lot::RwLock;
+
+#[derive(Default)]
+struct MemoryCollection {
+    docs: RwLock<Vec<OrderedDocument>>,
+}
+
+impl MemoryCollection {
+    fn find(
+        &self,
+        filter: &OrderedDocument,
+        projection: Option<&OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let mut res = vec![];
+        for doc in self.docs.read().iter() {
+            if query::matches(doc, filter)? {
+                res.push(match projection {
+                    Some(projection) => query::project(doc, projection),
+                    None => doc.clone(),
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    fn find_one(
+        &self,
+        filter: &OrderedDocument,
+        projection: Option<&OrderedDocument>,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        for doc in self.docs.read().iter() {
+            if query::matches(doc, filter)? {
+                return Ok(Some(match projection {
+                    Some(projection) => query::project(doc, projection),
+                    None => doc.clone(),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    fn count(&self, filter: &OrderedDocument) -> Result<u64, DatabaseError> {
+        let mut count = 0;
+        for doc in self.docs.read().iter() {
+            if query::matches(doc, filter)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn aggregate(
+        &self,
+        pipeline: &[OrderedDocument],
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        query::aggregate(self.docs.read().clone(), pipeline)
+    }
+
+    fn insert_one(&self, mut doc: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        let id = match doc.get("_id") {
+            Some(Bson::ObjectId(id)) => id.clone(),
+            _ => {
+                let id = ObjectId::new()?;
+                doc.insert("_id", id.clone());
+                id
+            }
+        };
+        self.docs.write().push(doc);
+        Ok(id)
+    }
+
+    /// Returns the number of matched documents.
+    fn update_one(
+        &self,
+        filter: &OrderedDocument,
+        update: &OrderedDocument,
+        upsert: bool,
+    ) -> Result<u64, DatabaseError> {
+        let mut docs = self.docs.write();
+        for doc in docs.iter_mut() {
+            if query::matches(doc, filter)? {
+                query::apply_update(doc, update)?;
+                return Ok(1);
+            }
+        }
+        if upsert {
+            let mut doc = query::upsert_document(filter);
+            query::apply_insert_update(&mut doc, update)?;
+            if doc.get("_id").is_none() {
+                doc.insert("_id", ObjectId::new()?);
+            }
+            docs.push(doc);
+        }
+        Ok(0)
+    }
+
+    /// Updates the first document matched by `filter` in the order of `sort` and returns it.
+    fn find_one_and_update(
+        &self,
+        filter: &OrderedDocument,
+        sort: &OrderedDocument,
+        update: &OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        let mut docs = self.docs.write();
+        let mut matched = vec![];
+        for (index, doc) in docs.iter().enumerate() {
+            if query::matches(doc, filter)? {
+                matched.push(index);
+            }
+        }
+        matched.sort_by(|a, b| query::sort_by(&docs[*a], &docs[*b], sort));
+        match matched.first() {
+            Some(index) => {
+                let doc = &mut docs[*index];
+                query::apply_update(doc, update)?;
+                Ok(Some(doc.clone()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the number of deleted documents.
+    fn delete_many(&self, filter: &OrderedDocument) -> Result<u64, DatabaseError> {
+        let mut docs = self.docs.write();
+        let count = docs.len();
+        let mut res = Ok(());
+        docs.retain(|doc| match query::matches(doc, filter) {
+            Ok(matched) => !matched,
+            Err(err) => {
+                res = Err(err);
+                true
+            }
+        });
+        res?;
+        Ok((count - docs.len()) as u64)
+    }
+
+    /// Returns the number of deleted documents.
+    fn delete_one(&self, filter: &OrderedDocument) -> Result<u64, DatabaseError> {
+        let mut docs = self.docs.write();
+        let mut index = None;
+        for (i, doc) in docs.iter().enumerate() {
+            if query::matches(doc, filter)? {
+                index = Some(i);
+                break;
+            }
+        }
+        if let Some(index) = index {
+            docs.remove(index);
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+/// A database backend keeping every collection in memory, e.g. for tests and local development.
+#[derive(Default)]
+pub struct MemoryDatabase {
+    courses: MemoryCollection,
+    courses2: MemoryCollection,
+    course2_data: MemoryCollection,
+    course2_history: MemoryCollection,
+    accounts: MemoryCollection,
+    votes: MemoryCollection,
+    downloads: MemoryCollection,
+    jobs: MemoryCollection,
+    meta: MemoryCollection,
+}
+
+impl MemoryDatabase {
+    pub fn new() -> Self {
+        println!("Using in-memory database");
+        MemoryDatabase::default()
+    }
+}
+
+impl AccountStore for MemoryDatabase {
+    fn find_account(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.accounts.find_one(&filter, None)
+    }
+
+    fn find_accounts(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.accounts.find(&filter, None)
+    }
+
+    fn insert_account(&self, account: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        self.accounts.insert_one(account)
+    }
+
+    fn update_account(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.accounts.update_one(&filter, &update, false)?;
+        Ok(())
+    }
+}
+
+impl CourseStore for MemoryDatabase {
+    fn get_courses(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.courses.aggregate(&query)
+    }
+
+    fn get_courses2(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.courses2.aggregate(&query)
+    }
+
+    fn find_courses2(
+        &self,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.courses2.find(&filter, projection.as_ref())
+    }
+
+    fn count_courses2(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.courses2.count(&filter)
+    }
+
+    fn insert_course2(&self, doc_meta: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        self.courses2.insert_one(doc_meta)
+    }
+
+    fn update_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        self.courses2.update_one(&filter, &update, false)
+    }
+
+    fn delete_course2_meta(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.courses2.delete_one(&filter)
+    }
+
+    fn get_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.course2_data.find_one(&filter, Some(&projection))
+    }
+
+    fn find_course2_data(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.course2_data.find(&filter, Some(&projection))
+    }
+
+    fn insert_course2_data(&self, doc: OrderedDocument) -> Result<(), DatabaseError> {
+        self.course2_data.insert_one(doc)?;
+        Ok(())
+    }
+
+    fn update_course2_data(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.course2_data.update_one(&filter, &update, false)?;
+        Ok(())
+    }
+
+    fn delete_course2_data(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.course2_data.delete_one(&filter)
+    }
+
+    fn find_course2_history(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.course2_history.find(&filter, Some(&projection))
+    }
+
+    fn insert_course2_history(&self, doc: OrderedDocument) -> Result<(), DatabaseError> {
+        self.course2_history.insert_one(doc)?;
+        Ok(())
+    }
+
+    fn update_course2_history(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.course2_history.update_one(&filter, &update, false)?;
+        Ok(())
+    }
+
+    fn delete_course2_history(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.course2_history.delete_many(&filter)
+    }
+}
+
+impl VoteStore for MemoryDatabase {
+    fn vote_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.votes.update_one(&filter, &update, true)?;
+        Ok(())
+    }
+
+    fn unvote_course2(&self, filter: OrderedDocument) -> Result<(), DatabaseError> {
+        self.votes.delete_one(&filter)?;
+        Ok(())
+    }
+
+    fn get_votes_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.votes.find(&filter, Some(&projection))
+    }
+}
+
+impl DownloadStore for MemoryDatabase {
+    fn find_download_course2(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.downloads.find_one(&filter, None)
+    }
+
+    fn upsert_download_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.downloads.update_one(&filter, &update, true)?;
+        Ok(())
+    }
+}
+
+impl JobStore for MemoryDatabase {
+    fn find_jobs(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        limit: Option<i64>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.jobs
+            .aggregate(&query::find_pipeline(filter, sort, limit))
+    }
+
+    fn count_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.jobs.count(&filter)
+    }
+
+    fn upsert_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.jobs.update_one(&filter, &update, true)?;
+        Ok(())
+    }
+
+    fn claim_job(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.jobs.find_one_and_update(&filter, &sort, &update)
+    }
+
+    fn update_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        self.jobs.update_one(&filter, &update, false)
+    }
+
+    fn delete_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.jobs.delete_many(&filter)
+    }
+}
+
+impl MetaStore for MemoryDatabase {
+    fn find_meta(&self, filter: OrderedDocument) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.meta.find_one(&filter, None)
+    }
+
+    fn update_meta(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.meta.update_one(&filter, &update, true)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_query_cases() {
+        query::tests::check_course_store(&MemoryDatabase::new());
+    }
+}
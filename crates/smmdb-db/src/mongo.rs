@@ -0,0 +1,622 @@
+use crate::{
+    collections::Collections, text::COURSE2_TEXT_INDEX, AccountStore, CourseStore, DatabaseError,
+    DownloadStore, JobStore, MetaStore, VoteStore,
+};
+
+use bson::{oid::ObjectId, ordered::OrderedDocument};
+use mongodb::{
+    coll::{
+        options::{
+            FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument, UpdateOptions,
+        },
+        results::{InsertOneResult, UpdateResult},
+        Collection,
+    },
+    db::ThreadedDatabase,
+    Client, ThreadedClient,
+};
+
+pub struct MongoDatabase {
+    courses: Collection,
+    _course_data: Collection,
+    courses2: Collection,
+    course2_data: Collection,
+    course2_history: Collection,
+    accounts: Collection,
+    votes: Collection,
+    downloads: Collection,
+    jobs: Collection,
+    meta: Collection,
+}
+
+impl MongoDatabase {
+    pub fn new(mongodb_uri: &str, database_name: &str) -> Result<Self, DatabaseError> {
+        println!("Connecting to MongoDB at {}", mongodb_uri);
+        let client = Client::with_uri(mongodb_uri)?;
+        let db = client.db(database_name);
+        let courses = db.collection(Collections::Courses.as_str());
+        let _course_data = db.collection(Collections::CourseData.as_str());
+        let courses2 = db.collection(Collections::Courses2.as_str());
+        let course2_data = db.collection(Collections::Course2Data.as_str());
+        let course2_history = db.collection(Collections::Course2History.as_str());
+        let accounts = db.collection(Collections::Accounts.as_str());
+        let votes = db.collection(Collections::Votes.as_str());
+        let downloads = db.collection(Collections::Downloads.as_str());
+        let jobs = db.collection(Collections::Jobs.as_str());
+        let migrations = db.collection(Collections::Meta.as_str());
+
+        if let Err(err) = MongoDatabase::generate_accounts_indexes(&accounts) {
+            println!("{}", err);
+        }
+        if let Err(err) = MongoDatabase::generate_course2_indexes(&courses2) {
+            println!("{}", err);
+        }
+        if let Err(err) = MongoDatabase::generate_course2_history_indexes(&course2_history) {
+            println!("{}", err);
+        }
+        if let Err(err) = MongoDatabase::generate_votes_indexes(&votes) {
+            println!("{}", err);
+        }
+        if let Err(err) = MongoDatabase::generate_downloads_indexes(&downloads) {
+            println!("{}", err);
+        }
+        if let Err(err) = MongoDatabase::generate_jobs_indexes(&jobs) {
+            println!("{}", err);
+        }
+
+        Ok(MongoDatabase {
+            courses,
+            _course_data,
+            courses2,
+            course2_data,
+            course2_history,
+            accounts,
+            votes,
+            downloads,
+            jobs,
+            meta: migrations,
+        })
+    }
+
+    fn generate_accounts_indexes(accounts: &Collection) -> Result<(), mongodb::Error> {
+        let indexes = vec![doc! {
+            "apikey": 1,
+        }];
+        let listed_indexes: Vec<OrderedDocument> =
+            accounts.list_indexes()?.filter_map(Result::ok).collect();
+        MongoDatabase::create_missing_indexes(accounts, &listed_indexes, indexes)
+    }
+
+    fn generate_course2_indexes(courses2: &Collection) -> Result<(), mongodb::Error> {
+        let indexes = vec![
+            doc! {
+                "last_modified": -1,
+                "course.header.title": -1,
+                "_id": -1
+            },
+            doc! {
+                "last_modified": -1,
+                "course.header.title": 1,
+                "_id": -1
+            },
+            doc! {
+                "last_modified": 1,
+                "course.header.title": -1,
+                "_id": -1
+            },
+            doc! {
+                "last_modified": 1,
+                "course.header.title": 1,
+                "_id": -1
+            },
+            doc! {
+                "votes": 1,
+                "course.header.title": -1,
+                "_id": -1
+            },
+            doc! {
+                "votes": -1,
+                "course.header.title": -1,
+                "_id": -1
+            },
+            doc! {
+                "votes": 1,
+                "last_modified": -1,
+                "course.header.title": -1,
+                "_id": -1
+            },
+            doc! {
+                "votes": -1,
+                "last_modified": -1,
+                "course.header.title": -1,
+                "_id": -1
+            },
+            doc! {
+                "downloads": 1,
+                "course.header.title": -1,
+                "_id": -1
+            },
+            doc! {
+                "downloads": -1,
+                "course.header.title": -1,
+                "_id": -1
+            },
+            doc! {
+                "trending": -1,
+                "course.header.title": -1,
+                "_id": -1
+            },
+            doc! {
+                "course.header.game_style": 1,
+                "course.course_area.course_theme": 1
+            },
+            doc! {
+                "course.course_area_sub.course_theme": 1
+            },
+            doc! {
+                "course.course_area.auto_scroll": 1
+            },
+            doc! {
+                "course.course_area_sub.auto_scroll": 1
+            },
+            doc! {
+                "course.header.clear_condition": 1
+            },
+            doc! {
+                "course.header.time": 1
+            },
+            doc! {
+                "uploaded": -1
+            },
+        ];
+        let listed_indexes: Vec<OrderedDocument> =
+            courses2.list_indexes()?.filter_map(Result::ok).collect();
+
+        // sort indexes used to end with the sort key, before `_id` has been added as tie breaker
+        let superseded_indexes: Vec<OrderedDocument> = indexes
+            .iter()
+            .filter(|index| index.contains_key("_id"))
+            .map(|index| {
+                let mut index = index.clone();
+                index.remove("_id");
+                index
+            })
+            .collect();
+        for idx in listed_indexes.iter() {
+            if let Ok(key) = idx.get_document("key") {
+                if superseded_indexes.contains(key) {
+                    println!("Dropping superseded index {}", key);
+                    if let Err(err) = courses2.drop_index(key.clone(), None) {
+                        println!("Failed to drop superseded index {}: {}", key, err);
+                    }
+                }
+            }
+        }
+
+        MongoDatabase::create_missing_indexes(courses2, &listed_indexes, indexes)?;
+        MongoDatabase::generate_course2_text_index(courses2)
+    }
+
+    /// Stemming is disabled by default, because course titles and descriptions are written in
+    /// all kinds of languages. It can still be enabled per query via `$language`.
+    fn generate_course2_text_index(courses2: &Collection) -> Result<(), mongodb::Error> {
+        let mut keys = OrderedDocument::new();
+        let mut weights = OrderedDocument::new();
+        for (field, weight) in COURSE2_TEXT_INDEX {
+            keys.insert(*field, "text");
+            weights.insert(*field, *weight);
+        }
+        let mut options = IndexOptions::new();
+        options.name = Some("course2_text".to_string());
+        options.default_language = Some("none".to_string());
+        options.language_override = Some("text_language".to_string());
+        options.weights = Some(weights);
+        courses2.create_index(keys, Some(options))?;
+        Ok(())
+    }
+
+    fn generate_votes_indexes(votes: &Collection) -> Result<(), mongodb::Error> {
+        let indexes = vec![doc! {
+            "account_id": 1,
+            "course_id": 1,
+        }];
+        let listed_indexes: Vec<OrderedDocument> =
+            votes.list_indexes()?.filter_map(Result::ok).collect();
+        MongoDatabase::create_missing_indexes(votes, &listed_indexes, indexes)
+    }
+
+    fn generate_downloads_indexes(downloads: &Collection) -> Result<(), mongodb::Error> {
+        let indexes = vec![doc! {
+            "course_id": 1,
+            "visitor": 1,
+        }];
+        let listed_indexes: Vec<OrderedDocument> =
+            downloads.list_indexes()?.filter_map(Result::ok).collect();
+        MongoDatabase::create_missing_indexes(downloads, &listed_indexes, indexes)
+    }
+
+    fn generate_course2_history_indexes(
+        course2_history: &Collection,
+    ) -> Result<(), mongodb::Error> {
+        let indexes = vec![doc! {
+            "course_id": 1,
+            "version": 1,
+        }];
+        let listed_indexes: Vec<OrderedDocument> = course2_history
+            .list_indexes()?
+            .filter_map(Result::ok)
+            .collect();
+        MongoDatabase::create_missing_indexes(course2_history, &listed_indexes, indexes)
+    }
+
+    fn generate_jobs_indexes(jobs: &Collection) -> Result<(), mongodb::Error> {
+        let indexes = vec![doc! {
+            "status": 1,
+            "run_after": 1,
+        }];
+        let listed_indexes: Vec<OrderedDocument> =
+            jobs.list_indexes()?.filter_map(Result::ok).collect();
+        MongoDatabase::create_missing_indexes(jobs, &listed_indexes, indexes)
+    }
+
+    /// Indexes are compared by their `key` document, because listed indexes also contain their
+    /// name, version and namespace.
+    fn create_missing_indexes(
+        collection: &Collection,
+        listed_indexes: &[OrderedDocument],
+        indexes: Vec<OrderedDocument>,
+    ) -> Result<(), mongodb::Error> {
+        for index in indexes {
+            if !listed_indexes
+                .iter()
+                .any(|idx| idx.get_document("key").map_or(false, |key| key == &index))
+            {
+                collection.create_index(index, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn find(
+        collection: &Collection,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let cursor = collection.find(
+            Some(filter),
+            Some(FindOptions {
+                projection,
+                ..FindOptions::default()
+            }),
+        )?;
+        Ok(cursor.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn find_one(
+        collection: &Collection,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        Ok(collection.find_one(
+            Some(filter),
+            Some(FindOptions {
+                projection,
+                ..FindOptions::default()
+            }),
+        )?)
+    }
+
+    fn aggregate(
+        collection: &Collection,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let cursor = collection.aggregate(query, None)?;
+        Ok(cursor.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn inserted_id(res: InsertOneResult) -> Result<ObjectId, DatabaseError> {
+        if let Some(write_exception) = res.write_exception {
+            return Err(write_exception.into());
+        }
+        let inserted_id = res
+            .inserted_id
+            .ok_or_else(|| mongodb::Error::ResponseError("inserted_id not given".to_string()))?;
+        let inserted_id = inserted_id.as_object_id().ok_or_else(|| {
+            mongodb::Error::ResponseError("inserted_id is not an ObjectId".to_string())
+        })?;
+        Ok(inserted_id.clone())
+    }
+
+    fn matched_count(res: UpdateResult) -> Result<u64, DatabaseError> {
+        if let Some(write_exception) = res.write_exception {
+            Err(write_exception.into())
+        } else {
+            Ok(res.matched_count as u64)
+        }
+    }
+
+    fn upsert() -> Option<UpdateOptions> {
+        Some(UpdateOptions {
+            upsert: Some(true),
+            ..UpdateOptions::default()
+        })
+    }
+}
+
+impl AccountStore for MongoDatabase {
+    fn find_account(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        MongoDatabase::find_one(&self.accounts, filter, None)
+    }
+
+    fn find_accounts(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        MongoDatabase::find(&self.accounts, filter, None)
+    }
+
+    fn insert_account(&self, account: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        MongoDatabase::inserted_id(self.accounts.insert_one(account, None)?)
+    }
+
+    fn update_account(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        MongoDatabase::matched_count(self.accounts.update_one(filter, update, None)?)?;
+        Ok(())
+    }
+}
+
+impl CourseStore for MongoDatabase {
+    fn get_courses(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        MongoDatabase::aggregate(&self.courses, query)
+    }
+
+    fn get_courses2(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        MongoDatabase::aggregate(&self.courses2, query)
+    }
+
+    fn find_courses2(
+        &self,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        MongoDatabase::find(&self.courses2, filter, projection)
+    }
+
+    fn count_courses2(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        Ok(self.courses2.count(Some(filter), None)? as u64)
+    }
+
+    fn insert_course2(&self, doc_meta: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        MongoDatabase::inserted_id(self.courses2.insert_one(doc_meta, None)?)
+    }
+
+    fn update_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        MongoDatabase::matched_count(self.courses2.update_one(filter, update, None)?)
+    }
+
+    fn delete_course2_meta(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        Ok(self.courses2.delete_one(filter, None)?.deleted_count as u64)
+    }
+
+    fn get_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        MongoDatabase::find_one(&self.course2_data, filter, Some(projection))
+    }
+
+    fn find_course2_data(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        MongoDatabase::find(&self.course2_data, filter, Some(projection))
+    }
+
+    fn insert_course2_data(&self, doc: OrderedDocument) -> Result<(), DatabaseError> {
+        let res = self.course2_data.insert_one(doc, None)?;
+        if let Some(write_exception) = res.write_exception {
+            Err(write_exception.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn update_course2_data(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        MongoDatabase::matched_count(self.course2_data.update_one(filter, update, None)?)?;
+        Ok(())
+    }
+
+    fn delete_course2_data(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        Ok(self.course2_data.delete_one(filter, None)?.deleted_count as u64)
+    }
+
+    fn find_course2_history(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        MongoDatabase::find(&self.course2_history, filter, Some(projection))
+    }
+
+    fn insert_course2_history(&self, doc: OrderedDocument) -> Result<(), DatabaseError> {
+        let res = self.course2_history.insert_one(doc, None)?;
+        if let Some(write_exception) = res.write_exception {
+            Err(write_exception.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn update_course2_history(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        MongoDatabase::matched_count(self.course2_history.update_one(filter, update, None)?)?;
+        Ok(())
+    }
+
+    fn delete_course2_history(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        Ok(self
+            .course2_history
+            .delete_many(filter, None)?
+            .deleted_count as u64)
+    }
+}
+
+impl VoteStore for MongoDatabase {
+    fn vote_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        MongoDatabase::matched_count(self.votes.update_one(
+            filter,
+            update,
+            MongoDatabase::upsert(),
+        )?)?;
+        Ok(())
+    }
+
+    fn unvote_course2(&self, filter: OrderedDocument) -> Result<(), DatabaseError> {
+        self.votes.delete_one(filter, None)?;
+        Ok(())
+    }
+
+    fn get_votes_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        MongoDatabase::find(&self.votes, filter, Some(projection))
+    }
+}
+
+impl DownloadStore for MongoDatabase {
+    fn find_download_course2(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        MongoDatabase::find_one(&self.downloads, filter, None)
+    }
+
+    fn upsert_download_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        MongoDatabase::matched_count(self.downloads.update_one(
+            filter,
+            update,
+            MongoDatabase::upsert(),
+        )?)?;
+        Ok(())
+    }
+}
+
+impl JobStore for MongoDatabase {
+    fn find_jobs(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        limit: Option<i64>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let cursor = self.jobs.find(
+            Some(filter),
+            Some(FindOptions {
+                sort: if sort.is_empty() { None } else { Some(sort) },
+                limit,
+                ..FindOptions::default()
+            }),
+        )?;
+        Ok(cursor.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn count_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        Ok(self.jobs.count(Some(filter), None)? as u64)
+    }
+
+    fn upsert_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        MongoDatabase::matched_count(self.jobs.update_one(
+            filter,
+            update,
+            MongoDatabase::upsert(),
+        )?)?;
+        Ok(())
+    }
+
+    fn claim_job(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        Ok(self.jobs.find_one_and_update(
+            filter,
+            update,
+            Some(FindOneAndUpdateOptions {
+                sort: Some(sort),
+                return_document: Some(ReturnDocument::After),
+                ..FindOneAndUpdateOptions::default()
+            }),
+        )?)
+    }
+
+    fn update_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        MongoDatabase::matched_count(self.jobs.update_one(filter, update, None)?)
+    }
+
+    fn delete_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        Ok(self.jobs.delete_many(filter, None)?.deleted_count as u64)
+    }
+}
+
+impl MetaStore for MongoDatabase {
+    fn find_meta(&self, filter: OrderedDocument) -> Result<Option<OrderedDocument>, DatabaseError> {
+        MongoDatabase::find_one(&self.meta, filter, None)
+    }
+
+    fn update_meta(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        MongoDatabase::matched_count(self.meta.update_one(
+            filter,
+            update,
+            MongoDatabase::upsert(),
+        )?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,734 @@
+use crate::{
+    collections::Collections,
+    query,
+    sql::{self, Dialect, Select, INDEXES},
+    AccountStore, CourseStore, DatabaseError, DownloadStore, JobStore, MetaStore, VoteStore,
+};
+
+use bson::{oid::ObjectId, ordered::OrderedDocument, Bson};
+use postgres::{types::ToSql, GenericClient, NoTls, Transaction};
+use r2d2::{Pool, PooledConnection};
+use r2d2_postgres::PostgresConnectionManager;
+
+type Row = (String, OrderedDocument);
+
+/// A database backend storing every collection as a table of BSON documents in PostgreSQL.
+///
+/// Like `SqliteDatabase`, documents are queried through a JSON representation of themselves,
+/// which is stored as `jsonb`.
+pub struct PostgresDatabase {
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+}
+
+impl PostgresDatabase {
+    pub fn new(uri: &str) -> Result<Self, DatabaseError> {
+        println!("Connecting to PostgreSQL...");
+        let manager = PostgresConnectionManager::new(uri.parse()?, NoTls);
+        let pool = Pool::new(manager)?;
+        let database = PostgresDatabase { pool };
+        database.generate_tables()?;
+        Ok(database)
+    }
+
+    fn generate_tables(&self) -> Result<(), DatabaseError> {
+        let mut conn = self.conn()?;
+        for collection in [
+            Collections::Courses,
+            Collections::CourseData,
+            Collections::Courses2,
+            Collections::Course2Data,
+            Collections::Course2History,
+            Collections::Accounts,
+            Collections::Votes,
+            Collections::Downloads,
+            Collections::Jobs,
+            Collections::Meta,
+        ]
+        .iter()
+        {
+            conn.batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS \"{table}\" (
+                    seq BIGSERIAL NOT NULL,
+                    id TEXT PRIMARY KEY NOT NULL,
+                    doc BYTEA NOT NULL,
+                    fields JSONB NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS \"{table}__id\" ON \"{table}\" ({id});",
+                table = collection.as_str(),
+                id = sql::index_expr("_id", Dialect::Postgres)
+            ))?;
+        }
+
+        for (collection, name, keys) in INDEXES {
+            let columns: Vec<String> = keys
+                .iter()
+                .map(|key| sql::index_expr(key, Dialect::Postgres))
+                .collect();
+            conn.batch_execute(&format!(
+                "CREATE INDEX IF NOT EXISTS \"{table}_{name}\" ON \"{table}\" ({columns});",
+                table = collection.as_str(),
+                name = name,
+                columns = columns.join(", ")
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn conn(&self) -> Result<PooledConnection<PostgresConnectionManager<NoTls>>, DatabaseError> {
+        Ok(self.pool.get()?)
+    }
+
+    /// Runs `f` in a transaction, which locks the table against concurrent writes from the
+    /// start, so that read-modify-write cycles of concurrent requests cannot interleave.
+    fn write<T, F>(&self, table: &str, f: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&mut Transaction) -> Result<T, DatabaseError>,
+    {
+        let mut conn = self.conn()?;
+        let mut tx = conn.transaction()?;
+        tx.batch_execute(&format!(
+            "LOCK TABLE \"{}\" IN SHARE ROW EXCLUSIVE MODE",
+            table
+        ))?;
+        let res = f(&mut tx)?;
+        tx.commit()?;
+        Ok(res)
+    }
+
+    fn encode(doc: &OrderedDocument) -> Result<Vec<u8>, DatabaseError> {
+        let mut buf = vec![];
+        bson::encode_document(&mut buf, doc)?;
+        Ok(buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<OrderedDocument, DatabaseError> {
+        Ok(bson::decode_document(&mut &buf[..])?)
+    }
+
+    fn params(select: &Select) -> Vec<&(dyn ToSql + Sync)> {
+        select
+            .params
+            .iter()
+            .map(|param| param as &(dyn ToSql + Sync))
+            .collect()
+    }
+
+    /// Runs `select` and evaluates the given filter in memory, if `select` only prefilters it.
+    fn select<C: GenericClient>(
+        client: &mut C,
+        table: &str,
+        select: &Select,
+        filter: Option<&OrderedDocument>,
+    ) -> Result<Vec<Row>, DatabaseError> {
+        let params = PostgresDatabase::params(select);
+        let mut res = vec![];
+        for row in client.query(select.to_sql(table).as_str(), &params)? {
+            let data: &[u8] = row.get(1);
+            let doc = PostgresDatabase::decode(data)?;
+            if let Some(filter) = filter {
+                if !query::matches(&doc, filter)? {
+                    continue;
+                }
+            }
+            let id: String = row.get(0);
+            res.push((id, doc));
+        }
+        Ok(res)
+    }
+
+    fn find_rows<C: GenericClient>(
+        client: &mut C,
+        table: &str,
+        filter: &OrderedDocument,
+        limit: Option<i64>,
+    ) -> Result<Vec<Row>, DatabaseError> {
+        let mut select = Select::from_filter(filter, Dialect::Postgres)?;
+        if !select.prefiltered {
+            select.limit = limit;
+            return PostgresDatabase::select(client, table, &select, None);
+        }
+        let mut rows = PostgresDatabase::select(client, table, &select, Some(filter))?;
+        if let Some(limit) = limit {
+            rows.truncate(limit as usize);
+        }
+        Ok(rows)
+    }
+
+    fn find(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let mut conn = self.conn()?;
+        let rows = PostgresDatabase::find_rows(&mut *conn, collection.as_str(), &filter, None)?;
+        Ok(rows
+            .into_iter()
+            .map(|(_, doc)| match &projection {
+                Some(projection) => query::project(&doc, projection),
+                None => doc,
+            })
+            .collect())
+    }
+
+    fn find_one(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        let mut conn = self.conn()?;
+        let row = PostgresDatabase::find_rows(&mut *conn, collection.as_str(), &filter, Some(1))?
+            .into_iter()
+            .next();
+        Ok(row.map(|(_, doc)| match &projection {
+            Some(projection) => query::project(&doc, projection),
+            None => doc,
+        }))
+    }
+
+    fn count(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        let mut conn = self.conn()?;
+        let table = collection.as_str();
+        let select = Select::from_filter(&filter, Dialect::Postgres)?;
+        if select.prefiltered {
+            let rows = PostgresDatabase::select(&mut *conn, table, &select, Some(&filter))?;
+            return Ok(rows.len() as u64);
+        }
+        let params = PostgresDatabase::params(&select);
+        let count: i64 = conn
+            .query_one(select.to_count_sql(table).as_str(), &params)?
+            .get(0);
+        Ok(count as u64)
+    }
+
+    fn aggregate(
+        &self,
+        collection: Collections,
+        pipeline: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let mut conn = self.conn()?;
+        let (select, translated) = Select::from_pipeline(&pipeline, Dialect::Postgres)?;
+        let docs = PostgresDatabase::select(&mut *conn, collection.as_str(), &select, None)?
+            .into_iter()
+            .map(|(_, doc)| doc)
+            .collect();
+        query::aggregate(docs, &pipeline[translated..])
+    }
+
+    fn insert_row(
+        tx: &mut Transaction,
+        table: &str,
+        doc: &OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        let id = doc.get("_id").map(sql::row_id).unwrap_or_default();
+        tx.execute(
+            format!(
+                "INSERT INTO \"{}\" (id, doc, fields) VALUES ($1, $2, $3)",
+                table
+            )
+            .as_str(),
+            &[&id, &PostgresDatabase::encode(doc)?, &sql::to_json(doc)],
+        )?;
+        Ok(())
+    }
+
+    fn update_row(
+        tx: &mut Transaction,
+        table: &str,
+        id: &str,
+        doc: &OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        tx.execute(
+            format!(
+                "UPDATE \"{}\" SET doc = $1, fields = $2 WHERE id = $3",
+                table
+            )
+            .as_str(),
+            &[&PostgresDatabase::encode(doc)?, &sql::to_json(doc), &id],
+        )?;
+        Ok(())
+    }
+
+    fn insert_one(
+        &self,
+        collection: Collections,
+        mut doc: OrderedDocument,
+    ) -> Result<ObjectId, DatabaseError> {
+        let id = match doc.get("_id") {
+            Some(Bson::ObjectId(id)) => id.clone(),
+            _ => {
+                let id = ObjectId::new()?;
+                doc.insert("_id", id.clone());
+                id
+            }
+        };
+        let table = collection.as_str();
+        self.write(table, |tx| PostgresDatabase::insert_row(tx, table, &doc))?;
+        Ok(id)
+    }
+
+    /// Returns the number of matched documents.
+    fn update_one(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+        upsert: bool,
+    ) -> Result<u64, DatabaseError> {
+        let table = collection.as_str();
+        self.write(table, |tx| {
+            let row = PostgresDatabase::find_rows(tx, table, &filter, Some(1))?
+                .into_iter()
+                .next();
+            match row {
+                Some((id, mut doc)) => {
+                    query::apply_update(&mut doc, &update)?;
+                    PostgresDatabase::update_row(tx, table, &id, &doc)?;
+                    Ok(1)
+                }
+                None if upsert => {
+                    let mut doc = query::upsert_document(&filter);
+                    query::apply_insert_update(&mut doc, &update)?;
+                    if doc.get("_id").is_none() {
+                        doc.insert("_id", ObjectId::new()?);
+                    }
+                    PostgresDatabase::insert_row(tx, table, &doc)?;
+                    Ok(0)
+                }
+                None => Ok(0),
+            }
+        })
+    }
+
+    /// Updates the first document matched by `filter` in the order of `sort` and returns it.
+    ///
+    /// The order is only evaluated in memory, if the filter or sort can't be translated.
+    fn find_one_and_update(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        let table = collection.as_str();
+        self.write(table, |tx| {
+            let mut select = Select::from_filter(&filter, Dialect::Postgres)?;
+            let row = if !select.prefiltered && select.add_sort(&sort) {
+                select.limit = Some(1);
+                PostgresDatabase::select(tx, table, &select, None)?
+                    .into_iter()
+                    .next()
+            } else {
+                let mut rows = PostgresDatabase::select(tx, table, &select, Some(&filter))?;
+                rows.sort_by(|(_, a), (_, b)| query::sort_by(a, b, &sort));
+                rows.into_iter().next()
+            };
+            match row {
+                Some((id, mut doc)) => {
+                    query::apply_update(&mut doc, &update)?;
+                    PostgresDatabase::update_row(tx, table, &id, &doc)?;
+                    Ok(Some(doc))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Returns the number of deleted documents.
+    fn delete_many(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        let table = collection.as_str();
+        self.write(table, |tx| {
+            let rows = PostgresDatabase::find_rows(tx, table, &filter, None)?;
+            let mut deleted = 0;
+            for (id, _) in rows {
+                deleted += tx.execute(
+                    format!("DELETE FROM \"{}\" WHERE id = $1", table).as_str(),
+                    &[&id],
+                )?;
+            }
+            Ok(deleted)
+        })
+    }
+
+    /// Returns the number of deleted documents.
+    fn delete_one(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        let table = collection.as_str();
+        self.write(table, |tx| {
+            let row = PostgresDatabase::find_rows(tx, table, &filter, Some(1))?
+                .into_iter()
+                .next();
+            match row {
+                Some((id, _)) => Ok(tx.execute(
+                    format!("DELETE FROM \"{}\" WHERE id = $1", table).as_str(),
+                    &[&id],
+                )?),
+                None => Ok(0),
+            }
+        })
+    }
+}
+
+impl AccountStore for PostgresDatabase {
+    fn find_account(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one(Collections::Accounts, filter, None)
+    }
+
+    fn find_accounts(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Accounts, filter, None)
+    }
+
+    fn insert_account(&self, account: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        self.insert_one(Collections::Accounts, account)
+    }
+
+    fn update_account(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Accounts, filter, update, false)?;
+        Ok(())
+    }
+}
+
+impl CourseStore for PostgresDatabase {
+    fn get_courses(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.aggregate(Collections::Courses, query)
+    }
+
+    fn get_courses2(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.aggregate(Collections::Courses2, query)
+    }
+
+    fn find_courses2(
+        &self,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Courses2, filter, projection)
+    }
+
+    fn count_courses2(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.count(Collections::Courses2, filter)
+    }
+
+    fn insert_course2(&self, doc_meta: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        self.insert_one(Collections::Courses2, doc_meta)
+    }
+
+    fn update_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        self.update_one(Collections::Courses2, filter, update, false)
+    }
+
+    fn delete_course2_meta(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.delete_one(Collections::Courses2, filter)
+    }
+
+    fn get_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one(Collections::Course2Data, filter, Some(projection))
+    }
+
+    fn find_course2_data(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Course2Data, filter, Some(projection))
+    }
+
+    fn insert_course2_data(&self, doc: OrderedDocument) -> Result<(), DatabaseError> {
+        self.insert_one(Collections::Course2Data, doc)?;
+        Ok(())
+    }
+
+    fn update_course2_data(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Course2Data, filter, update, false)?;
+        Ok(())
+    }
+
+    fn delete_course2_data(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.delete_one(Collections::Course2Data, filter)
+    }
+
+    fn find_course2_history(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Course2History, filter, Some(projection))
+    }
+
+    fn insert_course2_history(&self, doc: OrderedDocument) -> Result<(), DatabaseError> {
+        self.insert_one(Collections::Course2History, doc)?;
+        Ok(())
+    }
+
+    fn update_course2_history(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Course2History, filter, update, false)?;
+        Ok(())
+    }
+
+    fn delete_course2_history(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.delete_many(Collections::Course2History, filter)
+    }
+}
+
+impl VoteStore for PostgresDatabase {
+    fn vote_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Votes, filter, update, true)?;
+        Ok(())
+    }
+
+    fn unvote_course2(&self, filter: OrderedDocument) -> Result<(), DatabaseError> {
+        self.delete_one(Collections::Votes, filter)?;
+        Ok(())
+    }
+
+    fn get_votes_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Votes, filter, Some(projection))
+    }
+}
+
+impl DownloadStore for PostgresDatabase {
+    fn find_download_course2(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one(Collections::Downloads, filter, None)
+    }
+
+    fn upsert_download_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Downloads, filter, update, true)?;
+        Ok(())
+    }
+}
+
+impl JobStore for PostgresDatabase {
+    fn find_jobs(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        limit: Option<i64>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.aggregate(Collections::Jobs, query::find_pipeline(filter, sort, limit))
+    }
+
+    fn count_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.count(Collections::Jobs, filter)
+    }
+
+    fn upsert_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Jobs, filter, update, true)?;
+        Ok(())
+    }
+
+    fn claim_job(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one_and_update(Collections::Jobs, filter, sort, update)
+    }
+
+    fn update_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        self.update_one(Collections::Jobs, filter, update, false)
+    }
+
+    fn delete_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.delete_many(Collections::Jobs, filter)
+    }
+}
+
+impl MetaStore for PostgresDatabase {
+    fn find_meta(&self, filter: OrderedDocument) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one(Collections::Meta, filter, None)
+    }
+
+    fn update_meta(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Meta, filter, update, true)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres::Client;
+    use serde_json::json;
+    use std::env;
+
+    /// A database in a temporary schema, which is dropped on drop.
+    ///
+    /// Only created if `SMMDB_TEST_POSTGRES_URI` points to a PostgreSQL server, otherwise the
+    /// tests using it are skipped.
+    struct TestDatabase {
+        database: PostgresDatabase,
+        uri: String,
+        schema: String,
+    }
+
+    impl TestDatabase {
+        fn new() -> Option<Self> {
+            let uri = env::var("SMMDB_TEST_POSTGRES_URI").ok()?;
+            let schema = format!("smmdb_test_{}", ObjectId::new().unwrap().to_hex());
+            Client::connect(&uri, NoTls)
+                .unwrap()
+                .batch_execute(&format!("CREATE SCHEMA {}", schema))
+                .unwrap();
+            let separator = if uri.contains('?') { '&' } else { '?' };
+            let database = PostgresDatabase::new(&format!(
+                "{}{}options=-c%20search_path%3D{}",
+                uri, separator, schema
+            ))
+            .unwrap();
+            Some(TestDatabase {
+                database,
+                uri,
+                schema,
+            })
+        }
+    }
+
+    impl Drop for TestDatabase {
+        fn drop(&mut self) {
+            if let Ok(mut client) = Client::connect(&self.uri, NoTls) {
+                let _ = client.batch_execute(&format!("DROP SCHEMA {} CASCADE", self.schema));
+            }
+        }
+    }
+
+    #[test]
+    fn translates_filters_to_jsonb() {
+        let filter = doc! {
+            "votes" => { "$gt" => 1 },
+            "tags" => "castle",
+            "course.header.description" => { "$exists" => false }
+        };
+        let select = Select::from_filter(&filter, Dialect::Postgres).unwrap();
+        assert_eq!(
+            select.filter.as_deref(),
+            Some(
+                "(fields #> '{votes}') > $1::jsonb \
+                 AND ((fields #> '{tags}') = $2::jsonb \
+                 OR (jsonb_typeof((fields #> '{tags}')) = 'array' \
+                 AND EXISTS (SELECT 1 FROM jsonb_array_elements((fields #> '{tags}')) \
+                 AS elements(value) WHERE value = $3::jsonb))) \
+                 AND NOT ((fields #> '{course,header,description}') IS NOT NULL)"
+            )
+        );
+        assert_eq!(
+            select.params,
+            vec![json!(1), json!("castle"), json!("castle")]
+        );
+        assert_eq!(
+            select.to_sql("courses2"),
+            format!(
+                "SELECT id, doc FROM \"courses2\" WHERE {} ORDER BY seq",
+                select.filter.as_deref().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn evaluates_query_cases() {
+        if let Some(db) = TestDatabase::new() {
+            query::tests::check_course_store(&db.database);
+        }
+    }
+
+    #[test]
+    fn claims_jobs_in_sort_order() {
+        let db = match TestDatabase::new() {
+            Some(db) => db,
+            None => return,
+        };
+        for run_after in &[3, 1, 2] {
+            db.database
+                .upsert_job(
+                    doc! { "run_after" => *run_after },
+                    doc! { "$set" => { "status" => "pending" } },
+                )
+                .unwrap();
+        }
+        let claim = |sort: OrderedDocument| {
+            db.database
+                .claim_job(
+                    doc! { "status" => "pending" },
+                    sort,
+                    doc! { "$set" => { "status" => "running" } },
+                )
+                .unwrap()
+                .map(|job| job.get_i32("run_after").unwrap())
+        };
+        assert_eq!(claim(doc! { "run_after" => 1 }), Some(1));
+        assert_eq!(claim(doc! { "run_after" => -1 }), Some(3));
+        assert_eq!(claim(doc! { "run_after" => 1 }), Some(2));
+        assert_eq!(claim(doc! { "run_after" => 1 }), None);
+    }
+}
@@ -0,0 +1,826 @@
+//! Evaluation of the MongoDB query subset used by SMMDB on plain documents.
+
+use crate::{
+    text::{TextSearch, TEXT_SCORE_FIELD},
+    DatabaseError,
+};
+
+use bson::{doc, ordered::OrderedDocument, Bson};
+use rand::seq::SliceRandom;
+use regex::RegexBuilder;
+use std::{cmp::Ordering, collections::HashMap};
+
+pub fn get_path<'a>(doc: &'a OrderedDocument, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut current = doc.get(parts.next()?)?;
+    for part in parts {
+        current = match current {
+            Bson::Document(doc) => doc.get(part)?,
+            Bson::Array(array) => array.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+pub fn set_path(doc: &mut OrderedDocument, path: &str, value: Bson) {
+    match path.split_once('.') {
+        Some((head, tail)) => {
+            if !matches!(doc.get(head), Some(Bson::Document(_))) {
+                doc.insert(head, OrderedDocument::new());
+            }
+            if let Some(Bson::Document(inner)) = doc.get_mut(head) {
+                set_path(inner, tail, value);
+            }
+        }
+        None => {
+            doc.insert(path, value);
+        }
+    }
+}
+
+pub fn unset_path(doc: &mut OrderedDocument, path: &str) {
+    match path.split_once('.') {
+        Some((head, tail)) => {
+            if let Some(Bson::Document(inner)) = doc.get_mut(head) {
+                unset_path(inner, tail);
+            }
+        }
+        None => {
+            doc.remove(path);
+        }
+    }
+}
+
+pub fn is_truthy(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(b) => *b,
+        Bson::I32(i) => *i != 0,
+        Bson::I64(i) => *i != 0,
+        Bson::FloatingPoint(f) => *f != 0.0,
+        Bson::Null => false,
+        _ => true,
+    }
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::I32(i) => Some(f64::from(*i)),
+        Bson::I64(i) => Some(*i as f64),
+        Bson::FloatingPoint(f) => Some(*f),
+        _ => None,
+    }
+}
+
+fn type_rank(value: Option<&Bson>) -> u8 {
+    match value {
+        None | Some(Bson::Null) => 0,
+        Some(Bson::I32(_)) | Some(Bson::I64(_)) | Some(Bson::FloatingPoint(_)) => 1,
+        Some(Bson::String(_)) | Some(Bson::Symbol(_)) => 2,
+        Some(Bson::Document(_)) => 3,
+        Some(Bson::Array(_)) => 4,
+        Some(Bson::Binary(_, _)) => 5,
+        Some(Bson::ObjectId(_)) => 6,
+        Some(Bson::Boolean(_)) => 7,
+        Some(Bson::UtcDatetime(_)) | Some(Bson::TimeStamp(_)) => 8,
+        _ => 9,
+    }
+}
+
+/// Compares two values of the same kind. Returns `None` for values that cannot be compared.
+pub fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (as_f64(a), as_f64(b)) {
+        return a.partial_cmp(&b);
+    }
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::UtcDatetime(a), Bson::UtcDatetime(b)) => Some(a.cmp(b)),
+        (Bson::TimeStamp(a), Bson::TimeStamp(b)) => Some(a.cmp(b)),
+        (Bson::Null, Bson::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+/// Total order over optional values, following MongoDB's cross-type sort order.
+pub fn sort_order(a: Option<&Bson>, b: Option<&Bson>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            compare(a, b).unwrap_or_else(|| type_rank(Some(a)).cmp(&type_rank(Some(b))))
+        }
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+fn equals(value: &Bson, other: &Bson) -> bool {
+    match (value, other) {
+        (Bson::Array(values), other) if !matches!(other, Bson::Array(_)) => {
+            values.iter().any(|value| equals(value, other))
+        }
+        _ => compare(value, other).map_or_else(|| value == other, |ord| ord == Ordering::Equal),
+    }
+}
+
+fn regex_matches(value: &Bson, pattern: &str, options: &str) -> Result<bool, DatabaseError> {
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(options.contains('i'))
+        .multi_line(options.contains('m'))
+        .build()?;
+    Ok(match value {
+        Bson::String(s) => regex.is_match(s),
+        Bson::Array(values) => values
+            .iter()
+            .any(|value| matches!(value, Bson::String(s) if regex.is_match(s))),
+        _ => false,
+    })
+}
+
+fn is_operator_doc(value: &Bson) -> bool {
+    match value {
+        Bson::Document(doc) => doc.keys().next().map_or(false, |key| key.starts_with('$')),
+        _ => false,
+    }
+}
+
+pub fn matches(doc: &OrderedDocument, filter: &OrderedDocument) -> Result<bool, DatabaseError> {
+    for (key, condition) in filter.iter() {
+        let matched = match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let filters = condition
+                    .as_array()
+                    .ok_or_else(|| DatabaseError::UnsupportedQuery(key.clone()))?;
+                let mut results = vec![];
+                for filter in filters {
+                    let filter = filter
+                        .as_document()
+                        .ok_or_else(|| DatabaseError::UnsupportedQuery(key.clone()))?;
+                    results.push(matches(doc, filter)?);
+                }
+                match key.as_str() {
+                    "$and" => results.iter().all(|res| *res),
+                    "$or" => results.iter().any(|res| *res),
+                    _ => !results.iter().any(|res| *res),
+                }
+            }
+            "$text" => TextSearch::from_query(condition)?.score(doc).is_some(),
+            _ => matches_condition(get_path(doc, key), condition)?,
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn matches_condition(value: Option<&Bson>, condition: &Bson) -> Result<bool, DatabaseError> {
+    match condition {
+        Bson::Document(operators) if is_operator_doc(condition) => {
+            for (op, arg) in operators.iter() {
+                let ord = value.and_then(|value| compare(value, arg));
+                let matched = match op.as_str() {
+                    "$eq" => value.map_or(arg == &Bson::Null, |value| equals(value, arg)),
+                    "$ne" => !value.map_or(arg == &Bson::Null, |value| equals(value, arg)),
+                    "$gt" => ord == Some(Ordering::Greater),
+                    "$gte" => matches!(ord, Some(Ordering::Greater) | Some(Ordering::Equal)),
+                    "$lt" => ord == Some(Ordering::Less),
+                    "$lte" => matches!(ord, Some(Ordering::Less) | Some(Ordering::Equal)),
+                    "$in" | "$nin" => {
+                        let args = arg
+                            .as_array()
+                            .ok_or_else(|| DatabaseError::UnsupportedQuery(op.clone()))?;
+                        let mut found = false;
+                        for arg in args {
+                            if matches_condition(value, arg)? {
+                                found = true;
+                                break;
+                            }
+                        }
+                        found == (op == "$in")
+                    }
+                    "$exists" => value.is_some() == is_truthy(arg),
+                    "$regex" => {
+                        let pattern = arg
+                            .as_str()
+                            .ok_or_else(|| DatabaseError::UnsupportedQuery(op.clone()))?;
+                        let options = operators.get_str("$options").unwrap_or_default();
+                        value.map_or(Ok(false), |value| regex_matches(value, pattern, options))?
+                    }
+                    "$options" => true,
+                    "$not" => !matches_condition(value, arg)?,
+                    _ => return Err(DatabaseError::UnsupportedQuery(op.clone())),
+                };
+                if !matched {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        Bson::RegExp(pattern, options) => {
+            value.map_or(Ok(false), |value| regex_matches(value, pattern, options))
+        }
+        _ => Ok(value.map_or(condition == &Bson::Null, |value| equals(value, condition))),
+    }
+}
+
+/// Applies a `$set`/`$unset`/`$push`/`$inc` update document.
+///
+/// `$setOnInsert` is skipped, see `apply_insert_update`.
+pub fn apply_update(
+    doc: &mut OrderedDocument,
+    update: &OrderedDocument,
+) -> Result<(), DatabaseError> {
+    for (op, fields) in update.iter() {
+        let fields = fields
+            .as_document()
+            .ok_or_else(|| DatabaseError::UnsupportedQuery(op.clone()))?;
+        for (path, value) in fields.iter() {
+            match op.as_str() {
+                "$set" => set_path(doc, path, value.clone()),
+                "$setOnInsert" => {}
+                "$unset" => unset_path(doc, path),
+                "$push" => {
+                    let mut array = match get_path(doc, path) {
+                        Some(Bson::Array(array)) => array.clone(),
+                        _ => vec![],
+                    };
+                    array.push(value.clone());
+                    set_path(doc, path, Bson::Array(array));
+                }
+                "$inc" => {
+                    let current = get_path(doc, path).cloned().unwrap_or(Bson::I32(0));
+                    let res = match (current, value) {
+                        (Bson::I32(a), Bson::I32(b)) => Bson::I32(a + b),
+                        (current, value) => match (as_f64(&current), as_f64(value)) {
+                            (Some(a), Some(b)) if a.fract() == 0.0 && b.fract() == 0.0 => {
+                                Bson::I64((a + b) as i64)
+                            }
+                            (Some(a), Some(b)) => Bson::FloatingPoint(a + b),
+                            _ => return Err(DatabaseError::UnsupportedQuery(op.clone())),
+                        },
+                    };
+                    set_path(doc, path, res);
+                }
+                _ => return Err(DatabaseError::UnsupportedQuery(op.clone())),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies an update document to the document inserted by an upsert.
+pub fn apply_insert_update(
+    doc: &mut OrderedDocument,
+    update: &OrderedDocument,
+) -> Result<(), DatabaseError> {
+    apply_update(doc, update)?;
+    if let Ok(fields) = update.get_document("$setOnInsert") {
+        for (path, value) in fields.iter() {
+            set_path(doc, path, value.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Builds the document inserted by an upsert from the equality fields of `filter`.
+pub fn upsert_document(filter: &OrderedDocument) -> OrderedDocument {
+    let mut doc = OrderedDocument::new();
+    for (key, value) in filter.iter() {
+        if !key.starts_with('$') && !is_operator_doc(value) && !matches!(value, Bson::RegExp(_, _))
+        {
+            set_path(&mut doc, key, value.clone());
+        }
+    }
+    doc
+}
+
+pub fn project(doc: &OrderedDocument, projection: &OrderedDocument) -> OrderedDocument {
+    let include_id = projection.get("_id").map_or(true, is_truthy);
+    let inclusive = projection
+        .iter()
+        .any(|(key, value)| key != "_id" && is_truthy(value));
+    if inclusive {
+        let mut res = OrderedDocument::new();
+        if include_id {
+            if let Some(id) = doc.get("_id") {
+                res.insert("_id", id.clone());
+            }
+        }
+        for (key, value) in projection.iter() {
+            if key == "_id" || !is_truthy(value) {
+                continue;
+            }
+            if let Some(value) = get_path(doc, key) {
+                set_path(&mut res, key, value.clone());
+            }
+        }
+        res
+    } else {
+        let mut res = doc.clone();
+        for (key, _) in projection.iter() {
+            if key != "_id" || !include_id {
+                unset_path(&mut res, key);
+            }
+        }
+        res
+    }
+}
+
+pub fn sort(docs: &mut [OrderedDocument], sort: &OrderedDocument) {
+    docs.sort_by(|a, b| sort_by(a, b, sort));
+}
+
+/// Compares two documents by the keys of a `$sort` document.
+pub fn sort_by(a: &OrderedDocument, b: &OrderedDocument, sort: &OrderedDocument) -> Ordering {
+    for (key, dir) in sort.iter() {
+        let ord = sort_order(get_path(a, key), get_path(b, key));
+        let ord = if as_f64(dir).unwrap_or(1.0) < 0.0 {
+            ord.reverse()
+        } else {
+            ord
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+fn as_usize(stage: &str, value: &Bson) -> Result<usize, DatabaseError> {
+    as_f64(value)
+        .filter(|value| *value >= 0.0)
+        .map(|value| value as usize)
+        .ok_or_else(|| DatabaseError::UnsupportedQuery(stage.to_string()))
+}
+
+/// Evaluates a field path like `"$course.header.title"` or returns literal values as they are.
+fn expression_value(doc: &OrderedDocument, expression: &Bson) -> Bson {
+    match expression {
+        Bson::String(path) if path.starts_with('$') => {
+            get_path(doc, &path[1..]).cloned().unwrap_or(Bson::Null)
+        }
+        _ => expression.clone(),
+    }
+}
+
+fn group(
+    docs: Vec<OrderedDocument>,
+    spec: &OrderedDocument,
+) -> Result<Vec<OrderedDocument>, DatabaseError> {
+    let unsupported = || DatabaseError::UnsupportedQuery("$group".to_string());
+    let id = spec.get("_id").ok_or_else(unsupported)?;
+    let mut accumulators = vec![];
+    for (field, accumulator) in spec.iter() {
+        if field == "_id" {
+            continue;
+        }
+        let summand = accumulator
+            .as_document()
+            .and_then(|accumulator| accumulator.get("$sum"))
+            .ok_or_else(unsupported)?;
+        accumulators.push((field, summand));
+    }
+
+    let mut groups: Vec<(Bson, Vec<(f64, bool)>)> = vec![];
+    let mut group_indices = HashMap::new();
+    for doc in docs {
+        let key = expression_value(&doc, id);
+        let index = *group_indices
+            .entry(format!("{:?}", key))
+            .or_insert_with(|| {
+                groups.push((key, vec![(0., true); accumulators.len()]));
+                groups.len() - 1
+            });
+        for ((_, summand), (sum, integral)) in accumulators.iter().zip(groups[index].1.iter_mut()) {
+            let value = expression_value(&doc, summand);
+            if let Some(summand) = as_f64(&value) {
+                *sum += summand;
+                *integral &= !matches!(value, Bson::FloatingPoint(_));
+            }
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(key, sums)| {
+            let mut res = doc! { "_id" => key };
+            for ((field, _), (sum, integral)) in accumulators.iter().zip(sums) {
+                if integral {
+                    res.insert(field.to_string(), sum as i64);
+                } else {
+                    res.insert(field.to_string(), sum);
+                }
+            }
+            res
+        })
+        .collect())
+}
+
+/// Builds the pipeline of a find with an optional `sort` and `limit`.
+pub fn find_pipeline(
+    filter: OrderedDocument,
+    sort: OrderedDocument,
+    limit: Option<i64>,
+) -> Vec<OrderedDocument> {
+    let mut pipeline = vec![doc! { "$match" => filter }];
+    if !sort.is_empty() {
+        pipeline.push(doc! { "$sort" => sort });
+    }
+    if let Some(limit) = limit {
+        pipeline.push(doc! { "$limit" => limit });
+    }
+    pipeline
+}
+
+/// Runs an aggregation pipeline consisting of `$match`, `$sort`, `$skip`, `$limit`,
+/// `$project`, `$addFields`, `$group`, `$facet`, `$sample` and `$count` stages.
+///
+/// `$addFields` only supports literal values and `{ "$meta": "textScore" }`, `$group` only
+/// supports the `$sum` accumulator.
+pub fn aggregate(
+    mut docs: Vec<OrderedDocument>,
+    pipeline: &[OrderedDocument],
+) -> Result<Vec<OrderedDocument>, DatabaseError> {
+    for stage in pipeline {
+        let (name, arg) = stage
+            .iter()
+            .next()
+            .ok_or_else(|| DatabaseError::UnsupportedQuery("empty stage".to_string()))?;
+        docs = match name.as_str() {
+            "$match" => {
+                let filter = arg
+                    .as_document()
+                    .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?;
+                let text_search = match filter.get("$text") {
+                    Some(query) => Some(TextSearch::from_query(query)?),
+                    None => None,
+                };
+                let mut res = vec![];
+                for mut doc in docs {
+                    if matches(&doc, filter)? {
+                        if let Some(score) = text_search.as_ref().and_then(|text| text.score(&doc))
+                        {
+                            doc.insert(TEXT_SCORE_FIELD, score);
+                        }
+                        res.push(doc);
+                    }
+                }
+                res
+            }
+            "$addFields" => {
+                let fields = arg
+                    .as_document()
+                    .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?;
+                let mut res = vec![];
+                for mut doc in docs {
+                    for (path, value) in fields.iter() {
+                        let value = match value {
+                            Bson::Document(meta)
+                                if meta.get_str("$meta").ok() == Some("textScore") =>
+                            {
+                                doc.get(TEXT_SCORE_FIELD)
+                                    .cloned()
+                                    .unwrap_or(Bson::FloatingPoint(0.))
+                            }
+                            _ if is_operator_doc(value) => {
+                                return Err(DatabaseError::UnsupportedQuery(name.clone()))
+                            }
+                            _ => value.clone(),
+                        };
+                        set_path(&mut doc, path, value);
+                    }
+                    res.push(doc);
+                }
+                res
+            }
+            "$sort" => {
+                let sort_doc = arg
+                    .as_document()
+                    .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?;
+                sort(&mut docs, sort_doc);
+                docs
+            }
+            "$skip" => docs.into_iter().skip(as_usize(name, arg)?).collect(),
+            "$limit" => docs.into_iter().take(as_usize(name, arg)?).collect(),
+            "$project" => {
+                let projection = arg
+                    .as_document()
+                    .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?;
+                docs.iter().map(|doc| project(doc, projection)).collect()
+            }
+            "$sample" => {
+                let size = arg
+                    .as_document()
+                    .and_then(|sample| sample.get("size"))
+                    .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?;
+                let size = as_usize(name, size)?;
+                docs.shuffle(&mut rand::thread_rng());
+                docs.truncate(size);
+                docs
+            }
+            "$group" => {
+                let spec = arg
+                    .as_document()
+                    .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?;
+                group(docs, spec)?
+            }
+            "$facet" => {
+                let facets = arg
+                    .as_document()
+                    .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?;
+                let mut res = OrderedDocument::new();
+                for (facet, pipeline) in facets.iter() {
+                    let pipeline = pipeline
+                        .as_array()
+                        .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?
+                        .iter()
+                        .map(|stage| stage.as_document().cloned())
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?;
+                    let facet_docs: Vec<Bson> = aggregate(docs.clone(), &pipeline)?
+                        .into_iter()
+                        .map(Bson::Document)
+                        .collect();
+                    res.insert(facet.clone(), facet_docs);
+                }
+                vec![res]
+            }
+            "$count" => {
+                let field = arg
+                    .as_str()
+                    .ok_or_else(|| DatabaseError::UnsupportedQuery(name.clone()))?;
+                let mut res = OrderedDocument::new();
+                res.insert(field, docs.len() as i64);
+                vec![res]
+            }
+            _ => return Err(DatabaseError::UnsupportedQuery(name.clone())),
+        };
+    }
+    for doc in docs.iter_mut() {
+        doc.remove(TEXT_SCORE_FIELD);
+    }
+    Ok(docs)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::CourseStore;
+
+    fn strings(values: &[&str]) -> Bson {
+        Bson::Array(values.iter().map(|value| Bson::from(*value)).collect())
+    }
+
+    fn course(n: i32, title: &str, description: Option<&str>, uploader: &str) -> OrderedDocument {
+        let mut header = doc! {
+            "title" => title,
+            "game_style" => n % 3,
+            "time" => n * 100
+        };
+        if let Some(description) = description {
+            header.insert("description", description);
+        }
+        doc! {
+            "n" => n,
+            "uploader" => uploader,
+            "course" => {
+                "header" => header
+            }
+        }
+    }
+
+    /// Documents of `courses2` which the cases below are evaluated on.
+    pub fn fixture() -> Vec<OrderedDocument> {
+        let mut docs = vec![
+            course(1, "Speedrun Castle", Some("A fast castle"), "alice"),
+            course(2, "Puzzle Cave", Some("Think before you jump"), "bob"),
+            course(3, "Castle Puzzle", Some("speedrun puzzle"), "alice"),
+            course(4, "Auto Level", None, "carol"),
+        ];
+        docs[0].insert("votes", 5);
+        docs[0].insert("tags", strings(&["speedrun", "castle"]));
+        docs[0].insert("unlisted", false);
+        docs[1].insert("votes", 0);
+        docs[1].insert("tags", strings(&["puzzle"]));
+        docs[2].insert("votes", 12);
+        docs[2].insert("unlisted", true);
+        docs[3].insert("votes", -1);
+        docs[3].insert("tags", strings(&[]));
+        docs
+    }
+
+    /// Filters and the numbers of the documents of `fixture` they match, in insertion order.
+    pub fn filter_cases() -> Vec<(OrderedDocument, Vec<i32>)> {
+        vec![
+            (doc! {}, vec![1, 2, 3, 4]),
+            (doc! { "uploader" => "alice" }, vec![1, 3]),
+            (doc! { "course.header.game_style" => 2 }, vec![2]),
+            (doc! { "votes" => { "$gt" => 0 } }, vec![1, 3]),
+            (doc! { "votes" => { "$gte" => 0, "$lt" => 10 } }, vec![1, 2]),
+            (doc! { "votes" => { "$ne" => 5 } }, vec![2, 3, 4]),
+            (
+                doc! { "votes" => { "$in" => vec![Bson::I32(0), Bson::I32(12)] } },
+                vec![2, 3],
+            ),
+            (doc! { "tags" => "puzzle" }, vec![2]),
+            (
+                doc! { "tags" => { "$in" => strings(&["castle", "puzzle"]) } },
+                vec![1, 2],
+            ),
+            (
+                doc! { "tags" => { "$nin" => strings(&["puzzle"]) } },
+                vec![1, 3, 4],
+            ),
+            (doc! { "tags" => { "$ne" => "castle" } }, vec![2, 3, 4]),
+            (doc! { "tags" => { "$exists" => false } }, vec![3]),
+            (doc! { "unlisted" => true }, vec![3]),
+            (doc! { "unlisted" => { "$ne" => true } }, vec![1, 2, 4]),
+            (
+                doc! { "course.header.description" => { "$exists" => false } },
+                vec![4],
+            ),
+            (
+                doc! {
+                    "$or" => vec![
+                        Bson::Document(doc! { "uploader" => "bob" }),
+                        Bson::Document(doc! { "votes" => { "$lt" => 0 } }),
+                    ]
+                },
+                vec![2, 4],
+            ),
+            (
+                doc! {
+                    "$nor" => vec![Bson::Document(doc! { "uploader" => "alice" })]
+                },
+                vec![2, 4],
+            ),
+            (
+                doc! {
+                    "course.header.title" => Bson::RegExp("^castle".to_string(), "i".to_string())
+                },
+                vec![3],
+            ),
+            (
+                doc! { "course.header.title" => { "$regex" => "cave", "$options" => "i" } },
+                vec![2],
+            ),
+            (
+                doc! { "tags" => Bson::RegExp("^speed".to_string(), String::new()) },
+                vec![1],
+            ),
+            (doc! { "$text" => { "$search" => "castle" } }, vec![1, 3]),
+            (
+                doc! { "$text" => { "$search" => "\"think before\"" } },
+                vec![2],
+            ),
+            (
+                doc! { "$text" => { "$search" => "puzzle -castle" } },
+                vec![2],
+            ),
+            (
+                doc! { "$text" => { "$search" => "castle" }, "uploader" => "alice" },
+                vec![1, 3],
+            ),
+        ]
+    }
+
+    /// Pipelines and the numbers of the documents of `fixture` they return, in order.
+    pub fn pipeline_cases() -> Vec<(Vec<OrderedDocument>, Vec<i32>)> {
+        vec![
+            (
+                vec![
+                    doc! { "$match" => { "uploader" => "alice" } },
+                    doc! { "$sort" => { "votes" => -1 } },
+                ],
+                vec![3, 1],
+            ),
+            (
+                vec![
+                    doc! { "$sort" => { "votes" => 1 } },
+                    doc! { "$skip" => 1 },
+                    doc! { "$limit" => 2 },
+                ],
+                vec![2, 1],
+            ),
+            (
+                vec![
+                    doc! { "$sort" => { "course.header.time" => 1 } },
+                    doc! { "$limit" => 2 },
+                    doc! { "$match" => { "course.header.game_style" => 1 } },
+                ],
+                vec![1],
+            ),
+            (
+                vec![
+                    doc! { "$match" => { "$text" => { "$search" => "castle" } } },
+                    doc! { "$sort" => { "votes" => 1 } },
+                ],
+                vec![1, 3],
+            ),
+            (
+                vec![
+                    doc! { "$match" => { "tags" => "castle" } },
+                    doc! { "$project" => { "n" => 1 } },
+                ],
+                vec![1],
+            ),
+        ]
+    }
+
+    fn numbers(docs: &[OrderedDocument]) -> Vec<i32> {
+        docs.iter().map(|doc| doc.get_i32("n").unwrap()).collect()
+    }
+
+    /// Checks a backend against the cases, which are evaluated in memory by the tests below.
+    pub fn check_course_store<S: CourseStore>(store: &S) {
+        for doc in fixture() {
+            store.insert_course2(doc).unwrap();
+        }
+        for (filter, expected) in filter_cases() {
+            let found = store.find_courses2(filter.clone(), None).unwrap();
+            assert_eq!(numbers(&found), expected, "{}", filter);
+            let count = store.count_courses2(filter.clone()).unwrap();
+            assert_eq!(count, expected.len() as u64, "{}", filter);
+        }
+        for (pipeline, expected) in pipeline_cases() {
+            let found = store.get_courses2(pipeline.clone()).unwrap();
+            assert_eq!(numbers(&found), expected, "{:?}", pipeline);
+        }
+    }
+
+    #[test]
+    fn matches_filter_cases() {
+        let docs = fixture();
+        for (filter, expected) in filter_cases() {
+            let found: Vec<OrderedDocument> = docs
+                .iter()
+                .filter(|doc| matches(doc, &filter).unwrap())
+                .cloned()
+                .collect();
+            assert_eq!(numbers(&found), expected, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn aggregates_pipeline_cases() {
+        for (pipeline, expected) in pipeline_cases() {
+            let found = aggregate(fixture(), &pipeline).unwrap();
+            assert_eq!(numbers(&found), expected, "{:?}", pipeline);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_operators() {
+        let doc = fixture().remove(0);
+        assert!(matches(&doc, &doc! { "tags" => { "$size" => 2 } }).is_err());
+        assert!(apply_update(
+            &mut doc.clone(),
+            &doc! { "$pull" => { "tags" => "castle" } }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn applies_updates() {
+        let mut doc = fixture().remove(0);
+        apply_update(
+            &mut doc,
+            &doc! {
+                "$set" => { "course.header.title" => "Renamed" },
+                "$unset" => { "unlisted" => "" },
+                "$inc" => { "votes" => 2, "downloads" => 1 },
+                "$push" => { "tags" => "new" },
+                "$setOnInsert" => { "uploaded" => 1 }
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            get_path(&doc, "course.header.title"),
+            Some(&Bson::from("Renamed"))
+        );
+        assert_eq!(
+            get_path(&doc, "course.header.game_style"),
+            Some(&Bson::I32(1))
+        );
+        assert_eq!(doc.get("unlisted"), None);
+        assert_eq!(doc.get("votes"), Some(&Bson::I32(7)));
+        assert_eq!(doc.get("downloads"), Some(&Bson::I32(1)));
+        assert_eq!(
+            doc.get("tags"),
+            Some(&strings(&["speedrun", "castle", "new"]))
+        );
+        assert_eq!(doc.get("uploaded"), None);
+    }
+
+    #[test]
+    fn builds_upserted_documents() {
+        let filter = doc! {
+            "course_id" => "abc",
+            "votes" => { "$gt" => 1 },
+            "$or" => vec![Bson::Document(doc! { "n" => 1 })]
+        };
+        let mut doc = upsert_document(&filter);
+        apply_insert_update(&mut doc, &doc! { "$setOnInsert" => { "votes" => 0 } }).unwrap();
+        assert_eq!(doc, doc! { "course_id" => "abc", "votes" => 0 });
+    }
+}
@@ -0,0 +1,671 @@
+//! Translation of the MongoDB query subset used by SMMDB into SQL.
+//!
+//! Documents are stored next to a JSON representation of themselves, which is what the generated
+//! clauses operate on. SQLite queries it with the JSON1 functions `json_extract`, `json_type` and
+//! `json_each` and the `REGEXP` function registered by `SqliteDatabase`, PostgreSQL with the
+//! `jsonb` operators and POSIX regular expressions.
+//!
+//! Filters that cannot be translated are rejected with `DatabaseError::UnsupportedQuery` instead
+//! of being evaluated on a full table scan. `$text` is translated into a prefilter on the fields
+//! of the text index and evaluated in memory on the remaining candidates.
+
+use crate::{
+    collections::Collections,
+    query,
+    text::{TextSearch, COURSE2_TEXT_INDEX},
+    DatabaseError,
+};
+
+use bson::{ordered::OrderedDocument, Bson};
+use serde_json::{Map, Number, Value as JsonValue};
+
+/// Indexes on the JSON representation of the documents.
+///
+/// Indexed fields are assumed to never hold arrays, so that equality on them is translated into
+/// plain comparisons, which can use these indexes.
+pub const INDEXES: &[(Collections, &str, &[&str])] = &[
+    (Collections::Accounts, "apikey", &["apikey"]),
+    (
+        Collections::Courses2,
+        "last_modified_title",
+        &["last_modified", "course.header.title"],
+    ),
+    (
+        Collections::Courses2,
+        "uploaded_title",
+        &["uploaded", "course.header.title"],
+    ),
+    (
+        Collections::Courses2,
+        "votes_title",
+        &["votes", "course.header.title"],
+    ),
+    (
+        Collections::Courses2,
+        "votes_last_modified_title",
+        &["votes", "last_modified", "course.header.title"],
+    ),
+    (
+        Collections::Courses2,
+        "downloads_title",
+        &["downloads", "course.header.title"],
+    ),
+    (
+        Collections::Courses2,
+        "trending_title",
+        &["trending", "course.header.title"],
+    ),
+    (Collections::Courses2, "owner", &["owner"]),
+    (
+        Collections::Courses2,
+        "game_style_course_theme",
+        &[
+            "course.header.game_style",
+            "course.course_area.course_theme",
+        ],
+    ),
+    (
+        Collections::Courses2,
+        "course_theme_sub",
+        &["course.course_area_sub.course_theme"],
+    ),
+    (
+        Collections::Courses2,
+        "auto_scroll",
+        &["course.course_area.auto_scroll"],
+    ),
+    (
+        Collections::Courses2,
+        "auto_scroll_sub",
+        &["course.course_area_sub.auto_scroll"],
+    ),
+    (
+        Collections::Courses2,
+        "clear_condition",
+        &["course.header.clear_condition"],
+    ),
+    (Collections::Courses2, "time", &["course.header.time"]),
+    (
+        Collections::Course2History,
+        "course_version",
+        &["course_id", "version"],
+    ),
+    (
+        Collections::Votes,
+        "account_course",
+        &["account_id", "course_id"],
+    ),
+    (
+        Collections::Downloads,
+        "course_visitor",
+        &["course_id", "visitor"],
+    ),
+    (
+        Collections::Jobs,
+        "status_run_after",
+        &["status", "run_after"],
+    ),
+];
+
+/// The SQL dialect of the generated statements.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dialect {
+    Sqlite,
+    /// Parameters are bound as `jsonb`.
+    Postgres,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect::Sqlite
+    }
+}
+
+/// Converts a document into the JSON representation that is used for querying.
+///
+/// Object ids are converted to hex strings and dates to milliseconds. Binary data is replaced by
+/// `null`, so that `$exists` still works on such fields.
+pub fn to_json(doc: &OrderedDocument) -> JsonValue {
+    let mut map = Map::new();
+    for (key, value) in doc.iter() {
+        map.insert(key.clone(), bson_to_json(value));
+    }
+    JsonValue::Object(map)
+}
+
+fn bson_to_json(value: &Bson) -> JsonValue {
+    match value {
+        Bson::FloatingPoint(val) => Number::from_f64(*val)
+            .map(JsonValue::Number)
+            .unwrap_or(JsonValue::Null),
+        Bson::String(val) | Bson::Symbol(val) => JsonValue::String(val.clone()),
+        Bson::Array(array) => JsonValue::Array(array.iter().map(bson_to_json).collect()),
+        Bson::Document(doc) => to_json(doc),
+        Bson::Boolean(val) => JsonValue::Bool(*val),
+        Bson::I32(val) => (*val).into(),
+        Bson::I64(val) | Bson::TimeStamp(val) => (*val).into(),
+        Bson::ObjectId(oid) => JsonValue::String(oid.to_hex()),
+        Bson::UtcDatetime(date) => date.timestamp_millis().into(),
+        _ => JsonValue::Null,
+    }
+}
+
+/// Converts a scalar value into the JSON value it is compared against.
+pub fn to_sql_value(value: &Bson) -> Option<JsonValue> {
+    match value {
+        Bson::Array(_) | Bson::Document(_) => None,
+        Bson::FloatingPoint(val) => Number::from_f64(*val).map(JsonValue::Number),
+        Bson::String(_)
+        | Bson::Symbol(_)
+        | Bson::Boolean(_)
+        | Bson::I32(_)
+        | Bson::I64(_)
+        | Bson::TimeStamp(_)
+        | Bson::ObjectId(_)
+        | Bson::UtcDatetime(_)
+        | Bson::Null => Some(bson_to_json(value)),
+        _ => None,
+    }
+}
+
+/// The key of the row that stores a document with the given `_id`.
+pub fn row_id(id: &Bson) -> String {
+    match id {
+        Bson::ObjectId(oid) => oid.to_hex(),
+        Bson::String(val) => val.clone(),
+        _ => bson_to_json(id).to_string(),
+    }
+}
+
+/// Returns the expression of an indexed field.
+pub fn index_expr(key: &str, dialect: Dialect) -> String {
+    Field::new(key, dialect).expect("index keys are valid").expr
+}
+
+struct Field {
+    expr: String,
+    path: Option<String>,
+    /// Whether the field never holds an array, so that array elements need not be considered.
+    scalar: bool,
+    dialect: Dialect,
+}
+
+impl Field {
+    fn new(key: &str, dialect: Dialect) -> Option<Self> {
+        if key == "_id" && dialect == Dialect::Sqlite {
+            return Some(Field {
+                expr: "id".to_string(),
+                path: None,
+                scalar: true,
+                dialect,
+            });
+        }
+        let valid = key.split('.').all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+        if !valid {
+            return None;
+        }
+        let (expr, path) = match dialect {
+            Dialect::Sqlite => {
+                let path = format!("$.{}", key);
+                (format!("json_extract(fields, '{}')", path), path)
+            }
+            Dialect::Postgres => {
+                let path = format!("{{{}}}", key.replace('.', ","));
+                (format!("(fields #> '{}')", path), path)
+            }
+        };
+        Some(Field {
+            expr,
+            path: Some(path),
+            scalar: key == "_id"
+                || INDEXES
+                    .iter()
+                    .any(|(_, _, keys)| keys.iter().any(|indexed| *indexed == key)),
+            dialect,
+        })
+    }
+
+    fn is_null(&self) -> String {
+        match self.dialect {
+            Dialect::Sqlite => format!("{} IS NULL", self.expr),
+            Dialect::Postgres => format!("COALESCE({} = 'null'::jsonb, TRUE)", self.expr),
+        }
+    }
+
+    fn exists(&self) -> String {
+        match (self.dialect, &self.path) {
+            (Dialect::Sqlite, Some(path)) => format!("json_type(fields, '{}') IS NOT NULL", path),
+            (Dialect::Sqlite, None) => "TRUE".to_string(),
+            (Dialect::Postgres, _) => format!("{} IS NOT NULL", self.expr),
+        }
+    }
+
+    /// Applies `clause` to the field itself and, if it holds an array, to each of its elements.
+    ///
+    /// `clause` is called with the expression to test and binds its parameters once per call.
+    fn any_element<F>(&self, select: &mut Select, clause: F) -> String
+    where
+        F: Fn(&mut Select, &str) -> String,
+    {
+        let direct = clause(select, &self.expr);
+        let path = match &self.path {
+            Some(path) if !self.scalar => path,
+            _ => return direct,
+        };
+        let element = clause(select, "value");
+        match self.dialect {
+            Dialect::Sqlite => format!(
+                "({direct} OR (json_type(fields, '{path}') = 'array' AND EXISTS \
+                 (SELECT 1 FROM json_each(fields, '{path}') WHERE {element})))",
+                direct = direct,
+                path = path,
+                element = element,
+            ),
+            Dialect::Postgres => format!(
+                "({direct} OR (jsonb_typeof({expr}) = 'array' AND EXISTS \
+                 (SELECT 1 FROM jsonb_array_elements({expr}) AS elements(value) \
+                 WHERE {element})))",
+                direct = direct,
+                expr = self.expr,
+                element = element,
+            ),
+        }
+    }
+}
+
+/// A `SELECT` on a document table, built from the leading stages of an aggregation pipeline.
+#[derive(Debug, Default)]
+pub struct Select {
+    pub dialect: Dialect,
+    pub filter: Option<String>,
+    pub order_by: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: i64,
+    pub params: Vec<JsonValue>,
+    /// Whether `filter` only preselects candidates, which still have to be matched in memory.
+    pub prefiltered: bool,
+}
+
+impl Select {
+    pub fn from_filter(filter: &OrderedDocument, dialect: Dialect) -> Result<Self, DatabaseError> {
+        let mut select = Select {
+            dialect,
+            ..Select::default()
+        };
+        select.filter = Some(select.match_clause(filter)?);
+        Ok(select)
+    }
+
+    /// Translates as many leading `$match`, `$sort`, `$skip`, `$limit` and `$sample` stages as
+    /// possible.
+    ///
+    /// Returns the index of the first stage that has to be evaluated in memory. A `$match` stage
+    /// containing `$text` is translated into a prefilter and evaluated in memory again.
+    pub fn from_pipeline(
+        pipeline: &[OrderedDocument],
+        dialect: Dialect,
+    ) -> Result<(Self, usize), DatabaseError> {
+        let mut select = Select {
+            dialect,
+            ..Select::default()
+        };
+        for (index, stage) in pipeline.iter().enumerate() {
+            let translated = match stage.iter().next() {
+                Some((op, Bson::Document(filter))) if op == "$match" => {
+                    let translated = select.add_match(filter)?;
+                    if select.prefiltered {
+                        return Ok((select, index));
+                    }
+                    translated
+                }
+                Some((op, Bson::Document(sort))) if op == "$sort" => select.add_sort(sort),
+                Some((op, skip)) if op == "$skip" => select.add_skip(skip),
+                Some((op, limit)) if op == "$limit" => select.add_limit(limit),
+                Some((op, Bson::Document(sample))) if op == "$sample" => select.add_sample(sample),
+                _ => false,
+            };
+            if !translated {
+                return Ok((select, index));
+            }
+        }
+        Ok((select, pipeline.len()))
+    }
+
+    pub fn to_sql(&self, table: &str) -> String {
+        let mut sql = format!("SELECT id, doc FROM \"{}\"", table);
+        if let Some(filter) = &self.filter {
+            sql.push_str(&format!(" WHERE {}", filter));
+        }
+        let (natural_order, no_limit) = match self.dialect {
+            Dialect::Sqlite => ("rowid", " LIMIT -1"),
+            Dialect::Postgres => ("seq", ""),
+        };
+        sql.push_str(&format!(
+            " ORDER BY {}",
+            self.order_by.as_deref().unwrap_or(natural_order)
+        ));
+        match self.limit {
+            Some(limit) => sql.push_str(&format!(" LIMIT {}", limit)),
+            None => sql.push_str(no_limit),
+        }
+        if self.offset > 0 {
+            sql.push_str(&format!(" OFFSET {}", self.offset));
+        }
+        sql
+    }
+
+    pub fn to_count_sql(&self, table: &str) -> String {
+        let mut sql = format!("SELECT COUNT(*) FROM \"{}\"", table);
+        if let Some(filter) = &self.filter {
+            sql.push_str(&format!(" WHERE {}", filter));
+        }
+        sql
+    }
+
+    /// Binds a parameter and returns its placeholder.
+    fn param(&mut self, value: JsonValue) -> String {
+        self.params.push(value);
+        match self.dialect {
+            Dialect::Sqlite => "?".to_string(),
+            Dialect::Postgres => format!("${}::jsonb", self.params.len()),
+        }
+    }
+
+    fn regex_match(&mut self, expr: &str, pattern: String) -> String {
+        let pattern = self.param(JsonValue::String(pattern));
+        match self.dialect {
+            Dialect::Sqlite => format!("{} REGEXP {}", expr, pattern),
+            Dialect::Postgres => format!("({} #>> '{{}}') ~ ({} #>> '{{}}')", expr, pattern),
+        }
+    }
+
+    /// Returns `false`, if the stage has to be evaluated in memory, because it follows a stage
+    /// which cannot be combined with a `WHERE` clause.
+    fn add_match(&mut self, filter: &OrderedDocument) -> Result<bool, DatabaseError> {
+        if self.order_by.is_some() || self.limit.is_some() || self.offset > 0 {
+            return Ok(false);
+        }
+        let clause = self.match_clause(filter)?;
+        self.filter = Some(match self.filter.take() {
+            Some(filter) => format!("({}) AND ({})", filter, clause),
+            None => clause,
+        });
+        Ok(true)
+    }
+
+    /// Translates a filter, which may contain a top level `$text` query.
+    fn match_clause(&mut self, filter: &OrderedDocument) -> Result<String, DatabaseError> {
+        let mut filter = filter.clone();
+        let text_clause = match filter.remove("$text") {
+            Some(query) => {
+                self.prefiltered = true;
+                Some(self.text_clause(&TextSearch::from_query(&query)?))
+            }
+            None => None,
+        };
+        let clause = self
+            .where_clause(&filter)
+            .ok_or_else(|| DatabaseError::UnsupportedQuery(format!("{}", filter)))?;
+        Ok(match text_clause {
+            Some(text_clause) => format!("({}) AND ({})", text_clause, clause),
+            None => clause,
+        })
+    }
+
+    /// Preselects documents, which contain all substrings of the text search in one of the fields
+    /// of the text index.
+    fn text_clause(&mut self, text_search: &TextSearch) -> String {
+        let patterns = match text_search.candidate_patterns() {
+            Some(patterns) => patterns,
+            None => return "FALSE".to_string(),
+        };
+        let mut clauses = vec![];
+        for pattern in patterns {
+            let mut fields = vec![];
+            for (path, _) in COURSE2_TEXT_INDEX {
+                let field = Field::new(path, self.dialect).expect("text index paths are valid");
+                fields.push(self.regex_match(&field.expr, pattern.clone()));
+            }
+            clauses.push(format!("({})", fields.join(" OR ")));
+        }
+        if clauses.is_empty() {
+            "TRUE".to_string()
+        } else {
+            clauses.join(" AND ")
+        }
+    }
+
+    /// Returns `false`, if the sort has to be evaluated in memory.
+    pub fn add_sort(&mut self, sort: &OrderedDocument) -> bool {
+        if self.order_by.is_some() || self.limit.is_some() || self.offset > 0 {
+            return false;
+        }
+        let mut order_by = vec![];
+        for (key, dir) in sort.iter() {
+            let field = match Field::new(key, self.dialect) {
+                Some(field) => field,
+                None => return false,
+            };
+            let descending = match dir {
+                Bson::I32(dir) => *dir < 0,
+                Bson::I64(dir) => *dir < 0,
+                Bson::FloatingPoint(dir) => *dir < 0.,
+                _ => return false,
+            };
+            // missing fields come first in ascending order, like in MongoDB
+            let order = match (self.dialect, descending) {
+                (Dialect::Sqlite, false) => "ASC",
+                (Dialect::Sqlite, true) => "DESC",
+                (Dialect::Postgres, false) => "ASC NULLS FIRST",
+                (Dialect::Postgres, true) => "DESC NULLS LAST",
+            };
+            order_by.push(format!("{} {}", field.expr, order));
+        }
+        if !order_by.is_empty() {
+            self.order_by = Some(order_by.join(", "));
+        }
+        true
+    }
+
+    fn add_sample(&mut self, sample: &OrderedDocument) -> bool {
+        if self.order_by.is_some() || self.limit.is_some() || self.offset > 0 {
+            return false;
+        }
+        match sample.get("size").and_then(to_count) {
+            Some(size) => {
+                self.order_by = Some("RANDOM()".to_string());
+                self.limit = Some(size);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn add_skip(&mut self, skip: &Bson) -> bool {
+        match to_count(skip) {
+            Some(skip) => {
+                self.offset += skip;
+                self.limit = self.limit.map(|limit| (limit - skip).max(0));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn add_limit(&mut self, limit: &Bson) -> bool {
+        match to_count(limit) {
+            Some(limit) => {
+                self.limit = Some(self.limit.map_or(limit, |current| current.min(limit)));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn where_clause(&mut self, filter: &OrderedDocument) -> Option<String> {
+        let mut clauses = vec![];
+        for (key, value) in filter.iter() {
+            let clause = match key.as_str() {
+                "$and" | "$or" | "$nor" => {
+                    let mut sub_clauses = vec![];
+                    for filter in value.as_array()? {
+                        sub_clauses
+                            .push(format!("({})", self.where_clause(filter.as_document()?)?));
+                    }
+                    match key.as_str() {
+                        "$and" if sub_clauses.is_empty() => "TRUE".to_string(),
+                        "$and" => sub_clauses.join(" AND "),
+                        "$or" if sub_clauses.is_empty() => "FALSE".to_string(),
+                        "$or" => format!("({})", sub_clauses.join(" OR ")),
+                        _ if sub_clauses.is_empty() => "TRUE".to_string(),
+                        _ => format!("({}) IS NOT TRUE", sub_clauses.join(" OR ")),
+                    }
+                }
+                _ if key.starts_with('$') => return None,
+                _ => self.field_clause(&Field::new(key, self.dialect)?, value)?,
+            };
+            clauses.push(clause);
+        }
+        if clauses.is_empty() {
+            Some("TRUE".to_string())
+        } else {
+            Some(clauses.join(" AND "))
+        }
+    }
+
+    fn field_clause(&mut self, field: &Field, value: &Bson) -> Option<String> {
+        match value {
+            Bson::Document(doc) if doc.keys().any(|key| key.starts_with('$')) => {
+                let mut clauses = vec![];
+                for (op, operand) in doc.iter() {
+                    clauses.push(self.operator_clause(field, op, operand, doc)?);
+                }
+                Some(clauses.join(" AND "))
+            }
+            Bson::RegExp(pattern, options) => Some(self.regex_clause(field, pattern, options)),
+            _ => self.eq_clause(field, value),
+        }
+    }
+
+    fn eq_clause(&mut self, field: &Field, value: &Bson) -> Option<String> {
+        match to_sql_value(value)? {
+            JsonValue::Null => Some(field.is_null()),
+            value => Some(field.any_element(self, |select, expr| {
+                format!("{} = {}", expr, select.param(value.clone()))
+            })),
+        }
+    }
+
+    fn operator_clause(
+        &mut self,
+        field: &Field,
+        op: &str,
+        operand: &Bson,
+        doc: &OrderedDocument,
+    ) -> Option<String> {
+        let comparison = match op {
+            "$gt" => ">",
+            "$gte" => ">=",
+            "$lt" => "<",
+            "$lte" => "<=",
+            "$eq" => return self.eq_clause(field, operand),
+            "$ne" => {
+                let clause = self.eq_clause(field, operand)?;
+                return Some(format!("({}) IS NOT TRUE", clause));
+            }
+            "$in" | "$nin" => {
+                let mut values = vec![];
+                let mut null = false;
+                for value in operand.as_array()? {
+                    match to_sql_value(value)? {
+                        JsonValue::Null => null = true,
+                        value => values.push(value),
+                    }
+                }
+                let mut clauses = vec![];
+                if null {
+                    clauses.push(field.is_null());
+                }
+                if !values.is_empty() {
+                    clauses.push(field.any_element(self, |select, expr| {
+                        let placeholders: Vec<String> = values
+                            .iter()
+                            .map(|value| select.param(value.clone()))
+                            .collect();
+                        format!("{} IN ({})", expr, placeholders.join(", "))
+                    }));
+                }
+                let clause = if clauses.is_empty() {
+                    "FALSE".to_string()
+                } else {
+                    format!("({})", clauses.join(" OR "))
+                };
+                return if op == "$in" {
+                    Some(clause)
+                } else {
+                    Some(format!("({}) IS NOT TRUE", clause))
+                };
+            }
+            "$exists" => {
+                let exists = field.exists();
+                return if query::is_truthy(operand) {
+                    Some(exists)
+                } else {
+                    Some(format!("NOT ({})", exists))
+                };
+            }
+            "$regex" => {
+                let pattern = match operand {
+                    Bson::String(pattern) => pattern,
+                    Bson::RegExp(pattern, _) => pattern,
+                    _ => return None,
+                };
+                let options = doc.get_str("$options").unwrap_or_default();
+                return Some(self.regex_clause(field, pattern, options));
+            }
+            "$options" => return Some("TRUE".to_string()),
+            "$not" => {
+                let clause = self.field_clause(field, operand)?;
+                return Some(format!("({}) IS NOT TRUE", clause));
+            }
+            _ => return None,
+        };
+        match to_sql_value(operand)? {
+            JsonValue::Null => None,
+            value => Some(format!(
+                "{} {} {}",
+                field.expr,
+                comparison,
+                self.param(value)
+            )),
+        }
+    }
+
+    fn regex_clause(&mut self, field: &Field, pattern: &str, options: &str) -> String {
+        let flags: String = options
+            .chars()
+            .filter(|c| matches!(c, 'i' | 'm' | 's' | 'x'))
+            .collect();
+        let pattern = if flags.is_empty() {
+            pattern.to_string()
+        } else {
+            format!("(?{}){}", flags, pattern)
+        };
+        field.any_element(self, |select, expr| {
+            select.regex_match(expr, pattern.clone())
+        })
+    }
+}
+
+fn to_count(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::I32(val) if *val >= 0 => Some(*val as i64),
+        Bson::I64(val) if *val >= 0 => Some(*val),
+        _ => None,
+    }
+}
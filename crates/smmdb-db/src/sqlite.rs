@@ -0,0 +1,726 @@
+use crate::{
+    collections::Collections,
+    query,
+    sql::{self, Dialect, Select, INDEXES},
+    AccountStore, CourseStore, DatabaseError, DownloadStore, JobStore, MetaStore, VoteStore,
+};
+
+use bson::{oid::ObjectId, ordered::OrderedDocument, Bson};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use regex::Regex;
+use rusqlite::{
+    functions::FunctionFlags,
+    types::{Value, ValueRef},
+    Connection, ToSql, Transaction, TransactionBehavior,
+};
+use serde_json::Value as JsonValue;
+use std::sync::Arc;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+type Row = (String, OrderedDocument);
+
+/// A database backend storing every collection as a table of BSON documents in SQLite.
+pub struct SqliteDatabase {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl SqliteDatabase {
+    pub fn new(path: &str) -> Result<Self, DatabaseError> {
+        println!("Opening SQLite database at {}", path);
+        let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")?;
+            SqliteDatabase::register_regexp(conn)
+        });
+        let pool = Pool::new(manager)?;
+        let database = SqliteDatabase { pool };
+        database.generate_tables()?;
+        Ok(database)
+    }
+
+    fn register_regexp(conn: &Connection) -> Result<(), rusqlite::Error> {
+        conn.create_scalar_function(
+            "regexp",
+            2,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| {
+                let regex: Arc<Regex> = ctx.get_or_create_aux(0, |pattern| {
+                    Regex::new(pattern.as_str()?).map_err(|err| -> BoxError { Box::new(err) })
+                })?;
+                match ctx.get_raw(1) {
+                    ValueRef::Text(value) => Ok(regex.is_match(&String::from_utf8_lossy(value))),
+                    _ => Ok(false),
+                }
+            },
+        )
+    }
+
+    fn generate_tables(&self) -> Result<(), DatabaseError> {
+        let conn = self.pool.get()?;
+        for collection in [
+            Collections::Courses,
+            Collections::CourseData,
+            Collections::Courses2,
+            Collections::Course2Data,
+            Collections::Course2History,
+            Collections::Accounts,
+            Collections::Votes,
+            Collections::Downloads,
+            Collections::Jobs,
+            Collections::Meta,
+        ]
+        .iter()
+        {
+            conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS \"{}\" (
+                    id TEXT PRIMARY KEY NOT NULL,
+                    doc BLOB NOT NULL,
+                    fields TEXT NOT NULL
+                );",
+                collection.as_str()
+            ))?;
+        }
+
+        for (collection, name, keys) in INDEXES {
+            let columns: Vec<String> = keys
+                .iter()
+                .map(|key| sql::index_expr(key, Dialect::Sqlite))
+                .collect();
+            conn.execute_batch(&format!(
+                "CREATE INDEX IF NOT EXISTS \"{table}_{name}\" ON \"{table}\" ({columns});",
+                table = collection.as_str(),
+                name = name,
+                columns = columns.join(", ")
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>, DatabaseError> {
+        Ok(self.pool.get()?)
+    }
+
+    /// Runs `f` in a transaction, which holds the write lock from the start, so that
+    /// read-modify-write cycles of concurrent requests cannot interleave.
+    fn write<T, F>(&self, f: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&Transaction) -> Result<T, DatabaseError>,
+    {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let res = f(&tx)?;
+        tx.commit()?;
+        Ok(res)
+    }
+
+    fn encode(doc: &OrderedDocument) -> Result<Vec<u8>, DatabaseError> {
+        let mut buf = vec![];
+        bson::encode_document(&mut buf, doc)?;
+        Ok(buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<OrderedDocument, DatabaseError> {
+        Ok(bson::decode_document(&mut &buf[..])?)
+    }
+
+    /// Binds JSON parameters as the values `json_extract` returns for them.
+    fn params(select: &Select) -> Vec<Value> {
+        select
+            .params
+            .iter()
+            .map(|param| match param {
+                JsonValue::Null => Value::Null,
+                JsonValue::Bool(val) => Value::Integer(*val as i64),
+                JsonValue::Number(val) => match val.as_i64() {
+                    Some(val) => Value::Integer(val),
+                    None => Value::Real(val.as_f64().unwrap_or_default()),
+                },
+                JsonValue::String(val) => Value::Text(val.clone()),
+                JsonValue::Array(_) | JsonValue::Object(_) => Value::Text(param.to_string()),
+            })
+            .collect()
+    }
+
+    /// Runs `select` and evaluates the given filter in memory, if `select` only prefilters it.
+    fn select(
+        conn: &Connection,
+        table: &str,
+        select: &Select,
+        filter: Option<&OrderedDocument>,
+    ) -> Result<Vec<Row>, DatabaseError> {
+        let mut stmt = conn.prepare(&select.to_sql(table))?;
+        let rows = stmt.query_map(&SqliteDatabase::params(select), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        let mut res = vec![];
+        for row in rows {
+            let (id, buf) = row?;
+            let doc = SqliteDatabase::decode(&buf)?;
+            if let Some(filter) = filter {
+                if !query::matches(&doc, filter)? {
+                    continue;
+                }
+            }
+            res.push((id, doc));
+        }
+        Ok(res)
+    }
+
+    fn find_rows(
+        conn: &Connection,
+        table: &str,
+        filter: &OrderedDocument,
+        limit: Option<i64>,
+    ) -> Result<Vec<Row>, DatabaseError> {
+        let mut select = Select::from_filter(filter, Dialect::Sqlite)?;
+        if !select.prefiltered {
+            select.limit = limit;
+            return SqliteDatabase::select(conn, table, &select, None);
+        }
+        let mut rows = SqliteDatabase::select(conn, table, &select, Some(filter))?;
+        if let Some(limit) = limit {
+            rows.truncate(limit as usize);
+        }
+        Ok(rows)
+    }
+
+    fn find(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let conn = self.conn()?;
+        let rows = SqliteDatabase::find_rows(&conn, collection.as_str(), &filter, None)?;
+        Ok(rows
+            .into_iter()
+            .map(|(_, doc)| match &projection {
+                Some(projection) => query::project(&doc, projection),
+                None => doc,
+            })
+            .collect())
+    }
+
+    fn find_one(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        let conn = self.conn()?;
+        let row = SqliteDatabase::find_rows(&conn, collection.as_str(), &filter, Some(1))?
+            .into_iter()
+            .next();
+        Ok(row.map(|(_, doc)| match &projection {
+            Some(projection) => query::project(&doc, projection),
+            None => doc,
+        }))
+    }
+
+    fn count(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        let conn = self.conn()?;
+        let table = collection.as_str();
+        let select = Select::from_filter(&filter, Dialect::Sqlite)?;
+        if select.prefiltered {
+            return Ok(SqliteDatabase::select(&conn, table, &select, Some(&filter))?.len() as u64);
+        }
+        let params = SqliteDatabase::params(&select);
+        let count: i64 = conn.query_row(&select.to_count_sql(table), &params, |row| row.get(0))?;
+        Ok(count as u64)
+    }
+
+    fn aggregate(
+        &self,
+        collection: Collections,
+        pipeline: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let conn = self.conn()?;
+        let (select, translated) = Select::from_pipeline(&pipeline, Dialect::Sqlite)?;
+        let docs = SqliteDatabase::select(&conn, collection.as_str(), &select, None)?
+            .into_iter()
+            .map(|(_, doc)| doc)
+            .collect();
+        query::aggregate(docs, &pipeline[translated..])
+    }
+
+    fn insert_row(
+        tx: &Transaction,
+        table: &str,
+        doc: &OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        let id = doc.get("_id").map(sql::row_id).unwrap_or_default();
+        tx.execute(
+            &format!(
+                "INSERT INTO \"{}\" (id, doc, fields) VALUES (?, ?, ?)",
+                table
+            ),
+            &[
+                &id as &dyn ToSql,
+                &SqliteDatabase::encode(doc)?,
+                &sql::to_json(doc).to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn insert_one(
+        &self,
+        collection: Collections,
+        mut doc: OrderedDocument,
+    ) -> Result<ObjectId, DatabaseError> {
+        let id = match doc.get("_id") {
+            Some(Bson::ObjectId(id)) => id.clone(),
+            _ => {
+                let id = ObjectId::new()?;
+                doc.insert("_id", id.clone());
+                id
+            }
+        };
+        self.write(|tx| SqliteDatabase::insert_row(tx, collection.as_str(), &doc))?;
+        Ok(id)
+    }
+
+    /// Returns the number of matched documents.
+    fn update_one(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+        upsert: bool,
+    ) -> Result<u64, DatabaseError> {
+        let table = collection.as_str();
+        self.write(|tx| {
+            let row = SqliteDatabase::find_rows(tx, table, &filter, Some(1))?
+                .into_iter()
+                .next();
+            match row {
+                Some((id, mut doc)) => {
+                    query::apply_update(&mut doc, &update)?;
+                    tx.execute(
+                        &format!("UPDATE \"{}\" SET doc = ?, fields = ? WHERE id = ?", table),
+                        &[
+                            &SqliteDatabase::encode(&doc)? as &dyn ToSql,
+                            &sql::to_json(&doc).to_string(),
+                            &id,
+                        ],
+                    )?;
+                    Ok(1)
+                }
+                None if upsert => {
+                    let mut doc = query::upsert_document(&filter);
+                    query::apply_insert_update(&mut doc, &update)?;
+                    if doc.get("_id").is_none() {
+                        doc.insert("_id", ObjectId::new()?);
+                    }
+                    SqliteDatabase::insert_row(tx, table, &doc)?;
+                    Ok(0)
+                }
+                None => Ok(0),
+            }
+        })
+    }
+
+    /// Updates the first document matched by `filter` in the order of `sort` and returns it.
+    ///
+    /// The order is only evaluated in memory, if the filter or sort can't be translated.
+    fn find_one_and_update(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        let table = collection.as_str();
+        self.write(|tx| {
+            let mut select = Select::from_filter(&filter, Dialect::Sqlite)?;
+            let row = if !select.prefiltered && select.add_sort(&sort) {
+                select.limit = Some(1);
+                SqliteDatabase::select(tx, table, &select, None)?
+                    .into_iter()
+                    .next()
+            } else {
+                let mut rows = SqliteDatabase::select(tx, table, &select, Some(&filter))?;
+                rows.sort_by(|(_, a), (_, b)| query::sort_by(a, b, &sort));
+                rows.into_iter().next()
+            };
+            match row {
+                Some((id, mut doc)) => {
+                    query::apply_update(&mut doc, &update)?;
+                    tx.execute(
+                        &format!("UPDATE \"{}\" SET doc = ?, fields = ? WHERE id = ?", table),
+                        &[
+                            &SqliteDatabase::encode(&doc)? as &dyn ToSql,
+                            &sql::to_json(&doc).to_string(),
+                            &id,
+                        ],
+                    )?;
+                    Ok(Some(doc))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Returns the number of deleted documents.
+    fn delete_many(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        let table = collection.as_str();
+        self.write(|tx| {
+            let rows = SqliteDatabase::find_rows(tx, table, &filter, None)?;
+            let mut deleted = 0;
+            for (id, _) in rows {
+                deleted += tx.execute(&format!("DELETE FROM \"{}\" WHERE id = ?", table), &[id])?;
+            }
+            Ok(deleted as u64)
+        })
+    }
+
+    /// Returns the number of deleted documents.
+    fn delete_one(
+        &self,
+        collection: Collections,
+        filter: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        let table = collection.as_str();
+        self.write(|tx| {
+            let row = SqliteDatabase::find_rows(tx, table, &filter, Some(1))?
+                .into_iter()
+                .next();
+            match row {
+                Some((id, _)) => {
+                    let deleted =
+                        tx.execute(&format!("DELETE FROM \"{}\" WHERE id = ?", table), &[id])?;
+                    Ok(deleted as u64)
+                }
+                None => Ok(0),
+            }
+        })
+    }
+}
+
+impl AccountStore for SqliteDatabase {
+    fn find_account(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one(Collections::Accounts, filter, None)
+    }
+
+    fn find_accounts(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Accounts, filter, None)
+    }
+
+    fn insert_account(&self, account: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        self.insert_one(Collections::Accounts, account)
+    }
+
+    fn update_account(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Accounts, filter, update, false)?;
+        Ok(())
+    }
+}
+
+impl CourseStore for SqliteDatabase {
+    fn get_courses(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.aggregate(Collections::Courses, query)
+    }
+
+    fn get_courses2(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.aggregate(Collections::Courses2, query)
+    }
+
+    fn find_courses2(
+        &self,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Courses2, filter, projection)
+    }
+
+    fn count_courses2(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.count(Collections::Courses2, filter)
+    }
+
+    fn insert_course2(&self, doc_meta: OrderedDocument) -> Result<ObjectId, DatabaseError> {
+        self.insert_one(Collections::Courses2, doc_meta)
+    }
+
+    fn update_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        self.update_one(Collections::Courses2, filter, update, false)
+    }
+
+    fn delete_course2_meta(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.delete_one(Collections::Courses2, filter)
+    }
+
+    fn get_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one(Collections::Course2Data, filter, Some(projection))
+    }
+
+    fn find_course2_data(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Course2Data, filter, Some(projection))
+    }
+
+    fn insert_course2_data(&self, doc: OrderedDocument) -> Result<(), DatabaseError> {
+        self.insert_one(Collections::Course2Data, doc)?;
+        Ok(())
+    }
+
+    fn update_course2_data(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Course2Data, filter, update, false)?;
+        Ok(())
+    }
+
+    fn delete_course2_data(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.delete_one(Collections::Course2Data, filter)
+    }
+
+    fn find_course2_history(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Course2History, filter, Some(projection))
+    }
+
+    fn insert_course2_history(&self, doc: OrderedDocument) -> Result<(), DatabaseError> {
+        self.insert_one(Collections::Course2History, doc)?;
+        Ok(())
+    }
+
+    fn update_course2_history(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Course2History, filter, update, false)?;
+        Ok(())
+    }
+
+    fn delete_course2_history(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.delete_many(Collections::Course2History, filter)
+    }
+}
+
+impl VoteStore for SqliteDatabase {
+    fn vote_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Votes, filter, update, true)?;
+        Ok(())
+    }
+
+    fn unvote_course2(&self, filter: OrderedDocument) -> Result<(), DatabaseError> {
+        self.delete_one(Collections::Votes, filter)?;
+        Ok(())
+    }
+
+    fn get_votes_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find(Collections::Votes, filter, Some(projection))
+    }
+}
+
+impl DownloadStore for SqliteDatabase {
+    fn find_download_course2(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one(Collections::Downloads, filter, None)
+    }
+
+    fn upsert_download_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Downloads, filter, update, true)?;
+        Ok(())
+    }
+}
+
+impl JobStore for SqliteDatabase {
+    fn find_jobs(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        limit: Option<i64>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.aggregate(Collections::Jobs, query::find_pipeline(filter, sort, limit))
+    }
+
+    fn count_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.count(Collections::Jobs, filter)
+    }
+
+    fn upsert_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Jobs, filter, update, true)?;
+        Ok(())
+    }
+
+    fn claim_job(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one_and_update(Collections::Jobs, filter, sort, update)
+    }
+
+    fn update_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError> {
+        self.update_one(Collections::Jobs, filter, update, false)
+    }
+
+    fn delete_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError> {
+        self.delete_many(Collections::Jobs, filter)
+    }
+}
+
+impl MetaStore for SqliteDatabase {
+    fn find_meta(&self, filter: OrderedDocument) -> Result<Option<OrderedDocument>, DatabaseError> {
+        self.find_one(Collections::Meta, filter, None)
+    }
+
+    fn update_meta(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError> {
+        self.update_one(Collections::Meta, filter, update, true)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    /// A database in a temporary file, which is removed on drop.
+    ///
+    /// In-memory databases cannot be used, because they are not shared by the pooled connections.
+    struct TestDatabase {
+        database: SqliteDatabase,
+        path: String,
+    }
+
+    impl TestDatabase {
+        fn new() -> Self {
+            let path = env::temp_dir()
+                .join(format!(
+                    "smmdb-test-{}.sqlite",
+                    ObjectId::new().unwrap().to_hex()
+                ))
+                .to_string_lossy()
+                .to_string();
+            TestDatabase {
+                database: SqliteDatabase::new(&path).unwrap(),
+                path,
+            }
+        }
+    }
+
+    impl Drop for TestDatabase {
+        fn drop(&mut self) {
+            for suffix in &["", "-wal", "-shm"] {
+                let _ = fs::remove_file(format!("{}{}", self.path, suffix));
+            }
+        }
+    }
+
+    #[test]
+    fn evaluates_query_cases() {
+        let db = TestDatabase::new();
+        query::tests::check_course_store(&db.database);
+    }
+
+    #[test]
+    fn claims_jobs_in_sort_order() {
+        let db = TestDatabase::new();
+        for run_after in &[3, 1, 2] {
+            db.database
+                .upsert_job(
+                    doc! { "run_after" => *run_after },
+                    doc! { "$set" => { "status" => "pending" } },
+                )
+                .unwrap();
+        }
+        let claim = |sort: OrderedDocument| {
+            db.database
+                .claim_job(
+                    doc! { "status" => "pending" },
+                    sort,
+                    doc! { "$set" => { "status" => "running" } },
+                )
+                .unwrap()
+                .map(|job| job.get_i32("run_after").unwrap())
+        };
+        assert_eq!(claim(doc! { "run_after" => 1 }), Some(1));
+        assert_eq!(claim(doc! { "run_after" => -1 }), Some(3));
+        assert_eq!(claim(doc! { "run_after" => 1 }), Some(2));
+        assert_eq!(claim(doc! { "run_after" => 1 }), None);
+    }
+
+    #[test]
+    fn rejects_untranslatable_filters() {
+        let db = TestDatabase::new();
+        let res = db
+            .database
+            .find_courses2(doc! { "tags" => { "$size" => 2 } }, None);
+        assert!(matches!(res, Err(DatabaseError::UnsupportedQuery(_))));
+        let res = db
+            .database
+            .count_courses2(doc! { "$where" => "this.votes > 1" });
+        assert!(matches!(res, Err(DatabaseError::UnsupportedQuery(_))));
+    }
+}
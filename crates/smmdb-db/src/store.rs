@@ -0,0 +1,556 @@
+use crate::DatabaseError;
+
+use brotli2::{read::BrotliEncoder, CompressParams};
+use bson::{oid::ObjectId, ordered::OrderedDocument, spec::BinarySubtype, Bson};
+use sha2::{Digest, Sha256};
+
+pub trait Database:
+    AccountStore + CourseStore + VoteStore + DownloadStore + JobStore + MetaStore + Send + Sync
+{
+}
+
+impl<T> Database for T where
+    T: AccountStore + CourseStore + VoteStore + DownloadStore + JobStore + MetaStore + Send + Sync
+{
+}
+
+pub trait AccountStore {
+    fn find_account(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError>;
+
+    fn find_accounts(&self, filter: OrderedDocument)
+        -> Result<Vec<OrderedDocument>, DatabaseError>;
+
+    fn insert_account(&self, account: OrderedDocument) -> Result<ObjectId, DatabaseError>;
+
+    fn update_account(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError>;
+
+    fn get_accounts(&self, account_ids: Vec<Bson>) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.find_accounts(doc! {
+            "_id": {
+                "$in": account_ids
+            }
+        })
+    }
+
+    fn delete_account_session(&self, account_id: &ObjectId) -> Result<(), DatabaseError> {
+        let filter = doc! {
+            "_id" => account_id.clone()
+        };
+        let update = doc! {
+            "$unset" => {
+                "session" => ""
+            }
+        };
+        self.update_account(filter, update)
+    }
+}
+
+pub trait CourseStore {
+    fn get_courses(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError>;
+
+    fn get_courses2(
+        &self,
+        query: Vec<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError>;
+
+    fn find_courses2(
+        &self,
+        filter: OrderedDocument,
+        projection: Option<OrderedDocument>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError>;
+
+    fn count_courses2(&self, filter: OrderedDocument) -> Result<u64, DatabaseError>;
+
+    fn insert_course2(&self, doc_meta: OrderedDocument) -> Result<ObjectId, DatabaseError>;
+
+    /// Returns the number of matched courses.
+    fn update_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError>;
+
+    /// Returns the number of deleted courses.
+    fn delete_course2_meta(&self, filter: OrderedDocument) -> Result<u64, DatabaseError>;
+
+    fn get_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError>;
+
+    fn find_course2_data(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError>;
+
+    fn insert_course2_data(&self, doc: OrderedDocument) -> Result<(), DatabaseError>;
+
+    fn update_course2_data(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError>;
+
+    /// Returns the number of deleted course data documents.
+    fn delete_course2_data(&self, filter: OrderedDocument) -> Result<u64, DatabaseError>;
+
+    fn find_course2_history(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError>;
+
+    fn insert_course2_history(&self, doc: OrderedDocument) -> Result<(), DatabaseError>;
+
+    fn update_course2_history(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError>;
+
+    /// Returns the number of deleted versions.
+    fn delete_course2_history(&self, filter: OrderedDocument) -> Result<u64, DatabaseError>;
+
+    /// Returns the hashes of all courses modified at or after `modified_since`.
+    fn fill_lsh_index(
+        &self,
+        modified_since: Option<i64>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        let filter = match modified_since {
+            Some(modified_since) => doc! {
+                "last_modified" => {
+                    "$gte" => modified_since
+                }
+            },
+            None => doc! {},
+        };
+        self.find_courses2(
+            filter,
+            Some(doc! {
+                "hash" => 1,
+                "last_modified" => 1
+            }),
+        )
+    }
+
+    /// Counts the courses matching `filter` grouped by the value at each of the given paths.
+    ///
+    /// Returns a document with a list of `{ _id, count }` documents for every facet name, which
+    /// holds the `limit` most common values.
+    fn get_course2_facets(
+        &self,
+        filter: OrderedDocument,
+        facets: &[(&str, &str)],
+        limit: i64,
+    ) -> Result<OrderedDocument, DatabaseError> {
+        let mut facet = OrderedDocument::new();
+        for (name, path) in facets {
+            let pipeline: Vec<Bson> = vec![
+                doc! {
+                    "$group" => {
+                        "_id" => format!("${}", path),
+                        "count" => {
+                            "$sum" => 1
+                        }
+                    }
+                }
+                .into(),
+                doc! {
+                    "$sort" => {
+                        "count" => -1,
+                        "_id" => 1
+                    }
+                }
+                .into(),
+                doc! {
+                    "$limit" => limit
+                }
+                .into(),
+            ];
+            facet.insert(*name, pipeline);
+        }
+        let pipeline = vec![
+            doc! {
+                "$match" => filter
+            },
+            doc! {
+                "$facet" => facet
+            },
+        ];
+        Ok(self
+            .get_courses2(pipeline)?
+            .into_iter()
+            .next()
+            .unwrap_or_else(OrderedDocument::new))
+    }
+
+    /// Returns up to `size` uniformly random courses matching `filter`.
+    fn sample_courses2(
+        &self,
+        filter: OrderedDocument,
+        size: i64,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError> {
+        self.get_courses2(vec![
+            doc! {
+                "$match" => filter
+            },
+            doc! {
+                "$sample" => {
+                    "size" => size
+                }
+            },
+        ])
+    }
+
+    fn put_course2(
+        &self,
+        doc_meta: OrderedDocument,
+        course: &mut smmdb_lib::Course2,
+        thumb: Bson,
+        thumb_encrypted: Bson,
+    ) -> Result<ObjectId, DatabaseError> {
+        let inserted_id = self.insert_course2(doc_meta)?;
+        course.set_smmdb_id(inserted_id.to_string()).unwrap();
+        let mut course_data = course.get_course_data().to_vec();
+        smmdb_lib::Course2::encrypt(&mut course_data);
+        let content_hash = hash_course2_content(&course_data, &thumb, &thumb_encrypted);
+        let doc = doc! {
+            "_id" => inserted_id.clone(),
+            "data_encrypted" => Bson::Binary(BinarySubtype::Generic, course_data),
+            "thumb" => thumb,
+            "thumb_encrypted" => thumb_encrypted,
+            "content_hash" => content_hash,
+        };
+        self.insert_course2_data(doc)?;
+        Ok(inserted_id)
+    }
+
+    /// Replaces the course data and thumbnails of an existing course and unsets all fields
+    /// derived from them.
+    fn replace_course2_data(
+        &self,
+        course_id: ObjectId,
+        course: &mut smmdb_lib::Course2,
+        thumb: Bson,
+        thumb_encrypted: Bson,
+        derived_fields: &[String],
+    ) -> Result<(), DatabaseError> {
+        course.set_smmdb_id(course_id.to_string()).unwrap();
+        let mut course_data = course.get_course_data().to_vec();
+        smmdb_lib::Course2::encrypt(&mut course_data);
+        let content_hash = hash_course2_content(&course_data, &thumb, &thumb_encrypted);
+        let mut unset = doc! {};
+        for field in derived_fields {
+            unset.insert(field.clone(), "");
+        }
+        let filter = doc! {
+            "_id" => course_id
+        };
+        let update = doc! {
+            "$set" => {
+                "data_encrypted" => Bson::Binary(BinarySubtype::Generic, course_data),
+                "thumb" => thumb,
+                "thumb_encrypted" => thumb_encrypted,
+                "content_hash" => content_hash,
+            },
+            "$unset" => unset
+        };
+        self.update_course2_data(filter, update)
+    }
+
+    fn update_course2_thumbnail(
+        &self,
+        course_id: ObjectId,
+        size: String,
+        data: Vec<u8>,
+    ) -> Result<(), DatabaseError> {
+        let data = Bson::Binary(BinarySubtype::Generic, data);
+        let filter = doc! {
+            "_id" => course_id
+        };
+        let update = doc! {
+            "$set" => {
+                size => data
+            }
+        };
+        self.update_course2_data(filter, update)
+    }
+
+    fn delete_course2(&self, course_id: String, doc: OrderedDocument) -> Result<(), DatabaseError> {
+        self.delete_course2_meta(doc.clone())?;
+        if self.delete_course2_data(doc)? == 0 {
+            Err(DatabaseError::NotFound(course_id))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn add_course2_data_br(
+        &self,
+        course_id: ObjectId,
+        course: OrderedDocument,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        let course_data = course.get_binary_generic("data_encrypted")?.clone();
+        let data_br = compress_course2_data_br(course_data)?;
+
+        let filter = doc! {
+            "_id" => course_id,
+        };
+        let update = doc! {
+            "$set" => {
+                "data_br" => Bson::Binary(BinarySubtype::Generic, data_br.clone()),
+            }
+        };
+        self.update_course2_data(filter, update)?;
+        Ok(data_br)
+    }
+
+    fn add_course2_data_protobuf_br(
+        &self,
+        course_id: ObjectId,
+        course: OrderedDocument,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        let course_data = course.get_binary_generic("data_encrypted")?.clone();
+        let data_br = compress_course2_data_protobuf_br(course_data)?;
+
+        let filter = doc! {
+            "_id" => course_id,
+        };
+        let update = doc! {
+            "$set" => {
+                "data_protobuf_br" => Bson::Binary(BinarySubtype::Generic, data_br.clone()),
+            }
+        };
+        self.update_course2_data(filter, update)?;
+        Ok(data_br)
+    }
+}
+
+/// Hashes the encrypted course data and the thumbnails of an upload.
+///
+/// Everything served of a course version is derived from them, so the hash is stored with them
+/// and validates conditional requests without loading any of them.
+pub fn hash_course2_content(course_data: &[u8], thumb: &Bson, thumb_encrypted: &Bson) -> String {
+    let mut hasher = Sha256::new();
+    for part in [
+        course_data,
+        binary_or_empty(thumb),
+        binary_or_empty(thumb_encrypted),
+    ]
+    .iter()
+    {
+        hasher.update(&(part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hex::encode(&hasher.finalize()[..16])
+}
+
+fn binary_or_empty(value: &Bson) -> &[u8] {
+    match value {
+        Bson::Binary(_, data) => &data[..],
+        _ => &[],
+    }
+}
+
+/// Decrypts the course data and compresses it with brotli.
+pub fn compress_course2_data_br(mut course_data: Vec<u8>) -> Result<Vec<u8>, DatabaseError> {
+    smmdb_lib::Course2::decrypt(&mut course_data)?;
+    brotli(&course_data)
+}
+
+/// Converts the encrypted course data to protobuf and compresses it with brotli.
+pub fn compress_course2_data_protobuf_br(
+    mut course_data: Vec<u8>,
+) -> Result<Vec<u8>, DatabaseError> {
+    let course = smmdb_lib::Course2::from_switch_files(&mut course_data, None, true)?;
+    brotli(&course.get_proto()[..])
+}
+
+fn brotli(data: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+    use std::io::prelude::*;
+
+    let mut data_br = vec![];
+    let mut params = CompressParams::new();
+    params.quality(11);
+    BrotliEncoder::from_params(data, &params).read_to_end(&mut data_br)?;
+    Ok(data_br)
+}
+
+pub trait VoteStore {
+    /// Upserts the vote matched by `filter`.
+    fn vote_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError>;
+
+    fn unvote_course2(&self, filter: OrderedDocument) -> Result<(), DatabaseError>;
+
+    fn get_votes_course2(
+        &self,
+        filter: OrderedDocument,
+        projection: OrderedDocument,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError>;
+
+    fn get_vote_for_account(
+        &self,
+        account_id: &ObjectId,
+        course_id: &ObjectId,
+    ) -> Result<i32, DatabaseError> {
+        let filter = doc! {
+            "account_id" => account_id.clone(),
+            "course_id" => course_id.clone()
+        };
+        let projection = doc! {
+            "value" => true
+        };
+        let res = self
+            .get_votes_course2(filter, projection)?
+            .into_iter()
+            .next()
+            .map(|doc| doc.get_i32("value").unwrap_or_default())
+            .unwrap_or_default();
+        Ok(res)
+    }
+}
+
+pub trait DownloadStore {
+    fn find_download_course2(
+        &self,
+        filter: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError>;
+
+    /// Upserts the download matched by `filter`.
+    fn upsert_download_course2(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError>;
+}
+
+pub trait JobStore {
+    /// Returns the jobs matched by `filter` in the order of `sort`, at most `limit` of them.
+    fn find_jobs(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        limit: Option<i64>,
+    ) -> Result<Vec<OrderedDocument>, DatabaseError>;
+
+    fn count_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError>;
+
+    /// Upserts the job matched by `filter`.
+    ///
+    /// Fields of `$setOnInsert` are only set, if the job is inserted.
+    fn upsert_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError>;
+
+    /// Atomically updates the first job matched by `filter` in the order of `sort`.
+    ///
+    /// Returns the updated job.
+    fn claim_job(
+        &self,
+        filter: OrderedDocument,
+        sort: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<Option<OrderedDocument>, DatabaseError>;
+
+    /// Returns the number of matched jobs.
+    fn update_job(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<u64, DatabaseError>;
+
+    /// Returns the number of deleted jobs.
+    fn delete_jobs(&self, filter: OrderedDocument) -> Result<u64, DatabaseError>;
+}
+
+pub trait MetaStore {
+    fn find_meta(&self, filter: OrderedDocument) -> Result<Option<OrderedDocument>, DatabaseError>;
+
+    /// Upserts the meta document matched by `filter`.
+    fn update_meta(
+        &self,
+        filter: OrderedDocument,
+        update: OrderedDocument,
+    ) -> Result<(), DatabaseError>;
+
+    fn get_meta_value(&self, key: &str) -> Result<Option<Bson>, DatabaseError> {
+        let doc = self.find_meta(doc! {
+            key => {
+                "$exists" => true
+            }
+        })?;
+        Ok(doc.and_then(|doc| doc.get(key).cloned()))
+    }
+
+    fn set_meta_value(&self, key: &str, value: Bson) -> Result<(), DatabaseError> {
+        let filter = doc! {
+            key => {
+                "$exists" => true
+            }
+        };
+        let update = doc! {
+            "$set" => {
+                key => value
+            }
+        };
+        self.update_meta(filter, update)
+    }
+
+    fn get_missing_migrations(
+        &self,
+        migrations_to_run: Vec<String>,
+    ) -> Result<Vec<String>, DatabaseError> {
+        let doc = self.find_meta(doc! {
+            "migrations" => {
+                "$exists" => true
+            }
+        })?;
+        let migrations: Vec<String> = doc
+            .as_ref()
+            .and_then(|doc| doc.get_array("migrations").ok())
+            .map(|array| {
+                array
+                    .iter()
+                    .filter_map(|bson| bson.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(migrations_to_run
+            .into_iter()
+            .filter(|m| !migrations.contains(m))
+            .collect())
+    }
+
+    fn migration_completed(&self, migration: String) -> Result<(), DatabaseError> {
+        let filter = doc! {
+            "migrations" => {
+                "$exists" => true
+            }
+        };
+        let update = doc! {
+            "$push" => {
+                "migrations" => migration
+            }
+        };
+        self.update_meta(filter, update)
+    }
+}
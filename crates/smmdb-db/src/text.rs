@@ -0,0 +1,323 @@
+//! Evaluation of `$text` queries on plain documents.
+//!
+//! This mirrors the text index of MongoDB closely enough for the backends without one: the
+//! search string is split into terms, `"quoted phrases"` and `-negated` terms, all of which are
+//! matched case insensitively against the fields of `COURSE2_TEXT_INDEX`.
+//!
+//! Words are delimited by non-alphanumeric characters. Scripts without such delimiters, i.e.
+//! runs of CJK characters, are segmented into character bigrams instead, so that a term matches
+//! a title, if all of its bigrams occur in it.
+
+use crate::DatabaseError;
+
+use bson::{ordered::OrderedDocument, Bson};
+
+/// Fields covered by the text index of `courses2` and their weights.
+pub const COURSE2_TEXT_INDEX: &[(&str, i32)] = &[
+    ("course.header.title", 10),
+    ("uploader", 5),
+    ("course.header.description", 1),
+];
+
+/// Field holding the text score of a document during aggregation.
+pub const TEXT_SCORE_FIELD: &str = "$textScore";
+
+pub struct TextSearch {
+    /// Every term is a list of tokens, which all have to match.
+    terms: Vec<Vec<String>>,
+    phrases: Vec<String>,
+    negated: Vec<Vec<String>>,
+    case_sensitive: bool,
+}
+
+impl TextSearch {
+    pub fn from_query(query: &Bson) -> Result<Self, DatabaseError> {
+        let unsupported = || DatabaseError::UnsupportedQuery("$text".to_string());
+        let query = query.as_document().ok_or_else(unsupported)?;
+        let search = query.get_str("$search").map_err(|_| unsupported())?;
+        let case_sensitive = query.get_bool("$caseSensitive").unwrap_or_default();
+        let normalize = |text: &str| {
+            if case_sensitive {
+                text.to_string()
+            } else {
+                text.to_lowercase()
+            }
+        };
+
+        let mut terms = vec![];
+        let mut phrases = vec![];
+        let mut negated = vec![];
+        for (index, part) in search.split('"').enumerate() {
+            if index % 2 == 1 {
+                if !part.trim().is_empty() {
+                    phrases.push(normalize(part.trim()));
+                }
+                continue;
+            }
+            for word in part.split_whitespace() {
+                let (list, word) = match word.strip_prefix('-') {
+                    Some(word) => (&mut negated, word),
+                    None => (&mut terms, word),
+                };
+                list.extend(segment(&normalize(word)));
+            }
+        }
+        Ok(TextSearch {
+            terms,
+            phrases,
+            negated,
+            case_sensitive,
+        })
+    }
+
+    /// Returns regular expressions, of which each has to match one of the indexed fields of a
+    /// matching document, or `None`, if no document can match.
+    pub fn candidate_patterns(&self) -> Option<Vec<String>> {
+        let flags = if self.case_sensitive { "" } else { "(?i)" };
+        if !self.phrases.is_empty() {
+            Some(
+                self.phrases
+                    .iter()
+                    .map(|phrase| format!("{}{}", flags, regex::escape(phrase)))
+                    .collect(),
+            )
+        } else if !self.terms.is_empty() {
+            let terms: Vec<String> = self
+                .terms
+                .iter()
+                .flatten()
+                .map(|token| regex::escape(token))
+                .collect();
+            Some(vec![format!("{}({})", flags, terms.join("|"))])
+        } else {
+            None
+        }
+    }
+
+    /// Returns the score of the document or `None`, if it does not match.
+    pub fn score(&self, doc: &OrderedDocument) -> Option<f64> {
+        let mut score = 0.;
+        let mut matched_terms = false;
+        let mut matched_phrases = vec![false; self.phrases.len()];
+        for (path, weight) in COURSE2_TEXT_INDEX {
+            let text = match crate::query::get_path(doc, path) {
+                Some(Bson::String(text)) => text,
+                _ => continue,
+            };
+            let text = if self.case_sensitive {
+                text.clone()
+            } else {
+                text.to_lowercase()
+            };
+            let tokens = tokenize(&text);
+            if self.negated.iter().any(|term| contains_term(&tokens, term)) {
+                return None;
+            }
+            let hits = self
+                .terms
+                .iter()
+                .filter(|term| contains_term(&tokens, term))
+                .count();
+            if hits > 0 {
+                matched_terms = true;
+            }
+            let mut phrase_hits = 0;
+            for (phrase, matched) in self.phrases.iter().zip(matched_phrases.iter_mut()) {
+                if text.contains(phrase.as_str()) {
+                    *matched = true;
+                    phrase_hits += 1;
+                }
+            }
+            if !tokens.is_empty() {
+                score += f64::from(*weight) * (hits + phrase_hits) as f64 / tokens.len() as f64;
+            }
+        }
+
+        let matched = if self.phrases.is_empty() {
+            matched_terms
+        } else {
+            matched_phrases.iter().all(|matched| *matched)
+        };
+        if matched {
+            Some(score)
+        } else {
+            None
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    segment(text).concat()
+}
+
+/// Splits text into words, of which runs of CJK characters are split into bigrams.
+fn segment(text: &str) -> Vec<Vec<String>> {
+    let mut words = vec![];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let chars: Vec<char> = word.chars().collect();
+        let mut start = 0;
+        while start < chars.len() {
+            let cjk = is_cjk(chars[start]);
+            let end = chars[start..]
+                .iter()
+                .position(|c| is_cjk(*c) != cjk)
+                .map_or(chars.len(), |len| start + len);
+            let run = &chars[start..end];
+            if cjk && run.len() > 1 {
+                words.push(run.windows(2).map(|pair| pair.iter().collect()).collect());
+            } else {
+                words.push(vec![run.iter().collect()]);
+            }
+            start = end;
+        }
+    }
+    words
+}
+
+/// Whether all tokens of the term are contained in `tokens`.
+///
+/// A single CJK character can't be a bigram, so it matches any token containing it.
+fn contains_term(tokens: &[String], term: &[String]) -> bool {
+    term.iter().all(|token| {
+        let mut chars = token.chars();
+        let single_cjk = match (chars.next(), chars.next()) {
+            (Some(c), None) => is_cjk(c),
+            _ => false,
+        };
+        tokens
+            .iter()
+            .any(|t| t == token || (single_cjk && t.contains(token.as_str())))
+    })
+}
+
+/// Hiragana, Katakana, CJK ideographs and Hangul syllables.
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x3040..=0x30FF
+            | 0x3400..=0x4DBF
+            | 0x4E00..=0x9FFF
+            | 0xAC00..=0xD7AF
+            | 0xF900..=0xFAFF
+            | 0xFF66..=0xFF9F
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn course(title: &str, uploader: &str, description: &str) -> OrderedDocument {
+        doc! {
+            "uploader" => uploader,
+            "course" => {
+                "header" => {
+                    "title" => title,
+                    "description" => description
+                }
+            }
+        }
+    }
+
+    fn score(search: &str, doc: &OrderedDocument) -> Option<f64> {
+        TextSearch::from_query(&Bson::Document(doc! { "$search" => search }))
+            .unwrap()
+            .score(doc)
+    }
+
+    #[test]
+    fn weights_fields_by_text_index() {
+        let in_title = course("Castle Run", "bob", "a long course");
+        let in_description = course("Long Run", "bob", "a castle");
+        assert_eq!(score("castle", &in_title), Some(5.));
+        assert_eq!(score("castle", &in_description), Some(0.5));
+        assert_eq!(score("castle bob", &in_description), Some(5.5));
+    }
+
+    #[test]
+    fn matches_whole_terms_only() {
+        let doc = course("Castles!", "bob", "");
+        assert_eq!(score("castle", &doc), None);
+        assert!(score("CASTLES", &doc).is_some());
+        assert_eq!(score("", &doc), None);
+    }
+
+    #[test]
+    fn matches_phrases_and_negated_terms() {
+        let doc = course("Castle Run", "bob", "Speedrun through a castle");
+        assert!(score("\"castle run\"", &doc).is_some());
+        assert_eq!(score("\"run castle\"", &doc), None);
+        assert_eq!(score("\"castle run\" \"kaizo\"", &doc), None);
+        assert_eq!(score("castle -speedrun", &doc), None);
+        assert!(score("castle -kaizo", &doc).is_some());
+    }
+
+    #[test]
+    fn respects_case_sensitivity() {
+        let doc = course("castle run", "bob", "");
+        let search = |search: &str| {
+            TextSearch::from_query(&Bson::Document(doc! {
+                "$search" => search,
+                "$caseSensitive" => true
+            }))
+            .unwrap()
+        };
+        assert_eq!(search("Castle").score(&doc), None);
+        assert!(search("castle").score(&doc).is_some());
+        assert_eq!(
+            search("castle").candidate_patterns(),
+            Some(vec!["(castle)".to_string()])
+        );
+    }
+
+    #[test]
+    fn escapes_candidate_patterns() {
+        let search = |search: &str| {
+            TextSearch::from_query(&Bson::Document(doc! { "$search" => search })).unwrap()
+        };
+        assert_eq!(
+            search("castle run").candidate_patterns(),
+            Some(vec!["(?i)(castle|run)".to_string()])
+        );
+        assert_eq!(
+            search("\"1+1\" \"a.b\" castle").candidate_patterns(),
+            Some(vec![r"(?i)1\+1".to_string(), r"(?i)a\.b".to_string()])
+        );
+        assert_eq!(search("-castle").candidate_patterns(), None);
+    }
+
+    #[test]
+    fn segments_cjk_titles_into_bigrams() {
+        assert_eq!(
+            tokenize("スーパーマリオの城 3"),
+            vec![
+                "スー", "ーパ", "パー", "ーマ", "マリ", "リオ", "オの", "の城", "3"
+            ]
+        );
+        assert_eq!(tokenize("マリオ64"), vec!["マリ", "リオ", "64"]);
+
+        let doc = course("スーパーマリオの城", "ボブ", "");
+        assert_eq!(score("マリオ", &doc), Some(10. * 1. / 8.));
+        assert!(score("城", &doc).is_some());
+        assert!(score("ボブ", &doc).is_some());
+        assert_eq!(score("ルイージ", &doc), None);
+        assert_eq!(score("マオ", &doc), None);
+        assert!(score("\"マリオの\"", &doc).is_some());
+        assert_eq!(score("マリオ -城", &doc), None);
+        assert_eq!(
+            TextSearch::from_query(&Bson::Document(doc! { "$search" => "マリオ" }))
+                .unwrap()
+                .candidate_patterns(),
+            Some(vec!["(?i)(マリ|リオ)".to_string()])
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        assert!(TextSearch::from_query(&Bson::from("castle")).is_err());
+        assert!(TextSearch::from_query(&Bson::Document(doc! {})).is_err());
+    }
+}
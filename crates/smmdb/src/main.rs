@@ -7,21 +7,70 @@ mod routes;
 mod server;
 mod session;
 
+use config::{BlobBackend, BlobsConfig, Config, DatabaseBackend, DatabaseConfig};
 use migration::Migration;
 use server::Server;
 
 use smmdb_common::PermGen;
-use smmdb_db::Database;
-use std::io;
+use smmdb_db::{
+    BlobDatabase, BlobStore, Database, FsBlobStore, MemoryDatabase, MongoDatabase,
+    PostgresDatabase, S3BlobStore, S3Config, SqliteDatabase,
+};
+use std::{io, sync::Arc};
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
     println!("Starting...");
     std::env::set_var("RUST_BACKTRACE", "1");
-    use std::sync::Arc;
 
-    let database = Database::new();
-    let perm_gen = PermGen::new(128);
-    Migration::migrate(&database, &perm_gen);
-    Server::start(Arc::new(database), perm_gen).unwrap().await
+    let config = Config::load()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+    let database = connect_database(&config.database);
+    let database: Arc<dyn Database> = match connect_blob_store(&config.blobs) {
+        Some(blobs) => {
+            let database = Arc::new(BlobDatabase::new(database, blobs));
+            Migration::migrate_blobs(&database);
+            database
+        }
+        None => database,
+    };
+    let perm_gen = PermGen::new(config.similarity.permutations, config.similarity.seed);
+    Migration::migrate(&*database, &perm_gen);
+    Server::start(config, database, perm_gen)?.await
+}
+
+fn connect_database(config: &DatabaseConfig) -> Arc<dyn Database> {
+    match config.backend() {
+        DatabaseBackend::Sqlite => Arc::new(
+            SqliteDatabase::new(config.sqlite_path.as_ref().unwrap())
+                .expect("Failed to open SQLite database"),
+        ),
+        DatabaseBackend::Postgres => Arc::new(
+            PostgresDatabase::new(config.postgres_uri.as_ref().unwrap())
+                .expect("Failed to connect to PostgreSQL"),
+        ),
+        DatabaseBackend::Mongodb => Arc::new(
+            MongoDatabase::new(config.mongodb_uri.as_ref().unwrap(), &config.mongodb_name)
+                .expect("Failed to connect to MongoDB"),
+        ),
+        DatabaseBackend::Memory => Arc::new(MemoryDatabase::new()),
+    }
+}
+
+fn connect_blob_store(config: &BlobsConfig) -> Option<Arc<dyn BlobStore>> {
+    match config.backend.as_ref()? {
+        BlobBackend::Fs => Some(Arc::new(
+            FsBlobStore::new(config.path.as_ref().unwrap()).expect("Failed to open blob directory"),
+        )),
+        BlobBackend::S3 => Some(Arc::new(
+            S3BlobStore::new(S3Config {
+                endpoint: config.s3_endpoint.clone().unwrap(),
+                bucket: config.s3_bucket.clone().unwrap(),
+                region: config.s3_region.clone(),
+                access_key: config.s3_access_key.clone().unwrap(),
+                secret_key: config.s3_secret_key.clone().unwrap(),
+            })
+            .expect("Failed to configure S3 blob store"),
+        )),
+    }
 }
@@ -1,23 +1,21 @@
-use super::Database;
-
+use crate::server::{ZstdDictionary, ZSTD_MIN_SAMPLES, ZSTD_SAMPLE_SIZE};
 use bson::{oid::ObjectId, ordered::OrderedDocument, spec::BinarySubtype, Bson};
 use flate2::read::GzDecoder;
-use mongodb::coll::options::FindOptions;
 use parking_lot::Mutex;
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+use rand::{distributions::Alphanumeric, seq::SliceRandom, thread_rng, Rng};
 use rayon::prelude::*;
 use smmdb_common::{Course2, PermGen};
-use smmdb_db::DatabaseError;
-use std::{convert::TryInto, sync::Arc};
-use zstd::dict;
+use smmdb_db::{hash_course2_content, BlobDatabase, Database, DatabaseError};
+use std::{collections::HashMap, convert::TryInto, sync::Arc};
 
 pub struct Migration {
     name: String,
-    run: fn(&Database, &PermGen),
+    run: fn(&dyn Database, &PermGen),
 }
 
 impl Migration {
-    pub fn migrate(database: &Database, perm_gen: &PermGen) {
+    pub fn migrate(database: &dyn Database, perm_gen: &PermGen) {
         let mut migrations = vec![
             Migration {
                 name: "generate_api_keys".to_string(),
@@ -39,11 +37,14 @@ impl Migration {
                 name: "course2_hash_v2".to_string(),
                 run: Migration::course2_hash_v2,
             },
-            // TODO fix out of memory
-            // Migration {
-            //     name: "zstd_dictionary".to_string(),
-            //     run: Migration::zstd_dictionary,
-            // },
+            Migration {
+                name: "course2_uploader".to_string(),
+                run: Migration::course2_uploader,
+            },
+            Migration {
+                name: "course2_content_hash".to_string(),
+                run: Migration::course2_content_hash,
+            },
         ];
 
         let migrations_to_run = database
@@ -59,19 +60,57 @@ impl Migration {
             (migration.run)(database, perm_gen);
             Migration::store_migration_as_completed(database, migration);
         }
+
+        Migration::migrate_minhash_params(database, perm_gen);
+
+        let zstd_dictionary = "zstd_dictionary".to_string();
+        if database
+            .get_missing_migrations(vec![zstd_dictionary.clone()])
+            .unwrap()
+            .contains(&zstd_dictionary)
+            && Migration::migrate_zstd_dictionary(database)
+        {
+            database.migration_completed(zstd_dictionary).unwrap();
+        }
+    }
+
+    /// Rehashes all courses, if the MinHash parameters differ from those stored in `meta`.
+    ///
+    /// Hashes computed before the parameters were persisted used 128 permutations with seed 1.
+    fn migrate_minhash_params(database: &dyn Database, perm_gen: &PermGen) {
+        let stored = database.get_meta_value("minhash").unwrap();
+        let (num_perm, seed) = match &stored {
+            Some(Bson::Document(params)) => (
+                params.get_i64("num_perm").unwrap_or_default() as usize,
+                params.get_i64("seed").unwrap_or_default() as u32,
+            ),
+            _ => (128, 1),
+        };
+        if num_perm != perm_gen.num_perm() || seed != perm_gen.seed() {
+            println!(
+                "MinHash parameters changed from {} permutations with seed {} to {} permutations with seed {}",
+                num_perm,
+                seed,
+                perm_gen.num_perm(),
+                perm_gen.seed()
+            );
+            Migration::course2_hash_v2(database, perm_gen);
+        }
+        let params = doc! {
+            "num_perm" => perm_gen.num_perm() as i64,
+            "seed" => perm_gen.seed() as i64,
+        };
+        if stored != Some(Bson::Document(params.clone())) {
+            database.set_meta_value("minhash", params.into()).unwrap();
+        }
     }
 
-    fn store_migration_as_completed(database: &Database, migration: Migration) {
+    fn store_migration_as_completed(database: &dyn Database, migration: Migration) {
         database.migration_completed(migration.name).unwrap();
     }
 
-    fn generate_api_keys(database: &Database, _: &PermGen) {
-        let accounts: Vec<OrderedDocument> = database
-            .accounts
-            .find(None, None)
-            .unwrap()
-            .map(|item| item.unwrap())
-            .collect();
+    fn generate_api_keys(database: &dyn Database, _: &PermGen) {
+        let accounts: Vec<OrderedDocument> = database.find_accounts(doc! {}).unwrap();
         println!("Fixing missing apiKeys...");
         let mut fixed_count = 0u16;
         for account in accounts {
@@ -90,14 +129,14 @@ impl Migration {
                         "apikey" => apikey
                     }
                 };
-                database.accounts.update_one(filter, update, None).unwrap();
+                database.update_account(filter, update).unwrap();
                 fixed_count += 1;
             }
         }
         println!("Fixed {} accounts", fixed_count);
     }
 
-    fn migrate_bad_courses2(database: &Database, _: &PermGen) {
+    fn migrate_bad_courses2(database: &dyn Database, _: &PermGen) {
         println!("Fixing old SMM2 course formats...");
         let fixed_count = Arc::new(Mutex::new(0u32));
         Migration::get_courses2_result(database, vec![])
@@ -116,16 +155,17 @@ impl Migration {
         println!("Fixed {} SMM2 courses", fixed_count.lock());
     }
 
-    fn fix_course2(database: &Database, course_id: &Bson) -> Result<(), mongodb::Error> {
+    fn fix_course2(database: &dyn Database, course_id: &Bson) -> Result<(), DatabaseError> {
         use std::io::prelude::*;
 
         let doc = database
-            .course2_data
-            .find_one(
-                Some(doc! {
+            .get_course2(
+                doc! {
                     "_id" => course_id
-                }),
-                None,
+                },
+                doc! {
+                    "data_gz" => 1
+                },
             )?
             .unwrap();
         let bson = doc.get("data_gz").unwrap();
@@ -146,13 +186,13 @@ impl Migration {
                         "course" => doc_meta,
                     }
                 };
-                database.courses2.update_one(filter, update, None).unwrap();
+                database.update_course2(filter, update).unwrap();
             }
         }
         Ok(())
     }
 
-    fn migrate_course2_data(database: &Database, _: &PermGen) {
+    fn migrate_course2_data(database: &dyn Database, _: &PermGen) {
         println!("Converting SMM2 course data...");
         let fixed_count = Arc::new(Mutex::new(0u32));
         Migration::get_courses2_result(database, vec![])
@@ -168,16 +208,18 @@ impl Migration {
         println!("Converted {} SMM2 course data", fixed_count.lock());
     }
 
-    fn fix_course2_data(database: &Database, course: Course2) -> Result<bool, mongodb::Error> {
+    fn fix_course2_data(database: &dyn Database, course: Course2) -> Result<bool, DatabaseError> {
         use std::io::prelude::*;
 
         let doc = database
-            .course2_data
-            .find_one(
-                Some(doc! {
+            .get_course2(
+                doc! {
                     "_id" => course.get_id()
-                }),
-                None,
+                },
+                doc! {
+                    "data_gz" => 1,
+                    "thumb" => 1
+                },
             )?
             .unwrap();
         let bson_course = doc.get("data_gz");
@@ -211,16 +253,13 @@ impl Migration {
                         "data_br" => "",
                     }
                 };
-                database
-                    .course2_data
-                    .update_one(filter, update, None)
-                    .unwrap();
+                database.update_course2_data(filter, update).unwrap();
             }
         }
         Ok(true)
     }
 
-    fn add_smmdb_id(database: &Database, _: &PermGen) {
+    fn add_smmdb_id(database: &dyn Database, _: &PermGen) {
         println!("Adding SMMDB ID to course data...");
         let fixed_count = Arc::new(Mutex::new(0u32));
         let projection = doc! {
@@ -228,16 +267,9 @@ impl Migration {
             "data_encrypted" => 1
         };
         let courses: Vec<_> = database
-            .course2_data
-            .find(
-                None,
-                Some(FindOptions {
-                    projection: Some(projection),
-                    ..Default::default()
-                }),
-            )
+            .find_course2_data(doc! {}, projection)
             .unwrap()
-            .filter_map(Result::ok)
+            .into_iter()
             .filter_map(|doc| {
                 if let (Bson::Binary(_, data), Bson::ObjectId(course_id)) = (
                     doc.get("data_encrypted").unwrap().clone(),
@@ -260,10 +292,10 @@ impl Migration {
     }
 
     fn add_smmdb_id_to_course(
-        database: &Database,
+        database: &dyn Database,
         course_id: String,
         mut data: Vec<u8>,
-    ) -> Result<(), mongodb::Error> {
+    ) -> Result<(), DatabaseError> {
         let mut course = smmdb_lib::Course2::from_switch_files(&mut data, None, true).unwrap();
         course.set_smmdb_id(course_id.clone()).unwrap();
         let mut course_data = course.get_course_data_mut().to_vec();
@@ -277,22 +309,18 @@ impl Migration {
                 "data_encrypted" => Bson::Binary(BinarySubtype::Generic, course_data.to_vec()),
             }
         };
-        database
-            .course2_data
-            .update_one(filter, update, None)
-            .unwrap();
+        database.update_course2_data(filter, update).unwrap();
         Ok(())
     }
 
     fn get_courses2_result(
-        database: &Database,
+        database: &dyn Database,
         query: Vec<OrderedDocument>,
-    ) -> Result<Vec<Result<Course2, DatabaseError>>, mongodb::Error> {
-        let cursor = database.courses2.aggregate(query, None)?;
-
-        let courses: Vec<Result<Course2, DatabaseError>> = cursor
+    ) -> Result<Vec<Result<Course2, DatabaseError>>, DatabaseError> {
+        let courses: Vec<Result<Course2, DatabaseError>> = database
+            .get_courses2(query)?
+            .into_iter()
             .map(|item| -> Result<Course2, DatabaseError> {
-                let item = item?;
                 let course: Course2 = item
                     .clone()
                     .try_into()
@@ -304,33 +332,159 @@ impl Migration {
         Ok(courses)
     }
 
-    fn course2_hash_v2(database: &Database, perm_gen: &PermGen) {
+    /// Stores the username of the owner on every course, so that it is covered by the text index.
+    fn course2_uploader(database: &dyn Database, _: &PermGen) {
+        println!("Adding uploader to SMM2 courses...");
+        let usernames: HashMap<ObjectId, String> = database
+            .find_accounts(doc! {})
+            .unwrap()
+            .into_iter()
+            .filter_map(|account| {
+                let id = account.get_object_id("_id").ok()?.clone();
+                let username = account.get_str("username").ok()?.to_string();
+                Some((id, username))
+            })
+            .collect();
+        let courses = database
+            .find_courses2(
+                doc! {},
+                Some(doc! {
+                    "owner" => 1
+                }),
+            )
+            .unwrap();
+        let mut fixed_count = 0u32;
+        for course in courses {
+            let username = match course
+                .get_object_id("owner")
+                .ok()
+                .and_then(|owner| usernames.get(owner))
+            {
+                Some(username) => username.clone(),
+                None => continue,
+            };
+            let filter = doc! {
+                "_id" => course.get_object_id("_id").unwrap().clone()
+            };
+            let update = doc! {
+                "$set" => {
+                    "uploader" => username
+                }
+            };
+            database.update_course2(filter, update).unwrap();
+            fixed_count += 1;
+        }
+        println!("Added uploader to {} SMM2 courses", fixed_count);
+    }
+
+    /// Stores the content hash of all versions of all courses, which validates conditional
+    /// requests without loading the course.
+    ///
+    /// Versions are hashed one by one. Those, which can't be hashed, are hashed whenever they
+    /// are served instead.
+    fn course2_content_hash(database: &dyn Database, _: &PermGen) {
+        println!("Hashing content of SMM2 courses...");
+        let dictionary = ZstdDictionary::load_current(database).unwrap();
+        let mut projection = ZstdDictionary::data_projection();
+        projection.insert("thumb", 1);
+        projection.insert("thumb_encrypted", 1);
+        let missing = doc! {
+            "content_hash" => {
+                "$exists" => false
+            }
+        };
+        let mut fixed_count = 0u32;
+
+        for course_id in Migration::get_course2_data_ids(database, missing.clone()) {
+            let filter = doc! {
+                "_id" => course_id.clone()
+            };
+            let res = database
+                .get_course2(filter.clone(), projection.clone())
+                .and_then(|doc| match doc {
+                    Some(mut doc) => {
+                        let content_hash =
+                            Migration::content_hash_of(dictionary.as_ref(), database, &mut doc)?;
+                        let update = doc! {
+                            "$set" => {
+                                "content_hash" => content_hash
+                            }
+                        };
+                        database.update_course2_data(filter, update)
+                    }
+                    None => Ok(()),
+                });
+            match res {
+                Ok(()) => fixed_count += 1,
+                Err(err) => println!("Hashing course {} failed: {}", course_id, err),
+            }
+        }
+
+        let history_ids: Vec<Bson> = database
+            .find_course2_history(
+                missing,
+                doc! {
+                    "_id" => 1
+                },
+            )
+            .unwrap()
+            .into_iter()
+            .filter_map(|mut doc| doc.remove("_id"))
+            .collect();
+        for history_id in history_ids {
+            let filter = doc! {
+                "_id" => history_id.clone()
+            };
+            let res = database
+                .find_course2_history(filter.clone(), projection.clone())
+                .and_then(|docs| match docs.into_iter().next() {
+                    Some(mut doc) => {
+                        let content_hash =
+                            Migration::content_hash_of(dictionary.as_ref(), database, &mut doc)?;
+                        let update = doc! {
+                            "$set" => {
+                                "content_hash" => content_hash
+                            }
+                        };
+                        database.update_course2_history(filter, update)
+                    }
+                    None => Ok(()),
+                });
+            match res {
+                Ok(()) => fixed_count += 1,
+                Err(err) => println!("Hashing course version {} failed: {}", history_id, err),
+            }
+        }
+        println!("Hashed content of {} SMM2 course versions", fixed_count);
+    }
+
+    fn content_hash_of(
+        dictionary: Option<&ZstdDictionary>,
+        database: &dyn Database,
+        doc: &mut OrderedDocument,
+    ) -> Result<String, DatabaseError> {
+        let data = ZstdDictionary::take_data_encrypted(dictionary, database, doc)?;
+        let thumb = doc.get("thumb").unwrap_or(&Bson::Null);
+        let thumb_encrypted = doc.get("thumb_encrypted").unwrap_or(&Bson::Null);
+        Ok(hash_course2_content(&data, thumb, thumb_encrypted))
+    }
+
+    fn course2_hash_v2(database: &dyn Database, perm_gen: &PermGen) {
         println!("Adjusting course2 hashes...");
         let fixed_count = Arc::new(Mutex::new(0u32));
-        let projection = doc! {
-            "_id" => 1,
-            "data_encrypted" => 1
-        };
+        let dictionary = ZstdDictionary::load_current(database).unwrap();
+        let mut projection = ZstdDictionary::data_projection();
+        projection.insert("_id", 1);
         let courses: Vec<_> = database
-            .course2_data
-            .find(
-                None,
-                Some(FindOptions {
-                    projection: Some(projection),
-                    ..Default::default()
-                }),
-            )
+            .find_course2_data(doc! {}, projection)
             .unwrap()
-            .filter_map(Result::ok)
-            .filter_map(|doc| {
-                if let (Bson::Binary(_, data), Bson::ObjectId(course_id)) = (
-                    doc.get("data_encrypted").unwrap().clone(),
-                    doc.get("_id").unwrap().clone(),
-                ) {
-                    Some((course_id.to_string(), data))
-                } else {
-                    None
-                }
+            .into_iter()
+            .filter_map(|mut doc| {
+                let course_id = doc.get_object_id("_id").ok()?.to_string();
+                let data =
+                    ZstdDictionary::take_data_encrypted(dictionary.as_ref(), database, &mut doc)
+                        .ok()?;
+                Some((course_id, data))
             })
             .collect();
 
@@ -344,11 +498,11 @@ impl Migration {
     }
 
     fn adjust_hash(
-        database: &Database,
+        database: &dyn Database,
         perm_gen: &PermGen,
         course_id: String,
         mut data: Vec<u8>,
-    ) -> Result<(), mongodb::Error> {
+    ) -> Result<(), DatabaseError> {
         let course = smmdb_lib::Course2::from_switch_files(&mut data, None, true).unwrap();
         let course = Course2::insert(ObjectId::new().unwrap(), &course, None, perm_gen);
 
@@ -361,51 +515,189 @@ impl Migration {
                 "hash" => hash,
             }
         };
-        database.courses2.update_one(filter, update, None).unwrap();
+        database.update_course2(filter, update).unwrap();
         Ok(())
     }
 
-    #[allow(unused)]
-    fn zstd_dictionary(database: &Database) {
-        let options = FindOptions {
-            projection: Some(doc! {
-                "data_encrypted" => 1,
-            }),
-            ..FindOptions::default()
+    /// Moves course binaries of all versions, which are still stored in the database, into the
+    /// blob store.
+    ///
+    /// Courses are moved one by one, so this resumes where it stopped, if it is interrupted. It
+    /// only runs again, if moving a course failed.
+    pub fn migrate_blobs(database: &BlobDatabase) {
+        let migration = "blobs".to_string();
+        if !database
+            .get_missing_migrations(vec![migration.clone()])
+            .unwrap()
+            .contains(&migration)
+        {
+            return;
+        }
+
+        let fixed_count = Arc::new(Mutex::new(0u32));
+        let failed_count = Arc::new(Mutex::new(0u32));
+        let course_ids = Migration::get_course2_data_ids(database, doc! {});
+        course_ids.into_par_iter().for_each(|course_id| {
+            match database.externalize_course2(&course_id) {
+                Ok(true) => *fixed_count.lock() += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    println!("Moving course {} to blob store failed: {}", course_id, err);
+                    *failed_count.lock() += 1;
+                }
+            }
+        });
+
+        let projection = doc! {
+            "_id" => 1
+        };
+        let history_ids: Vec<Bson> = database
+            .find_course2_history(doc! {}, projection)
+            .unwrap()
+            .into_iter()
+            .filter_map(|mut doc| doc.remove("_id"))
+            .collect();
+        history_ids.into_par_iter().for_each(|history_id| {
+            match database.externalize_course2_history(&history_id) {
+                Ok(true) => *fixed_count.lock() += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    println!(
+                        "Moving course version {} to blob store failed: {}",
+                        history_id, err
+                    );
+                    *failed_count.lock() += 1;
+                }
+            }
+        });
+
+        let fixed_count = *fixed_count.lock();
+        if fixed_count > 0 {
+            println!("Moved {} SMM2 course versions to blob store", fixed_count);
+        }
+        let failed_count = *failed_count.lock();
+        if failed_count == 0 {
+            database.migration_completed(migration).unwrap();
+        }
+    }
+
+    /// Trains a zstd dictionary, once there are enough courses, and compresses the data of all
+    /// courses, which haven't been compressed with the current dictionary yet.
+    ///
+    /// Only course ids are loaded at once, so this resumes where it stopped, if it is interrupted.
+    /// Returns whether all courses have been compressed. Courses uploaded afterwards are
+    /// compressed by jobs.
+    fn migrate_zstd_dictionary(database: &dyn Database) -> bool {
+        let dictionary = match ZstdDictionary::load_current(database).unwrap() {
+            Some(dictionary) => dictionary,
+            None => match Migration::train_zstd_dictionary(database) {
+                Some(dictionary) => dictionary,
+                None => return false,
+            },
+        };
+
+        let filter = doc! {
+            "data_zstd_version" => {
+                "$ne" => dictionary.version
+            }
         };
-        let cursor = database.course2_data.find(None, Some(options)).unwrap();
-
-        let sample_sizes = Arc::new(Mutex::new(vec![]));
-        let courses = Arc::new(Mutex::new(Vec::new()));
-        // courses
-        //     .lock()
-        //     .try_reserve(376_832 * cursor.size_hint().0)
-        //     .unwrap();
-        // courses.append(
-
-        // );
-        cursor.into_iter().par_bridge().for_each(|course| {
-            let mut course = course.unwrap();
-            let course = course.get_binary_generic_mut(&"data_encrypted").unwrap();
-            smmdb_lib::Course2::decrypt(course);
-
-            if course.len() != 376_832 {
-                panic!("sample size must not change");
+        let course_ids = Migration::get_course2_data_ids(database, filter);
+        if course_ids.is_empty() {
+            return true;
+        }
+        println!(
+            "Compressing {} SMM2 courses with zstd dictionary v{}...",
+            course_ids.len(),
+            dictionary.version
+        );
+        let fixed_count = Arc::new(Mutex::new(0u32));
+        let failed_count = Arc::new(Mutex::new(0u32));
+        course_ids.into_par_iter().for_each(|course_id| {
+            let res =
+                Migration::get_course2_data_encrypted(database, &course_id).and_then(|data| {
+                    match data {
+                        Some(data) => dictionary.store_course(database, course_id, data).map(Some),
+                        None => Ok(None),
+                    }
+                });
+            match res {
+                Ok(Some(_)) => *fixed_count.lock() += 1,
+                Ok(None) => {}
+                Err(err) => {
+                    println!("Compressing course failed: {}", err);
+                    *failed_count.lock() += 1;
+                }
             }
-            // if let Ok(course) = courses.lock().try_reserve(376_832) {}
-            // sample_sizes.lock().push(*sample_size.read());
-            // // dbg!(course.len());
-            // courses.lock().extend(&course[..])
         });
+        println!("Compressed {} SMM2 courses", fixed_count.lock());
+        let failed_count = *failed_count.lock();
+        failed_count == 0
+    }
 
-        let dict = dict::from_continuous(
-            // &courses[..],
-            &Arc::try_unwrap(courses).unwrap().into_inner()[..],
-            &Arc::try_unwrap(sample_sizes).unwrap().into_inner()[..],
-            376_832,
-        )
-        .unwrap();
+    /// Trains a dictionary on a random sample of courses, which is bounded by
+    /// `ZSTD_SAMPLE_SIZE`.
+    fn train_zstd_dictionary(database: &dyn Database) -> Option<ZstdDictionary> {
+        let mut course_ids = Migration::get_course2_data_ids(database, doc! {});
+        if course_ids.len() < ZSTD_MIN_SAMPLES {
+            return None;
+        }
+        println!("Training zstd dictionary...");
+        course_ids.shuffle(&mut thread_rng());
+
+        let mut samples = vec![];
+        let mut sample_sizes = vec![];
+        for course_id in course_ids {
+            let mut data = match Migration::get_course2_data_encrypted(database, &course_id) {
+                Ok(Some(data)) => data,
+                _ => continue,
+            };
+            if smmdb_lib::Course2::decrypt(&mut data).is_err() {
+                continue;
+            }
+            if samples.len() + data.len() > ZSTD_SAMPLE_SIZE {
+                break;
+            }
+            sample_sizes.push(data.len());
+            samples.extend_from_slice(&data);
+        }
+        if sample_sizes.len() < ZSTD_MIN_SAMPLES {
+            return None;
+        }
+
+        let dictionary = ZstdDictionary::train(database, &samples, &sample_sizes).unwrap();
+        dictionary.store(database).unwrap();
+        println!(
+            "Trained zstd dictionary v{} on {} SMM2 courses",
+            dictionary.version,
+            sample_sizes.len()
+        );
+        Some(dictionary)
+    }
 
-        todo!();
+    fn get_course2_data_ids(database: &dyn Database, filter: OrderedDocument) -> Vec<ObjectId> {
+        let projection = doc! {
+            "_id" => 1
+        };
+        database
+            .find_course2_data(filter, projection)
+            .unwrap()
+            .into_iter()
+            .filter_map(|doc| doc.get_object_id("_id").ok().cloned())
+            .collect()
+    }
+
+    fn get_course2_data_encrypted(
+        database: &dyn Database,
+        course_id: &ObjectId,
+    ) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let filter = doc! {
+            "_id" => course_id.clone()
+        };
+        match database.get_course2(filter, ZstdDictionary::data_projection())? {
+            Some(mut doc) => Ok(Some(ZstdDictionary::take_data_encrypted(
+                None, database, &mut doc,
+            )?)),
+            None => Ok(None),
+        }
     }
 }
@@ -1,5 +1,5 @@
 use crate::{
-    server::{Data, ServerData},
+    server::{block, Data, ServerData},
     Database,
 };
 
@@ -9,6 +9,7 @@ use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema, Mounta
 use protobuf::ProtobufEnum;
 use serde::Deserialize;
 use serde_qs::actix::QsQuery;
+use smmdb_db::DatabaseError;
 use smmdb_lib::proto::SMMCourse::{
     SMMCourse_AutoScroll, SMMCourse_CourseTheme, SMMCourse_GameStyle,
 };
@@ -24,7 +25,8 @@ async fn get_courses(
     query: QsQuery<GetCourses>,
     _req: HttpRequest,
 ) -> Result<String, GetCoursesError> {
-    data.get_courses(query.into_inner())
+    let query = query.into_inner();
+    block(move || data.get_courses(query)).await
 }
 
 #[derive(Apiv2Schema, Deserialize, Debug)]
@@ -62,7 +64,7 @@ pub struct GetCourses {
 impl GetCourses {
     pub fn into_ordered_document(
         self,
-        database: &Database,
+        database: &dyn Database,
     ) -> Result<Vec<OrderedDocument>, GetCoursesError> {
         let mut pipeline = vec![];
 
@@ -84,7 +86,10 @@ impl GetCourses {
         Ok(pipeline)
     }
 
-    fn get_match(&self, database: &Database) -> Result<Option<OrderedDocument>, GetCoursesError> {
+    fn get_match(
+        &self,
+        database: &dyn Database,
+    ) -> Result<Option<OrderedDocument>, GetCoursesError> {
         let mut res = doc! {};
         if let Some(id) = &self.id {
             GetCourses::insert_objectid(&mut res, "_id".to_string(), id)?;
@@ -278,7 +283,7 @@ impl Default for Limit {
     }
 }
 
-#[api_v2_errors(code = 400)]
+#[api_v2_errors(code = 400, code = 500)]
 #[derive(Apiv2Schema, Debug, Error)]
 pub enum GetCoursesError {
     #[error("[GetCoursesError::LimitTooLow]: limit must be at least 1")]
@@ -289,6 +294,8 @@ pub enum GetCoursesError {
     Deserialize(String),
     #[error("[GetCoursesError::UploaderUnknown]: {0}")]
     UploaderUnknown(String),
+    #[error("[GetCoursesError::Database]: {0}")]
+    Database(#[from] DatabaseError),
 }
 
 impl ResponseError for GetCoursesError {
@@ -298,6 +305,7 @@ impl ResponseError for GetCoursesError {
             GetCoursesError::LimitTooHigh => HttpResponse::new(StatusCode::BAD_REQUEST),
             GetCoursesError::Deserialize(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
             GetCoursesError::UploaderUnknown(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            GetCoursesError::Database(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
         }
     }
 }
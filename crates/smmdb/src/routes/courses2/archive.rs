@@ -0,0 +1,491 @@
+//! Streaming tar and zip archives.
+//!
+//! Entries are written one after another as soon as their content is available, so that an
+//! archive never has to be buffered as a whole. Zip entries are stored uncompressed, since
+//! encrypted and brotli compressed course data doesn't compress any further.
+
+use super::{
+    cache::Validators,
+    download::{DownloadCourse2Error, FileFormat},
+};
+
+use actix_http::{body::SizedStream, http::header};
+use actix_web::{
+    dev::HttpResponseBuilder,
+    web::{BufMut, Bytes, BytesMut},
+    Error, HttpResponse,
+};
+use futures::{
+    stream::{self, LocalBoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
+use std::{convert::TryFrom, time::SystemTime};
+use tar::Header;
+
+const TAR_BLOCK_SIZE: u64 = 512;
+const ZIP_LOCAL_HEADER_SIZE: u64 = 30;
+const ZIP_CENTRAL_HEADER_SIZE: u64 = 46;
+const ZIP_END_SIZE: u64 = 22;
+
+pub type ArchiveEntry = (String, Vec<u8>);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+impl TryFrom<&FileFormat> for ArchiveFormat {
+    type Error = DownloadCourse2Error;
+
+    fn try_from(file_format: &FileFormat) -> Result<Self, Self::Error> {
+        match file_format {
+            FileFormat::Tar => Ok(ArchiveFormat::Tar),
+            FileFormat::Zip => Ok(ArchiveFormat::Zip),
+            FileFormat::Raw => Err(DownloadCourse2Error::UnsupportedFileFormat),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    /// Returns validators of the archive, which is fully determined by the names of its entries,
+    /// their modification time and the content they are derived from.
+    pub fn validators(
+        self,
+        mtime: u64,
+        names: &[String],
+        content_hash: &str,
+        last_modified: Option<i64>,
+    ) -> Validators {
+        let mtime = mtime.to_le_bytes();
+        let mut parts: Vec<&[u8]> =
+            vec![self.extension().as_bytes(), &mtime, content_hash.as_bytes()];
+        for name in names {
+            parts.push(name.as_bytes());
+        }
+        Validators::new(&parts, last_modified)
+    }
+
+    /// Returns the exact size of an archive with the given entries.
+    pub fn archive_len(self, entries: &[ArchiveEntry]) -> u64 {
+        let entries_len: u64 = entries
+            .iter()
+            .map(|(name, content)| {
+                let (name_len, content_len) = (name.len() as u64, content.len() as u64);
+                match self {
+                    ArchiveFormat::Tar => {
+                        TAR_BLOCK_SIZE
+                            + (content_len + TAR_BLOCK_SIZE - 1) / TAR_BLOCK_SIZE * TAR_BLOCK_SIZE
+                    }
+                    ArchiveFormat::Zip => {
+                        ZIP_LOCAL_HEADER_SIZE + ZIP_CENTRAL_HEADER_SIZE + 2 * name_len + content_len
+                    }
+                }
+            })
+            .sum();
+        match self {
+            ArchiveFormat::Tar => entries_len + 2 * TAR_BLOCK_SIZE,
+            ArchiveFormat::Zip => entries_len + ZIP_END_SIZE,
+        }
+    }
+
+    /// Rejects entries, which don't fit into the fixed size fields of the format.
+    fn check_limits(self, entries: &[ArchiveEntry]) -> Result<(), DownloadCourse2Error> {
+        match self {
+            ArchiveFormat::Tar => Ok(()),
+            ArchiveFormat::Zip => {
+                if entries.len() > u16::MAX as usize
+                    || entries
+                        .iter()
+                        .any(|(name, _)| name.len() > u16::MAX as usize)
+                    || self.archive_len(entries) > u32::MAX as u64
+                {
+                    Err(DownloadCourse2Error::ArchiveTooLarge)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+/// Responds with an archive of entries, which are already in memory.
+///
+/// `mtime` is the modification time of all entries as Unix timestamp in seconds.
+pub fn archive_response(
+    format: ArchiveFormat,
+    name: &str,
+    entries: Vec<ArchiveEntry>,
+    mtime: u64,
+) -> Result<HttpResponse, DownloadCourse2Error> {
+    format.check_limits(&entries)?;
+    let len = format.archive_len(&entries);
+    let entries = stream::once(async { Ok(entries) }).boxed_local();
+    let stream = archive_stream(format, mtime, entries)
+        .map_err(Error::from)
+        .boxed_local();
+    Ok(response_builder(format, name).body(SizedStream::new(len, stream)))
+}
+
+/// Responds with an archive of entries, which are only fetched when the previous entries have
+/// been written.
+///
+/// The size of the archive is unknown, so the response is chunked.
+pub fn streaming_archive_response<S>(format: ArchiveFormat, name: &str, entries: S) -> HttpResponse
+where
+    S: Stream<Item = Result<Vec<ArchiveEntry>, DownloadCourse2Error>> + 'static,
+{
+    response_builder(format, name)
+        .streaming(archive_stream(format, unix_time(), entries.boxed_local()).boxed_local())
+}
+
+fn response_builder(format: ArchiveFormat, name: &str) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::Ok();
+    builder.content_type(format.content_type()).set_header(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}.{}\"", name, format.extension()),
+    );
+    builder
+}
+
+fn archive_stream(
+    format: ArchiveFormat,
+    mtime: u64,
+    entries: LocalBoxStream<'static, Result<Vec<ArchiveEntry>, DownloadCourse2Error>>,
+) -> impl Stream<Item = Result<Bytes, DownloadCourse2Error>> {
+    let writer = ArchiveWriter::new(format, mtime);
+    stream::unfold(Some((writer, entries)), |state| async move {
+        let (mut writer, mut entries) = state?;
+        match entries.next().await {
+            Some(Ok(entries_chunk)) => {
+                let mut chunk = BytesMut::new();
+                for (name, content) in entries_chunk {
+                    if let Err(err) = writer.write_entry(&mut chunk, &name, &content) {
+                        return Some((Err(err), None));
+                    }
+                }
+                Some((Ok(chunk.freeze()), Some((writer, entries))))
+            }
+            Some(Err(err)) => Some((Err(err), None)),
+            None => {
+                let mut chunk = BytesMut::new();
+                match writer.finish(&mut chunk) {
+                    Ok(()) => Some((Ok(chunk.freeze()), None)),
+                    Err(err) => Some((Err(err), None)),
+                }
+            }
+        }
+    })
+}
+
+struct ArchiveWriter {
+    format: ArchiveFormat,
+    /// Unix timestamp in seconds.
+    mtime: u64,
+    offset: u64,
+    central_directory: BytesMut,
+    entries: u16,
+}
+
+impl ArchiveWriter {
+    fn new(format: ArchiveFormat, mtime: u64) -> Self {
+        ArchiveWriter {
+            format,
+            mtime,
+            offset: 0,
+            central_directory: BytesMut::new(),
+            entries: 0,
+        }
+    }
+
+    fn write_entry(
+        &mut self,
+        buf: &mut BytesMut,
+        name: &str,
+        content: &[u8],
+    ) -> Result<(), DownloadCourse2Error> {
+        let start = buf.len();
+        match self.format {
+            ArchiveFormat::Tar => {
+                let mut header = Header::new_gnu();
+                header
+                    .set_path(name)
+                    .map_err(|_| DownloadCourse2Error::InvalidEntryName(name.to_string()))?;
+                header.set_size(content.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(self.mtime);
+                header.set_cksum();
+                buf.put_slice(header.as_bytes());
+                buf.put_slice(content);
+                let padding =
+                    (TAR_BLOCK_SIZE - content.len() as u64 % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+                buf.put_slice(&vec![0; padding as usize]);
+            }
+            ArchiveFormat::Zip => {
+                if self.entries == u16::MAX
+                    || name.len() > u16::MAX as usize
+                    || content.len() as u64 > u32::MAX as u64
+                    || self.offset > u32::MAX as u64
+                {
+                    return Err(DownloadCourse2Error::ArchiveTooLarge);
+                }
+                let (time, date) = dos_date_time(self.mtime);
+                let crc = crc32fast::hash(content);
+                let name = name.as_bytes();
+
+                buf.put_u32_le(0x0403_4b50);
+                buf.put_u16_le(10);
+                buf.put_u16_le(0);
+                buf.put_u16_le(0);
+                buf.put_u16_le(time);
+                buf.put_u16_le(date);
+                buf.put_u32_le(crc);
+                buf.put_u32_le(content.len() as u32);
+                buf.put_u32_le(content.len() as u32);
+                buf.put_u16_le(name.len() as u16);
+                buf.put_u16_le(0);
+                buf.put_slice(name);
+                buf.put_slice(content);
+
+                let central_directory = &mut self.central_directory;
+                central_directory.put_u32_le(0x0201_4b50);
+                // made by Unix, so that file permissions are honored
+                central_directory.put_u16_le(0x031e);
+                central_directory.put_u16_le(10);
+                central_directory.put_u16_le(0);
+                central_directory.put_u16_le(0);
+                central_directory.put_u16_le(time);
+                central_directory.put_u16_le(date);
+                central_directory.put_u32_le(crc);
+                central_directory.put_u32_le(content.len() as u32);
+                central_directory.put_u32_le(content.len() as u32);
+                central_directory.put_u16_le(name.len() as u16);
+                central_directory.put_u16_le(0);
+                central_directory.put_u16_le(0);
+                central_directory.put_u16_le(0);
+                central_directory.put_u16_le(0);
+                central_directory.put_u32_le(0o100_644 << 16);
+                central_directory.put_u32_le(self.offset as u32);
+                central_directory.put_slice(name);
+            }
+        }
+        self.offset += (buf.len() - start) as u64;
+        self.entries = self.entries.saturating_add(1);
+        Ok(())
+    }
+
+    fn finish(&mut self, buf: &mut BytesMut) -> Result<(), DownloadCourse2Error> {
+        match self.format {
+            ArchiveFormat::Tar => buf.put_slice(&[0; 2 * TAR_BLOCK_SIZE as usize]),
+            ArchiveFormat::Zip => {
+                if self.offset > u32::MAX as u64
+                    || self.central_directory.len() as u64 > u32::MAX as u64
+                {
+                    return Err(DownloadCourse2Error::ArchiveTooLarge);
+                }
+                buf.put_slice(&self.central_directory);
+                buf.put_u32_le(0x0605_4b50);
+                buf.put_u16_le(0);
+                buf.put_u16_le(0);
+                buf.put_u16_le(self.entries);
+                buf.put_u16_le(self.entries);
+                buf.put_u32_le(self.central_directory.len() as u32);
+                buf.put_u32_le(self.offset as u32);
+                buf.put_u16_le(0);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the current Unix timestamp in seconds.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Converts a Unix timestamp to MS-DOS time and date.
+fn dos_date_time(secs: u64) -> (u16, u16) {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    if year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let secs = secs % 86400;
+    let time = (secs / 3600) << 11 | (secs % 3600 / 60) << 5 | (secs % 60) / 2;
+    let date = ((year - 1980) as u64) << 9 | (month as u64) << 5 | day as u64;
+    (time as u16, date as u16)
+}
+
+/// Converts days since the Unix epoch to year, month and day of the proleptic Gregorian
+/// calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Cursor, Read};
+
+    // 2020-09-13T12:26:40Z
+    const MTIME: u64 = 1_600_000_000;
+
+    fn entries() -> Vec<ArchiveEntry> {
+        vec![
+            ("course_data_000.bcd".to_string(), vec![1; 1000]),
+            ("course_thumb_000.btl".to_string(), vec![2; 512]),
+            ("empty".to_string(), vec![]),
+        ]
+    }
+
+    fn write_archive(
+        format: ArchiveFormat,
+        entries: &[ArchiveEntry],
+    ) -> Result<BytesMut, DownloadCourse2Error> {
+        let mut writer = ArchiveWriter::new(format, MTIME);
+        let mut buf = BytesMut::new();
+        for (name, content) in entries {
+            writer.write_entry(&mut buf, name, content)?;
+        }
+        writer.finish(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn round_trips_zip_archives() {
+        let entries = entries();
+        let bytes = write_archive(ArchiveFormat::Zip, &entries).unwrap();
+        assert_eq!(ArchiveFormat::Zip.archive_len(&entries), bytes.len() as u64);
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(&bytes[..])).unwrap();
+        assert_eq!(archive.len(), entries.len());
+        for (i, (name, content)) in entries.iter().enumerate() {
+            let mut file = archive.by_index(i).unwrap();
+            assert_eq!(file.name(), name);
+            let last_modified = file.last_modified();
+            assert_eq!(
+                (
+                    last_modified.year(),
+                    last_modified.month(),
+                    last_modified.day()
+                ),
+                (2020, 9, 13)
+            );
+            assert_eq!(
+                (
+                    last_modified.hour(),
+                    last_modified.minute(),
+                    last_modified.second()
+                ),
+                (12, 26, 40)
+            );
+            let mut read = vec![];
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(&read, content);
+        }
+    }
+
+    #[test]
+    fn round_trips_tar_archives() {
+        let entries = entries();
+        let bytes = write_archive(ArchiveFormat::Tar, &entries).unwrap();
+        assert_eq!(ArchiveFormat::Tar.archive_len(&entries), bytes.len() as u64);
+
+        let mut archive = tar::Archive::new(&bytes[..]);
+        let mut read_entries = vec![];
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), MTIME);
+            let name = entry.path().unwrap().to_str().unwrap().to_string();
+            let mut content = vec![];
+            entry.read_to_end(&mut content).unwrap();
+            read_entries.push((name, content));
+        }
+        assert_eq!(read_entries, entries);
+    }
+
+    #[test]
+    fn rejects_invalid_tar_entry_names() {
+        let entries = vec![("../course_data_000.bcd".to_string(), vec![])];
+        assert!(matches!(
+            write_archive(ArchiveFormat::Tar, &entries),
+            Err(DownloadCourse2Error::InvalidEntryName(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_zip_archives_with_too_many_entries() {
+        let entries = vec![(String::new(), vec![]); u16::MAX as usize + 1];
+        assert!(matches!(
+            ArchiveFormat::Zip.check_limits(&entries),
+            Err(DownloadCourse2Error::ArchiveTooLarge)
+        ));
+        assert!(ArchiveFormat::Zip
+            .check_limits(&entries[..u16::MAX as usize])
+            .is_ok());
+        assert!(matches!(
+            write_archive(ArchiveFormat::Zip, &entries),
+            Err(DownloadCourse2Error::ArchiveTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_zip_entries_beyond_4_gib() {
+        let mut writer = ArchiveWriter::new(ArchiveFormat::Zip, MTIME);
+        writer.offset = u32::MAX as u64 + 1;
+        assert!(matches!(
+            writer.write_entry(&mut BytesMut::new(), "course_data_000.bcd", &[]),
+            Err(DownloadCourse2Error::ArchiveTooLarge)
+        ));
+        assert!(matches!(
+            writer.finish(&mut BytesMut::new()),
+            Err(DownloadCourse2Error::ArchiveTooLarge)
+        ));
+    }
+
+    #[test]
+    fn converts_days_to_civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(3652), (1980, 1, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn converts_unix_time_to_dos_date_time() {
+        assert_eq!(dos_date_time(0), (0, 1 << 5 | 1));
+        assert_eq!(dos_date_time(3652 * 86400), (0, 1 << 5 | 1));
+        assert_eq!(
+            dos_date_time(MTIME),
+            (12 << 11 | 26 << 5 | 20, 40 << 9 | 9 << 5 | 13)
+        );
+    }
+}
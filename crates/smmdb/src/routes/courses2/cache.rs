@@ -0,0 +1,89 @@
+//! Validators for conditional requests of immutable course content.
+
+use actix_http::http::{
+    header::{self, EntityTag, Header, HttpDate, IfModifiedSince, IfNoneMatch},
+    HeaderValue, StatusCode,
+};
+use actix_web::{HttpRequest, HttpResponse};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime};
+
+/// Cache policy of course downloads, which only change when a course is re-uploaded.
+pub const DOWNLOAD_CACHE_CONTROL: &str = "public, max-age=86400, must-revalidate";
+
+/// Cache policy of versioned zstd dictionaries, which never change.
+pub const DICTIONARY_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Cache policy of course thumbnails.
+pub const THUMBNAIL_CACHE_CONTROL: &str = "public, max-age=604800";
+
+pub struct Validators {
+    etag: EntityTag,
+    last_modified: Option<HttpDate>,
+}
+
+impl Validators {
+    /// Derives a strong ETag from the hash of all parts identifying the content.
+    ///
+    /// Course content is identified by the content hash stored with it and by the requested
+    /// representation, so that it doesn't have to be loaded to validate a request.
+    ///
+    /// `last_modified` is given in milliseconds.
+    pub fn new(parts: &[&[u8]], last_modified: Option<i64>) -> Self {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(&(part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        let etag = EntityTag::strong(hex::encode(&hasher.finalize()[..16]));
+        let last_modified = last_modified.map(|last_modified| {
+            let secs = (last_modified / 1000).max(0) as u64;
+            HttpDate::from(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+        });
+        Validators {
+            etag,
+            last_modified,
+        }
+    }
+
+    /// Returns whether the copy cached by the client is still valid.
+    ///
+    /// `If-Modified-Since` is only evaluated without `If-None-Match`.
+    pub fn is_fresh(&self, req: &HttpRequest) -> bool {
+        if req.headers().contains_key(header::IF_NONE_MATCH) {
+            return match IfNoneMatch::parse(req) {
+                Ok(IfNoneMatch::Any) => true,
+                Ok(IfNoneMatch::Items(etags)) => etags.iter().any(|etag| etag.weak_eq(&self.etag)),
+                Err(_) => false,
+            };
+        }
+        match (&self.last_modified, IfModifiedSince::parse(req)) {
+            (Some(last_modified), Ok(IfModifiedSince(since))) => *last_modified <= since,
+            _ => false,
+        }
+    }
+
+    pub fn not_modified(&self, cache_control: &'static str) -> HttpResponse {
+        let mut res = HttpResponse::new(StatusCode::NOT_MODIFIED);
+        self.set_headers(&mut res, cache_control);
+        res
+    }
+
+    pub fn set_headers(&self, res: &mut HttpResponse, cache_control: &'static str) {
+        let headers = res.headers_mut();
+        headers.insert(
+            header::ETAG,
+            HeaderValue::from_str(&self.etag.to_string()).unwrap(),
+        );
+        if let Some(last_modified) = &self.last_modified {
+            headers.insert(
+                header::LAST_MODIFIED,
+                HeaderValue::from_str(&last_modified.to_string()).unwrap(),
+            );
+        }
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(cache_control),
+        );
+    }
+}
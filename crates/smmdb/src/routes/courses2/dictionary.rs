@@ -0,0 +1,85 @@
+use super::cache::{Validators, DICTIONARY_CACHE_CONTROL, DOWNLOAD_CACHE_CONTROL};
+use crate::server::{block, ServerData};
+
+use actix_http::http::{header, HeaderName, HeaderValue};
+use actix_web::{error::ResponseError, http::StatusCode, HttpRequest, HttpResponse};
+use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema};
+use serde::Deserialize;
+use serde_qs::actix::QsQuery;
+use smmdb_db::DatabaseError;
+use thiserror::Error;
+
+/// Header carrying the version of the zstd dictionary, which course data has been compressed with.
+pub const DICTIONARY_VERSION_HEADER: &str = "x-dictionary-version";
+
+/// Returns the zstd dictionary needed to decompress course data downloaded in the `zstd` course
+/// format.
+///
+/// Without `version`, the current dictionary is returned. Dictionaries of a given version never
+/// change.
+#[api_v2_operation(tags(SMM2))]
+pub async fn get_dictionary(
+    data: web::Data<ServerData>,
+    query: QsQuery<GetDictionary>,
+    req: HttpRequest,
+) -> Result<HttpResponse, GetDictionaryError> {
+    let version = query.version;
+    let dictionary = block(move || data.get_zstd_dictionary(version))
+        .await?
+        .ok_or(GetDictionaryError::NotFound)?;
+
+    let cache_control = if version.is_some() {
+        DICTIONARY_CACHE_CONTROL
+    } else {
+        DOWNLOAD_CACHE_CONTROL
+    };
+    let validators = Validators::new(&[&dictionary.dictionary], Some(dictionary.created));
+    let mut res = if validators.is_fresh(&req) {
+        validators.not_modified(cache_control)
+    } else {
+        let mut res = HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .set_header(
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"smmdb_v{}.dict\"",
+                    dictionary.version
+                ),
+            )
+            .body(dictionary.dictionary);
+        validators.set_headers(&mut res, cache_control);
+        res
+    };
+    set_dictionary_version(&mut res, dictionary.version);
+    Ok(res)
+}
+
+pub fn set_dictionary_version(res: &mut HttpResponse, version: i32) {
+    res.headers_mut().insert(
+        HeaderName::from_static(DICTIONARY_VERSION_HEADER),
+        HeaderValue::from(version),
+    );
+}
+
+#[derive(Apiv2Schema, Debug, Deserialize)]
+pub struct GetDictionary {
+    version: Option<i32>,
+}
+
+#[api_v2_errors(code = 404, code = 500)]
+#[derive(Apiv2Schema, Debug, Error)]
+pub enum GetDictionaryError {
+    #[error("[GetDictionaryError::NotFound]")]
+    NotFound,
+    #[error("[GetDictionaryError::Database]: {0}")]
+    Database(#[from] DatabaseError),
+}
+
+impl ResponseError for GetDictionaryError {
+    fn error_response(&self) -> HttpResponse {
+        match *self {
+            GetDictionaryError::NotFound => HttpResponse::new(StatusCode::NOT_FOUND),
+            GetDictionaryError::Database(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
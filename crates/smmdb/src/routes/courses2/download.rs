@@ -1,70 +1,316 @@
-use crate::server::ServerData;
+use super::{
+    archive::{archive_response, streaming_archive_response, ArchiveFormat},
+    cache::{Validators, DOWNLOAD_CACHE_CONTROL},
+    dictionary::set_dictionary_version,
+    GetCourses2, GetCourses2Error,
+};
+use crate::server::{block, JobPending, ServerData, JOB_RETRY_AFTER, JOB_WAIT};
 
-use actix_http::http::header;
-use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
-use bson::{oid::ObjectId, ValueAccessError};
+use actix_http::{
+    body::Body,
+    http::{header, HeaderValue},
+};
+use actix_web::{
+    error::{PayloadError, ResponseError},
+    http::StatusCode,
+    rt::time::delay_for,
+    HttpRequest, HttpResponse,
+};
+use bson::{oid::ObjectId, spec::BinarySubtype, Bson, ValueAccessError};
+use futures::{stream, StreamExt};
 use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema};
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer};
 use serde_qs::actix::QsQuery;
-use smmdb_db::DatabaseError;
-use std::{io, time::SystemTime};
-use tar::{Builder, Header};
+use smmdb_auth::Identity;
+use smmdb_db::{hash_course2_content, DatabaseError};
+use std::{
+    convert::TryFrom,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 
+/// Number of course slots of an SMM2 save file.
+pub const SLOT_COUNT: u8 = 180;
+
+/// Maximum number of courses of a batch download.
+pub const MAX_BATCH_DOWNLOAD: usize = 120;
+
+/// Downloads the files of a course.
+///
+/// Previous versions of re-uploaded courses are downloaded with `version`.
+///
+/// Responses carry an `ETag` and `Last-Modified`, so that conditional requests are answered with
+/// `304 Not Modified` while the cached copy is still valid. If the requested format is still being
+/// computed, `202 Accepted` is returned with a `Retry-After` header.
 #[api_v2_operation(tags(SMM2))]
 pub async fn download_course(
     data: web::Data<ServerData>,
     path: web::Path<String>,
     query: QsQuery<DownloadCourse2>,
+    req: HttpRequest,
+    identity: Option<Identity>,
 ) -> Result<HttpResponse, DownloadCourse2Error> {
     let course_id = path.into_inner();
     let course_oid = ObjectId::with_string(&course_id)?;
+    let visitor = get_visitor(&req, identity, &data.trusted_proxies);
+
+    let course_format = query.course_format.clone();
+    let version = query.version;
+    let dictionary_version = data.zstd_dictionary_version();
+    let revision = {
+        let data = data.clone();
+        let course_oid = course_oid.clone();
+        block(move || data.get_course2_revision(course_oid, version)).await?
+    };
+    if let Some((content_hash, last_modified)) = &revision {
+        let validators =
+            download_validators(&query, dictionary_version, content_hash, *last_modified)?;
+        if validators.is_fresh(&req) {
+            return Ok(validators.not_modified(DOWNLOAD_CACHE_CONTROL));
+        }
+    }
+
+    let (course, thumb, last_modified) = {
+        let data = data.clone();
+        let course_oid = course_oid.clone();
+        block(move || -> Result<_, DownloadCourse2Error> {
+            if let Some(version) = version {
+                if let Some(files) =
+                    data.get_course2_version_files(course_oid.clone(), version, &course_format)?
+                {
+                    return Ok(files);
+                }
+            }
+            let (course, thumb) = data.get_course2_files(course_oid.clone(), &course_format)?;
+            let last_modified = data.get_course2_last_modified(course_oid)?;
+            Ok((course, thumb, last_modified))
+        })
+        .await?
+    };
+    let content_hash = match revision {
+        Some((content_hash, _)) => content_hash,
+        // until the migration has stored its content hash, the course is hashed as it is served
+        None => hash_course2_content(
+            &course,
+            &Bson::Binary(BinarySubtype::Generic, thumb.clone()),
+            &Bson::Null,
+        ),
+    };
+    let validators = download_validators(&query, dictionary_version, &content_hash, last_modified)?;
+    if validators.is_fresh(&req) {
+        return Ok(validators.not_modified(DOWNLOAD_CACHE_CONTROL));
+    }
+
+    let slot = query.slot.0;
+    let mut res = if query.file_format == FileFormat::Raw {
+        let (name, content) = match query.part {
+            Part::Course => (course_entry_name(&query.course_format, slot), course),
+            Part::Thumb => (thumb_entry_name(slot), thumb),
+        };
+        HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .set_header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", name),
+            )
+            .body(content)
+    } else {
+        let entries = vec![
+            (course_entry_name(&query.course_format, slot), course),
+            (thumb_entry_name(slot), thumb),
+        ];
+        let format = ArchiveFormat::try_from(&query.file_format)?;
+        archive_response(format, &course_id, entries, archive_mtime(last_modified))?
+    };
+    validators.set_headers(&mut res, DOWNLOAD_CACHE_CONTROL);
+    if let (CourseFormat::Zstd, Some(version)) = (&query.course_format, dictionary_version) {
+        set_dictionary_version(&mut res, version);
+    }
+
+    if let Err(err) = block(move || data.count_course2_download(course_oid, visitor)).await {
+        println!("Could not count download: {}", err);
+    }
+    Ok(res)
+}
+
+/// Returns validators of the requested file or archive of a course.
+///
+/// They only depend on the content hash of the course version and on the requested
+/// representation, so that conditional requests are answered without loading the course.
+fn download_validators(
+    query: &DownloadCourse2,
+    dictionary_version: Option<i32>,
+    content_hash: &str,
+    last_modified: Option<i64>,
+) -> Result<Validators, DownloadCourse2Error> {
+    let slot = query.slot.0;
+    let mut content_hash = content_hash.to_string();
+    if let (CourseFormat::Zstd, Some(version)) = (&query.course_format, dictionary_version) {
+        // course data is compressed again, whenever a new dictionary is trained
+        content_hash = format!("{}:zstd{}", content_hash, version);
+    }
+    if query.file_format == FileFormat::Raw {
+        let name = match query.part {
+            Part::Course => course_entry_name(&query.course_format, slot),
+            Part::Thumb => thumb_entry_name(slot),
+        };
+        Ok(Validators::new(
+            &[content_hash.as_bytes(), name.as_bytes()],
+            last_modified,
+        ))
+    } else {
+        let format = ArchiveFormat::try_from(&query.file_format)?;
+        let names = [
+            course_entry_name(&query.course_format, slot),
+            thumb_entry_name(slot),
+        ];
+        Ok(format.validators(
+            archive_mtime(last_modified),
+            &names,
+            &content_hash,
+            last_modified,
+        ))
+    }
+}
+
+/// Entries get the upload time, so that archives of the same course are identical.
+fn archive_mtime(last_modified: Option<i64>) -> u64 {
+    last_modified
+        .map(|ms| ms.max(0) as u64 / 1000)
+        .unwrap_or_default()
+}
+
+/// Packs many courses into one archive.
+///
+/// Courses are either given by their ids or selected with the filters of `GET /courses2`, if
+/// no ids are given. Every course is placed into its own slot counting up from `slot`.
+#[api_v2_operation(tags(SMM2))]
+pub async fn download_courses(
+    data: web::Data<ServerData>,
+    query: QsQuery<DownloadCourse2>,
+    courses_query: QsQuery<GetCourses2>,
+    body: web::Json<DownloadCourses2>,
+    req: HttpRequest,
+    identity: Option<Identity>,
+) -> Result<HttpResponse, DownloadCourse2Error> {
+    let format = ArchiveFormat::try_from(&query.file_format)?;
+    let visitor = get_visitor(&req, identity, &data.trusted_proxies);
+    let slot = query.slot.0;
+    let max_courses = MAX_BATCH_DOWNLOAD.min((SLOT_COUNT - slot) as usize);
 
-    let (data, thumb) = match (&query.course_format, &query.thumb_format) {
-        (CourseFormat::Encrypted, ThumbFormat::Encrypted) => data.get_course2(course_oid)?,
-        (CourseFormat::Br, ThumbFormat::Encrypted) => data.get_course2_br(course_oid)?,
-        (CourseFormat::ProtobufBr, ThumbFormat::Encrypted) => data.get_course2_proto(course_oid)?,
+    let ids = body.into_inner().ids;
+    let courses_query = courses_query.into_inner();
+    let course_ids = {
+        let data = data.clone();
+        block(move || data.get_courses2_download_ids(ids, courses_query, max_courses, visitor))
+            .await?
     };
 
-    match query.file_format {
-        FileFormat::Tar => {
-            let mut builder = Builder::new(vec![]);
-            let mtime = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-
-            let mut header = Header::new_gnu();
-            header
-                .set_path(match &query.course_format {
-                    CourseFormat::Encrypted => "course_data_000.bcd",
-                    CourseFormat::Br => "course_data_000.br",
-                    CourseFormat::ProtobufBr => "course_data_000.proto.br",
-                })
-                .unwrap();
-            header.set_size(data.len() as u64);
-            header.set_mode(0o644);
-            header.set_mtime(mtime);
-            header.set_cksum();
-            builder.append(&header, &data[..])?;
-
-            let mut header = Header::new_gnu();
-            header.set_path("course_thumb_000.btl").unwrap();
-            header.set_size(thumb.len() as u64);
-            header.set_mode(0o644);
-            header.set_mtime(mtime);
-            header.set_cksum();
-            builder.append(&header, &thumb[..])?;
-
-            Ok(HttpResponse::Ok()
-                .content_type("application/x-tar")
-                .set_header(
-                    header::CONTENT_DISPOSITION,
-                    format!("attachment; filename=\"{}.tar\"", course_id),
-                )
-                .body(builder.into_inner()?))
+    let course_format = query.course_format.clone();
+    let dictionary_version = data.zstd_dictionary_version();
+    let entries =
+        stream::iter(course_ids.into_iter().enumerate()).then(move |(index, course_id)| {
+            let data = data.clone();
+            let course_format = course_format.clone();
+            async move {
+                let slot = slot + index as u8;
+                let course_name = course_entry_name(&course_format, slot);
+                let (course, thumb) =
+                    get_course2_files_awaiting_jobs(data, course_id, course_format).await?;
+                Ok::<_, DownloadCourse2Error>(vec![
+                    (course_name, course),
+                    (thumb_entry_name(slot), thumb),
+                ])
+            }
+        });
+    let mut res = streaming_archive_response(format, "courses", entries);
+    if let (CourseFormat::Zstd, Some(version)) = (&query.course_format, dictionary_version) {
+        set_dictionary_version(&mut res, version);
+    }
+    Ok(res)
+}
+
+/// Returns the files of a course of a batch download.
+///
+/// Its archive is already being streamed, so jobs running elsewhere are awaited without blocking
+/// a thread of the blocking pool, instead of asking the client to retry.
+async fn get_course2_files_awaiting_jobs(
+    data: web::Data<ServerData>,
+    course_id: ObjectId,
+    course_format: CourseFormat,
+) -> Result<(Vec<u8>, Vec<u8>), DownloadCourse2Error> {
+    let deadline = Instant::now() + JOB_WAIT;
+    loop {
+        let data = data.clone();
+        let course_id = course_id.clone();
+        let course_format = course_format.clone();
+        match block(move || data.get_course2_files(course_id, &course_format)).await {
+            Err(DownloadCourse2Error::JobPending(_)) if Instant::now() < deadline => {
+                delay_for(Duration::from_secs(JOB_RETRY_AFTER)).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+pub fn course_entry_name(course_format: &CourseFormat, slot: u8) -> String {
+    match course_format {
+        CourseFormat::Encrypted => format!("course_data_{:03}.bcd", slot),
+        CourseFormat::Br => format!("course_data_{:03}.br", slot),
+        CourseFormat::ProtobufBr => format!("course_data_{:03}.proto.br", slot),
+        CourseFormat::Zstd => format!("course_data_{:03}.zst", slot),
+    }
+}
+
+pub fn thumb_entry_name(slot: u8) -> String {
+    format!("course_thumb_{:03}.btl", slot)
+}
+
+/// Identifies the downloading account or, for anonymous downloads, the IP address.
+///
+/// The IP address is the address of the peer, unless the peer is a trusted proxy. Then it is
+/// the last address of `X-Forwarded-For`, which has not been added by a trusted proxy.
+pub fn get_visitor(
+    req: &HttpRequest,
+    identity: Option<Identity>,
+    trusted_proxies: &[IpAddr],
+) -> String {
+    if let Some(identity) = identity {
+        return format!("account:{}", identity.get_account().get_id().to_hex());
+    }
+    let mut ip = match req.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return "ip:".to_string(),
+    };
+    if trusted_proxies.contains(&ip) {
+        let forwarded_for = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        for addr in forwarded_for.into_iter().rev() {
+            match parse_ip(addr) {
+                Some(addr) => {
+                    ip = addr;
+                    if !trusted_proxies.contains(&ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
         }
     }
+    format!("ip:{}", ip)
+}
+
+fn parse_ip(addr: &str) -> Option<IpAddr> {
+    addr.parse::<IpAddr>()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
 }
 
 #[derive(Apiv2Schema, Debug, Deserialize)]
@@ -75,12 +321,29 @@ pub struct DownloadCourse2 {
     pub course_format: CourseFormat,
     #[serde(default)]
     pub thumb_format: ThumbFormat,
+    /// File returned by the `raw` file format.
+    #[serde(default)]
+    pub part: Part,
+    /// Save slot used in the file names, e.g. `course_data_042.bcd`.
+    #[serde(default)]
+    pub slot: Slot,
+    /// Version of a re-uploaded course. Defaults to the current version and is ignored by batch
+    /// downloads.
+    pub version: Option<i32>,
+}
+
+#[derive(Apiv2Schema, Debug, Deserialize)]
+pub struct DownloadCourses2 {
+    /// Ids of the courses in the order of their slots.
+    ids: Option<Vec<String>>,
 }
 
 #[derive(Apiv2Schema, Clone, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum FileFormat {
     Tar,
+    Zip,
+    Raw,
 }
 
 impl Default for FileFormat {
@@ -89,12 +352,46 @@ impl Default for FileFormat {
     }
 }
 
+#[derive(Apiv2Schema, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Part {
+    Course,
+    Thumb,
+}
+
+impl Default for Part {
+    fn default() -> Self {
+        Part::Course
+    }
+}
+
+/// Save slots range from 0 to 179.
+#[derive(Apiv2Schema, Debug, Default, Deserialize)]
+pub struct Slot(#[serde(deserialize_with = "deserialize_slot")] pub u8);
+
+fn deserialize_slot<'de, D>(de: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let val = u8::deserialize(de)?;
+    if val >= SLOT_COUNT {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(val.into()),
+            &"slot must be at most 179",
+        ))
+    } else {
+        Ok(val)
+    }
+}
+
 #[derive(Apiv2Schema, Clone, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum CourseFormat {
     Encrypted,
     Br,
     ProtobufBr,
+    /// Decrypted course data compressed with the dictionary of `GET /courses2/dictionary`.
+    Zstd,
 }
 
 impl Default for CourseFormat {
@@ -115,17 +412,43 @@ impl Default for ThumbFormat {
     }
 }
 
-#[api_v2_errors(code = 400, code = 404, code = 500)]
+#[api_v2_errors(code = 202, code = 400, code = 404, code = 500)]
 #[derive(Apiv2Schema, Debug, Error)]
 pub enum DownloadCourse2Error {
     #[error("[DownloadCourse2Error::CourseNotFound]")]
     CourseNotFound(ObjectId),
+    #[error("[DownloadCourse2Error::VersionNotFound]: version {0} does not exist")]
+    VersionNotFound(i32),
+    #[error("[DownloadCourse2Error::DictionaryMissing]: no zstd dictionary has been trained yet")]
+    DictionaryMissing,
+    #[error("[DownloadCourse2Error::CoursesNotFound]: {}", .0.join(", "))]
+    CoursesNotFound(Vec<String>),
+    #[error(
+        "[DownloadCourse2Error::TooManyCourses]: at most {0} courses can be downloaded at once"
+    )]
+    TooManyCourses(usize),
+    #[error(
+        "[DownloadCourse2Error::UnsupportedFileFormat]: raw is only supported for single courses"
+    )]
+    UnsupportedFileFormat,
+    #[error("[DownloadCourse2Error::Query]: {0}")]
+    Query(#[from] GetCourses2Error),
+    #[error("[DownloadCourse2Error::NotEnoughSlots]: only {0} course slots are free")]
+    NotEnoughSlots(usize),
+    #[error("[DownloadCourse2Error::Payload]: {0}")]
+    Payload(#[from] PayloadError),
+    #[error("[DownloadCourse2Error::Smmdb]: {0}")]
+    Smmdb(#[from] smmdb_lib::Error),
+    #[error("[DownloadCourse2Error::InvalidEntryName]: {0}")]
+    InvalidEntryName(String),
+    #[error("[DownloadCourse2Error::ArchiveTooLarge]: archive exceeds the limits of its format")]
+    ArchiveTooLarge,
     #[error("[DownloadCourse2Error::IoError]: {0}")]
     IoError(#[from] io::Error),
+    #[error("[DownloadCourse2Error::JobPending]: {0}")]
+    JobPending(#[from] JobPending),
     #[error("[DownloadCourse2Error::MongoOid]: {0}")]
     MongoOid(#[from] bson::oid::Error),
-    #[error("[DownloadCourse2Error::Mongo]: {0}")]
-    Mongo(#[from] mongodb::Error),
     #[error("[DownloadCourse2Error::ValueAccess]: {0}")]
     ValueAccess(#[from] ValueAccessError),
     #[error("[DownloadCourse2Error::Database: {0}")]
@@ -139,13 +462,48 @@ impl ResponseError for DownloadCourse2Error {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
             DownloadCourse2Error::CourseNotFound(_) => HttpResponse::new(StatusCode::NOT_FOUND),
+            DownloadCourse2Error::VersionNotFound(_) => {
+                HttpResponse::new(StatusCode::NOT_FOUND).set_body(Body::from(format!("{}", self)))
+            }
+            DownloadCourse2Error::DictionaryMissing => {
+                HttpResponse::new(StatusCode::NOT_FOUND).set_body(Body::from(format!("{}", self)))
+            }
+            DownloadCourse2Error::CoursesNotFound(_) => {
+                HttpResponse::new(StatusCode::NOT_FOUND).set_body(Body::from(format!("{}", self)))
+            }
+            DownloadCourse2Error::TooManyCourses(_) => {
+                HttpResponse::new(StatusCode::BAD_REQUEST).set_body(Body::from(format!("{}", self)))
+            }
+            DownloadCourse2Error::UnsupportedFileFormat => {
+                HttpResponse::new(StatusCode::BAD_REQUEST).set_body(Body::from(format!("{}", self)))
+            }
+            DownloadCourse2Error::Query(ref err) => err.error_response(),
+            DownloadCourse2Error::NotEnoughSlots(_) => {
+                HttpResponse::new(StatusCode::BAD_REQUEST).set_body(Body::from(format!("{}", self)))
+            }
+            DownloadCourse2Error::Payload(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            DownloadCourse2Error::Smmdb(_) => {
+                HttpResponse::new(StatusCode::BAD_REQUEST).set_body(Body::from(format!("{}", self)))
+            }
+            DownloadCourse2Error::InvalidEntryName(_) => {
+                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            DownloadCourse2Error::ArchiveTooLarge => {
+                HttpResponse::new(StatusCode::BAD_REQUEST).set_body(Body::from(format!("{}", self)))
+            }
+            DownloadCourse2Error::JobPending(_) => {
+                let mut res = HttpResponse::new(StatusCode::ACCEPTED)
+                    .set_body(Body::from(format!("{}", self)));
+                res.headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(JOB_RETRY_AFTER));
+                res
+            }
             DownloadCourse2Error::MongoOid(bson::oid::Error::FromHexError(_)) => {
                 HttpResponse::new(StatusCode::BAD_REQUEST)
             }
             DownloadCourse2Error::MongoOid(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
-            DownloadCourse2Error::Mongo(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
             DownloadCourse2Error::ValueAccess(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
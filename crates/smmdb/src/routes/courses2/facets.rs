@@ -0,0 +1,92 @@
+use super::{GetCourses2, GetCourses2Error};
+use crate::server::{block, ServerData};
+
+use bson::{ordered::OrderedDocument, Bson};
+use paperclip::actix::{api_v2_operation, web, Apiv2Schema};
+use serde::Serialize;
+use serde_qs::actix::QsQuery;
+use smmdb_auth::Identity;
+
+/// Facet names and the paths of the counted values.
+pub const COURSE2_FACETS: &[(&str, &str)] = &[
+    ("difficulty", "difficulty"),
+    ("game_style", "course.header.game_style"),
+    ("course_theme", "course.course_area.course_theme"),
+    ("clear_condition", "course.header.clear_condition"),
+    ("uploader", "uploader"),
+];
+
+/// Maximum number of values returned per facet.
+pub const COURSE2_FACET_LIMIT: i64 = 100;
+
+/// Counts the courses matching the filters of `GET /courses2` grouped by difficulty, game
+/// style, theme, clear condition and uploader.
+///
+/// Paging and sorting parameters are ignored.
+#[api_v2_operation(tags(SMM2))]
+pub async fn get_facets(
+    data: web::Data<ServerData>,
+    query: QsQuery<GetCourses2>,
+    identity: Option<Identity>,
+) -> Result<web::Json<Courses2Facets>, GetCourses2Error> {
+    let query = query.into_inner();
+    let account = identity.map(|identity| identity.get_account());
+    let res = block(move || data.get_courses2_facets(query, account)).await?;
+    Ok(web::Json(res))
+}
+
+#[derive(Apiv2Schema, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Courses2Facets {
+    difficulty: Vec<FacetCount>,
+    game_style: Vec<FacetCount>,
+    course_theme: Vec<FacetCount>,
+    clear_condition: Vec<FacetCount>,
+    uploader: Vec<FacetCount>,
+}
+
+/// Number of courses with the given value.
+///
+/// Values are formatted the same way as the filter parameters, so clear conditions are numeric
+/// strings. Courses without a value are not counted.
+#[derive(Apiv2Schema, Debug, Serialize)]
+pub struct FacetCount {
+    value: String,
+    count: u64,
+}
+
+impl From<OrderedDocument> for Courses2Facets {
+    fn from(doc: OrderedDocument) -> Self {
+        let get_counts = |facet: &str| -> Vec<FacetCount> {
+            doc.get_array(facet)
+                .map(|counts| counts.iter().filter_map(FacetCount::from_bson).collect())
+                .unwrap_or_default()
+        };
+        Courses2Facets {
+            difficulty: get_counts("difficulty"),
+            game_style: get_counts("game_style"),
+            course_theme: get_counts("course_theme"),
+            clear_condition: get_counts("clear_condition"),
+            uploader: get_counts("uploader"),
+        }
+    }
+}
+
+impl FacetCount {
+    fn from_bson(bson: &Bson) -> Option<Self> {
+        let doc = bson.as_document()?;
+        let value = match doc.get("_id")? {
+            Bson::String(value) => value.clone(),
+            Bson::I32(value) => value.to_string(),
+            Bson::I64(value) => value.to_string(),
+            _ => return None,
+        };
+        let count = match doc.get("count")? {
+            Bson::I32(count) => *count as u64,
+            Bson::I64(count) => *count as u64,
+            Bson::FloatingPoint(count) => *count as u64,
+            _ => return None,
+        };
+        Some(FacetCount { value, count })
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    server::{Data, ServerData},
+    server::{block, Data, ServerData},
     Database,
 };
 
@@ -8,8 +8,12 @@ use bson::{oid::ObjectId, ordered::OrderedDocument, Bson};
 use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema};
 use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_qs::actix::QsQuery;
-use smmdb_auth::Identity;
+use smmdb_auth::{Account, Identity};
 use smmdb_common::{Course2Response, Difficulty};
+use smmdb_db::DatabaseError;
+use smmdb_lib::proto::SMM2Course::{
+    SMM2CourseArea_AutoScroll, SMM2CourseArea_CourseTheme, SMM2CourseHeader_GameStyle,
+};
 use std::{
     convert::{TryFrom, TryInto},
     io,
@@ -22,13 +26,28 @@ pub async fn get_courses(
     query: QsQuery<GetCourses2>,
     identity: Option<Identity>,
 ) -> Result<web::Json<Vec<Course2Response>>, GetCourses2Error> {
-    let res = data.get_courses2(
-        query.into_inner(),
-        identity.map(|identity| identity.get_account()),
-    )?;
+    let query = query.into_inner();
+    let account = identity.map(|identity| identity.get_account());
+    let res = block(move || data.get_courses2(query, account)).await?;
+    Ok(web::Json(res))
+}
+
+/// Same as `GET /courses2`, but wraps the courses in a page with a continuation cursor.
+#[api_v2_operation(tags(SMM2))]
+pub async fn get_courses_page(
+    data: web::Data<ServerData>,
+    query: QsQuery<GetCourses2>,
+    identity: Option<Identity>,
+) -> Result<web::Json<Courses2Page>, GetCourses2Error> {
+    let query = query.into_inner();
+    let account = identity.map(|identity| identity.get_account());
+    let res = block(move || data.get_courses2_page(query, account)).await?;
     Ok(web::Json(res))
 }
 
+/// Field the text score is projected to, if the `relevance` sort is used.
+const SCORE_KEY: &str = "score";
+
 fn is_true() -> bool {
     true
 }
@@ -37,7 +56,16 @@ fn is_true() -> bool {
 pub struct GetCourses2 {
     #[serde(default)]
     limit: Limit,
+    /// Number of courses to skip. Cannot be combined with `cursor`.
     skip: Option<u32>,
+    /// Full-text search over titles, descriptions and uploader names.
+    ///
+    /// Supports `"quoted phrases"` and `-negated` terms. Sorts by `relevance`, if no other sort
+    /// is given.
+    q: Option<String>,
+    /// Language used for stemming and stop words of `q`. Defaults to none, since courses are
+    /// written in all kinds of languages.
+    language: Option<TextLanguage>,
     id: Option<String>,
     ids: Option<Vec<String>>,
     title: Option<String>,
@@ -51,21 +79,89 @@ pub struct GetCourses2 {
     uploader: Option<String>,
     sort: Option<Vec<Sort>>,
     difficulty: Option<Difficulty>,
+    game_style: Option<Vec<SMM2CourseHeader_GameStyle>>,
+    course_theme: Option<Vec<SMM2CourseArea_CourseTheme>>,
+    course_theme_sub: Option<Vec<SMM2CourseArea_CourseTheme>>,
+    auto_scroll: Option<Vec<SMM2CourseArea_AutoScroll>>,
+    auto_scroll_sub: Option<Vec<SMM2CourseArea_AutoScroll>>,
+    clear_condition: Option<Vec<u32>>,
+    /// Timer in seconds.
+    time_gte: Option<u32>,
+    time_lte: Option<u32>,
+    /// Unix timestamp in milliseconds.
+    uploaded_gte: Option<i64>,
+    uploaded_lte: Option<i64>,
+    /// Unix timestamp in milliseconds.
+    last_modified_gte: Option<i64>,
+    last_modified_lte: Option<i64>,
+    votes_gte: Option<i32>,
+    votes_lte: Option<i32>,
+    /// Continuation cursor of a previous page. Cannot be combined with `skip`.
+    cursor: Option<String>,
+    /// Whether the total number of matching courses should be counted.
+    ///
+    /// Counting visits all matching courses, so it is only done on request.
+    #[serde(default)]
+    total: bool,
 }
 
 impl GetCourses2 {
     pub fn into_ordered_document(
         self,
-        database: &Database,
+        database: &dyn Database,
+        own_account: Option<&Account>,
     ) -> Result<Vec<OrderedDocument>, GetCourses2Error> {
+        Ok(self.into_page_query(database, own_account)?.pipeline)
+    }
+
+    /// Returns the filter of all matching courses ignoring paging and sorting.
+    pub fn into_filter(
+        self,
+        database: &dyn Database,
+        own_account: Option<&Account>,
+    ) -> Result<OrderedDocument, GetCourses2Error> {
+        Ok(self
+            .get_match(database, own_account)?
+            .unwrap_or_else(OrderedDocument::new))
+    }
+
+    pub fn into_page_query(
+        self,
+        database: &dyn Database,
+        own_account: Option<&Account>,
+    ) -> Result<Courses2PageQuery, GetCourses2Error> {
+        if self.cursor.is_some() && self.skip.is_some() {
+            return Err(GetCourses2Error::CursorWithSkip);
+        }
         let mut pipeline = vec![];
+        let filter = self.get_match(database, own_account)?;
+        let sort = self.get_sort_doc()?;
+
+        if let Some(filter) = &filter {
+            pipeline.push(doc! { "$match" => filter.clone() });
+        }
 
-        if let Some(pipeline_match) = self.get_match(database)? {
-            pipeline.push(doc! { "$match" => pipeline_match });
+        // the text score is only available after the `$text` match
+        if self.q.is_some() {
+            pipeline.push(doc! {
+                "$addFields" => {
+                    SCORE_KEY => {
+                        "$meta" => "textScore"
+                    }
+                }
+            });
         }
 
-        pipeline.push(self.get_sort_doc());
+        if let Some(cursor) = &self.cursor {
+            pipeline.push(doc! { "$match" => get_cursor_match(&sort, cursor)? });
+        }
 
+        pipeline.push(doc! {
+            "$sort" => sort.clone()
+        });
+
+        // offset paging limits to `limit + skip` first, so that the sort only has to keep the
+        // top courses, and then skips
         let limit = self.get_limit();
         pipeline.push(doc! {
             "$limit" => limit
@@ -77,11 +173,38 @@ impl GetCourses2 {
             });
         }
 
-        Ok(pipeline)
+        Ok(Courses2PageQuery {
+            pipeline,
+            count_filter: if self.total {
+                Some(filter.unwrap_or_else(OrderedDocument::new))
+            } else {
+                None
+            },
+            sort,
+            limit: self.limit.0 as usize,
+        })
     }
 
-    fn get_match(&self, database: &Database) -> Result<Option<OrderedDocument>, GetCourses2Error> {
+    /// Unlisted courses are only matched by their id or, if `own_account` owns them, by their
+    /// owner.
+    fn get_match(
+        &self,
+        database: &dyn Database,
+        own_account: Option<&Account>,
+    ) -> Result<Option<OrderedDocument>, GetCourses2Error> {
         let mut res = doc! {};
+        if let Some(q) = &self.q {
+            let mut text = doc! {
+                "$search" => q.clone()
+            };
+            if let Some(language) = &self.language {
+                text.insert("$language", serde_json::to_value(language)?);
+            }
+            res.insert("$text", text);
+        } else if self.language.is_some() {
+            return Err(GetCourses2Error::Deserialize("language".to_string()));
+        }
+
         if let Some(id) = &self.id {
             GetCourses2::insert_objectid(&mut res, "_id".to_string(), id)?;
         }
@@ -138,6 +261,60 @@ impl GetCourses2 {
             res.insert("difficulty", difficulty.clone());
         }
 
+        if let Some(game_styles) = &self.game_style {
+            GetCourses2::insert_in(&mut res, "course.header.game_style", game_styles)?;
+        }
+        if let Some(course_themes) = &self.course_theme {
+            GetCourses2::insert_in(&mut res, "course.course_area.course_theme", course_themes)?;
+        }
+        if let Some(course_themes) = &self.course_theme_sub {
+            GetCourses2::insert_in(
+                &mut res,
+                "course.course_area_sub.course_theme",
+                course_themes,
+            )?;
+        }
+        if let Some(auto_scrolls) = &self.auto_scroll {
+            GetCourses2::insert_in(&mut res, "course.course_area.auto_scroll", auto_scrolls)?;
+        }
+        if let Some(auto_scrolls) = &self.auto_scroll_sub {
+            GetCourses2::insert_in(&mut res, "course.course_area_sub.auto_scroll", auto_scrolls)?;
+        }
+        if let Some(clear_conditions) = &self.clear_condition {
+            GetCourses2::insert_in(&mut res, "course.header.clear_condition", clear_conditions)?;
+        }
+        GetCourses2::insert_boundaries(
+            &mut res,
+            "course.header.time",
+            self.time_gte.map(i64::from),
+            self.time_lte.map(i64::from),
+        );
+        GetCourses2::insert_boundaries(&mut res, "uploaded", self.uploaded_gte, self.uploaded_lte);
+        GetCourses2::insert_boundaries(
+            &mut res,
+            "last_modified",
+            self.last_modified_gte,
+            self.last_modified_lte,
+        );
+        GetCourses2::insert_boundaries(
+            &mut res,
+            "votes",
+            self.votes_gte.map(i64::from),
+            self.votes_lte.map(i64::from),
+        );
+
+        let own_courses = own_account.map_or(false, |account| {
+            res.get_object_id("owner").ok() == Some(account.get_id())
+        });
+        if self.id.is_none() && self.ids.is_none() && !own_courses {
+            res.insert(
+                "unlisted",
+                doc! {
+                    "$ne" => true
+                },
+            );
+        }
+
         if res.is_empty() {
             Ok(None)
         } else {
@@ -145,20 +322,32 @@ impl GetCourses2 {
         }
     }
 
-    fn get_sort_doc(&self) -> OrderedDocument {
+    /// Courses are finally sorted by their id, so that cursors point to a unique position.
+    fn get_sort_doc(&self) -> Result<OrderedDocument, GetCourses2Error> {
         let mut query = OrderedDocument::new();
         for sort in self.get_sort() {
-            let sort_dir: String = sort.val.try_into().unwrap_or_default();
+            let sort_dir: String = if sort.val == SortValue::Relevance {
+                if self.q.is_none() {
+                    return Err(GetCourses2Error::Deserialize("sort".to_string()));
+                }
+                SCORE_KEY.to_string()
+            } else {
+                sort.val.try_into().unwrap_or_default()
+            };
             query.insert(sort_dir, sort.dir);
         }
-        doc! {
-            "$sort" => query
-        }
+        query.insert("_id", -1);
+        Ok(query)
     }
 
     fn get_sort(&self) -> Vec<Sort> {
         let mut res = if self.sort.is_some() {
             self.sort.clone().unwrap()
+        } else if self.q.is_some() {
+            vec![Sort {
+                val: SortValue::Relevance,
+                dir: -1,
+            }]
         } else {
             vec![Sort::default()]
         };
@@ -203,6 +392,41 @@ impl GetCourses2 {
         doc.insert_bson(key, Bson::RegExp(matched_str, options_str));
     }
 
+    /// Matches any of the given values, which are converted the same way as stored courses.
+    fn insert_in<T>(
+        doc: &mut OrderedDocument,
+        key: &str,
+        values: &[T],
+    ) -> Result<(), GetCourses2Error>
+    where
+        T: Serialize,
+    {
+        let values: Vec<Bson> = values
+            .iter()
+            .map(|val| -> Result<Bson, serde_json::Error> { Ok(serde_json::to_value(val)?.into()) })
+            .collect::<Result<_, _>>()?;
+        doc.insert_bson(
+            key.to_string(),
+            Bson::Document(doc! {
+                "$in" => values
+            }),
+        );
+        Ok(())
+    }
+
+    fn insert_boundaries(doc: &mut OrderedDocument, key: &str, gte: Option<i64>, lte: Option<i64>) {
+        let mut boundaries = doc! {};
+        if let Some(gte) = gte {
+            boundaries.insert("$gte", gte);
+        }
+        if let Some(lte) = lte {
+            boundaries.insert("$lte", lte);
+        }
+        if !boundaries.is_empty() {
+            doc.insert_bson(key.to_string(), Bson::Document(boundaries));
+        }
+    }
+
     fn insert_objectid(
         doc: &mut OrderedDocument,
         key: String,
@@ -218,6 +442,117 @@ impl GetCourses2 {
     }
 }
 
+pub struct Courses2PageQuery {
+    pipeline: Vec<OrderedDocument>,
+    count_filter: Option<OrderedDocument>,
+    sort: OrderedDocument,
+    limit: usize,
+}
+
+impl Courses2PageQuery {
+    pub fn get_pipeline(&self) -> &Vec<OrderedDocument> {
+        &self.pipeline
+    }
+
+    /// Filter of all matching courses, if they should be counted.
+    pub fn get_count_filter(&self) -> Option<&OrderedDocument> {
+        self.count_filter.as_ref()
+    }
+
+    /// Returns the cursor pointing after the last course, if the page is full.
+    pub fn get_next_cursor(&self, courses: &[OrderedDocument]) -> Option<String> {
+        if courses.len() < self.limit {
+            return None;
+        }
+        let course = courses.last()?;
+        let values: Vec<Bson> = self
+            .sort
+            .keys()
+            .map(|key| get_path(course, key).cloned().unwrap_or(Bson::Null))
+            .collect();
+        let cursor = doc! {
+            "sort" => self.sort.clone(),
+            "values" => values
+        };
+        let mut buf = vec![];
+        bson::encode_document(&mut buf, &cursor).ok()?;
+        Some(base64::encode_config(&buf, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+#[derive(Apiv2Schema, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Courses2Page {
+    courses: Vec<Course2Response>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+impl Courses2Page {
+    pub fn new(
+        courses: Vec<Course2Response>,
+        total: Option<u64>,
+        next_cursor: Option<String>,
+    ) -> Self {
+        Courses2Page {
+            courses,
+            total,
+            next_cursor,
+        }
+    }
+}
+
+fn get_path<'a>(doc: &'a OrderedDocument, path: &str) -> Option<&'a Bson> {
+    let mut keys = path.split('.');
+    let mut value = doc.get(keys.next()?)?;
+    for key in keys {
+        value = value.as_document()?.get(key)?;
+    }
+    Some(value)
+}
+
+/// Matches all courses sorted after the position of the cursor.
+///
+/// Cursors are only valid for the sort order they have been created with.
+fn get_cursor_match(
+    sort: &OrderedDocument,
+    cursor: &str,
+) -> Result<OrderedDocument, GetCourses2Error> {
+    let invalid = || GetCourses2Error::Deserialize("cursor".to_string());
+    let buf = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+    let cursor = bson::decode_document(&mut &buf[..]).map_err(|_| invalid())?;
+    if cursor.get_document("sort").ok() != Some(sort) {
+        return Err(invalid());
+    }
+    let values = cursor.get_array("values").map_err(|_| invalid())?;
+    if values.len() != sort.len() {
+        return Err(invalid());
+    }
+
+    let mut branches = vec![];
+    let mut equal = OrderedDocument::new();
+    for ((key, dir), value) in sort.iter().zip(values) {
+        let descending = matches!(dir, Bson::I32(dir) if *dir < 0);
+        let after = match value {
+            Bson::Null if descending => None,
+            Bson::Null => Some(doc! { "$ne" => Bson::Null }),
+            _ if descending => Some(doc! { "$lt" => value.clone() }),
+            _ => Some(doc! { "$gt" => value.clone() }),
+        };
+        if let Some(after) = after {
+            let mut branch = equal.clone();
+            branch.insert(key.clone(), after);
+            branches.push(Bson::Document(branch));
+        }
+        equal.insert(key.clone(), value.clone());
+    }
+    Ok(doc! {
+        "$or" => branches
+    })
+}
+
 #[derive(Apiv2Schema, Debug, Deserialize)]
 struct Limit(#[serde(deserialize_with = "deserialize_limit")] u32);
 
@@ -273,6 +608,14 @@ enum SortValue {
     CourseHeaderTitle,
     #[serde(rename = "votes")]
     Votes,
+    #[serde(rename = "downloads")]
+    Downloads,
+    /// Downloads decayed exponentially by their age.
+    #[serde(rename = "trending")]
+    Trending,
+    /// Text score of `q`.
+    #[serde(rename = "relevance")]
+    Relevance,
 }
 
 impl TryFrom<SortValue> for String {
@@ -292,6 +635,28 @@ impl Default for SortValue {
     }
 }
 
+/// Languages supported by the MongoDB text index.
+#[derive(Apiv2Schema, Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextLanguage {
+    None,
+    Danish,
+    Dutch,
+    English,
+    Finnish,
+    French,
+    German,
+    Hungarian,
+    Italian,
+    Norwegian,
+    Portuguese,
+    Romanian,
+    Russian,
+    Spanish,
+    Swedish,
+    Turkish,
+}
+
 fn deserialize_dir<'de, D>(de: D) -> Result<i32, D::Error>
 where
     D: Deserializer<'de>,
@@ -319,10 +684,12 @@ pub enum GetCourses2Error {
     Deserialize(String),
     #[error("[GetCourses2Error::UploaderUnknown]: {0}")]
     UploaderUnknown(String),
+    #[error("[GetCourses2Error::CursorWithSkip]: cursor and skip must not be combined")]
+    CursorWithSkip,
     #[error("[GetCourses2Error::SerdeJson]: {0}")]
     SerdeJson(#[from] serde_json::Error),
-    #[error("[GetCourses2Error::Mongo]: {0}")]
-    Mongo(#[from] mongodb::Error),
+    #[error("[GetCourses2Error::Database]: {0}")]
+    Database(#[from] DatabaseError),
 }
 
 impl ResponseError for GetCourses2Error {
@@ -330,8 +697,116 @@ impl ResponseError for GetCourses2Error {
         match *self {
             GetCourses2Error::Deserialize(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
             GetCourses2Error::UploaderUnknown(_) => HttpResponse::new(StatusCode::NOT_FOUND),
+            GetCourses2Error::CursorWithSkip => HttpResponse::new(StatusCode::BAD_REQUEST),
             GetCourses2Error::SerdeJson(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
-            GetCourses2Error::Mongo(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            GetCourses2Error::Database(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smmdb_db::{CourseStore, MemoryDatabase};
+
+    fn page_query(
+        query: &str,
+        database: &dyn Database,
+    ) -> Result<Courses2PageQuery, GetCourses2Error> {
+        let query: GetCourses2 = serde_qs::from_str(query).unwrap();
+        query.into_page_query(database, None)
+    }
+
+    fn course(title: &str, last_modified: i32) -> OrderedDocument {
+        doc! {
+            "_id" => ObjectId::new().unwrap(),
+            "course" => {
+                "header" => {
+                    "title" => title
+                }
+            },
+            "last_modified" => last_modified
+        }
+    }
+
+    #[test]
+    fn pages_through_courses_with_cursors() {
+        let database = MemoryDatabase::new();
+        for (title, last_modified) in [("a", 1), ("b", 2), ("c", 2), ("d", 3), ("e", 3)].iter() {
+            database
+                .insert_course2(course(title, *last_modified))
+                .unwrap();
         }
+
+        let mut titles = vec![];
+        let mut cursor = None;
+        loop {
+            let query = match &cursor {
+                Some(cursor) => format!("limit=2&cursor={}", cursor),
+                None => "limit=2".to_string(),
+            };
+            let query = page_query(&query, &database).unwrap();
+            let courses = database.get_courses2(query.get_pipeline().clone()).unwrap();
+            for course in courses.iter() {
+                let title = get_path(course, "course.header.title").unwrap();
+                titles.push(title.as_str().unwrap().to_string());
+            }
+            cursor = query.get_next_cursor(&courses);
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(titles, vec!["e", "d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn rejects_cursor_with_skip_or_other_sort() {
+        let database = MemoryDatabase::new();
+        let cursor = page_query("limit=1", &database)
+            .unwrap()
+            .get_next_cursor(&[course("a", 1)])
+            .unwrap();
+
+        let res = page_query(&format!("skip=1&cursor={}", cursor), &database);
+        assert!(matches!(res, Err(GetCourses2Error::CursorWithSkip)));
+        let res = page_query(
+            &format!("sort[0][val]=votes&sort[0][dir]=1&cursor={}", cursor),
+            &database,
+        );
+        assert!(matches!(res, Err(GetCourses2Error::Deserialize(_))));
+        let res = page_query("cursor=invalid", &database);
+        assert!(matches!(res, Err(GetCourses2Error::Deserialize(_))));
+    }
+
+    #[test]
+    fn matches_unlisted_courses_of_the_own_account() {
+        let database = MemoryDatabase::new();
+        let account = Account::from(doc! {
+            "_id" => ObjectId::new().unwrap(),
+            "googleid" => "",
+            "username" => "maker",
+            "email" => "",
+            "apikey" => ""
+        });
+        let other = ObjectId::new().unwrap();
+        let unlisted = |owner: &ObjectId| {
+            let query = format!("owner={}", owner.to_hex());
+            let query: GetCourses2 = serde_qs::from_str(&query).unwrap();
+            query
+                .into_filter(&database, Some(&account))
+                .unwrap()
+                .contains_key("unlisted")
+        };
+
+        assert!(!unlisted(account.get_id()));
+        assert!(unlisted(&other));
+
+        let query: GetCourses2 = serde_qs::from_str("").unwrap();
+        let filter = query.into_filter(&database, Some(&account)).unwrap();
+        assert!(filter.contains_key("unlisted"));
+        let query = format!("owner={}", account.get_id().to_hex());
+        let query: GetCourses2 = serde_qs::from_str(&query).unwrap();
+        let filter = query.into_filter(&database, None).unwrap();
+        assert!(filter.contains_key("unlisted"));
     }
 }
@@ -0,0 +1,133 @@
+use crate::server::{block, JobStatus, ServerData};
+
+use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
+use bson::{oid::ObjectId, ordered::OrderedDocument};
+use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema};
+use serde::{Deserialize, Serialize};
+use serde_qs::actix::QsQuery;
+use smmdb_auth::Identity;
+use smmdb_db::DatabaseError;
+use thiserror::Error;
+
+/// Maximum number of jobs returned at once.
+const JOBS_LIMIT: usize = 1000;
+
+/// Lists the jobs precomputing compressed course data and thumbnails, most recently updated
+/// first. Succeeded jobs are deleted.
+///
+/// Only available to admins.
+#[api_v2_operation(tags(SMM2))]
+pub async fn get_jobs(
+    data: web::Data<ServerData>,
+    query: QsQuery<GetJobs>,
+    identity: Identity,
+) -> Result<web::Json<Jobs>, GetJobsError> {
+    if !identity.get_account().is_admin() {
+        return Err(GetJobsError::Forbidden);
+    }
+    let query = query.into_inner();
+    let course_id = match query.course_id {
+        Some(course_id) => Some(ObjectId::with_string(&course_id)?),
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(100).min(JOBS_LIMIT);
+    let res = block(move || data.get_jobs(query.status, course_id, limit)).await?;
+    Ok(web::Json(res))
+}
+
+#[derive(Apiv2Schema, Debug, Deserialize)]
+pub struct GetJobs {
+    status: Option<JobStatus>,
+    course_id: Option<String>,
+    /// Defaults to 100, at most 1000.
+    limit: Option<usize>,
+}
+
+#[derive(Apiv2Schema, Debug, Serialize)]
+pub struct Jobs {
+    /// Counts of all jobs regardless of the filters.
+    counts: JobCounts,
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    pub fn new(counts: JobCounts, jobs: Vec<OrderedDocument>) -> Self {
+        Jobs {
+            counts,
+            jobs: jobs.into_iter().map(Job::from).collect(),
+        }
+    }
+}
+
+#[derive(Apiv2Schema, Debug, Default, Serialize)]
+pub struct JobCounts {
+    pending: u64,
+    running: u64,
+    failed: u64,
+}
+
+impl JobCounts {
+    pub fn set(&mut self, status: JobStatus, count: u64) {
+        match status {
+            JobStatus::Pending => self.pending = count,
+            JobStatus::Running => self.running = count,
+            JobStatus::Failed => self.failed = count,
+        }
+    }
+}
+
+/// Timestamps are given in milliseconds.
+#[derive(Apiv2Schema, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    id: String,
+    course_id: String,
+    derivative: String,
+    status: Option<JobStatus>,
+    attempts: i32,
+    run_after: i64,
+    created: i64,
+    updated: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<OrderedDocument> for Job {
+    fn from(doc: OrderedDocument) -> Self {
+        Job {
+            id: doc.get_str("_id").unwrap_or_default().to_string(),
+            course_id: doc
+                .get_object_id("course_id")
+                .map(ObjectId::to_hex)
+                .unwrap_or_default(),
+            derivative: doc.get_str("derivative").unwrap_or_default().to_string(),
+            status: doc.get_str("status").ok().and_then(JobStatus::parse),
+            attempts: doc.get_i32("attempts").unwrap_or_default(),
+            run_after: doc.get_i64("run_after").unwrap_or_default(),
+            created: doc.get_i64("created").unwrap_or_default(),
+            updated: doc.get_i64("updated").unwrap_or_default(),
+            error: doc.get_str("error").ok().map(ToString::to_string),
+        }
+    }
+}
+
+#[api_v2_errors(code = 400, code = 401, code = 403, code = 500)]
+#[derive(Apiv2Schema, Debug, Error)]
+pub enum GetJobsError {
+    #[error("[GetJobsError::Forbidden]")]
+    Forbidden,
+    #[error("[GetJobsError::MongoOid]: {0}")]
+    MongoOid(#[from] bson::oid::Error),
+    #[error("[GetJobsError::Database]: {0}")]
+    Database(#[from] DatabaseError),
+}
+
+impl ResponseError for GetJobsError {
+    fn error_response(&self) -> HttpResponse {
+        match *self {
+            GetJobsError::Forbidden => HttpResponse::new(StatusCode::FORBIDDEN),
+            GetJobsError::MongoOid(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            GetJobsError::Database(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
@@ -1,17 +1,190 @@
-use crate::server::ServerData;
+use crate::server::{block, ServerData};
 
 use actix_http::body::Body;
 use actix_web::{error::ResponseError, http::StatusCode, HttpRequest, HttpResponse};
-use bson::oid::ObjectId;
+use bson::{oid::ObjectId, ordered::OrderedDocument, Bson};
+use lazy_static::lazy_static;
 use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema, NoContent};
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
 use smmdb_auth::Identity;
 use smmdb_common::Difficulty;
+use smmdb_db::DatabaseError;
 use thiserror::Error;
 
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LENGTH: usize = 32;
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+
+/// Characters used by Super Mario Maker 2 in course ids.
+const NINTENDO_ID_CHARS: &str = "0123456789BCDFGHJKLMNPQRSTVWXY";
+
+lazy_static! {
+    static ref YOUTUBE_URL: Regex = Regex::new(
+        r"^https?://(www\.|m\.)?(youtube\.com/watch\?(\S*&)?v=|youtu\.be/)[\w-]{11}([?&#]\S*)?$",
+    )
+    .unwrap();
+    static ref TWITCH_URL: Regex =
+        Regex::new(r"^https?://(www\.|m\.)?twitch\.tv/videos/\d+([?#]\S*)?$").unwrap();
+}
+
+/// Omitted fields are left unchanged, fields set to `null` are removed.
 #[derive(Apiv2Schema, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct PostCourse2Meta {
-    difficulty: Option<Difficulty>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    difficulty: Option<Option<Difficulty>>,
+    /// Link to a YouTube or Twitch video.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    video_url: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    tags: Option<Option<Vec<String>>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    description: Option<Option<String>>,
+    /// Course id in Super Mario Maker 2, e.g. `1AB-2CD-3FG`.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    nintendo_id: Option<Option<String>>,
+    unlisted: Option<bool>,
+}
+
+/// Distinguishes between an omitted field and a field set to `null`.
+fn deserialize_some<'de, T, D>(de: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Deserialize::deserialize(de).map(Some)
+}
+
+impl PostCourse2Meta {
+    /// Validates all given fields and returns the resulting update.
+    pub fn into_update(self) -> Result<OrderedDocument, PostCourse2MetaError> {
+        let mut set = doc! {};
+        let mut unset = doc! {};
+        let mut update_field = |key: &str, value: Option<Bson>| match value {
+            Some(value) => {
+                set.insert(key, value);
+            }
+            None => {
+                unset.insert(key, "");
+            }
+        };
+
+        if let Some(difficulty) = self.difficulty {
+            update_field("difficulty", difficulty.map(Bson::from));
+        }
+        if let Some(video_url) = self.video_url {
+            let video_url = video_url.map(validate_video_url).transpose()?;
+            update_field("video_url", video_url.map(Bson::from));
+        }
+        if let Some(tags) = self.tags {
+            let tags = validate_tags(tags.unwrap_or_default())?;
+            update_field(
+                "tags",
+                tags.map(|tags| Bson::Array(tags.into_iter().map(Bson::String).collect())),
+            );
+        }
+        if let Some(description) = self.description {
+            let description = description.map(validate_description).transpose()?;
+            update_field("description", description.map(Bson::from));
+        }
+        if let Some(nintendo_id) = self.nintendo_id {
+            let nintendo_id = nintendo_id.map(validate_nintendo_id).transpose()?;
+            update_field("nintendo_id", nintendo_id.map(Bson::from));
+        }
+        if let Some(unlisted) = self.unlisted {
+            update_field("unlisted", Some(Bson::Boolean(unlisted)));
+        }
+
+        let mut update = doc! {};
+        if !set.is_empty() {
+            update.insert("$set", set);
+        }
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
+        }
+        Ok(update)
+    }
+}
+
+fn validate_video_url(video_url: String) -> Result<String, PostCourse2MetaError> {
+    let video_url = video_url.trim();
+    if YOUTUBE_URL.is_match(video_url) || TWITCH_URL.is_match(video_url) {
+        Ok(video_url.to_string())
+    } else {
+        Err(PostCourse2MetaError::Invalid(
+            "videoUrl",
+            "must link to a YouTube or Twitch video".to_string(),
+        ))
+    }
+}
+
+/// Tags are trimmed, lowercased and deduplicated. No remaining tags remove all tags.
+fn validate_tags(tags: Vec<String>) -> Result<Option<Vec<String>>, PostCourse2MetaError> {
+    let mut res: Vec<String> = vec![];
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || res.contains(&tag) {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH {
+            return Err(PostCourse2MetaError::Invalid(
+                "tags",
+                format!(
+                    "must not contain tags longer than {} characters",
+                    MAX_TAG_LENGTH
+                ),
+            ));
+        }
+        res.push(tag);
+    }
+    if res.len() > MAX_TAGS {
+        return Err(PostCourse2MetaError::Invalid(
+            "tags",
+            format!("must not contain more than {} tags", MAX_TAGS),
+        ));
+    }
+    Ok(if res.is_empty() { None } else { Some(res) })
+}
+
+fn validate_description(description: String) -> Result<String, PostCourse2MetaError> {
+    let description = description.trim();
+    if description.is_empty() {
+        Err(PostCourse2MetaError::Invalid(
+            "description",
+            "must not be empty".to_string(),
+        ))
+    } else if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        Err(PostCourse2MetaError::Invalid(
+            "description",
+            format!(
+                "must not be longer than {} characters",
+                MAX_DESCRIPTION_LENGTH
+            ),
+        ))
+    } else {
+        Ok(description.to_string())
+    }
+}
+
+/// Accepts course ids with or without dashes and normalizes them to `XXX-XXX-XXX`.
+fn validate_nintendo_id(nintendo_id: String) -> Result<String, PostCourse2MetaError> {
+    let chars: Vec<char> = nintendo_id
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if chars.len() != 9 || chars.iter().any(|c| !NINTENDO_ID_CHARS.contains(*c)) {
+        return Err(PostCourse2MetaError::Invalid(
+            "nintendoId",
+            "must be a course id like 1AB-2CD-3FG".to_string(),
+        ));
+    }
+    Ok(chars
+        .chunks(3)
+        .map(|chunk| chunk.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-"))
 }
 
 #[api_v2_operation(tags(SMM2))]
@@ -25,24 +198,29 @@ pub async fn post_meta(
     let course_id = path.into_inner();
     let course_id = ObjectId::with_string(&course_id)?;
     let account = identity.get_account();
-    if !data.does_account_own_course(account.get_id().clone(), course_id.clone()) {
-        return Err(PostCourse2MetaError::Unauthorized);
-    }
-    let difficulty = meta.difficulty.clone();
-    data.post_course2_meta(course_id, difficulty)?;
+    let update = meta.into_inner().into_update()?;
+    block(move || {
+        if !data.does_account_own_course(account.get_id().clone(), course_id.clone()) {
+            return Err(PostCourse2MetaError::Unauthorized);
+        }
+        data.post_course2_meta(course_id, update)
+    })
+    .await?;
     Ok(NoContent)
 }
 
 #[api_v2_errors(code = 400, code = 401, code = 404, code = 500)]
 #[derive(Apiv2Schema, Debug, Error)]
 pub enum PostCourse2MetaError {
-    #[error("[PutCourses2Error::MongoOid]: {0}")]
+    #[error("[PostCourse2MetaError::CourseNotFound]: {0}")]
+    CourseNotFound(ObjectId),
+    #[error("[PostCourse2MetaError::Invalid]: {0} {1}")]
+    Invalid(&'static str, String),
+    #[error("[PostCourse2MetaError::MongoOid]: {0}")]
     MongoOid(#[from] bson::oid::Error),
-    #[error("[PutCourses2Error::Mongo]: {0}")]
-    Mongo(#[from] mongodb::Error),
-    #[error("[PutCourses2Error::MongoColl]: {0}")]
-    MongoColl(#[from] mongodb::coll::error::WriteException),
-    #[error("[PutCourses2Error::Unauthorized]")]
+    #[error("[PostCourse2MetaError::Database]: {0}")]
+    Database(#[from] DatabaseError),
+    #[error("[PostCourse2MetaError::Unauthorized]")]
     Unauthorized,
 }
 
@@ -55,8 +233,9 @@ impl ResponseError for PostCourse2MetaError {
             PostCourse2MetaError::MongoOid(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
-            PostCourse2MetaError::Mongo(_) => HttpResponse::new(StatusCode::NOT_FOUND),
-            PostCourse2MetaError::MongoColl(_) => {
+            PostCourse2MetaError::CourseNotFound(_) => HttpResponse::new(StatusCode::NOT_FOUND),
+            PostCourse2MetaError::Invalid(_, _) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            PostCourse2MetaError::Database(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
             PostCourse2MetaError::Unauthorized => HttpResponse::new(StatusCode::UNAUTHORIZED),
@@ -64,3 +243,186 @@ impl ResponseError for PostCourse2MetaError {
         res.set_body(Body::from(format!("{}", self)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(meta: serde_json::Value) -> Result<OrderedDocument, PostCourse2MetaError> {
+        serde_json::from_value::<PostCourse2Meta>(meta)
+            .unwrap()
+            .into_update()
+    }
+
+    #[test]
+    fn accepts_youtube_and_twitch_videos() {
+        for video_url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "http://youtube.com/watch?feature=share&v=dQw4w9WgXcQ&t=42",
+            "https://m.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?t=42",
+            " https://www.twitch.tv/videos/123456789 ",
+            "https://twitch.tv/videos/123456789#t=1m",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                validate_video_url(video_url.to_string()).unwrap(),
+                video_url.trim()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_video_urls() {
+        for video_url in [
+            "",
+            "dQw4w9WgXcQ",
+            "ftp://youtu.be/dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgX",
+            "https://www.youtube.com/channel/dQw4w9WgXcQ",
+            "https://youtube.com.evil.com/watch?v=dQw4w9WgXcQ",
+            "https://www.twitch.tv/someone",
+            "https://www.twitch.tv/videos/abc",
+            "https://youtu.be/dQw4w9WgXcQ <script>",
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                validate_video_url(video_url.to_string()),
+                Err(PostCourse2MetaError::Invalid("videoUrl", _))
+            ));
+        }
+    }
+
+    #[test]
+    fn normalizes_nintendo_ids() {
+        for nintendo_id in [
+            "1BC-2DF-3GH",
+            "1bc2df3gh",
+            " 1BC 2DF 3GH ",
+            "1-b-c-2-d-f-3-g-h",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                validate_nintendo_id(nintendo_id.to_string()).unwrap(),
+                "1BC-2DF-3GH"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_nintendo_ids() {
+        // course ids don't contain vowels and Z
+        for nintendo_id in [
+            "",
+            "1BC-2DF-3G",
+            "1BC-2DF-3GHJ",
+            "1AB-2CD-3EF",
+            "ZZZ-ZZZ-ZZZ",
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                validate_nintendo_id(nintendo_id.to_string()),
+                Err(PostCourse2MetaError::Invalid("nintendoId", _))
+            ));
+        }
+    }
+
+    #[test]
+    fn leaves_omitted_fields_unchanged() {
+        assert_eq!(update(serde_json::json!({})).unwrap(), doc! {});
+    }
+
+    #[test]
+    fn removes_fields_set_to_null() {
+        let update = update(serde_json::json!({
+            "difficulty": null,
+            "videoUrl": null,
+            "tags": null,
+            "description": null,
+            "nintendoId": null,
+        }))
+        .unwrap();
+        assert_eq!(
+            update,
+            doc! {
+                "$unset" => {
+                    "difficulty" => "",
+                    "video_url" => "",
+                    "tags" => "",
+                    "description" => "",
+                    "nintendo_id" => "",
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn sets_given_fields() {
+        let update = update(serde_json::json!({
+            "videoUrl": "https://youtu.be/dQw4w9WgXcQ",
+            "tags": [" Speedrun ", "speedrun", ""],
+            "description": " hard ",
+            "nintendoId": "1bc2df3gh",
+            "unlisted": true,
+        }))
+        .unwrap();
+        assert_eq!(
+            update,
+            doc! {
+                "$set" => {
+                    "video_url" => "https://youtu.be/dQw4w9WgXcQ",
+                    "tags" => vec![Bson::String("speedrun".to_string())],
+                    "description" => "hard",
+                    "nintendo_id" => "1BC-2DF-3GH",
+                    "unlisted" => true,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn removes_tags_when_none_remain() {
+        let update = update(serde_json::json!({
+            "tags": [" "],
+            "description": "hard",
+        }))
+        .unwrap();
+        assert_eq!(
+            update,
+            doc! {
+                "$set" => {
+                    "description" => "hard",
+                },
+                "$unset" => {
+                    "tags" => "",
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        assert!(matches!(
+            update(serde_json::json!({ "videoUrl": "https://example.com" })),
+            Err(PostCourse2MetaError::Invalid("videoUrl", _))
+        ));
+        assert!(matches!(
+            update(serde_json::json!({ "description": "  " })),
+            Err(PostCourse2MetaError::Invalid("description", _))
+        ));
+        let tags: Vec<String> = (0..=MAX_TAGS).map(|i| format!("tag{}", i)).collect();
+        assert!(matches!(
+            update(serde_json::json!({ "tags": tags })),
+            Err(PostCourse2MetaError::Invalid("tags", _))
+        ));
+        let tag = "a".repeat(MAX_TAG_LENGTH + 1);
+        assert!(matches!(
+            update(serde_json::json!({ "tags": [tag] })),
+            Err(PostCourse2MetaError::Invalid("tags", _))
+        ));
+    }
+}
@@ -1,9 +1,17 @@
+mod archive;
+mod cache;
 mod delete;
+pub mod dictionary;
 pub mod download;
+pub mod facets;
 mod get;
+pub mod jobs;
 pub mod meta;
+pub mod package;
 mod post;
 mod put;
+pub mod random;
+pub mod similar;
 pub mod thumbnail;
 mod vote;
 
@@ -22,8 +30,21 @@ pub fn service() -> impl dev::HttpServiceFactory + Mountable {
                 .route(web::get().to(get::get_courses))
                 .route(web::put().to(put::put_courses)),
         )
+        .service(web::resource("/page").route(web::get().to(get::get_courses_page)))
+        .service(web::resource("/facets").route(web::get().to(facets::get_facets)))
+        .service(web::resource("/random").route(web::get().to(random::get_random_courses)))
         .service(web::resource("/analyze").route(web::post().to(post::post_analyze_courses)))
+        .service(web::resource("/similar").route(web::post().to(similar::post_similar_courses)))
+        .service(web::resource("/download").route(web::post().to(download::download_courses)))
+        .service(web::resource("/package").route(web::post().to(package::package_courses)))
+        .service(web::resource("/jobs").route(web::get().to(jobs::get_jobs)))
+        .service(web::resource("/dictionary").route(web::get().to(dictionary::get_dictionary)))
         .service(web::resource("/{course_id}").route(web::delete().to(delete::delete_course)))
+        .service(web::resource("/{course_id}/data").route(web::put().to(put::put_course_data)))
+        .service(
+            web::resource("/{course_id}/similar")
+                .route(web::get().to(similar::get_similar_courses)),
+        )
         .service(
             web::resource("/download/{course_id}").route(web::get().to(download::download_course)),
         )
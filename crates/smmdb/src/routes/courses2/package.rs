@@ -0,0 +1,160 @@
+use super::{
+    archive::{archive_response, streaming_archive_response, unix_time, ArchiveFormat},
+    download::{
+        course_entry_name, get_visitor, thumb_entry_name, CourseFormat, DownloadCourse2Error,
+        FileFormat, Slot, MAX_BATCH_DOWNLOAD, SLOT_COUNT,
+    },
+    GetCourses2,
+};
+use crate::server::{block, ServerData};
+
+use actix_http::http::{HeaderName, HeaderValue};
+use actix_web::{HttpRequest, HttpResponse};
+use futures::{self, stream, StreamExt};
+use paperclip::actix::{api_v2_operation, web, Apiv2Schema};
+use serde::Deserialize;
+use serde_qs::actix::QsQuery;
+use smmdb_auth::Identity;
+use smmdb_lib::Save;
+use std::{convert::TryFrom, env, fs, io, path::PathBuf};
+
+/// Packages the courses matching the filters of `GET /courses2` as files of an SMM2 save.
+///
+/// If the body contains an existing `save.dat`, the courses are added to its free course slots
+/// and the updated `save.dat` is part of the package. Otherwise the courses are numbered
+/// consecutively. The filled slots are listed in the `X-Filled-Slots` header.
+#[api_v2_operation(tags(SMM2))]
+pub async fn package_courses(
+    data: web::Data<ServerData>,
+    query: QsQuery<PackageCourses2>,
+    courses_query: QsQuery<GetCourses2>,
+    mut payload: web::Payload,
+    req: HttpRequest,
+    identity: Option<Identity>,
+) -> Result<HttpResponse, DownloadCourse2Error> {
+    let format = ArchiveFormat::try_from(&query.file_format)?;
+    let mut save_data = web::BytesMut::new();
+    while let Some(item) = payload.next().await {
+        save_data.extend_from_slice(&item?);
+    }
+
+    let visitor = get_visitor(&req, identity, &data.trusted_proxies);
+    let slot = query.slot.0;
+    let max_courses = MAX_BATCH_DOWNLOAD.min((SLOT_COUNT - slot) as usize);
+    let courses_query = courses_query.into_inner();
+    let course_ids = {
+        let data = data.clone();
+        block(move || data.get_courses2_download_ids(None, courses_query, max_courses, visitor))
+            .await?
+    };
+
+    let (mut res, slots) = if save_data.is_empty() {
+        let slots: Vec<u8> = (slot..).take(course_ids.len()).collect();
+        let entries = stream::iter(slots.clone().into_iter().zip(course_ids)).then(
+            move |(slot, course_id)| {
+                let data = data.clone();
+                async move {
+                    let (course, thumb) =
+                        block(move || data.get_course2_files(course_id, &CourseFormat::Encrypted))
+                            .await?;
+                    Ok::<_, DownloadCourse2Error>(vec![
+                        (course_entry_name(&CourseFormat::Encrypted, slot), course),
+                        (thumb_entry_name(slot), thumb),
+                    ])
+                }
+            },
+        );
+        (streaming_archive_response(format, "save", entries), slots)
+    } else {
+        let courses = block(move || {
+            course_ids
+                .into_iter()
+                .map(|course_id| data.get_course2_files(course_id, &CourseFormat::Encrypted))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await?;
+        let (entries, slots) = merge_into_save(&save_data[..], slot, courses).await?;
+        (
+            archive_response(format, "save", entries, unix_time())?,
+            slots,
+        )
+    };
+
+    let filled_slots: Vec<String> = slots.iter().map(|slot| slot.to_string()).collect();
+    res.headers_mut().insert(
+        HeaderName::from_static("x-filled-slots"),
+        HeaderValue::from_str(&filled_slots.join(",")).unwrap(),
+    );
+    Ok(res)
+}
+
+#[derive(Apiv2Schema, Debug, Deserialize)]
+pub struct PackageCourses2 {
+    #[serde(default)]
+    file_format: FileFormat,
+    /// First slot to be filled.
+    #[serde(default)]
+    slot: Slot,
+}
+
+/// Adds the courses to the free own course slots of the save, starting at `slot`.
+///
+/// Returns the updated `save.dat` and the files of the added courses.
+async fn merge_into_save(
+    save_data: &[u8],
+    slot: u8,
+    courses: Vec<(Vec<u8>, Vec<u8>)>,
+) -> Result<(Vec<(String, Vec<u8>)>, Vec<u8>), DownloadCourse2Error> {
+    let dir = TempDir::new()?;
+    fs::write(dir.0.join("save.dat"), save_data)?;
+    let mut save = Save::new(dir.0.clone()).await?;
+
+    let free_slots: Vec<u8> = save
+        .get_own_courses()
+        .iter()
+        .enumerate()
+        .filter(|(index, course)| *index >= slot as usize && course.is_none())
+        .map(|(index, _)| index as u8)
+        .collect();
+    if free_slots.len() < courses.len() {
+        return Err(DownloadCourse2Error::NotEnoughSlots(free_slots.len()));
+    }
+    let slots: Vec<u8> = free_slots.into_iter().take(courses.len()).collect();
+    for (slot, (mut course, thumb)) in slots.iter().zip(courses) {
+        let course = smmdb_lib::Course2::from_switch_files(&mut course, Some(thumb), true)?;
+        save.add_course(*slot, course).await?;
+    }
+    save.save().await?;
+
+    let mut entries = vec![("save.dat".to_string(), fs::read(dir.0.join("save.dat"))?)];
+    for slot in slots.iter() {
+        for name in [
+            course_entry_name(&CourseFormat::Encrypted, *slot),
+            thumb_entry_name(*slot),
+        ]
+        .iter()
+        {
+            entries.push((name.clone(), fs::read(dir.0.join(name))?));
+        }
+    }
+    Ok((entries, slots))
+}
+
+/// Directory which is removed with all its content when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> io::Result<Self> {
+        let path = env::temp_dir().join(format!("smmdb-save-{:016x}", rand::random::<u64>()));
+        fs::create_dir(&path)?;
+        Ok(TempDir(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            println!("Could not remove {}: {}", self.0.display(), err);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::server::ServerData;
+use crate::server::{block, ServerData};
 
 use actix_http::body::Body;
 use actix_web::{
@@ -7,12 +7,14 @@ use actix_web::{
     web::{self},
     HttpRequest, HttpResponse,
 };
+use bson::oid::ObjectId;
 use futures::{self, StreamExt};
 use paperclip::actix::{api_v2_errors, api_v2_operation, Apiv2Schema};
 use serde::{Deserialize, Serialize, Serializer};
 use serde_qs::actix::QsQuery;
 use smmdb_auth::Identity;
 use smmdb_common::{Course2Response, Course2SimilarityError, Difficulty};
+use smmdb_db::DatabaseError;
 use std::io;
 use thiserror::Error;
 
@@ -37,15 +39,39 @@ pub async fn put_courses(
     match smmdb_lib::Course2::from_packed(&bytes[..]) {
         Ok(courses) => {
             let account = identity.get_account();
-            match data.put_courses2(courses, &account, query.difficulty) {
-                Ok(res) => Ok(web::Json(res)),
-                Err(err) => Err(err),
-            }
+            let res = block(move || data.put_courses2(courses, &account, query.difficulty)).await?;
+            Ok(web::Json(res))
         }
         Err(err) => Err(PutCourses2Error::from(err)),
     }
 }
 
+/// Replaces the course data of an owned course with a single re-uploaded course.
+///
+/// The course keeps its id, votes and downloads. The replaced course data is kept and can still
+/// be downloaded with the `version` parameter of `GET /courses2/download/{course_id}`.
+#[api_v2_operation(tags(SMM2))]
+pub async fn put_course_data(
+    data: web::Data<ServerData>,
+    path: web::Path<String>,
+    mut payload: web::Payload,
+    identity: Identity,
+) -> Result<web::Json<Course2Response>, PutCourse2DataError> {
+    let course_id = ObjectId::with_string(&path.into_inner())?;
+    let mut bytes = web::BytesMut::new();
+    while let Some(item) = payload.next().await {
+        bytes.extend_from_slice(&item?);
+    }
+    let mut courses = smmdb_lib::Course2::from_packed(&bytes[..])?;
+    if courses.len() != 1 {
+        return Err(PutCourse2DataError::CourseCount(courses.len()));
+    }
+    let course = courses.remove(0);
+    let account = identity.get_account();
+    let res = block(move || data.put_course2_data(course_id, course, &account)).await?;
+    Ok(web::Json(res))
+}
+
 #[api_v2_errors(code = 400, code = 404, code = 500)]
 #[derive(Apiv2Schema, Debug, Error)]
 pub enum PutCourses2Error {
@@ -61,8 +87,8 @@ pub enum PutCourses2Error {
     SerdeJson(#[from] serde_json::Error),
     #[error("[PutCourses2Error::ThumbnailMissing]")]
     ThumbnailMissing,
-    #[error("[PutCourses2Error::Mongo]: {0}")]
-    Mongo(#[from] mongodb::Error),
+    #[error("[PutCourses2Error::Database]: {0}")]
+    Database(#[from] DatabaseError),
 }
 
 impl ResponseError for PutCourses2Error {
@@ -74,7 +100,7 @@ impl ResponseError for PutCourses2Error {
             PutCourses2Error::Smmdb(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
             PutCourses2Error::SerdeJson(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
             PutCourses2Error::ThumbnailMissing => HttpResponse::new(StatusCode::BAD_REQUEST),
-            PutCourses2Error::Mongo(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            PutCourses2Error::Database(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
         };
         res.set_body(Body::from(format!("{}", self)))
     }
@@ -121,3 +147,58 @@ impl Default for PutCourses2Response {
         Self::new()
     }
 }
+
+#[api_v2_errors(code = 400, code = 401, code = 404, code = 409, code = 500)]
+#[derive(Apiv2Schema, Debug, Error)]
+pub enum PutCourse2DataError {
+    #[error("[PutCourse2DataError::CourseNotFound]: {0}")]
+    CourseNotFound(ObjectId),
+    #[error("[PutCourse2DataError::CourseCount]: expected exactly one course, got {0}")]
+    CourseCount(usize),
+    #[error("[PutCourse2DataError::Course2SimilarityError]: {0}")]
+    Similarity(Course2SimilarityError),
+    #[error("[PutCourse2DataError::Conflict]: the course has been modified concurrently")]
+    Conflict,
+    #[error("[PutCourse2DataError::ThumbnailMissing]")]
+    ThumbnailMissing,
+    #[error("[PutCourse2DataError::Unauthorized]")]
+    Unauthorized,
+    #[error("[PutCourse2DataError::Payload]: {0}")]
+    Payload(#[from] PayloadError),
+    #[error("[PutCourse2DataError::Smmdb]: {0}")]
+    Smmdb(#[from] smmdb_lib::Error),
+    #[error("[PutCourse2DataError::SerdeJson]: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("[PutCourse2DataError::MongoOid]: {0}")]
+    MongoOid(#[from] bson::oid::Error),
+    #[error("[PutCourse2DataError::Database]: {0}")]
+    Database(#[from] DatabaseError),
+}
+
+impl ResponseError for PutCourse2DataError {
+    fn error_response(&self) -> HttpResponse {
+        let res = match *self {
+            PutCourse2DataError::CourseNotFound(_) => HttpResponse::new(StatusCode::NOT_FOUND),
+            PutCourse2DataError::CourseCount(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            PutCourse2DataError::Similarity(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            PutCourse2DataError::Conflict => HttpResponse::new(StatusCode::CONFLICT),
+            PutCourse2DataError::ThumbnailMissing => HttpResponse::new(StatusCode::BAD_REQUEST),
+            PutCourse2DataError::Unauthorized => HttpResponse::new(StatusCode::UNAUTHORIZED),
+            PutCourse2DataError::Payload(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            PutCourse2DataError::Smmdb(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            PutCourse2DataError::SerdeJson(_) => {
+                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            PutCourse2DataError::MongoOid(bson::oid::Error::FromHexError(_)) => {
+                HttpResponse::new(StatusCode::BAD_REQUEST)
+            }
+            PutCourse2DataError::MongoOid(_) => {
+                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            PutCourse2DataError::Database(_) => {
+                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+        res.set_body(Body::from(format!("{}", self)))
+    }
+}
@@ -0,0 +1,61 @@
+use super::{GetCourses2, GetCourses2Error};
+use crate::server::{block, ServerData};
+
+use paperclip::actix::{api_v2_operation, web, Apiv2Schema};
+use serde::{de, Deserialize, Deserializer};
+use serde_qs::actix::QsQuery;
+use smmdb_auth::Identity;
+use smmdb_common::Course2Response;
+
+/// Returns uniformly random courses matching the filters of `GET /courses2`.
+///
+/// Paging and sorting parameters are ignored.
+#[api_v2_operation(tags(SMM2))]
+pub async fn get_random_courses(
+    data: web::Data<ServerData>,
+    query: QsQuery<GetCourses2>,
+    random: QsQuery<GetRandomCourses2>,
+    identity: Option<Identity>,
+) -> Result<web::Json<Vec<Course2Response>>, GetCourses2Error> {
+    let query = query.into_inner();
+    let count = random.into_inner().count.0;
+    let account = identity.map(|identity| identity.get_account());
+    let res = block(move || data.get_random_courses2(query, count, account)).await?;
+    Ok(web::Json(res))
+}
+
+#[derive(Apiv2Schema, Debug, Deserialize)]
+pub struct GetRandomCourses2 {
+    /// Number of courses. Fewer courses are returned, if not enough courses match.
+    #[serde(default)]
+    count: Count,
+}
+
+#[derive(Apiv2Schema, Debug, Deserialize)]
+struct Count(#[serde(deserialize_with = "deserialize_count")] u32);
+
+impl Default for Count {
+    fn default() -> Count {
+        Count(1)
+    }
+}
+
+fn deserialize_count<'de, D>(de: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let val = u32::deserialize(de)?;
+    if val == 0 {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(val.into()),
+            &"count must be at least 1",
+        ))
+    } else if val > 120 {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(val.into()),
+            &"count must be at most 120",
+        ))
+    } else {
+        Ok(val)
+    }
+}
@@ -0,0 +1,158 @@
+use crate::server::{block, ServerData};
+
+use actix_http::body::Body;
+use actix_web::{
+    error::{PayloadError, ResponseError},
+    http::StatusCode,
+    HttpResponse,
+};
+use bson::oid::ObjectId;
+use futures::{self, StreamExt};
+use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_qs::actix::QsQuery;
+use smmdb_auth::Identity;
+use smmdb_common::Course2Response;
+use smmdb_db::DatabaseError;
+use thiserror::Error;
+
+/// Returns courses similar to the given course, ranked by their Jaccard similarity.
+///
+/// Only courses sharing at least one band of the `LshIndex` are considered candidates.
+#[api_v2_operation(tags(SMM2))]
+pub async fn get_similar_courses(
+    data: web::Data<ServerData>,
+    path: web::Path<String>,
+    query: QsQuery<GetSimilarCourses2>,
+    identity: Option<Identity>,
+) -> Result<web::Json<Vec<SimilarCourse2>>, SimilarCourses2Error> {
+    let course_id = ObjectId::with_string(&path.into_inner())?;
+    let query = query.into_inner();
+    let account = identity.map(|identity| identity.get_account());
+    let res = block(move || data.get_similar_courses2(course_id, query, account)).await?;
+    Ok(web::Json(res))
+}
+
+/// Returns courses similar to each course of the uploaded file in the same order.
+#[api_v2_operation(tags(SMM2))]
+pub async fn post_similar_courses(
+    data: web::Data<ServerData>,
+    query: QsQuery<GetSimilarCourses2>,
+    mut payload: web::Payload,
+    identity: Option<Identity>,
+) -> Result<web::Json<Vec<Vec<SimilarCourse2>>>, SimilarCourses2Error> {
+    let query = query.into_inner();
+    let mut bytes = web::BytesMut::new();
+    while let Some(item) = payload.next().await {
+        bytes.extend_from_slice(&item?);
+    }
+    let courses = smmdb_lib::Course2::from_packed(&bytes[..])?;
+    let account = identity.map(|identity| identity.get_account());
+    let res = block(move || data.find_similar_courses2(courses, query, account)).await?;
+    Ok(web::Json(res))
+}
+
+#[derive(Apiv2Schema, Debug, Deserialize)]
+pub struct GetSimilarCourses2 {
+    /// Defaults to `similarity.min_score` of the server config.
+    #[serde(default, deserialize_with = "deserialize_min_score")]
+    min_score: Option<f64>,
+    #[serde(default)]
+    limit: Limit,
+}
+
+impl GetSimilarCourses2 {
+    pub fn get_min_score(&self) -> Option<f64> {
+        self.min_score
+    }
+
+    pub fn get_limit(&self) -> usize {
+        self.limit.0 as usize
+    }
+}
+
+fn deserialize_min_score<'de, D>(de: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let val = f64::deserialize(de)?;
+    if (0. ..=1.).contains(&val) {
+        Ok(Some(val))
+    } else {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Float(val),
+            &"min_score must be within [0, 1]",
+        ))
+    }
+}
+
+#[derive(Apiv2Schema, Debug, Deserialize)]
+struct Limit(#[serde(deserialize_with = "deserialize_limit")] u32);
+
+impl Default for Limit {
+    fn default() -> Limit {
+        Limit(10)
+    }
+}
+
+fn deserialize_limit<'de, D>(de: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let val = u32::deserialize(de)?;
+    if val == 0 {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(val.into()),
+            &"limit must be at least 1",
+        ))
+    } else if val > 120 {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(val.into()),
+            &"limit must be at most 120",
+        ))
+    } else {
+        Ok(val)
+    }
+}
+
+#[derive(Apiv2Schema, Debug, Serialize)]
+pub struct SimilarCourse2 {
+    jaccard: f64,
+    course: Course2Response,
+}
+
+impl SimilarCourse2 {
+    pub fn new(jaccard: f64, course: Course2Response) -> Self {
+        SimilarCourse2 { jaccard, course }
+    }
+}
+
+#[api_v2_errors(code = 400, code = 404, code = 500)]
+#[derive(Apiv2Schema, Debug, Error)]
+pub enum SimilarCourses2Error {
+    #[error("Object id invalid.\nReason: {0}")]
+    MongoOid(#[from] bson::oid::Error),
+    #[error("[SimilarCourses2Error::CourseNotFound]: {0}")]
+    CourseNotFound(ObjectId),
+    #[error("[SimilarCourses2Error::Payload]: {0}")]
+    Payload(#[from] PayloadError),
+    #[error("[SimilarCourses2Error::Smmdb]: {0}")]
+    Smmdb(#[from] smmdb_lib::Error),
+    #[error("[SimilarCourses2Error::Database]: {0}")]
+    Database(#[from] DatabaseError),
+}
+
+impl ResponseError for SimilarCourses2Error {
+    fn error_response(&self) -> HttpResponse {
+        let res = match *self {
+            SimilarCourses2Error::MongoOid(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            SimilarCourses2Error::CourseNotFound(_) => HttpResponse::new(StatusCode::NOT_FOUND),
+            SimilarCourses2Error::Payload(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            SimilarCourses2Error::Smmdb(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            SimilarCourses2Error::Database(_) => {
+                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+        res.set_body(Body::from(format!("{}", self)))
+    }
+}
@@ -1,29 +1,161 @@
-use crate::server::ServerData;
+use super::cache::{Validators, THUMBNAIL_CACHE_CONTROL};
+use crate::server::{block, JobPending, ServerData, JOB_RETRY_AFTER};
 
+use actix_http::{
+    body::Body,
+    http::{
+        header::{self, Accept, Header},
+        HeaderValue,
+    },
+};
 use actix_web::{error::ResponseError, http::StatusCode, HttpRequest, HttpResponse};
-use bson::oid::ObjectId;
+use bson::{oid::ObjectId, Bson};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder},
+    DynamicImage, ImageError,
+};
 use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema};
 use serde::Deserialize;
 use serde_qs::actix::QsQuery;
+use smmdb_db::{hash_course2_content, DatabaseError};
 use thiserror::Error;
 
+/// Returns the thumbnail of a course.
+///
+/// Without a `format` parameter, the format is negotiated with the `Accept` header and falls
+/// back to JPEG. AVIF is only negotiated, once it has been precomputed. Thumbnails may be cached
+/// by proxies. Conditional requests are validated against the `ETag` and `Last-Modified` headers.
+/// If the thumbnail is still being computed, `202 Accepted` is returned with a `Retry-After`
+/// header.
 #[api_v2_operation(tags(SMM2))]
 pub async fn get_thumbnail(
     data: web::Data<ServerData>,
     path: web::Path<String>,
     query: QsQuery<GetThumbnail2>,
-    _req: HttpRequest,
+    req: HttpRequest,
 ) -> Result<HttpResponse, GetCourse2ThumbnailError> {
     let course_id = path.into_inner();
     let course_id = ObjectId::with_string(&course_id)?;
-    let thumb = data.get_course2_thumbnail(course_id, query.into_inner())?;
-    Ok(HttpResponse::Ok().content_type("image/jpeg").body(thumb))
+    let query = query.into_inner();
+    let negotiated = query.format.is_none() && query.size != Size2::Encrypted;
+    let formats = match query.format {
+        Some(format) => vec![format],
+        None if negotiated => ThumbnailFormat::negotiate(&req),
+        None => vec![ThumbnailFormat::Jpeg],
+    };
+    let width = query.width_bucket();
+    let size = query.size.clone();
+    let revision = {
+        let data = data.clone();
+        let course_id = course_id.clone();
+        block(move || data.get_course2_revision(course_id, None)).await?
+    };
+    let mut not_modified = None;
+    if let Some((content_hash, last_modified)) = &revision {
+        for format in formats.iter() {
+            let validators =
+                thumbnail_validators(content_hash, &size, width, *format, *last_modified);
+            if validators.is_fresh(&req) {
+                not_modified = Some(validators.not_modified(THUMBNAIL_CACHE_CONTROL));
+                break;
+            }
+        }
+    }
+
+    let mut res = match not_modified {
+        Some(res) => res,
+        None => {
+            let (thumb, format, last_modified) =
+                block(move || -> Result<_, GetCourse2ThumbnailError> {
+                    let last_modified = data.get_course2_last_modified(course_id.clone())?;
+                    let (thumb, format) = data.get_course2_thumbnail(course_id, query, &formats)?;
+                    Ok((thumb, format, last_modified))
+                })
+                .await?;
+            let content_hash = match revision {
+                Some((content_hash, _)) => content_hash,
+                // until the migration has stored its content hash, the thumbnail is hashed
+                None => hash_course2_content(&thumb, &Bson::Null, &Bson::Null),
+            };
+            let validators =
+                thumbnail_validators(&content_hash, &size, width, format, last_modified);
+            if validators.is_fresh(&req) {
+                validators.not_modified(THUMBNAIL_CACHE_CONTROL)
+            } else {
+                let mut res = HttpResponse::Ok()
+                    .content_type(format.content_type())
+                    .body(thumb);
+                validators.set_headers(&mut res, THUMBNAIL_CACHE_CONTROL);
+                res
+            }
+        }
+    };
+    if negotiated {
+        res.headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("Accept"));
+    }
+    Ok(res)
 }
 
+/// Returns validators of the thumbnail in the given format.
+///
+/// They only depend on the content hash of the course and on the requested representation, so
+/// that conditional requests are answered without loading the thumbnail.
+fn thumbnail_validators(
+    content_hash: &str,
+    size: &Size2,
+    width: Option<u32>,
+    format: ThumbnailFormat,
+    last_modified: Option<i64>,
+) -> Validators {
+    let field = format.field(size.clone());
+    let width = width.unwrap_or_default().to_le_bytes();
+    Validators::new(
+        &[content_hash.as_bytes(), field.as_bytes(), &width],
+        last_modified,
+    )
+}
+
+/// Widths of thumbnails with custom dimensions.
+pub const THUMBNAIL_WIDTHS: &[u32] = &[80, 120, 160, 240, 320, 480, 640];
+
 #[derive(Apiv2Schema, Debug, Deserialize)]
 pub struct GetThumbnail2 {
     #[serde(default)]
     pub size: Size2,
+    /// Image format. The `encrypted` size only supports `jpeg`.
+    pub format: Option<ThumbnailFormat>,
+    /// Minimum width, which is rounded up to the next of `THUMBNAIL_WIDTHS`. Larger widths are
+    /// clamped to 640. Overrides `size` unless it is `encrypted`.
+    pub width: Option<u32>,
+    /// Minimum height. All thumbnails are 640x360, so the height is converted to the width with
+    /// the same 16:9 aspect ratio, which is then rounded up and clamped like `width`.
+    pub height: Option<u32>,
+}
+
+impl GetThumbnail2 {
+    /// Returns the allowed width fitting the requested dimensions, if any are given.
+    ///
+    /// Dimensions larger than the original thumbnail are clamped to the largest width, because
+    /// thumbnails are never scaled up.
+    pub fn width_bucket(&self) -> Option<u32> {
+        if self.size == Size2::Encrypted || (self.width.is_none() && self.height.is_none()) {
+            return None;
+        }
+        let (max_width, max_height) = Size2::Original.get_dimensions();
+        let height_width = self
+            .height
+            .map(|height| (height.min(max_height) * max_width + max_height - 1) / max_height);
+        let width = self
+            .width
+            .unwrap_or_default()
+            .max(height_width.unwrap_or_default());
+        THUMBNAIL_WIDTHS
+            .iter()
+            .find(|bucket| **bucket >= width)
+            .or_else(|| THUMBNAIL_WIDTHS.last())
+            .cloned()
+    }
 }
 
 #[derive(Apiv2Schema, Clone, Debug, Deserialize, PartialEq)]
@@ -54,6 +186,132 @@ impl Default for Size2 {
     }
 }
 
+#[derive(Apiv2Schema, Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFormat {
+    Jpeg,
+    Webp,
+    Png,
+    Avif,
+}
+
+impl ThumbnailFormat {
+    pub const ALL: [ThumbnailFormat; 4] = [
+        ThumbnailFormat::Jpeg,
+        ThumbnailFormat::Webp,
+        ThumbnailFormat::Png,
+        ThumbnailFormat::Avif,
+    ];
+
+    /// Formats in the order of preference, if the client accepts several of them equally.
+    const PREFERENCE: [ThumbnailFormat; 4] = [
+        ThumbnailFormat::Avif,
+        ThumbnailFormat::Webp,
+        ThumbnailFormat::Jpeg,
+        ThumbnailFormat::Png,
+    ];
+
+    /// Returns the formats explicitly listed in the `Accept` header ordered by their quality,
+    /// followed by JPEG.
+    ///
+    /// Wildcards are ignored, so that clients, which accept anything, keep receiving JPEG.
+    pub fn negotiate(req: &HttpRequest) -> Vec<Self> {
+        let mut accepted = vec![];
+        if let Ok(accept) = Accept::parse(req) {
+            for format in ThumbnailFormat::PREFERENCE.iter() {
+                let quality = accept
+                    .iter()
+                    .filter(|item| item.item.essence_str() == format.content_type())
+                    .map(|item| item.quality)
+                    .max();
+                if let Some(quality) = quality {
+                    if quality > header::q(0u16) {
+                        accepted.push((*format, quality));
+                    }
+                }
+            }
+        }
+        // the sort is stable, so that equal qualities keep the order of preference
+        accepted.sort_by(|(_, a), (_, b)| b.cmp(a));
+        let mut formats: Vec<Self> = accepted.into_iter().map(|(format, _)| format).collect();
+        if !formats.contains(&ThumbnailFormat::Jpeg) {
+            formats.push(ThumbnailFormat::Jpeg);
+        }
+        formats
+    }
+
+    /// Whether encoding takes too long for a request, so that the format is only served, once it
+    /// has been precomputed, unless it is requested explicitly.
+    pub fn is_slow(self) -> bool {
+        self == ThumbnailFormat::Avif
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Webp => "image/webp",
+            ThumbnailFormat::Png => "image/png",
+            ThumbnailFormat::Avif => "image/avif",
+        }
+    }
+
+    /// Name of the field caching the thumbnail of the given size in this format.
+    ///
+    /// JPEG thumbnails keep the field names without suffix.
+    pub fn field(self, size: Size2) -> String {
+        let field: String = size.into();
+        match self {
+            ThumbnailFormat::Jpeg => field,
+            ThumbnailFormat::Webp => format!("{}_webp", field),
+            ThumbnailFormat::Png => format!("{}_png", field),
+            ThumbnailFormat::Avif => format!("{}_avif", field),
+        }
+    }
+
+    /// Encodes the image, which may be of any color type.
+    ///
+    /// Alpha channels are kept, except for JPEG.
+    pub fn encode(self, image: &DynamicImage) -> Result<Vec<u8>, ImageError> {
+        let image = match (self, image.color().has_alpha()) {
+            (ThumbnailFormat::Jpeg, _) | (_, false) => DynamicImage::ImageRgb8(image.to_rgb8()),
+            (_, true) => DynamicImage::ImageRgba8(image.to_rgba8()),
+        };
+        let (width, height) = (image.width(), image.height());
+        let color = image.color();
+        let mut res = vec![];
+        match self {
+            ThumbnailFormat::Jpeg => {
+                JpegEncoder::new_with_quality(&mut res, 85).encode(
+                    image.as_bytes(),
+                    width,
+                    height,
+                    color,
+                )?;
+            }
+            ThumbnailFormat::Webp => {
+                let encoder = if color.has_alpha() {
+                    webp::Encoder::from_rgba(image.as_bytes(), width, height)
+                } else {
+                    webp::Encoder::from_rgb(image.as_bytes(), width, height)
+                };
+                res.extend_from_slice(&encoder.encode(85.));
+            }
+            ThumbnailFormat::Png => {
+                PngEncoder::new(&mut res).encode(image.as_bytes(), width, height, color)?;
+            }
+            ThumbnailFormat::Avif => {
+                AvifEncoder::new_with_speed_quality(&mut res, 6, 70).write_image(
+                    image.as_bytes(),
+                    width,
+                    height,
+                    color,
+                )?;
+            }
+        }
+        Ok(res)
+    }
+}
+
 impl From<Size2> for String {
     fn from(val: Size2) -> Self {
         match val {
@@ -66,15 +324,19 @@ impl From<Size2> for String {
     }
 }
 
-#[api_v2_errors(code = 400, code = 404, code = 500)]
+#[api_v2_errors(code = 202, code = 400, code = 404, code = 500)]
 #[derive(Apiv2Schema, Debug, Error)]
 pub enum GetCourse2ThumbnailError {
     #[error("[GetCourse2ThumbnailError::CourseNotFound]")]
     CourseNotFound(ObjectId),
+    #[error("[GetCourse2ThumbnailError::UnsupportedFormat]: encrypted thumbnails are only available as jpeg")]
+    UnsupportedFormat,
+    #[error("[GetCourse2ThumbnailError::JobPending]: {0}")]
+    JobPending(#[from] JobPending),
     #[error("[GetCourse2ThumbnailError::MongoOid]: {0}")]
     MongoOid(#[from] bson::oid::Error),
-    #[error("[GetCourse2ThumbnailError::Mongo]: {0}")]
-    Mongo(#[from] mongodb::Error),
+    #[error("[GetCourse2ThumbnailError::Database]: {0}")]
+    Database(#[from] DatabaseError),
     #[error("[GetCourse2ThumbnailError::Image]: {0}")]
     Image(#[from] image::ImageError),
 }
@@ -83,13 +345,23 @@ impl ResponseError for GetCourse2ThumbnailError {
     fn error_response(&self) -> HttpResponse {
         match *self {
             GetCourse2ThumbnailError::CourseNotFound(_) => HttpResponse::new(StatusCode::NOT_FOUND),
+            GetCourse2ThumbnailError::UnsupportedFormat => {
+                HttpResponse::new(StatusCode::BAD_REQUEST).set_body(Body::from(format!("{}", self)))
+            }
+            GetCourse2ThumbnailError::JobPending(_) => {
+                let mut res = HttpResponse::new(StatusCode::ACCEPTED)
+                    .set_body(Body::from(format!("{}", self)));
+                res.headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(JOB_RETRY_AFTER));
+                res
+            }
             GetCourse2ThumbnailError::MongoOid(bson::oid::Error::FromHexError(_)) => {
                 HttpResponse::new(StatusCode::BAD_REQUEST)
             }
             GetCourse2ThumbnailError::MongoOid(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
-            GetCourse2ThumbnailError::Mongo(_) => {
+            GetCourse2ThumbnailError::Database(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
             GetCourse2ThumbnailError::Image(_) => {
@@ -98,3 +370,58 @@ impl ResponseError for GetCourse2ThumbnailError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn negotiate(accept: &str) -> Vec<ThumbnailFormat> {
+        ThumbnailFormat::negotiate(&TestRequest::with_header("accept", accept).to_http_request())
+    }
+
+    #[test]
+    fn orders_formats_by_quality_and_preference() {
+        use ThumbnailFormat::*;
+        assert_eq!(
+            negotiate("image/avif,image/webp,*/*"),
+            vec![Avif, Webp, Jpeg]
+        );
+        assert_eq!(
+            negotiate("image/avif;q=0.5,image/webp"),
+            vec![Webp, Avif, Jpeg]
+        );
+        assert_eq!(negotiate("image/png,image/jpeg;q=0.9"), vec![Png, Jpeg]);
+        assert_eq!(negotiate("image/webp;q=0,*/*"), vec![Jpeg]);
+        assert_eq!(negotiate("*/*"), vec![Jpeg]);
+    }
+
+    fn width_bucket(width: Option<u32>, height: Option<u32>) -> Option<u32> {
+        GetThumbnail2 {
+            size: Size2::Original,
+            format: None,
+            width,
+            height,
+        }
+        .width_bucket()
+    }
+
+    #[test]
+    fn rounds_dimensions_up_to_width_buckets() {
+        assert_eq!(width_bucket(None, None), None);
+        assert_eq!(width_bucket(Some(100), None), Some(120));
+        assert_eq!(width_bucket(Some(640), None), Some(640));
+        assert_eq!(width_bucket(None, Some(90)), Some(160));
+        assert_eq!(width_bucket(None, Some(91)), Some(240));
+        assert_eq!(width_bucket(Some(200), Some(90)), Some(240));
+    }
+
+    #[test]
+    fn clamps_dimensions_to_the_largest_width_bucket() {
+        assert_eq!(width_bucket(Some(641), None), Some(640));
+        assert_eq!(width_bucket(None, Some(361)), Some(640));
+        assert_eq!(width_bucket(Some(u32::MAX), Some(u32::MAX)), Some(640));
+        assert_eq!(width_bucket(Some(0), None), Some(80));
+        assert_eq!(width_bucket(None, Some(0)), Some(80));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::server::ServerData;
+use crate::server::{block, ServerData};
 
 use actix_http::body::Body;
 use actix_web::{error::ResponseError, http::StatusCode, HttpRequest, HttpResponse};
@@ -6,6 +6,7 @@ use bson::oid::ObjectId;
 use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema, NoContent};
 use serde::Deserialize;
 use smmdb_auth::Identity;
+use smmdb_db::DatabaseError;
 use thiserror::Error;
 
 #[derive(Apiv2Schema, Debug, Deserialize)]
@@ -33,7 +34,8 @@ pub async fn vote_course(
     if body.value > 1 || body.value < -1 {
         return Err(VoteCourse2Error::BadValue(body.value));
     }
-    data.vote_course2(account.get_id().clone(), course_oid, body.value)?;
+    let value = body.value;
+    block(move || data.vote_course2(account.get_id().clone(), course_oid, value)).await?;
     Ok(NoContent)
 }
 
@@ -42,8 +44,8 @@ pub async fn vote_course(
 pub enum VoteCourse2Error {
     #[error("[VoteCourse2Error::MongoOid]: {0}")]
     MongoOid(#[from] bson::oid::Error),
-    #[error("[VoteCourse2Error::Mongo]: {0}")]
-    Mongo(#[from] mongodb::Error),
+    #[error("[VoteCourse2Error::Database]: {0}")]
+    Database(#[from] DatabaseError),
     #[error("[VoteCourse2Error::BadValue]: {0}")]
     BadValue(i32),
 }
@@ -52,7 +54,7 @@ impl ResponseError for VoteCourse2Error {
     fn error_response(&self) -> HttpResponse {
         let res = match *self {
             VoteCourse2Error::MongoOid(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
-            VoteCourse2Error::Mongo(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            VoteCourse2Error::Database(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
             VoteCourse2Error::BadValue(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
         };
         res.set_body(Body::from(format!("{}", self)))
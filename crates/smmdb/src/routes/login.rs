@@ -1,4 +1,4 @@
-use crate::server::ServerData;
+use crate::server::{block, ServerData};
 
 use actix_session::Session;
 use actix_web::{
@@ -8,6 +8,7 @@ use awc::{error::JsonPayloadError, SendClientRequest};
 use paperclip::actix::{api_v2_errors, api_v2_operation, web, Apiv2Schema, Mountable};
 use serde::Deserialize;
 use smmdb_auth::{AccountConvertError, AccountReq, AccountRes, AuthSession, IdInfo, Identity};
+use smmdb_db::DatabaseError;
 use std::convert::TryInto;
 use thiserror::Error;
 
@@ -72,10 +73,8 @@ async fn login_with_google(
         session
             .set("expires_at", json.token_obj.expires_at)
             .unwrap();
-        let account = data.add_or_get_account(
-            account,
-            AuthSession::new(id_token.clone(), json.token_obj.expires_at),
-        )?;
+        let session = AuthSession::new(id_token.clone(), json.token_obj.expires_at);
+        let account = block(move || data.add_or_get_account(account, session)).await?;
         // TODO get stars from database
         let account = AccountRes::new(&account);
         session.set("account_id", account.get_id()).unwrap();
@@ -96,8 +95,8 @@ enum LoginError {
     SerdeJson(#[from] serde_json::Error),
     #[error("[LoginError::AccountConvert]: {0}")]
     AccountConvert(#[from] AccountConvertError),
-    #[error("[LoginError::Mongodb]: {0}")]
-    Mongodb(#[from] mongodb::Error),
+    #[error("[LoginError::Database]: {0}")]
+    Database(#[from] DatabaseError),
 }
 
 impl ResponseError for LoginError {
@@ -108,7 +107,7 @@ impl ResponseError for LoginError {
             LoginError::JsonPayload(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
             LoginError::SerdeJson(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
             LoginError::AccountConvert(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
-            LoginError::Mongodb(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            LoginError::Database(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
         }
     }
 }
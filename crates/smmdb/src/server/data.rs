@@ -1,112 +1,117 @@
 use crate::{
-    config::GOOGLE_CLIENT_ID,
+    config::Config,
     routes::{
         courses,
         courses2::{
             self,
-            download::DownloadCourse2Error,
+            download::{CourseFormat, DownloadCourse2Error},
+            facets::{Courses2Facets, COURSE2_FACETS, COURSE2_FACET_LIMIT},
+            jobs::{JobCounts, Jobs},
             meta::PostCourse2MetaError,
-            thumbnail::{GetCourse2ThumbnailError, GetThumbnail2, Size2},
-            PutCourses2Response,
+            similar::{GetSimilarCourses2, SimilarCourse2, SimilarCourses2Error},
+            thumbnail::{GetCourse2ThumbnailError, GetThumbnail2, Size2, ThumbnailFormat},
+            PutCourse2DataError, PutCourses2Response,
         },
     },
+    server::{
+        job_retry_backoff, Derivative, JobPending, JobStatus, SimilarityIndex, ThumbnailCache,
+        ThumbnailKey, ZstdDictionary, JOB_LEASE,
+    },
     session::AuthReq,
 };
 
-use bson::{oid::ObjectId, ordered::OrderedDocument, spec::BinarySubtype, Bson};
-use image::{
-    error::{ImageError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind},
-    imageops::FilterType,
-    jpeg::JpegEncoder,
-    load_from_memory, DynamicImage,
-};
+use bson::{oid::ObjectId, ordered::OrderedDocument, spec::BinarySubtype, Bson, ValueAccessError};
+use image::{imageops::FilterType, load_from_memory};
 use rayon::prelude::*;
 use smmdb_auth::{Account, AccountReq, AuthSession};
 use smmdb_common::{
-    Course, Course2, Course2Response, Course2SimilarityError, CourseResponse, Difficulty, LshIndex,
-    MinHash, PermGen, Vote,
+    Course, Course2, Course2Response, Course2SimilarityError, CourseResponse, Difficulty, MinHash,
+    PermGen, Vote,
+};
+use smmdb_db::{
+    compress_course2_data_br, compress_course2_data_protobuf_br, Database, DatabaseError,
 };
-use smmdb_db::Database;
 use std::{
-    convert::TryInto,
-    io,
-    sync::{Arc, Mutex},
-    time::SystemTime,
+    cmp::Ordering, convert::TryInto, fmt::Display, io, net::IpAddr, sync::Arc, time::SystemTime,
 };
 
-const SIMILARITY_THRESHOLD: f64 = 0.95;
-
 pub struct Data {
-    database: Arc<Database>,
-    pub google_client_id: &'static str,
+    database: Arc<dyn Database>,
+    pub google_client_id: String,
+    pub similarity_threshold: f64,
+    pub similarity_min_score: f64,
     pub perm_gen: PermGen,
-    pub lsh_index: Arc<Mutex<LshIndex>>,
+    similarity_index: SimilarityIndex,
+    /// In milliseconds.
+    download_dedup_window: i64,
+    /// In milliseconds.
+    trending_half_life: f64,
+    pub trusted_proxies: Vec<IpAddr>,
+    thumbnail_cache: ThumbnailCache,
+    job_max_attempts: i32,
+    /// The dictionary only changes with migrations, which run before the server is started.
+    zstd_dictionary: Option<ZstdDictionary>,
 }
 
 pub type ServerData = Arc<Data>;
 
 impl Data {
-    pub fn new(database: Arc<Database>, perm_gen: PermGen) -> Self {
-        let mut lsh_index = LshIndex::new(8);
-        println!("Filling LshIndex");
-        Data::fill_lsh_index(&database, &mut lsh_index);
-        println!("Filling LshIndex completed!");
+    pub fn new(config: &Config, database: Arc<dyn Database>, perm_gen: PermGen) -> Self {
+        let similarity_index = SimilarityIndex::new(&config.similarity, &perm_gen);
+        let zstd_dictionary =
+            ZstdDictionary::load_current(&*database).expect("Failed to load zstd dictionary");
         Data {
             database,
-            google_client_id: GOOGLE_CLIENT_ID,
+            google_client_id: config.google_client_id.clone(),
+            similarity_threshold: config.similarity.threshold,
+            similarity_min_score: config.similarity.min_score,
             perm_gen,
-            lsh_index: Arc::new(Mutex::new(lsh_index)),
+            similarity_index,
+            download_dedup_window: config.downloads.dedup_window as i64 * 1000,
+            trending_half_life: config.downloads.trending_half_life as f64 * 1000.,
+            trusted_proxies: config.downloads.trusted_proxies.clone(),
+            thumbnail_cache: ThumbnailCache::new(config.thumbnails.cache_size),
+            job_max_attempts: config.jobs.max_attempts as i32,
+            zstd_dictionary,
         }
     }
 
-    pub fn fill_lsh_index(database: &Database, lsh_index: &mut LshIndex) {
-        if let Ok(cursor) = database.fill_lsh_index() {
-            cursor.filter_map(Result::ok).for_each(|item| {
-                if let (Some(id), Some(hash)) = (item.get("_id"), item.get("hash")) {
-                    let hash: serde_json::Value = hash.clone().into();
-                    let hash: Result<MinHash, _> = serde_json::from_value(hash);
-                    if let (Bson::ObjectId(id), Ok(hash)) = (id.clone(), hash) {
-                        lsh_index.insert(id.to_hex(), &hash);
-                    }
-                }
-            });
-        }
+    pub fn load_similarity_index(&self) -> Result<(), DatabaseError> {
+        self.similarity_index.load(&*self.database)
+    }
+
+    pub fn snapshot_similarity_index(&self) -> io::Result<()> {
+        self.similarity_index.snapshot()
     }
 
     pub fn get_courses(
         &self,
         query: courses::GetCourses,
     ) -> Result<String, courses::GetCoursesError> {
-        match query.into_ordered_document(&self.database) {
-            Ok(query) => Ok(match self.database.get_courses(query) {
-                Ok(cursor) => {
-                    let (account_ids, courses): (Vec<Bson>, Vec<Course>) = cursor
-                        .map(|item| {
-                            let course: Course = item.unwrap().into();
-                            (course.get_owner().clone().into(), course)
-                        })
-                        .unzip();
-
-                    let accounts = self.get_accounts(account_ids);
-                    let courses: Vec<CourseResponse> = courses
-                        .into_iter()
-                        .map(|course| {
-                            let account = accounts
-                                .iter()
-                                .find(|account| {
-                                    account.get_id().to_string() == course.get_owner().to_string()
-                                })
-                                .unwrap();
-                            CourseResponse::from_course(course, account)
-                        })
-                        .collect();
+        let query = query.into_ordered_document(&*self.database)?;
+        let (account_ids, courses): (Vec<Bson>, Vec<Course>) = self
+            .database
+            .get_courses(query)?
+            .into_iter()
+            .map(|item| {
+                let course: Course = item.into();
+                (course.get_owner().clone().into(), course)
+            })
+            .unzip();
 
-                    serde_json::to_string(&courses).unwrap()
-                }
-                Err(e) => e.to_string(),
-            }),
-            Err(error) => Err(error),
-        }
+        let accounts = self.get_accounts(account_ids);
+        let courses: Vec<CourseResponse> = courses
+            .into_iter()
+            .map(|course| {
+                let account = accounts
+                    .iter()
+                    .find(|account| account.get_id().to_string() == course.get_owner().to_string())
+                    .unwrap();
+                CourseResponse::from_course(course, account)
+            })
+            .collect();
+
+        Ok(serde_json::to_string(&courses).unwrap())
     }
 
     pub fn get_courses2(
@@ -114,12 +119,59 @@ impl Data {
         query: courses2::GetCourses2,
         own_account: Option<Account>,
     ) -> Result<Vec<Course2Response>, courses2::GetCourses2Error> {
-        let query = query.into_ordered_document(&self.database)?;
-        let cursor = self.database.get_courses2(query)?;
+        let query = query.into_ordered_document(&*self.database, own_account.as_ref())?;
+        let courses = self.database.get_courses2(query)?;
+        Ok(self.course2_responses(courses, own_account.as_ref()))
+    }
 
-        let (account_ids, courses): (Vec<Bson>, Vec<Course2>) = cursor
+    pub fn get_courses2_page(
+        &self,
+        query: courses2::GetCourses2,
+        own_account: Option<Account>,
+    ) -> Result<courses2::Courses2Page, courses2::GetCourses2Error> {
+        let query = query.into_page_query(&*self.database, own_account.as_ref())?;
+        let total = match query.get_count_filter() {
+            Some(filter) => Some(self.database.count_courses2(filter.clone())?),
+            None => None,
+        };
+        let courses = self.database.get_courses2(query.get_pipeline().clone())?;
+        let next_cursor = query.get_next_cursor(&courses);
+        let courses = self.course2_responses(courses, own_account.as_ref());
+        Ok(courses2::Courses2Page::new(courses, total, next_cursor))
+    }
+
+    pub fn get_courses2_facets(
+        &self,
+        query: courses2::GetCourses2,
+        own_account: Option<Account>,
+    ) -> Result<Courses2Facets, courses2::GetCourses2Error> {
+        let filter = query.into_filter(&*self.database, own_account.as_ref())?;
+        let facets =
+            self.database
+                .get_course2_facets(filter, COURSE2_FACETS, COURSE2_FACET_LIMIT)?;
+        Ok(facets.into())
+    }
+
+    pub fn get_random_courses2(
+        &self,
+        query: courses2::GetCourses2,
+        count: u32,
+        own_account: Option<Account>,
+    ) -> Result<Vec<Course2Response>, courses2::GetCourses2Error> {
+        let filter = query.into_filter(&*self.database, own_account.as_ref())?;
+        let courses = self.database.sample_courses2(filter, count.into())?;
+        Ok(self.course2_responses(courses, own_account.as_ref()))
+    }
+
+    fn course2_responses(
+        &self,
+        courses: Vec<OrderedDocument>,
+        own_account: Option<&Account>,
+    ) -> Vec<Course2Response> {
+        let (account_ids, courses): (Vec<Bson>, Vec<Course2>) = courses
+            .into_iter()
             .map(|item| -> Result<(Bson, Course2), serde_json::Error> {
-                let course: Course2 = item.unwrap().try_into()?;
+                let course: Course2 = item.try_into()?;
                 Ok((course.get_owner().clone().into(), course))
             })
             .filter_map(Result::ok)
@@ -127,23 +179,281 @@ impl Data {
 
         let accounts = self.get_accounts(account_ids);
 
-        let courses: Vec<Course2Response> = courses
+        courses
             .into_iter()
             .map(|course| {
                 let account = accounts
                     .iter()
                     .find(|account| account.get_id().to_string() == course.get_owner().to_string())
                     .unwrap();
-                Course2Response::from_course(course, account, own_account.as_ref(), &*self.database)
+                Course2Response::from_course(course, account, own_account, &*self.database)
             })
+            .collect()
+    }
+
+    /// Returns course data and thumbnail of the course in the given format.
+    pub fn get_course2_files(
+        &self,
+        course_id: ObjectId,
+        course_format: &CourseFormat,
+    ) -> Result<(Vec<u8>, Vec<u8>), DownloadCourse2Error> {
+        match course_format {
+            CourseFormat::Encrypted => self.get_course2(course_id),
+            CourseFormat::Br => self.get_course2_br(course_id),
+            CourseFormat::ProtobufBr => self.get_course2_proto(course_id),
+            CourseFormat::Zstd => self.get_course2_zstd(course_id),
+        }
+    }
+
+    /// Returns course data, thumbnail and last modification of a previous version of the course.
+    ///
+    /// Returns `None`, if `version` is the current version.
+    pub fn get_course2_version_files(
+        &self,
+        course_id: ObjectId,
+        version: i32,
+        course_format: &CourseFormat,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>, Option<i64>)>, DownloadCourse2Error> {
+        let filter = doc! {
+            "course_id" => course_id.clone(),
+            "version" => version
+        };
+        let thumb: String = Size2::Encrypted.into();
+        let mut projection = ZstdDictionary::data_projection();
+        projection.insert(thumb.clone(), 1);
+        projection.insert("last_modified", 1);
+        let mut doc = match self
+            .database
+            .find_course2_history(filter, projection)?
+            .into_iter()
+            .next()
+        {
+            Some(doc) => doc,
+            None => {
+                let filter = doc! {
+                    "_id" => course_id.clone()
+                };
+                let projection = doc! {
+                    "version" => 1
+                };
+                let course = self
+                    .database
+                    .find_courses2(filter, Some(projection))?
+                    .into_iter()
+                    .next()
+                    .ok_or(DownloadCourse2Error::CourseNotFound(course_id))?;
+                return if course.get_i32("version").unwrap_or(1) == version {
+                    Ok(None)
+                } else {
+                    Err(DownloadCourse2Error::VersionNotFound(version))
+                };
+            }
+        };
+        let data = self.take_course2_data_encrypted(&mut doc)?;
+        let thumb = take_binary(&mut doc, &thumb)?;
+        let data = match course_format {
+            CourseFormat::Encrypted => data,
+            CourseFormat::Br => compress_course2_data_br(data)?,
+            CourseFormat::ProtobufBr => compress_course2_data_protobuf_br(data)?,
+            CourseFormat::Zstd => {
+                let dictionary = self
+                    .zstd_dictionary
+                    .as_ref()
+                    .ok_or(DownloadCourse2Error::DictionaryMissing)?;
+                let mut data = data;
+                smmdb_lib::Course2::decrypt(&mut data)?;
+                dictionary.compress(&data)?
+            }
+        };
+        Ok(Some((data, thumb, doc.get_i64("last_modified").ok())))
+    }
+
+    /// Returns the current zstd dictionary or the one of the given version.
+    pub fn get_zstd_dictionary(
+        &self,
+        version: Option<i32>,
+    ) -> Result<Option<ZstdDictionary>, DatabaseError> {
+        match version {
+            Some(version) if Some(version) != self.zstd_dictionary_version() => {
+                ZstdDictionary::load(&*self.database, version)
+            }
+            _ => Ok(self.zstd_dictionary.clone()),
+        }
+    }
+
+    pub fn zstd_dictionary_version(&self) -> Option<i32> {
+        self.zstd_dictionary
+            .as_ref()
+            .map(|dictionary| dictionary.version)
+    }
+
+    /// Returns the last modification of the course in milliseconds.
+    pub fn get_course2_last_modified(
+        &self,
+        course_id: ObjectId,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let filter = doc! {
+            "_id" => course_id
+        };
+        let projection = doc! {
+            "last_modified" => 1
+        };
+        Ok(self
+            .database
+            .find_courses2(filter, Some(projection))?
+            .into_iter()
+            .next()
+            .and_then(|course| course.get_i64("last_modified").ok()))
+    }
+
+    /// Returns the content hash and last modification in milliseconds of the course or of one of
+    /// its previous versions.
+    ///
+    /// Returns `None`, if the version doesn't exist or its content hash hasn't been stored yet.
+    pub fn get_course2_revision(
+        &self,
+        course_id: ObjectId,
+        version: Option<i32>,
+    ) -> Result<Option<(String, Option<i64>)>, DatabaseError> {
+        if let Some(version) = version {
+            let filter = doc! {
+                "course_id" => course_id.clone(),
+                "version" => version
+            };
+            let projection = doc! {
+                "content_hash" => 1,
+                "last_modified" => 1
+            };
+            if let Some(doc) = self
+                .database
+                .find_course2_history(filter, projection)?
+                .into_iter()
+                .next()
+            {
+                return Ok(doc.get_str("content_hash").ok().map(|content_hash| {
+                    (content_hash.to_string(), doc.get_i64("last_modified").ok())
+                }));
+            }
+        }
+        let filter = doc! {
+            "_id" => course_id.clone()
+        };
+        let projection = doc! {
+            "last_modified" => 1,
+            "version" => 1
+        };
+        let course = match self
+            .database
+            .find_courses2(filter.clone(), Some(projection))?
+            .into_iter()
+            .next()
+        {
+            Some(course) => course,
+            None => return Ok(None),
+        };
+        if version.map_or(false, |version| {
+            course.get_i32("version").unwrap_or(1) != version
+        }) {
+            return Ok(None);
+        }
+        let projection = doc! {
+            "content_hash" => 1
+        };
+        Ok(self
+            .database
+            .get_course2(filter, projection)?
+            .and_then(|doc| doc.get_str("content_hash").ok().map(str::to_string))
+            .map(|content_hash| (content_hash, course.get_i64("last_modified").ok())))
+    }
+
+    /// Returns the ids of all given courses or of all courses matching `query`, if no ids are
+    /// given, and counts them as downloaded.
+    ///
+    /// Fails with all missing ids, if any course could not be found.
+    pub fn get_courses2_download_ids(
+        &self,
+        ids: Option<Vec<String>>,
+        query: courses2::GetCourses2,
+        max_courses: usize,
+        visitor: String,
+    ) -> Result<Vec<ObjectId>, DownloadCourse2Error> {
+        let ids: Vec<ObjectId> = match ids {
+            Some(ids) => ids
+                .iter()
+                .map(|id| ObjectId::with_string(id))
+                .collect::<Result<_, _>>()?,
+            None => {
+                let mut pipeline = query.into_ordered_document(&*self.database, None)?;
+                pipeline.push(doc! {
+                    "$project" => {
+                        "_id" => 1
+                    }
+                });
+                self.database
+                    .get_courses2(pipeline)?
+                    .into_iter()
+                    .filter_map(|course| course.get_object_id("_id").ok().cloned())
+                    .collect()
+            }
+        };
+        if ids.len() > max_courses {
+            return Err(DownloadCourse2Error::TooManyCourses(max_courses));
+        }
+
+        let filter = doc! {
+            "_id" => {
+                "$in" => ids.iter().cloned().map(Bson::ObjectId).collect::<Vec<_>>()
+            }
+        };
+        let projection = doc! {
+            "_id" => 1
+        };
+        let found: Vec<ObjectId> = self
+            .database
+            .find_courses2(filter, Some(projection))?
+            .into_iter()
+            .filter_map(|course| course.get_object_id("_id").ok().cloned())
+            .collect();
+        let missing_ids: Vec<String> = ids
+            .iter()
+            .filter(|course_id| !found.contains(course_id))
+            .map(ObjectId::to_hex)
             .collect();
+        if !missing_ids.is_empty() {
+            return Err(DownloadCourse2Error::CoursesNotFound(missing_ids));
+        }
 
-        Ok(courses)
+        for course_id in ids.iter() {
+            if let Err(err) = self.count_course2_download(course_id.clone(), visitor.clone()) {
+                println!("Could not count download: {}", err);
+            }
+        }
+        Ok(ids)
     }
 
     pub fn get_course2(
         &self,
         course_id: ObjectId,
+    ) -> Result<(Vec<u8>, Vec<u8>), DownloadCourse2Error> {
+        let doc = doc! {
+            "_id" => course_id.clone()
+        };
+        let thumb: String = Size2::Encrypted.into();
+        let mut projection = ZstdDictionary::data_projection();
+        projection.insert(thumb.clone(), 1);
+        let course = self.database.get_course2(doc, projection)?;
+        if let Some(mut course) = course {
+            let data = self.take_course2_data_encrypted(&mut course)?;
+            let thumb = take_binary(&mut course, &thumb)?;
+            Ok((data, thumb))
+        } else {
+            Err(DownloadCourse2Error::CourseNotFound(course_id))
+        }
+    }
+
+    pub fn get_course2_br(
+        &self,
+        course_id: ObjectId,
     ) -> Result<(Vec<u8>, Vec<u8>), DownloadCourse2Error> {
         let doc = doc! {
             "_id" => course_id.clone()
@@ -151,19 +461,25 @@ impl Data {
         let thumb: String = Size2::Encrypted.into();
         let projection = doc! {
             thumb.clone() => 1,
-            "data_encrypted" => 1
+            "data_br" => 1,
         };
         let course = self.database.get_course2(doc, projection)?;
-        if let Some(course) = course {
-            let data = course.get_binary_generic(&"data_encrypted")?;
-            let thumb = course.get_binary_generic(&thumb)?;
-            Ok((data.clone(), thumb.clone()))
+        if let Some(mut course) = course {
+            let thumb = take_binary(&mut course, &thumb)?;
+            if let Ok(data) = take_binary(&mut course, "data_br") {
+                Ok((data, thumb))
+            } else {
+                let data = self.run_course2_job(&course_id, Derivative::DataBr, || {
+                    self.compute_course2_data_br(&course_id)
+                })?;
+                Ok((data, thumb))
+            }
         } else {
             Err(DownloadCourse2Error::CourseNotFound(course_id))
         }
     }
 
-    pub fn get_course2_br(
+    pub fn get_course2_proto(
         &self,
         course_id: ObjectId,
     ) -> Result<(Vec<u8>, Vec<u8>), DownloadCourse2Error> {
@@ -173,229 +489,901 @@ impl Data {
         let thumb: String = Size2::Encrypted.into();
         let projection = doc! {
             thumb.clone() => 1,
-            "data_br" => 1,
-            "data_encrypted" => 1,
+            "data_protobuf_br" => 1,
         };
         let course = self.database.get_course2(doc, projection)?;
-        if let Some(course) = course {
-            let thumb = course.get_binary_generic(&thumb)?;
-            if let Ok(data) = course.get_binary_generic(&"data_br") {
-                Ok((data.clone(), thumb.clone()))
+        if let Some(mut course) = course {
+            let thumb = take_binary(&mut course, &thumb)?;
+            if let Ok(data) = take_binary(&mut course, "data_protobuf_br") {
+                Ok((data, thumb))
             } else {
-                Ok((
-                    self.database
-                        .add_course2_data_br(course_id, course.clone())?,
-                    thumb.clone(),
-                ))
+                let data = self.run_course2_job(&course_id, Derivative::DataProtobufBr, || {
+                    self.compute_course2_data_proto(&course_id)
+                })?;
+                Ok((data, thumb))
             }
         } else {
             Err(DownloadCourse2Error::CourseNotFound(course_id))
         }
     }
 
-    pub fn get_course2_proto(
+    pub fn get_course2_zstd(
+        &self,
+        course_id: ObjectId,
+    ) -> Result<(Vec<u8>, Vec<u8>), DownloadCourse2Error> {
+        if self.zstd_dictionary.is_none() {
+            return Err(DownloadCourse2Error::DictionaryMissing);
+        }
+        let doc = doc! {
+            "_id" => course_id.clone()
+        };
+        let thumb: String = Size2::Encrypted.into();
+        let projection = doc! {
+            thumb.clone() => 1,
+            "data_zstd" => 1,
+        };
+        let course = self.database.get_course2(doc, projection)?;
+        if let Some(mut course) = course {
+            let thumb = take_binary(&mut course, &thumb)?;
+            if let Ok(data) = take_binary(&mut course, "data_zstd") {
+                Ok((data, thumb))
+            } else {
+                let data = self.run_course2_job(&course_id, Derivative::DataZstd, || {
+                    self.compute_course2_data_zstd(&course_id)
+                })?;
+                Ok((data, thumb))
+            }
+        } else {
+            Err(DownloadCourse2Error::CourseNotFound(course_id))
+        }
+    }
+
+    fn compute_course2_data_zstd(
+        &self,
+        course_id: &ObjectId,
+    ) -> Result<Vec<u8>, DownloadCourse2Error> {
+        let dictionary = self
+            .zstd_dictionary
+            .as_ref()
+            .ok_or(DownloadCourse2Error::DictionaryMissing)?;
+        let doc = doc! {
+            "_id" => course_id.clone()
+        };
+        match self
+            .database
+            .get_course2(doc, ZstdDictionary::data_projection())?
+        {
+            Some(mut course) => {
+                let data = self.take_course2_data_encrypted(&mut course)?;
+                Ok(dictionary.store_course(&*self.database, course_id.clone(), data)?)
+            }
+            None => Err(DownloadCourse2Error::CourseNotFound(course_id.clone())),
+        }
+    }
+
+    /// Takes the encrypted course data out of a course data document, which has been loaded
+    /// with `ZstdDictionary::data_projection`.
+    fn take_course2_data_encrypted(
+        &self,
+        course: &mut OrderedDocument,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        ZstdDictionary::take_data_encrypted(self.zstd_dictionary.as_ref(), &*self.database, course)
+    }
+
+    fn compute_course2_data_br(
+        &self,
+        course_id: &ObjectId,
+    ) -> Result<Vec<u8>, DownloadCourse2Error> {
+        let doc = doc! {
+            "_id" => course_id.clone()
+        };
+        match self
+            .database
+            .get_course2(doc, ZstdDictionary::data_projection())?
+        {
+            Some(mut course) => {
+                let data = self.take_course2_data_encrypted(&mut course)?;
+                let course = doc! {
+                    "data_encrypted" => Bson::Binary(BinarySubtype::Generic, data)
+                };
+                Ok(self
+                    .database
+                    .add_course2_data_br(course_id.clone(), course)?)
+            }
+            None => Err(DownloadCourse2Error::CourseNotFound(course_id.clone())),
+        }
+    }
+
+    fn compute_course2_data_proto(
+        &self,
+        course_id: &ObjectId,
+    ) -> Result<Vec<u8>, DownloadCourse2Error> {
+        let doc = doc! {
+            "_id" => course_id.clone()
+        };
+        match self
+            .database
+            .get_course2(doc, ZstdDictionary::data_projection())?
+        {
+            Some(mut course) => {
+                let data = self.take_course2_data_encrypted(&mut course)?;
+                let course = doc! {
+                    "data_encrypted" => Bson::Binary(BinarySubtype::Generic, data)
+                };
+                Ok(self
+                    .database
+                    .add_course2_data_protobuf_br(course_id.clone(), course)?)
+            }
+            None => Err(DownloadCourse2Error::CourseNotFound(course_id.clone())),
+        }
+    }
+
+    /// Returns the thumbnail in the first of the given formats, which is available.
+    ///
+    /// Slow formats are skipped, until they have been precomputed, unless they are the last
+    /// given format. Their computation is enqueued instead.
+    pub fn get_course2_thumbnail(
+        &self,
+        course_id: ObjectId,
+        query: GetThumbnail2,
+        formats: &[ThumbnailFormat],
+    ) -> Result<(Vec<u8>, ThumbnailFormat), GetCourse2ThumbnailError> {
+        let (format, fallbacks) = match formats.split_last() {
+            Some((format, fallbacks)) => (*format, fallbacks),
+            None => (ThumbnailFormat::Jpeg, &[][..]),
+        };
+        for fallback in fallbacks {
+            if !fallback.is_slow() {
+                let thumb = self.get_course2_thumbnail_in(course_id, query, *fallback)?;
+                return Ok((thumb, *fallback));
+            }
+            let derivative = match query.width_bucket() {
+                None => Derivative::thumbnail(&query.size, *fallback),
+                Some(_) => None,
+            };
+            if let Some(derivative) = derivative {
+                if let Some(thumb) = self.read_course2_derivative(&course_id, derivative)? {
+                    return Ok((thumb, *fallback));
+                }
+                self.enqueue_course2_job(&course_id, derivative)?;
+            }
+        }
+        let thumb = self.get_course2_thumbnail_in(course_id, query, format)?;
+        Ok((thumb, format))
+    }
+
+    fn get_course2_thumbnail_in(
+        &self,
+        course_id: ObjectId,
+        query: GetThumbnail2,
+        format: ThumbnailFormat,
+    ) -> Result<Vec<u8>, GetCourse2ThumbnailError> {
+        if query.size == Size2::Encrypted && format != ThumbnailFormat::Jpeg {
+            return Err(GetCourse2ThumbnailError::UnsupportedFormat);
+        }
+        if let Some(width) = query.width_bucket() {
+            return self.get_course2_thumbnail_resized(course_id, width, format);
+        }
+        let doc = doc! {
+            "_id" => course_id.clone()
+        };
+        let field = format.field(query.size.clone());
+        let projection = doc! {
+            field.clone() => 1
+        };
+        let thumb = self.database.get_course2(doc, projection)?;
+        if let Some(mut thumb) = thumb {
+            match take_binary(&mut thumb, &field) {
+                Ok(thumb) => Ok(thumb),
+                Err(_) => {
+                    if query.size == Size2::Original && format == ThumbnailFormat::Jpeg {
+                        Err(GetCourse2ThumbnailError::CourseNotFound(course_id))
+                    } else if let Some(derivative) = Derivative::thumbnail(&query.size, format) {
+                        self.run_course2_job(&course_id, derivative, || {
+                            self.compute_course2_thumbnail(&course_id, query.size.clone(), format)
+                        })
+                    } else {
+                        self.compute_course2_thumbnail(&course_id, query.size, format)
+                    }
+                }
+            }
+        } else {
+            Err(GetCourse2ThumbnailError::CourseNotFound(course_id))
+        }
+    }
+
+    fn compute_course2_thumbnail(
+        &self,
+        course_id: &ObjectId,
+        size: Size2,
+        format: ThumbnailFormat,
+    ) -> Result<Vec<u8>, GetCourse2ThumbnailError> {
+        let doc = doc! {
+            "_id" => course_id.clone()
+        };
+        let size_original: String = Size2::Original.into();
+        let projection = doc! {
+            size_original.clone() => 1
+        };
+        let mut thumb = self
+            .database
+            .get_course2(doc, projection)?
+            .ok_or_else(|| GetCourse2ThumbnailError::CourseNotFound(course_id.clone()))?;
+        let thumb = take_binary(&mut thumb, &size_original).unwrap_or_else(|_| {
+            panic!(
+                "mongodb corrupted. thumbnail missing for course {}",
+                course_id
+            )
+        });
+
+        let mut image = load_from_memory(&thumb[..])?;
+        if size != Size2::Original {
+            let (nwidth, nheight) = size.get_dimensions();
+            image = image.resize_exact(nwidth, nheight, FilterType::Gaussian);
+        }
+        let res = format.encode(&image)?;
+        self.database.update_course2_thumbnail(
+            course_id.clone(),
+            format.field(size),
+            res.clone(),
+        )?;
+        Ok(res)
+    }
+
+    /// Returns the thumbnail resized to `width` from the thumbnail cache, which unlike the fixed
+    /// sizes isn't persisted.
+    fn get_course2_thumbnail_resized(
+        &self,
+        course_id: ObjectId,
+        width: u32,
+        format: ThumbnailFormat,
+    ) -> Result<Vec<u8>, GetCourse2ThumbnailError> {
+        let key = ThumbnailKey {
+            course_id: course_id.to_hex(),
+            width,
+            format,
+        };
+        if let Some(thumb) = self.thumbnail_cache.get(&key) {
+            return Ok(thumb);
+        }
+
+        let doc = doc! {
+            "_id" => course_id.clone()
+        };
+        let size_original: String = Size2::Original.into();
+        let projection = doc! {
+            size_original.clone() => 1
+        };
+        let thumb = match self.database.get_course2(doc, projection)? {
+            Some(mut thumb) => take_binary(&mut thumb, &size_original)
+                .map_err(|_| GetCourse2ThumbnailError::CourseNotFound(course_id))?,
+            None => return Err(GetCourse2ThumbnailError::CourseNotFound(course_id)),
+        };
+        let image = load_from_memory(&thumb[..])?;
+        let height = ((width as u64 * image.height() as u64 + image.width() as u64 / 2)
+            / image.width().max(1) as u64)
+            .max(1) as u32;
+        let image = image.resize_exact(width, height, FilterType::Gaussian);
+        let res = format.encode(&image)?;
+        self.thumbnail_cache.insert(key, res.clone());
+        Ok(res)
+    }
+
+    pub fn put_courses2(
+        &self,
+        mut courses: Vec<smmdb_lib::Course2>,
+        account: &Account,
+        difficulty: Option<Difficulty>,
+    ) -> Result<PutCourses2Response, courses2::PutCourses2Error> {
+        let prepared: Vec<_> = courses
+            .par_iter_mut()
+            .map(|smm_course| -> Result<_, courses2::PutCourses2Error> {
+                let course = Course2::insert(
+                    account.get_id().clone(),
+                    smm_course,
+                    difficulty.clone(),
+                    &self.perm_gen,
+                );
+                let course_meta = serde_json::to_value(&course)?;
+                let (thumb, thumb_encrypted) = course2_thumbnails(smm_course)
+                    .ok_or(courses2::PutCourses2Error::ThumbnailMissing)?;
+
+                Ok((course, course_meta, thumb, thumb_encrypted))
+            })
+            .collect();
+
+        let mut response = PutCourses2Response::new();
+        let mut succeeded = vec![];
+        for (smm_course, prepared) in courses.iter_mut().zip(prepared) {
+            match prepared.and_then(|prepared| self.put_course2(smm_course, account, prepared)) {
+                Ok(course) => succeeded.push(course),
+                Err(err) => response.add_failed(err),
+            }
+        }
+        response.set_succeeded(succeeded);
+        Ok(response)
+    }
+
+    /// Rejects courses too similar to existing ones before inserting them.
+    ///
+    /// The `LshIndex` is only locked to query and update it, so that database calls of concurrent
+    /// uploads don't have to wait for each other.
+    fn put_course2(
+        &self,
+        smm_course: &mut smmdb_lib::Course2,
+        account: &Account,
+        (mut course, course_meta, thumb, thumb_encrypted): (Course2, serde_json::Value, Bson, Bson),
+    ) -> Result<Course2Response, courses2::PutCourses2Error> {
+        let mut doc_meta = match Bson::from(course_meta) {
+            Bson::Document(doc_meta) => doc_meta,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "".to_string()).into()),
+        };
+        // denormalized for the text index
+        doc_meta.insert("uploader", account.get_username().clone());
+
+        if let Some(err) = self.find_too_similar_course2(course.get_hash(), None)? {
+            return Err(courses2::PutCourses2Error::Similarity(err));
+        }
+
+        let inserted_id =
+            self.database
+                .put_course2(doc_meta, smm_course, thumb, thumb_encrypted)?;
+        if let Err(err) = self.enqueue_course2_jobs(&inserted_id) {
+            println!("Enqueueing jobs of course {} failed: {}", inserted_id, err);
+        }
+        course.set_id(inserted_id);
+        self.similarity_index.insert(
+            &*self.database,
+            course.get_id().to_hex(),
+            course.get_hash(),
+            course.get_last_modified(),
+        );
+        Ok(Course2Response::from_course(
+            course,
+            account,
+            None,
+            &*self.database,
+        ))
+    }
+
+    /// Returns the first course, which is too similar to the given hash to be uploaded.
+    fn find_too_similar_course2(
+        &self,
+        hash: &MinHash,
+        exclude: Option<&ObjectId>,
+    ) -> Result<Option<Course2SimilarityError>, DatabaseError> {
+        let similar_courses = self.find_candidate_courses2(hash)?;
+        for similar_course in similar_courses {
+            if Some(similar_course.get_id()) == exclude {
+                continue;
+            }
+            let jaccard = hash.jaccard(similar_course.get_hash());
+            if jaccard > self.similarity_threshold {
+                return Ok(Some(Course2SimilarityError::new(
+                    similar_course.get_id().to_hex(),
+                    similar_course
+                        .get_course()
+                        .get_header()
+                        .get_title()
+                        .to_string(),
+                    jaccard,
+                )));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Replaces the course data of an existing course and keeps the replaced data as previous
+    /// version.
+    ///
+    /// The course is only checked for similarity with other courses. The new version is claimed
+    /// before any data is written, so that concurrent re-uploads of the same course are rejected
+    /// without touching the data of the winning one.
+    pub fn put_course2_data(
+        &self,
+        course_id: ObjectId,
+        mut smm_course: smmdb_lib::Course2,
+        account: &Account,
+    ) -> Result<Course2Response, PutCourse2DataError> {
+        let mut course = self
+            .find_courses2(doc! {
+                "_id" => course_id.clone()
+            })?
+            .into_iter()
+            .next()
+            .ok_or_else(|| PutCourse2DataError::CourseNotFound(course_id.clone()))?;
+        if course.get_owner() != account.get_id() {
+            return Err(PutCourse2DataError::Unauthorized);
+        }
+        let previous_version = course.get_version();
+        let previous_last_modified = course.get_last_modified();
+        course.reupload(&smm_course, &self.perm_gen);
+
+        if let Some(err) = self.find_too_similar_course2(course.get_hash(), Some(&course_id))? {
+            return Err(PutCourse2DataError::Similarity(err));
+        }
+        let (thumb, thumb_encrypted) =
+            course2_thumbnails(&mut smm_course).ok_or(PutCourse2DataError::ThumbnailMissing)?;
+
+        let filter = doc! {
+            "_id" => course_id.clone()
+        };
+        let thumb_field: String = Size2::Original.into();
+        let thumb_encrypted_field: String = Size2::Encrypted.into();
+        let mut projection = ZstdDictionary::data_projection();
+        projection.insert(thumb_field, 1);
+        projection.insert(thumb_encrypted_field, 1);
+        projection.insert("content_hash", 1);
+        let mut previous = self
+            .database
+            .get_course2(filter.clone(), projection)?
+            .ok_or_else(|| PutCourse2DataError::CourseNotFound(course_id.clone()))?;
+
+        self.claim_course2_version(
+            &course_id,
+            previous_last_modified,
+            course.get_last_modified(),
+            course.get_version(),
+        )?;
+
+        previous.remove("_id");
+        previous.insert("course_id", course_id.clone());
+        previous.insert("version", previous_version);
+        previous.insert("last_modified", previous_last_modified);
+        previous.insert("replaced", course.get_last_modified());
+        self.database.insert_course2_history(previous)?;
+        self.database.replace_course2_data(
+            course_id.clone(),
+            &mut smm_course,
+            thumb,
+            thumb_encrypted,
+            &course2_derived_fields(),
+        )?;
+
+        let mut set = doc! {};
+        if let Bson::Document(mut doc_meta) = Bson::from(serde_json::to_value(&course)?) {
+            for field in ["course", "hash"].iter() {
+                if let Some(value) = doc_meta.remove(field) {
+                    set.insert(*field, value);
+                }
+            }
+        }
+        let filter = doc! {
+            "_id" => course_id.clone()
+        };
+        let update = doc! {
+            "$set" => set
+        };
+        self.database.update_course2(filter, update)?;
+
+        if let Err(err) = self.enqueue_course2_jobs(&course_id) {
+            println!("Enqueueing jobs of course {} failed: {}", course_id, err);
+        }
+        self.thumbnail_cache.remove_course(&course_id.to_hex());
+        self.similarity_index.insert(
+            &*self.database,
+            course_id.to_hex(),
+            course.get_hash(),
+            course.get_last_modified(),
+        );
+        Ok(Course2Response::from_course(
+            course,
+            account,
+            None,
+            &*self.database,
+        ))
+    }
+
+    /// Bumps `last_modified` and `version` of the course, unless it has been modified since
+    /// `previous_last_modified`.
+    fn claim_course2_version(
+        &self,
+        course_id: &ObjectId,
+        previous_last_modified: i64,
+        last_modified: i64,
+        version: i32,
+    ) -> Result<(), PutCourse2DataError> {
+        let filter = doc! {
+            "_id" => course_id.clone(),
+            "last_modified" => previous_last_modified
+        };
+        let update = doc! {
+            "$set" => {
+                "last_modified" => last_modified,
+                "version" => version,
+            }
+        };
+        if self.database.update_course2(filter, update)? == 0 {
+            return Err(PutCourse2DataError::Conflict);
+        }
+        Ok(())
+    }
+
+    /// Enqueues jobs precomputing all derivatives of the course.
+    ///
+    /// Existing jobs of the course are reset.
+    fn enqueue_course2_jobs(&self, course_id: &ObjectId) -> Result<(), DatabaseError> {
+        let now = now_millis();
+        for derivative in Derivative::ALL.iter() {
+            if *derivative == Derivative::DataZstd && self.zstd_dictionary.is_none() {
+                continue;
+            }
+            let filter = doc! {
+                "_id" => derivative.job_id(course_id)
+            };
+            let update = doc! {
+                "$set" => {
+                    "course_id" => course_id.clone(),
+                    "derivative" => derivative.field(),
+                    "status" => JobStatus::Pending.as_str(),
+                    "attempts" => 0,
+                    "run_after" => now,
+                    "updated" => now,
+                },
+                "$unset" => {
+                    "error" => ""
+                },
+                "$setOnInsert" => {
+                    "created" => now
+                }
+            };
+            self.database.upsert_job(filter, update)?;
+        }
+        Ok(())
+    }
+
+    /// Claims and runs the next due job. Returns whether there was one.
+    pub fn run_next_job(&self) -> Result<bool, DatabaseError> {
+        let job = match self.claim_job(due_job_filter(now_millis()))? {
+            Some(job) => job,
+            None => return Ok(false),
+        };
+        let derivative = job
+            .get_str("derivative")
+            .ok()
+            .and_then(Derivative::from_field);
+        let res = match (job.get_object_id("course_id"), derivative) {
+            (Ok(course_id), Some(derivative)) => {
+                self.compute_course2_derivative(course_id, derivative)
+            }
+            _ => Err("invalid job".to_string()),
+        };
+        self.finish_job(&job, res)?;
+        Ok(true)
+    }
+
+    fn compute_course2_derivative(
+        &self,
+        course_id: &ObjectId,
+        derivative: Derivative,
+    ) -> Result<(), String> {
+        if self
+            .read_course2_derivative(course_id, derivative)
+            .map_err(|err| err.to_string())?
+            .is_some()
+        {
+            return Ok(());
+        }
+        if let Some((size, format)) = derivative.get_thumbnail() {
+            self.compute_course2_thumbnail(course_id, size, format)
+                .map_err(|err| err.to_string())?;
+            return Ok(());
+        }
+        match derivative {
+            Derivative::DataBr => self
+                .compute_course2_data_br(course_id)
+                .map_err(|err| err.to_string())?,
+            Derivative::DataProtobufBr => self
+                .compute_course2_data_proto(course_id)
+                .map_err(|err| err.to_string())?,
+            Derivative::DataZstd => self
+                .compute_course2_data_zstd(course_id)
+                .map_err(|err| err.to_string())?,
+            _ => return Err("invalid derivative".to_string()),
+        };
+        Ok(())
+    }
+
+    fn read_course2_derivative(
         &self,
-        course_id: ObjectId,
-    ) -> Result<(Vec<u8>, Vec<u8>), DownloadCourse2Error> {
+        course_id: &ObjectId,
+        derivative: Derivative,
+    ) -> Result<Option<Vec<u8>>, DatabaseError> {
         let doc = doc! {
             "_id" => course_id.clone()
         };
-        let thumb: String = Size2::Encrypted.into();
         let projection = doc! {
-            thumb.clone() => 1,
-            "data_protobuf_br" => 1
+            derivative.field() => 1
         };
-        let course = self.database.get_course2(doc, projection)?;
-        if let Some(course) = course {
-            let thumb = course.get_binary_generic(&thumb)?;
-            if let Ok(data) = course.get_binary_generic(&"data_protobuf_br") {
-                Ok((data.clone(), thumb.clone()))
-            } else {
-                Ok((
-                    self.database
-                        .add_course2_data_protobuf_br(course_id, course.clone())?,
-                    thumb.clone(),
-                ))
-            }
-        } else {
-            Err(DownloadCourse2Error::CourseNotFound(course_id))
+        Ok(self
+            .database
+            .get_course2(doc, projection)?
+            .and_then(|mut course| take_binary(&mut course, derivative.field()).ok()))
+    }
+
+    /// Computes a derivative, which is missing in a request, within its job.
+    ///
+    /// If the job is already running elsewhere, `JobPending` is returned instead of waiting for
+    /// its result, so that the request can be retried once the job has finished.
+    fn run_course2_job<E>(
+        &self,
+        course_id: &ObjectId,
+        derivative: Derivative,
+        compute: impl Fn() -> Result<Vec<u8>, E>,
+    ) -> Result<Vec<u8>, E>
+    where
+        E: From<DatabaseError> + From<JobPending> + Display,
+    {
+        let job_id = derivative.job_id(course_id);
+        self.enqueue_course2_job(course_id, derivative)?;
+
+        let filter = doc! {
+            "_id" => job_id.clone(),
+            "$or" => vec![
+                Bson::Document(doc! {
+                    "status" => {
+                        "$ne" => JobStatus::Running.as_str()
+                    }
+                }),
+                Bson::Document(stale_job_filter(now_millis())),
+            ]
+        };
+        if let Some(job) = self.claim_job(filter)? {
+            let res = match self.read_course2_derivative(course_id, derivative)? {
+                Some(data) => Ok(data),
+                None => compute(),
+            };
+            self.finish_job(
+                &job,
+                res.as_ref().map(|_| ()).map_err(|err| err.to_string()),
+            )?;
+            return res;
+        }
+        if let Some(data) = self.read_course2_derivative(course_id, derivative)? {
+            return Ok(data);
+        }
+        let filter = doc! {
+            "_id" => job_id
+        };
+        if self.database.count_jobs(filter)? == 0 {
+            return compute();
         }
+        Err(JobPending.into())
     }
 
-    pub fn get_course2_thumbnail(
+    /// Enqueues the job computing the derivative, unless it already exists.
+    fn enqueue_course2_job(
         &self,
-        course_id: ObjectId,
-        query: GetThumbnail2,
-    ) -> Result<Vec<u8>, GetCourse2ThumbnailError> {
-        let doc = doc! {
-            "_id" => course_id.clone()
+        course_id: &ObjectId,
+        derivative: Derivative,
+    ) -> Result<(), DatabaseError> {
+        let now = now_millis();
+        let filter = doc! {
+            "_id" => derivative.job_id(course_id)
         };
-        let size: String = query.size.clone().into();
-        let projection = doc! {
-            size.clone() => 1
+        let update = doc! {
+            "$setOnInsert" => {
+                "course_id" => course_id.clone(),
+                "derivative" => derivative.field(),
+                "status" => JobStatus::Pending.as_str(),
+                "attempts" => 0,
+                "run_after" => now,
+                "created" => now,
+                "updated" => now,
+            }
         };
-        let thumb = self.database.get_course2(doc, projection)?;
-        if let Some(thumb) = thumb {
-            match thumb.get_binary_generic(&size) {
-                Ok(thumb) => Ok(thumb.clone()),
-                Err(_) => {
-                    if query.size == Size2::Original {
-                        Err(GetCourse2ThumbnailError::CourseNotFound(course_id))
-                    } else {
-                        let doc = doc! {
-                            "_id" => course_id.clone()
-                        };
-                        let size_original: String = Size2::Original.into();
-                        let projection = doc! {
-                            size_original.clone() => 1
-                        };
-                        let thumb = self.database.get_course2(doc, projection)?.unwrap();
-                        let thumb = thumb
-                            .get_binary_generic(&size_original)
-                            .unwrap_or_else(|_| {
-                                panic!(
-                                    "mongodb corrupted. thumbnail missing for course {}",
-                                    course_id
-                                )
-                            })
-                            .clone();
-
-                        let image = load_from_memory(&thumb[..])?;
-                        let (nwidth, nheight) = query.size.get_dimensions();
-                        let image = image.resize_exact(nwidth, nheight, FilterType::Gaussian);
-                        let color = image.color();
-
-                        match image {
-                            DynamicImage::ImageRgb8(buffer) => {
-                                let (width, height) = buffer.dimensions();
-                                let mut res = vec![];
-                                let mut encoder = JpegEncoder::new_with_quality(&mut res, 85);
-                                encoder
-                                    .encode(&buffer.into_raw()[..], width, height, color)
-                                    .map_err(ImageError::from)?;
-                                self.database.update_course2_thumbnail(
-                                    course_id,
-                                    size,
-                                    res.clone(),
-                                )?;
-                                Ok(res)
-                            }
-                            _ => Err(ImageError::Unsupported(
-                                UnsupportedError::from_format_and_kind(
-                                    ImageFormatHint::Unknown,
-                                    UnsupportedErrorKind::GenericFeature(
-                                        "expected image rgb8".to_string(),
-                                    ),
-                                ),
-                            )
-                            .into()),
+        self.database.upsert_job(filter, update)
+    }
+
+    fn claim_job(&self, filter: OrderedDocument) -> Result<Option<OrderedDocument>, DatabaseError> {
+        let sort = doc! {
+            "run_after" => 1
+        };
+        let update = doc! {
+            "$set" => {
+                "status" => JobStatus::Running.as_str(),
+                "updated" => now_millis(),
+            },
+            "$inc" => {
+                "attempts" => 1
+            }
+        };
+        self.database.claim_job(filter, sort, update)
+    }
+
+    /// Deletes a successful job or schedules a retry of a failed job until it ran out of attempts.
+    ///
+    /// Nothing happens, if the job has been claimed again in the meantime.
+    fn finish_job(
+        &self,
+        job: &OrderedDocument,
+        res: Result<(), String>,
+    ) -> Result<(), DatabaseError> {
+        let job_id = job.get_str("_id").unwrap_or_default();
+        let attempts = job.get_i32("attempts").unwrap_or_default();
+        let filter = doc! {
+            "_id" => job_id,
+            "status" => JobStatus::Running.as_str(),
+            "attempts" => attempts,
+        };
+        match res {
+            Ok(()) => {
+                self.database.delete_jobs(filter)?;
+            }
+            Err(err) => {
+                println!("Job {} failed: {}", job_id, err);
+                let now = now_millis();
+                let update = if attempts < self.job_max_attempts {
+                    doc! {
+                        "$set" => {
+                            "status" => JobStatus::Pending.as_str(),
+                            "run_after" => now + job_retry_backoff(attempts),
+                            "updated" => now,
+                            "error" => err,
                         }
                     }
-                }
+                } else {
+                    doc! {
+                        "$set" => {
+                            "status" => JobStatus::Failed.as_str(),
+                            "updated" => now,
+                            "error" => err,
+                        }
+                    }
+                };
+                self.database.update_job(filter, update)?;
             }
-        } else {
-            Err(GetCourse2ThumbnailError::CourseNotFound(course_id))
         }
+        Ok(())
     }
 
-    pub fn put_courses2(
+    /// Returns the most recently updated jobs and the number of jobs per status.
+    pub fn get_jobs(
         &self,
-        mut courses: Vec<smmdb_lib::Course2>,
-        account: &Account,
-        difficulty: Option<Difficulty>,
-    ) -> Result<PutCourses2Response, courses2::PutCourses2Error> {
-        let lsh_index = self.lsh_index.clone();
-        let response = Arc::new(Mutex::new(PutCourses2Response::new()));
-        let succeeded: Vec<_> = courses
-            .par_iter_mut()
-            .map(
-                |smm_course| -> Result<Course2Response, courses2::PutCourses2Error> {
-                    let mut course = Course2::insert(
-                        account.get_id().clone(),
-                        smm_course,
-                        difficulty.clone(),
-                        &self.perm_gen,
-                    );
-                    let course_meta = serde_json::to_value(&course)?;
-
-                    let course_thumb = smm_course
-                        .get_course_thumb_mut()
-                        .ok_or(courses2::PutCourses2Error::ThumbnailMissing)?;
-                    let mut thumb_data = course_thumb.get_jpeg().to_vec();
-                    smmdb_lib::Thumbnail2::encrypt(&mut thumb_data);
-                    let thumb_encrypted = Bson::Binary(BinarySubtype::Generic, thumb_data);
-                    let thumb =
-                        Bson::Binary(BinarySubtype::Generic, course_thumb.get_jpeg().to_vec());
-
-                    if let Bson::Document(doc_meta) = Bson::from(course_meta) {
-                        let mut lsh_index = lsh_index.lock().unwrap();
-                        let query: Vec<Bson> = lsh_index
-                            .query(course.get_hash())
-                            .into_iter()
-                            .map(|id| -> Bson { ObjectId::with_string(&id).unwrap().into() })
-                            .collect();
-                        let query = doc! {
-                            "_id" => {
-                                "$in" => query
-                            }
-                        };
-                        let similar_courses = self.find_courses2(query)?;
-                        for similar_course in similar_courses {
-                            let jaccard = course.get_hash().jaccard(similar_course.get_hash());
-                            if jaccard > SIMILARITY_THRESHOLD {
-                                return Err(courses2::PutCourses2Error::Similarity(
-                                    Course2SimilarityError::new(
-                                        similar_course.get_id().to_hex(),
-                                        similar_course
-                                            .get_course()
-                                            .get_header()
-                                            .get_title()
-                                            .to_string(),
-                                        jaccard,
-                                    ),
-                                ));
-                            }
-                        }
+        status: Option<JobStatus>,
+        course_id: Option<ObjectId>,
+        limit: usize,
+    ) -> Result<Jobs, DatabaseError> {
+        let mut counts = JobCounts::default();
+        for job_status in JobStatus::ALL.iter() {
+            let filter = doc! {
+                "status" => job_status.as_str()
+            };
+            counts.set(*job_status, self.database.count_jobs(filter)?);
+        }
+        let mut filter = doc! {};
+        if let Some(status) = status {
+            filter.insert("status", status.as_str());
+        }
+        if let Some(course_id) = course_id {
+            filter.insert("course_id", course_id);
+        }
+        let sort = doc! {
+            "updated" => -1
+        };
+        let jobs = self.database.find_jobs(filter, sort, Some(limit as i64))?;
+        Ok(Jobs::new(counts, jobs))
+    }
 
-                        let inserted_id = self.database.put_course2(
-                            doc_meta,
-                            smm_course,
-                            thumb,
-                            thumb_encrypted,
-                        )?;
-                        course.set_id(inserted_id);
-                        lsh_index.insert(course.get_id().to_hex(), course.get_hash());
-                        let course =
-                            Course2Response::from_course(course, account, None, &*self.database);
-                        Ok(course)
-                    } else {
-                        Err(io::Error::new(io::ErrorKind::Other, "".to_string()).into())
-                    }
-                },
-            )
-            .filter_map(|course| {
-                if let Err(err) = course {
-                    response.lock().unwrap().add_failed(err);
-                    None
-                } else {
-                    Result::ok(course)
-                }
+    pub fn get_similar_courses2(
+        &self,
+        course_id: ObjectId,
+        query: GetSimilarCourses2,
+        own_account: Option<Account>,
+    ) -> Result<Vec<SimilarCourse2>, SimilarCourses2Error> {
+        let course = self
+            .find_courses2(doc! {
+                "_id" => course_id.clone()
+            })?
+            .into_iter()
+            .next()
+            .ok_or(SimilarCourses2Error::CourseNotFound(course_id))?;
+        self.rank_similar_courses2(
+            course.get_hash(),
+            Some(course.get_id()),
+            &query,
+            own_account.as_ref(),
+        )
+    }
+
+    pub fn find_similar_courses2(
+        &self,
+        courses: Vec<smmdb_lib::Course2>,
+        query: GetSimilarCourses2,
+        own_account: Option<Account>,
+    ) -> Result<Vec<Vec<SimilarCourse2>>, SimilarCourses2Error> {
+        courses
+            .iter()
+            .map(|course| {
+                let mut hash = MinHash::new(&self.perm_gen);
+                hash.update(&self.perm_gen, course.get_course_data());
+                self.rank_similar_courses2(&hash, None, &query, own_account.as_ref())
             })
+            .collect()
+    }
+
+    fn rank_similar_courses2(
+        &self,
+        hash: &MinHash,
+        exclude: Option<&ObjectId>,
+        query: &GetSimilarCourses2,
+        own_account: Option<&Account>,
+    ) -> Result<Vec<SimilarCourse2>, SimilarCourses2Error> {
+        let min_score = query.get_min_score().unwrap_or(self.similarity_min_score);
+        let mut courses: Vec<(f64, Course2)> = self
+            .find_candidate_courses2(hash)?
+            .into_iter()
+            .filter(|course| Some(course.get_id()) != exclude && !course.is_unlisted())
+            .map(|course| (hash.jaccard(course.get_hash()), course))
+            .filter(|(jaccard, _)| *jaccard >= min_score)
             .collect();
-        let mut response = Arc::try_unwrap(response).unwrap().into_inner().unwrap();
-        response.set_succeeded(succeeded);
-        Ok(response)
+        courses.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+        courses.truncate(query.get_limit());
+
+        let account_ids: Vec<Bson> = courses
+            .iter()
+            .map(|(_, course)| course.get_owner().clone().into())
+            .collect();
+        let accounts = self.get_accounts(account_ids);
+
+        let courses = courses
+            .into_iter()
+            .filter_map(|(jaccard, course)| {
+                let account = accounts
+                    .iter()
+                    .find(|account| account.get_id() == course.get_owner())?;
+                Some(SimilarCourse2::new(
+                    jaccard,
+                    Course2Response::from_course(course, account, own_account, &*self.database),
+                ))
+            })
+            .collect();
+        Ok(courses)
+    }
+
+    /// Returns all courses sharing at least one band with the given hash in the `LshIndex`.
+    ///
+    /// Courses which no longer exist are removed from the index.
+    fn find_candidate_courses2(&self, hash: &MinHash) -> Result<Vec<Course2>, DatabaseError> {
+        let keys = self.similarity_index.query(&*self.database, hash)?;
+        let ids: Vec<Bson> = keys
+            .iter()
+            .filter_map(|id| ObjectId::with_string(id).ok())
+            .map(Bson::from)
+            .collect();
+        let courses = self.find_courses2(doc! {
+            "_id" => {
+                "$in" => ids
+            }
+        })?;
+        for key in keys.iter().filter(|key| {
+            !courses
+                .iter()
+                .any(|course| &course.get_id().to_hex() == *key)
+        }) {
+            self.similarity_index.remove(&*self.database, key);
+        }
+        Ok(courses)
     }
 
     pub fn delete_course2(
         &self,
         course_id: String,
         course_oid: ObjectId,
-    ) -> Result<(), mongodb::Error> {
+    ) -> Result<(), DatabaseError> {
         let query = doc! {
-            "_id" => course_oid
+            "_id" => course_oid.clone()
         };
-        self.database.delete_course2(course_id, query)
+        self.database.delete_course2(course_id.clone(), query)?;
+        self.database.delete_jobs(doc! {
+            "course_id" => course_oid.clone()
+        })?;
+        self.database.delete_course2_history(doc! {
+            "course_id" => course_oid
+        })?;
+        self.similarity_index.remove(&*self.database, &course_id);
+        self.thumbnail_cache.remove_course(&course_id);
+        Ok(())
     }
 
     pub fn vote_course2(
@@ -403,7 +1391,7 @@ impl Data {
         account_id: ObjectId,
         course_id: ObjectId,
         value: i32,
-    ) -> Result<(), mongodb::Error> {
+    ) -> Result<(), DatabaseError> {
         let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
@@ -434,18 +1422,11 @@ impl Data {
             "value" => 1,
             "timestamp" => 1,
         };
-        let votes: Result<Vec<Vote>, mongodb::Error> = self
+        let votes: Result<Vec<Vote>, serde_json::Error> = self
             .database
             .get_votes_course2(filter, projection)?
-            .map(|item| {
-                item.map(|item| -> Result<Vote, serde_json::Error> { item.try_into() })
-                    .map_err(|err| {
-                        mongodb::Error::ResponseError(format!("get_votes_course2 failed: {}", err))
-                    })?
-                    .map_err(|err| {
-                        mongodb::Error::ResponseError(format!("get_votes_course2 failed: {}", err))
-                    })
-            })
+            .into_iter()
+            .map(|item| item.try_into())
             .collect();
         let vote_value: i32 = votes?.iter().fold(0, |acc, vote| acc + vote.get_value());
         let filter = doc! {
@@ -460,33 +1441,117 @@ impl Data {
         Ok(())
     }
 
+    /// Counts a download of the course, unless the visitor already downloaded it within the
+    /// dedup window.
+    ///
+    /// The trending score is the logarithm of the sum of all downloads, each weighted by
+    /// `2^(downloaded / half_life)`. Scaling every weight by the same factor doesn't change the
+    /// order, so it can be updated incrementally and still ranks courses by exponentially decayed
+    /// downloads.
+    pub fn count_course2_download(
+        &self,
+        course_id: ObjectId,
+        visitor: String,
+    ) -> Result<(), DatabaseError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let filter = doc! {
+            "course_id" => course_id.clone(),
+            "visitor" => visitor.clone(),
+        };
+        if let Some(download) = self.database.find_download_course2(filter.clone())? {
+            let downloaded = download.get_i64("downloaded").unwrap_or_default();
+            if now - downloaded < self.download_dedup_window {
+                return Ok(());
+            }
+        }
+        let update = doc! {
+            "$set" => {
+                "course_id" => course_id.clone(),
+                "visitor" => visitor,
+                "downloaded" => now,
+            }
+        };
+        self.database.upsert_download_course2(filter, update)?;
+
+        // the log-sum-exp of the trending score can't be expressed as an atomic update, so it is
+        // only written, if the score hasn't been changed concurrently
+        let score = now as f64 / self.trending_half_life * std::f64::consts::LN_2;
+        let projection = doc! {
+            "trending" => 1,
+        };
+        loop {
+            let filter = doc! {
+                "_id" => course_id.clone(),
+            };
+            let course = match self
+                .database
+                .find_courses2(filter, Some(projection.clone()))?
+                .into_iter()
+                .next()
+            {
+                Some(course) => course,
+                None => return Ok(()),
+            };
+            let mut filter = doc! {
+                "_id" => course_id.clone(),
+            };
+            let trending = match course.get("trending") {
+                Some(previous) => {
+                    filter.insert("trending", previous.clone());
+                    match previous.as_f64() {
+                        Some(trending) => {
+                            let (max, min) = if trending > score {
+                                (trending, score)
+                            } else {
+                                (score, trending)
+                            };
+                            max + (min - max).exp().ln_1p()
+                        }
+                        None => score,
+                    }
+                }
+                None => {
+                    filter.insert(
+                        "trending",
+                        doc! {
+                            "$exists" => false
+                        },
+                    );
+                    score
+                }
+            };
+            let update = doc! {
+                "$inc" => {
+                    "downloads" => 1i64,
+                },
+                "$set" => {
+                    "trending" => trending,
+                }
+            };
+            if self.database.update_course2(filter, update)? > 0 {
+                return Ok(());
+            }
+        }
+    }
+
     pub fn post_course2_meta(
         &self,
         course_id: ObjectId,
-        difficulty: Option<Difficulty>,
+        update: OrderedDocument,
     ) -> Result<(), PostCourse2MetaError> {
         let filter = doc! {
             "_id" => course_id.clone()
         };
-        let mut set = doc! {};
-        let mut unset = doc! {};
-        if let Some(difficulty) = difficulty {
-            set.insert("difficulty", format!("{:?}", difficulty).to_lowercase());
+        let count = if update.is_empty() {
+            self.database.count_courses2(filter)?
         } else {
-            unset.insert("difficulty", "");
-        }
-        let mut update = doc! {};
-        if !set.is_empty() {
-            update.insert("$set", set);
-        }
-        if !unset.is_empty() {
-            update.insert("$unset", unset);
-        }
-        let update = self.database.update_courses2(filter, update)?;
-        if let Some(write_exception) = update.write_exception {
-            Err(write_exception.into())
-        } else if update.matched_count == 0 {
-            Err(mongodb::Error::ArgumentError(course_id.to_string()).into())
+            self.database.update_course2(filter, update)?
+        };
+        if count == 0 {
+            Err(PostCourse2MetaError::CourseNotFound(course_id))
         } else {
             Ok(())
         }
@@ -496,8 +1561,8 @@ impl Data {
         &self,
         account: AccountReq,
         session: AuthSession,
-    ) -> Result<Account, mongodb::Error> {
-        match Data::find_account(&self.database, account.as_find()) {
+    ) -> Result<Account, DatabaseError> {
+        match Data::find_account(&*self.database, account.as_find()) {
             Some(account) => {
                 let filter = doc! {
                     "_id" => account.get_id().clone()
@@ -512,20 +1577,10 @@ impl Data {
                 Ok(account)
             }
             None => {
-                let res = self
+                let inserted_id = self
                     .database
                     .insert_account(account.clone().into_ordered_document())?;
-                let account = Account::new(
-                    account,
-                    res.inserted_id
-                        .ok_or_else(|| {
-                            mongodb::Error::ResponseError("insert_id missing".to_string())
-                        })?
-                        .as_object_id()
-                        .unwrap()
-                        .clone(),
-                    session,
-                );
+                let account = Account::new(account, inserted_id, session);
                 let filter = doc! {
                     "_id" => account.get_id()
                 };
@@ -540,12 +1595,12 @@ impl Data {
         }
     }
 
-    pub fn delete_account_session(&self, account: Account) -> Result<(), mongodb::Error> {
+    pub fn delete_account_session(&self, account: Account) -> Result<(), DatabaseError> {
         self.database.delete_account_session(account.get_id())
     }
 
     pub fn get_account_from_auth(&self, auth_req: AuthReq) -> Option<Account> {
-        Data::find_account(&self.database, auth_req.into())
+        Data::find_account(&*self.database, auth_req.into())
     }
 
     pub fn does_account_own_course(&self, account_id: ObjectId, course_oid: ObjectId) -> bool {
@@ -560,34 +1615,352 @@ impl Data {
         }
     }
 
-    fn find_courses2(&self, doc: OrderedDocument) -> Result<Vec<Course2>, mongodb::Error> {
-        match self.database.find_courses2(doc) {
-            Ok(cursor) => {
-                let courses: Vec<Course2> = cursor
-                    .map(|item| -> Result<Course2, serde_json::Error> {
-                        let course: Course2 = item.unwrap().try_into()?;
-                        Ok(course)
-                    })
-                    .filter_map(Result::ok)
-                    .collect();
-                Ok(courses)
-            }
-            Err(err) => Err(err),
-        }
+    fn find_courses2(&self, doc: OrderedDocument) -> Result<Vec<Course2>, DatabaseError> {
+        let courses: Vec<Course2> = self
+            .database
+            .find_courses2(doc, None)?
+            .into_iter()
+            .map(|item| -> Result<Course2, serde_json::Error> {
+                let course: Course2 = item.try_into()?;
+                Ok(course)
+            })
+            .filter_map(Result::ok)
+            .collect();
+        Ok(courses)
     }
 
     fn get_accounts(&self, account_ids: Vec<Bson>) -> Vec<Account> {
         self.database
             .get_accounts(account_ids)
             .unwrap()
-            .map(|item| item.unwrap().into())
+            .into_iter()
+            .map(|item| item.into())
             .collect()
     }
 
-    pub fn find_account(database: &Database, filter: OrderedDocument) -> Option<Account> {
+    pub fn find_account(database: &dyn Database, filter: OrderedDocument) -> Option<Account> {
         database
             .find_account(filter)
             .unwrap()
             .map(|item| item.into())
     }
 }
+
+/// Returns the JPEG thumbnail of the course and its encrypted counterpart.
+fn course2_thumbnails(smm_course: &mut smmdb_lib::Course2) -> Option<(Bson, Bson)> {
+    let course_thumb = smm_course.get_course_thumb_mut()?;
+    let mut thumb_data = course_thumb.get_jpeg().to_vec();
+    smmdb_lib::Thumbnail2::encrypt(&mut thumb_data);
+    let thumb_encrypted = Bson::Binary(BinarySubtype::Generic, thumb_data);
+    let thumb = Bson::Binary(BinarySubtype::Generic, course_thumb.get_jpeg().to_vec());
+    Some((thumb, thumb_encrypted))
+}
+
+/// Fields of the course data document, which are derived from course data or thumbnail.
+fn course2_derived_fields() -> Vec<String> {
+    let mut fields: Vec<String> = Derivative::ALL
+        .iter()
+        .map(|derivative| derivative.field().to_string())
+        .collect();
+    fields.push("data_zstd_version".to_string());
+    for size in [Size2::S, Size2::M, Size2::L, Size2::Original].iter() {
+        for format in ThumbnailFormat::ALL.iter() {
+            if *size != Size2::Original || *format != ThumbnailFormat::Jpeg {
+                fields.push(format.field(size.clone()));
+            }
+        }
+    }
+    fields.sort();
+    fields.dedup();
+    fields
+}
+
+/// Moves a generic binary out of the document instead of cloning it.
+fn take_binary(doc: &mut OrderedDocument, key: &str) -> Result<Vec<u8>, ValueAccessError> {
+    match doc.remove(key) {
+        Some(Bson::Binary(BinarySubtype::Generic, data)) => Ok(data),
+        Some(_) => Err(ValueAccessError::UnexpectedType),
+        None => Err(ValueAccessError::NotPresent),
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+/// Matches running jobs, whose lease expired.
+fn stale_job_filter(now: i64) -> OrderedDocument {
+    doc! {
+        "status" => JobStatus::Running.as_str(),
+        "updated" => {
+            "$lte" => now - JOB_LEASE
+        }
+    }
+}
+
+/// Matches pending jobs, which are due, and running jobs, whose lease expired.
+fn due_job_filter(now: i64) -> OrderedDocument {
+    doc! {
+        "$or" => vec![
+            Bson::Document(doc! {
+                "status" => JobStatus::Pending.as_str(),
+                "run_after" => {
+                    "$lte" => now
+                }
+            }),
+            Bson::Document(stale_job_filter(now)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smmdb_db::MemoryDatabase;
+
+    fn data() -> Data {
+        let config = Config::default();
+        let perm_gen = PermGen::new(config.similarity.permutations, config.similarity.seed);
+        Data::new(&config, Arc::new(MemoryDatabase::new()), perm_gen)
+    }
+
+    fn insert_job(data: &Data, job_id: &str, status: JobStatus, run_after: i64, updated: i64) {
+        let filter = doc! {
+            "_id" => job_id
+        };
+        let update = doc! {
+            "$set" => {
+                "status" => status.as_str(),
+                "attempts" => 0,
+                "run_after" => run_after,
+                "updated" => updated,
+            }
+        };
+        data.database.upsert_job(filter, update).unwrap();
+    }
+
+    fn find_job(data: &Data, job_id: &str) -> Option<OrderedDocument> {
+        let filter = doc! {
+            "_id" => job_id
+        };
+        data.database
+            .find_jobs(filter, doc! {}, None)
+            .unwrap()
+            .into_iter()
+            .next()
+    }
+
+    fn claim_next(data: &Data) -> Option<String> {
+        data.claim_job(due_job_filter(now_millis()))
+            .unwrap()
+            .map(|job| job.get_str("_id").unwrap().to_string())
+    }
+
+    #[test]
+    fn rejects_concurrent_course_versions() {
+        let data = data();
+        let course_id = ObjectId::new().unwrap();
+        let course = doc! {
+            "_id" => course_id.clone(),
+            "last_modified" => 1i64,
+            "version" => 1
+        };
+        data.database.insert_course2(course).unwrap();
+        let course_data = doc! {
+            "_id" => course_id.clone(),
+            "data_encrypted" => Bson::Binary(BinarySubtype::Generic, vec![1])
+        };
+        data.database.insert_course2_data(course_data).unwrap();
+
+        data.claim_course2_version(&course_id, 1, 2, 2).unwrap();
+        let res = data.claim_course2_version(&course_id, 1, 3, 2);
+        assert!(matches!(res, Err(PutCourse2DataError::Conflict)));
+
+        let filter = doc! {
+            "_id" => course_id.clone()
+        };
+        let course = data
+            .database
+            .find_courses2(filter.clone(), None)
+            .unwrap()
+            .remove(0);
+        assert_eq!(course.get_i64("last_modified").unwrap(), 2);
+        assert_eq!(course.get_i32("version").unwrap(), 2);
+        let course_data = data.database.get_course2(filter, doc! {}).unwrap().unwrap();
+        assert_eq!(
+            course_data.get_binary_generic("data_encrypted").unwrap(),
+            &vec![1]
+        );
+    }
+
+    #[test]
+    fn returns_content_hashes_of_course_versions() {
+        let data = data();
+        let course_id = ObjectId::new().unwrap();
+        let course = doc! {
+            "_id" => course_id.clone(),
+            "last_modified" => 2i64,
+            "version" => 2
+        };
+        data.database.insert_course2(course).unwrap();
+        let course_data = doc! {
+            "_id" => course_id.clone(),
+            "content_hash" => "current"
+        };
+        data.database.insert_course2_data(course_data).unwrap();
+        let previous = doc! {
+            "course_id" => course_id.clone(),
+            "version" => 1,
+            "last_modified" => 1i64,
+            "content_hash" => "previous"
+        };
+        data.database.insert_course2_history(previous).unwrap();
+
+        let revision = |version| {
+            data.get_course2_revision(course_id.clone(), version)
+                .unwrap()
+        };
+        assert_eq!(revision(None), Some(("current".to_string(), Some(2))));
+        assert_eq!(revision(Some(2)), Some(("current".to_string(), Some(2))));
+        assert_eq!(revision(Some(1)), Some(("previous".to_string(), Some(1))));
+        assert_eq!(revision(Some(3)), None);
+
+        let filter = doc! {
+            "_id" => course_id.clone()
+        };
+        let update = doc! {
+            "$unset" => {
+                "content_hash" => ""
+            }
+        };
+        data.database.update_course2_data(filter, update).unwrap();
+        assert_eq!(revision(None), None);
+    }
+
+    #[test]
+    fn accumulates_trending_scores_of_downloads() {
+        let data = data();
+        let course_id = ObjectId::new().unwrap();
+        let course = doc! {
+            "_id" => course_id.clone(),
+            "downloads" => 0i64
+        };
+        data.database.insert_course2(course).unwrap();
+        let get_course = || {
+            let filter = doc! {
+                "_id" => course_id.clone()
+            };
+            data.database.find_courses2(filter, None).unwrap().remove(0)
+        };
+
+        data.count_course2_download(course_id.clone(), "ip:1".to_string())
+            .unwrap();
+        let first = get_course().get_f64("trending").unwrap();
+        data.count_course2_download(course_id.clone(), "ip:2".to_string())
+            .unwrap();
+        data.count_course2_download(course_id.clone(), "ip:2".to_string())
+            .unwrap();
+
+        let course = get_course();
+        assert_eq!(course.get_i64("downloads").unwrap(), 2);
+        let second = course.get_f64("trending").unwrap();
+        assert!((second - first - std::f64::consts::LN_2).abs() < 1e-3);
+    }
+
+    #[test]
+    fn claims_due_jobs_by_run_after() {
+        let data = data();
+        let now = now_millis();
+        insert_job(&data, "late", JobStatus::Pending, now - 1000, now);
+        insert_job(&data, "early", JobStatus::Pending, now - 2000, now);
+        insert_job(&data, "future", JobStatus::Pending, now + 60 * 1000, now);
+        insert_job(&data, "failed", JobStatus::Failed, 0, now);
+
+        assert_eq!(claim_next(&data), Some("early".to_string()));
+        assert_eq!(claim_next(&data), Some("late".to_string()));
+        assert_eq!(claim_next(&data), None);
+
+        let job = find_job(&data, "early").unwrap();
+        assert_eq!(job.get_str("status").unwrap(), JobStatus::Running.as_str());
+        assert_eq!(job.get_i32("attempts").unwrap(), 1);
+    }
+
+    #[test]
+    fn reclaims_running_jobs_after_their_lease() {
+        let data = data();
+        let now = now_millis();
+        insert_job(&data, "active", JobStatus::Running, 0, now);
+        insert_job(
+            &data,
+            "stale",
+            JobStatus::Running,
+            0,
+            now - JOB_LEASE - 1000,
+        );
+
+        assert_eq!(claim_next(&data), Some("stale".to_string()));
+        assert_eq!(claim_next(&data), None);
+    }
+
+    #[test]
+    fn retries_failed_jobs_with_backoff() {
+        let data = data();
+        insert_job(&data, "job", JobStatus::Pending, 0, 0);
+
+        for attempts in 1..data.job_max_attempts {
+            let job = data
+                .claim_job(due_job_filter(now_millis()))
+                .unwrap()
+                .unwrap();
+            let before = now_millis();
+            data.finish_job(&job, Err("error".to_string())).unwrap();
+
+            let job = find_job(&data, "job").unwrap();
+            assert_eq!(job.get_str("status").unwrap(), JobStatus::Pending.as_str());
+            assert_eq!(job.get_i32("attempts").unwrap(), attempts);
+            assert_eq!(job.get_str("error").unwrap(), "error");
+            assert!(job.get_i64("run_after").unwrap() >= before + job_retry_backoff(attempts));
+            assert_eq!(claim_next(&data), None);
+
+            let update = doc! {
+                "$set" => {
+                    "run_after" => 0i64
+                }
+            };
+            data.database
+                .update_job(doc! { "_id" => "job" }, update)
+                .unwrap();
+        }
+
+        let job = data
+            .claim_job(due_job_filter(now_millis()))
+            .unwrap()
+            .unwrap();
+        data.finish_job(&job, Err("error".to_string())).unwrap();
+        let job = find_job(&data, "job").unwrap();
+        assert_eq!(job.get_str("status").unwrap(), JobStatus::Failed.as_str());
+        assert_eq!(claim_next(&data), None);
+    }
+
+    #[test]
+    fn ignores_results_of_reclaimed_jobs() {
+        let data = data();
+        let now = now_millis();
+        insert_job(&data, "job", JobStatus::Running, 0, now - JOB_LEASE - 1000);
+        let stale = doc! {
+            "_id" => "job",
+            "attempts" => 0
+        };
+
+        assert_eq!(claim_next(&data), Some("job".to_string()));
+        data.finish_job(&stale, Ok(())).unwrap();
+        assert!(find_job(&data, "job").is_some());
+
+        let job = find_job(&data, "job").unwrap();
+        data.finish_job(&job, Ok(())).unwrap();
+        assert!(find_job(&data, "job").is_none());
+    }
+}
@@ -0,0 +1,225 @@
+use bson::{oid::ObjectId, ordered::OrderedDocument, spec::BinarySubtype, Bson, ValueAccessError};
+use smmdb_db::{Database, DatabaseError};
+use std::{io, time::SystemTime};
+use zstd::{
+    block::{Compressor, Decompressor},
+    dict,
+};
+
+/// Maximum size of a trained dictionary, which is the default of zstd.
+pub const ZSTD_DICTIONARY_SIZE: usize = 112_640;
+
+/// Upper bound of decrypted course data loaded to train a dictionary.
+pub const ZSTD_SAMPLE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Number of courses at least required to train a dictionary.
+pub const ZSTD_MIN_SAMPLES: usize = 16;
+
+pub const ZSTD_LEVEL: i32 = 19;
+
+/// Upper bound of decompressed course data.
+pub const ZSTD_MAX_DATA_SIZE: usize = 1024 * 1024;
+
+/// A zstd dictionary trained on decrypted SMM2 course data.
+///
+/// Dictionaries are stored in `meta` under their version, which is never reused, so that clients
+/// can cache them indefinitely.
+#[derive(Clone)]
+pub struct ZstdDictionary {
+    pub version: i32,
+    pub dictionary: Vec<u8>,
+    /// In milliseconds.
+    pub created: i64,
+}
+
+impl ZstdDictionary {
+    const CURRENT_KEY: &'static str = "zstd_dictionary";
+
+    fn key(version: i32) -> String {
+        format!("zstd_dictionary_v{}", version)
+    }
+
+    /// Trains the successor of the current dictionary.
+    ///
+    /// `samples` is the concatenation of all samples.
+    pub fn train(
+        database: &dyn Database,
+        samples: &[u8],
+        sample_sizes: &[usize],
+    ) -> Result<Self, DatabaseError> {
+        let dictionary = dict::from_continuous(samples, sample_sizes, ZSTD_DICTIONARY_SIZE)?;
+        let version = match database.get_meta_value(ZstdDictionary::CURRENT_KEY)? {
+            Some(Bson::I32(version)) => version + 1,
+            _ => 1,
+        };
+        Ok(ZstdDictionary {
+            version,
+            dictionary,
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64,
+        })
+    }
+
+    /// Stores the dictionary and makes it the current one.
+    pub fn store(&self, database: &dyn Database) -> Result<(), DatabaseError> {
+        let doc = doc! {
+            "dictionary" => Bson::Binary(BinarySubtype::Generic, self.dictionary.clone()),
+            "created" => self.created,
+        };
+        database.set_meta_value(&ZstdDictionary::key(self.version), doc.into())?;
+        database.set_meta_value(ZstdDictionary::CURRENT_KEY, self.version.into())
+    }
+
+    pub fn load_current(database: &dyn Database) -> Result<Option<Self>, DatabaseError> {
+        match database.get_meta_value(ZstdDictionary::CURRENT_KEY)? {
+            Some(Bson::I32(version)) => ZstdDictionary::load(database, version),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn load(database: &dyn Database, version: i32) -> Result<Option<Self>, DatabaseError> {
+        match database.get_meta_value(&ZstdDictionary::key(version))? {
+            Some(Bson::Document(mut doc)) => {
+                let dictionary = match doc.remove("dictionary") {
+                    Some(Bson::Binary(BinarySubtype::Generic, dictionary)) => dictionary,
+                    _ => return Ok(None),
+                };
+                Ok(Some(ZstdDictionary {
+                    version,
+                    dictionary,
+                    created: doc.get_i64("created").unwrap_or_default(),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Compressor::with_dict(self.dictionary.clone()).compress(data, ZSTD_LEVEL)
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Decompressor::with_dict(self.dictionary.clone()).decompress(data, ZSTD_MAX_DATA_SIZE)
+    }
+
+    /// Compresses the decrypted course data with the dictionary and stores it instead of the
+    /// encrypted course data.
+    pub fn store_course(
+        &self,
+        database: &dyn Database,
+        course_id: ObjectId,
+        mut data_encrypted: Vec<u8>,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        smmdb_lib::Course2::decrypt(&mut data_encrypted)?;
+        let data_zstd = self.compress(&data_encrypted)?;
+        let filter = doc! {
+            "_id" => course_id
+        };
+        let update = doc! {
+            "$set" => {
+                "data_zstd" => Bson::Binary(BinarySubtype::Generic, data_zstd.clone()),
+                "data_zstd_version" => self.version,
+            },
+            "$unset" => {
+                "data_encrypted" => ""
+            }
+        };
+        database.update_course2_data(filter, update)?;
+        Ok(data_zstd)
+    }
+
+    /// Projection of the course data document, which is needed by `take_data_encrypted`.
+    pub fn data_projection() -> OrderedDocument {
+        doc! {
+            "data_encrypted" => 1,
+            "data_zstd" => 1,
+            "data_zstd_version" => 1,
+        }
+    }
+
+    /// Takes the encrypted course data out of a course data document.
+    ///
+    /// Course data, which has been compressed with a dictionary, is decompressed and encrypted
+    /// again. Dictionaries of previous versions are loaded from `database`.
+    pub fn take_data_encrypted(
+        current: Option<&ZstdDictionary>,
+        database: &dyn Database,
+        doc: &mut OrderedDocument,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        if let Some(Bson::Binary(BinarySubtype::Generic, data)) = doc.remove("data_encrypted") {
+            return Ok(data);
+        }
+        let data_zstd = match doc.remove("data_zstd") {
+            Some(Bson::Binary(BinarySubtype::Generic, data)) => data,
+            _ => return Err(ValueAccessError::NotPresent.into()),
+        };
+        let version = doc.get_i32("data_zstd_version")?;
+        let mut data = match current {
+            Some(dictionary) if dictionary.version == version => {
+                dictionary.decompress(&data_zstd)?
+            }
+            _ => ZstdDictionary::load(database, version)?
+                .ok_or_else(|| DatabaseError::NotFound(format!("zstd dictionary v{}", version)))?
+                .decompress(&data_zstd)?,
+        };
+        smmdb_lib::Course2::encrypt(&mut data);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smmdb_db::MemoryDatabase;
+
+    fn sample(index: usize) -> Vec<u8> {
+        (0..4096)
+            .map(|i| match i % 64 {
+                0..=15 => (index % 7) as u8,
+                16..=31 => b"course data"[i % 11],
+                _ => ((i * 31 + index * 17) % 251) as u8,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_course_data() {
+        let database = MemoryDatabase::new();
+        let samples: Vec<Vec<u8>> = (0..512).map(sample).collect();
+        let sample_sizes: Vec<usize> = samples.iter().map(Vec::len).collect();
+        let dictionary =
+            ZstdDictionary::train(&database, &samples.concat(), &sample_sizes).unwrap();
+        assert_eq!(dictionary.version, 1);
+
+        let data = sample(1000);
+        let compressed = dictionary.compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(dictionary.decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn loads_stored_dictionaries() {
+        let database = MemoryDatabase::new();
+        let samples: Vec<Vec<u8>> = (0..512).map(sample).collect();
+        let sample_sizes: Vec<usize> = samples.iter().map(Vec::len).collect();
+        let dictionary =
+            ZstdDictionary::train(&database, &samples.concat(), &sample_sizes).unwrap();
+        dictionary.store(&database).unwrap();
+
+        let current = ZstdDictionary::load_current(&database).unwrap().unwrap();
+        assert_eq!(current.version, dictionary.version);
+        assert_eq!(current.dictionary, dictionary.dictionary);
+
+        let next = ZstdDictionary::train(&database, &samples.concat(), &sample_sizes).unwrap();
+        assert_eq!(next.version, dictionary.version + 1);
+
+        let data = sample(1000);
+        let compressed = dictionary.compress(&data).unwrap();
+        let previous = ZstdDictionary::load(&database, dictionary.version)
+            .unwrap()
+            .unwrap();
+        assert_eq!(previous.decompress(&compressed).unwrap(), data);
+    }
+}
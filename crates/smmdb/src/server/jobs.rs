@@ -0,0 +1,169 @@
+use crate::routes::courses2::thumbnail::{Size2, ThumbnailFormat};
+
+use bson::oid::ObjectId;
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Time in milliseconds after which a running job is considered abandoned, e.g. because the
+/// server was stopped, and may be claimed again.
+pub const JOB_LEASE: i64 = 10 * 60 * 1000;
+
+/// Backoff in milliseconds before the first retry of a failed job. It doubles with every attempt.
+pub const JOB_RETRY_BACKOFF: i64 = 30 * 1000;
+
+/// Maximum backoff in milliseconds between retries.
+pub const JOB_MAX_RETRY_BACKOFF: i64 = 60 * 60 * 1000;
+
+/// Seconds after which a request should be retried, if the job computing the requested
+/// derivative is running elsewhere.
+pub const JOB_RETRY_AFTER: u64 = 2;
+
+/// Time a batch download waits for a job, which is running elsewhere. Its archive is already
+/// being streamed, so it can't be retried later.
+pub const JOB_WAIT: Duration = Duration::from_secs(30);
+
+/// The derivative is missing and the job computing it is running elsewhere.
+#[derive(Debug, Error)]
+#[error(
+    "[JobPending]: derivative is being computed, retry in {} seconds",
+    JOB_RETRY_AFTER
+)]
+pub struct JobPending;
+
+/// Artifacts, which are derived from uploaded courses and cached in their data document.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Derivative {
+    DataBr,
+    DataProtobufBr,
+    DataZstd,
+    ThumbS,
+    ThumbM,
+    ThumbL,
+    ThumbAvif,
+    ThumbSAvif,
+    ThumbMAvif,
+    ThumbLAvif,
+}
+
+impl Derivative {
+    pub const ALL: [Derivative; 10] = [
+        Derivative::DataBr,
+        Derivative::DataProtobufBr,
+        Derivative::DataZstd,
+        Derivative::ThumbS,
+        Derivative::ThumbM,
+        Derivative::ThumbL,
+        Derivative::ThumbAvif,
+        Derivative::ThumbSAvif,
+        Derivative::ThumbMAvif,
+        Derivative::ThumbLAvif,
+    ];
+
+    /// Returns the derivative of the thumbnail, if it is precomputed.
+    pub fn thumbnail(size: &Size2, format: ThumbnailFormat) -> Option<Self> {
+        match (size, format) {
+            (Size2::S, ThumbnailFormat::Jpeg) => Some(Derivative::ThumbS),
+            (Size2::M, ThumbnailFormat::Jpeg) => Some(Derivative::ThumbM),
+            (Size2::L, ThumbnailFormat::Jpeg) => Some(Derivative::ThumbL),
+            (Size2::Original, ThumbnailFormat::Avif) => Some(Derivative::ThumbAvif),
+            (Size2::S, ThumbnailFormat::Avif) => Some(Derivative::ThumbSAvif),
+            (Size2::M, ThumbnailFormat::Avif) => Some(Derivative::ThumbMAvif),
+            (Size2::L, ThumbnailFormat::Avif) => Some(Derivative::ThumbLAvif),
+            _ => None,
+        }
+    }
+
+    /// Returns the size and format of a thumbnail derivative.
+    pub fn get_thumbnail(self) -> Option<(Size2, ThumbnailFormat)> {
+        match self {
+            Derivative::ThumbS => Some((Size2::S, ThumbnailFormat::Jpeg)),
+            Derivative::ThumbM => Some((Size2::M, ThumbnailFormat::Jpeg)),
+            Derivative::ThumbL => Some((Size2::L, ThumbnailFormat::Jpeg)),
+            Derivative::ThumbAvif => Some((Size2::Original, ThumbnailFormat::Avif)),
+            Derivative::ThumbSAvif => Some((Size2::S, ThumbnailFormat::Avif)),
+            Derivative::ThumbMAvif => Some((Size2::M, ThumbnailFormat::Avif)),
+            Derivative::ThumbLAvif => Some((Size2::L, ThumbnailFormat::Avif)),
+            _ => None,
+        }
+    }
+
+    /// Name of the field of the course data document the derivative is stored in.
+    pub fn field(self) -> &'static str {
+        match self {
+            Derivative::DataBr => "data_br",
+            Derivative::DataProtobufBr => "data_protobuf_br",
+            Derivative::DataZstd => "data_zstd",
+            Derivative::ThumbS => "thumb_s",
+            Derivative::ThumbM => "thumb_m",
+            Derivative::ThumbL => "thumb_l",
+            Derivative::ThumbAvif => "thumb_avif",
+            Derivative::ThumbSAvif => "thumb_s_avif",
+            Derivative::ThumbMAvif => "thumb_m_avif",
+            Derivative::ThumbLAvif => "thumb_l_avif",
+        }
+    }
+
+    pub fn from_field(field: &str) -> Option<Self> {
+        Derivative::ALL
+            .iter()
+            .find(|derivative| derivative.field() == field)
+            .cloned()
+    }
+
+    /// There is at most one job per course and derivative, so that concurrent requests for the
+    /// same derivative share it.
+    pub fn job_id(self, course_id: &ObjectId) -> String {
+        format!("{}:{}", self.field(), course_id.to_hex())
+    }
+}
+
+/// Jobs are deleted, once they succeeded.
+#[derive(Apiv2Schema, Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Failed,
+}
+
+impl JobStatus {
+    pub const ALL: [JobStatus; 3] = [JobStatus::Pending, JobStatus::Running, JobStatus::Failed];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(JobStatus::Pending),
+            "running" => Some(JobStatus::Running),
+            "failed" => Some(JobStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the backoff in milliseconds after the given number of failed attempts.
+pub fn job_retry_backoff(attempts: i32) -> i64 {
+    let exp = (attempts - 1).max(0).min(16) as u32;
+    (JOB_RETRY_BACKOFF << exp).min(JOB_MAX_RETRY_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_retry_backoff_up_to_the_maximum() {
+        assert_eq!(job_retry_backoff(1), JOB_RETRY_BACKOFF);
+        assert_eq!(job_retry_backoff(2), 2 * JOB_RETRY_BACKOFF);
+        assert_eq!(job_retry_backoff(3), 4 * JOB_RETRY_BACKOFF);
+        assert_eq!(job_retry_backoff(100), JOB_MAX_RETRY_BACKOFF);
+    }
+}
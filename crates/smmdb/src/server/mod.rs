@@ -1,3 +1,4 @@
+use crate::config::Config;
 use crate::routes::{courses, courses2, index, login, logout};
 use crate::session::Auth;
 
@@ -5,7 +6,7 @@ use actix_cors::Cors;
 use actix_session::CookieSession;
 use actix_web::{
     client::Client,
-    dev::Server as ActixServer,
+    error::BlockingError,
     middleware::{Compress, Logger},
     App, HttpServer,
 };
@@ -14,23 +15,64 @@ use paperclip::{
     v2::models::{DefaultApiRaw, Info, Tag},
 };
 use smmdb_common::PermGen;
-use smmdb_db::Database;
-use std::{io, sync::Arc};
+use smmdb_db::{Database, DatabaseError};
+use std::{fmt::Debug, future::Future, io, sync::Arc, thread, time::Duration};
 
 mod data;
+mod dictionary;
+mod jobs;
+mod similarity;
+mod thumbnail_cache;
 
 pub use data::*;
+pub use dictionary::*;
+pub use jobs::*;
+pub use similarity::*;
+pub use thumbnail_cache::*;
 
 pub struct Server;
 
+/// Runs `f` on the blocking thread pool, so that database calls don't stall the worker thread.
+pub async fn block<F, I, E>(f: F) -> Result<I, E>
+where
+    F: FnOnce() -> Result<I, E> + Send + 'static,
+    I: Send + 'static,
+    E: From<DatabaseError> + Debug + Send + 'static,
+{
+    actix_web::web::block(f).await.map_err(|err| match err {
+        BlockingError::Error(err) => err,
+        BlockingError::Canceled => DatabaseError::Canceled.into(),
+    })
+}
+
 impl Server {
-    pub fn start(database: Arc<Database>, perm_gen: PermGen) -> Result<ActixServer, io::Error> {
+    pub fn start(
+        config: Config,
+        database: Arc<dyn Database>,
+        perm_gen: PermGen,
+    ) -> Result<impl Future<Output = io::Result<()>>, io::Error> {
         println!("Starting SMMDB API server");
         std::env::set_var("RUST_LOG", "actix_web=debug");
         env_logger::init();
-        let data = Arc::new(Data::new(database, perm_gen));
-
-        Ok(HttpServer::new(move || {
+        let session_key = config
+            .session_key()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        let session_config = config.session.clone();
+        let bind_address = config.server.bind_address.clone();
+        let workers = config.server.workers;
+        let data = Arc::new(Data::new(&config, database, perm_gen));
+        Server::maintain_similarity_index(
+            data.clone(),
+            Duration::from_secs(config.similarity.lsh_snapshot_interval),
+        );
+        Server::run_jobs(
+            data.clone(),
+            config.jobs.workers,
+            Duration::from_secs(config.jobs.poll_interval),
+        );
+
+        let app_data = data.clone();
+        let server = HttpServer::new(move || {
             let spec = DefaultApiRaw {
                 tags: vec![Tag {
                     name: "SMM1".to_string(),
@@ -102,7 +144,7 @@ Chocolatey install instructions/docs [Chocolatey.org](https://chocolatey.org/ins
 
             App::new()
                 .wrap_api_with_spec(spec)
-                .data(data.clone())
+                .data(app_data.clone())
                 .data(Client::default())
                 .service(courses::service())
                 .service(courses2::service())
@@ -112,19 +154,76 @@ Chocolatey install instructions/docs [Chocolatey.org](https://chocolatey.org/ins
                 .with_json_spec_at("/api/spec")
                 .wrap(Auth)
                 .wrap(
-                    CookieSession::signed(&[0; 32])
+                    CookieSession::signed(&session_key)
                         .name("smmdb")
                         .path("/")
-                        .max_age(3600 * 24 * 7)
-                        .secure(false),
+                        .max_age(session_config.max_age)
+                        .secure(session_config.secure),
                 )
                 .wrap(Cors::permissive())
                 .wrap(Compress::default())
                 .wrap(Logger::default())
                 .build()
         })
-        .bind("0.0.0.0:3030")?
-        .workers(num_cpus::get())
-        .run())
+        .bind(bind_address)?
+        .workers(workers)
+        .run();
+
+        Ok(async move {
+            let res = server.await;
+            if let Err(err) = data.snapshot_similarity_index() {
+                println!("Snapshotting LshIndex failed: {}", err);
+            }
+            res
+        })
+    }
+
+    /// Loads the `LshIndex` in the background and periodically snapshots it.
+    fn maintain_similarity_index(data: ServerData, interval: Duration) {
+        actix_web::rt::spawn(async move {
+            let load_data = data.clone();
+            if let Err(err) = block(move || load_data.load_similarity_index()).await {
+                println!("Loading LshIndex failed: {}", err);
+            }
+
+            let mut interval = actix_web::rt::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let data = data.clone();
+                let res = block(move || -> Result<(), DatabaseError> {
+                    Ok(data.snapshot_similarity_index()?)
+                })
+                .await;
+                if let Err(err) = res {
+                    println!("Snapshotting LshIndex failed: {}", err);
+                }
+            }
+        });
+    }
+
+    /// Spawns worker threads, which run due jobs one after another and poll for new ones while
+    /// idle.
+    ///
+    /// Jobs run on their own threads, so that they don't occupy the blocking thread pool of
+    /// the request handlers.
+    fn run_jobs(data: ServerData, workers: usize, poll_interval: Duration) {
+        for worker in 0..workers {
+            let data = data.clone();
+            let res = thread::Builder::new()
+                .name(format!("job-worker-{}", worker))
+                .spawn(move || loop {
+                    match data.run_next_job() {
+                        Ok(true) => {}
+                        Ok(false) => thread::sleep(poll_interval),
+                        Err(err) => {
+                            println!("Running job failed: {}", err);
+                            thread::sleep(poll_interval);
+                        }
+                    }
+                });
+            if let Err(err) = res {
+                println!("Spawning job worker failed: {}", err);
+            }
+        }
     }
 }
@@ -0,0 +1,338 @@
+use crate::config::SimilarityConfig;
+
+use bson::{ordered::OrderedDocument, Bson};
+use serde::{Deserialize, Serialize};
+use smmdb_common::{LshIndex, MinHash, PermGen};
+use smmdb_db::{Database, DatabaseError};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+const SNAPSHOT_VERSION: u32 = 2;
+
+/// `LshIndex` over the hashes of all courses.
+///
+/// The index is loaded on first use, either from its last snapshot, which is then caught up with
+/// all courses modified since and stripped of all courses deleted since, or from the database.
+pub struct SimilarityIndex {
+    state: Mutex<Option<IndexState>>,
+    /// Held while loading, so that `state` is not locked during the load.
+    loading: Mutex<()>,
+    header: SnapshotHeader,
+    snapshot_path: Option<PathBuf>,
+}
+
+struct IndexState {
+    index: LshIndex,
+    last_modified: i64,
+    dirty: bool,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct SnapshotHeader {
+    version: u32,
+    band_size: u64,
+    num_perm: usize,
+    seed: u32,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    header: &'a SnapshotHeader,
+    last_modified: i64,
+    keys: &'a HashMap<String, Vec<u64>>,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    header: SnapshotHeader,
+    last_modified: i64,
+    keys: HashMap<String, Vec<u64>>,
+}
+
+impl SimilarityIndex {
+    pub fn new(config: &SimilarityConfig, perm_gen: &PermGen) -> Self {
+        SimilarityIndex {
+            state: Mutex::new(None),
+            loading: Mutex::new(()),
+            header: SnapshotHeader {
+                version: SNAPSHOT_VERSION,
+                band_size: config.lsh_band_size,
+                num_perm: perm_gen.num_perm(),
+                seed: perm_gen.seed(),
+            },
+            snapshot_path: config.lsh_snapshot_path.as_ref().map(PathBuf::from),
+        }
+    }
+
+    pub fn load(&self, database: &dyn Database) -> Result<(), DatabaseError> {
+        self.with_state(database, |_| {})
+    }
+
+    pub fn query(
+        &self,
+        database: &dyn Database,
+        hash: &MinHash,
+    ) -> Result<Vec<String>, DatabaseError> {
+        self.with_state(database, |state| state.index.query(hash))
+    }
+
+    /// Inserts the course or replaces its previous hash.
+    ///
+    /// If the index cannot be loaded, the course is picked up from the database by the next load.
+    pub fn insert(&self, database: &dyn Database, key: String, hash: &MinHash, last_modified: i64) {
+        let res = self.with_state(database, |state| {
+            state.index.insert(key, hash);
+            state.last_modified = state.last_modified.max(last_modified);
+            state.dirty = true;
+        });
+        if let Err(err) = res {
+            println!("Loading LshIndex failed: {}", err);
+        }
+    }
+
+    pub fn remove(&self, database: &dyn Database, key: &str) {
+        let res = self.with_state(database, |state| {
+            if state.index.remove(key) {
+                state.dirty = true;
+            }
+        });
+        if let Err(err) = res {
+            println!("Loading LshIndex failed: {}", err);
+        }
+    }
+
+    /// Writes a snapshot, if the index changed since it has been loaded or last snapshotted.
+    ///
+    /// The snapshot is written to a temporary file first, so that a crash never leaves a
+    /// truncated snapshot behind.
+    pub fn snapshot(&self) -> io::Result<()> {
+        let path = match &self.snapshot_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let data = {
+            let mut state = self.state.lock().unwrap();
+            match state.as_mut() {
+                Some(state) if state.dirty => {
+                    let snapshot = SnapshotRef {
+                        header: &self.header,
+                        last_modified: state.last_modified,
+                        keys: state.index.keys(),
+                    };
+                    let data = bincode::serialize(&snapshot)
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                    state.dirty = false;
+                    data
+                }
+                _ => return Ok(()),
+            }
+        };
+
+        let tmp_path = path.with_extension("tmp");
+        let res = fs::write(&tmp_path, data).and_then(|_| fs::rename(&tmp_path, path));
+        if res.is_err() {
+            if let Some(state) = self.state.lock().unwrap().as_mut() {
+                state.dirty = true;
+            }
+        }
+        res
+    }
+
+    /// Runs `f` on the index, which is loaded first, if necessary.
+    ///
+    /// A failed load is not cached, so that the next call tries again.
+    fn with_state<R>(
+        &self,
+        database: &dyn Database,
+        f: impl FnOnce(&mut IndexState) -> R,
+    ) -> Result<R, DatabaseError> {
+        if let Some(state) = self.state.lock().unwrap().as_mut() {
+            return Ok(f(state));
+        }
+
+        let _loading = self.loading.lock().unwrap();
+        if self.state.lock().unwrap().is_none() {
+            let state = self.load_state(database)?;
+            *self.state.lock().unwrap() = Some(state);
+        }
+        let mut state = self.state.lock().unwrap();
+        Ok(f(state.as_mut().expect("LshIndex has been loaded")))
+    }
+
+    fn load_state(&self, database: &dyn Database) -> Result<IndexState, DatabaseError> {
+        let band_size = self.header.band_size;
+        let (mut state, modified_since) = match self.read_snapshot() {
+            Ok(Some(snapshot)) => {
+                println!("Loaded LshIndex snapshot");
+                let state = IndexState {
+                    index: LshIndex::from_keys(band_size, snapshot.keys),
+                    last_modified: snapshot.last_modified,
+                    dirty: false,
+                };
+                (state, Some(snapshot.last_modified))
+            }
+            res => {
+                if let Err(err) = res {
+                    println!("Could not read LshIndex snapshot: {}", err);
+                }
+                let state = IndexState {
+                    index: LshIndex::new(band_size),
+                    last_modified: 0,
+                    dirty: true,
+                };
+                (state, None)
+            }
+        };
+
+        println!("Filling LshIndex");
+        for item in database.fill_lsh_index(modified_since)? {
+            if let Some((id, hash, last_modified)) = parse_course_hash(item) {
+                state.index.insert(id, &hash);
+                state.last_modified = state.last_modified.max(last_modified);
+                state.dirty = true;
+            }
+        }
+        if modified_since.is_some() {
+            let ids: HashSet<String> = database
+                .find_courses2(doc! {}, Some(doc! { "_id" => 1 }))?
+                .into_iter()
+                .filter_map(|item| Some(item.get_object_id("_id").ok()?.to_hex()))
+                .collect();
+            let deleted: Vec<String> = state
+                .index
+                .keys()
+                .keys()
+                .filter(|key| !ids.contains(*key))
+                .cloned()
+                .collect();
+            for key in deleted {
+                state.index.remove(&key);
+                state.dirty = true;
+            }
+        }
+        println!("Filling LshIndex completed!");
+        Ok(state)
+    }
+
+    fn read_snapshot(&self) -> io::Result<Option<Snapshot>> {
+        let path = match &self.snapshot_path {
+            Some(path) if path.exists() => path,
+            _ => return Ok(None),
+        };
+        let data = fs::read(path)?;
+        let snapshot: Snapshot = bincode::deserialize(&data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if snapshot.header != self.header {
+            println!("LshIndex snapshot is outdated");
+            return Ok(None);
+        }
+        Ok(Some(snapshot))
+    }
+}
+
+fn parse_course_hash(item: OrderedDocument) -> Option<(String, MinHash, i64)> {
+    let id = item.get_object_id("_id").ok()?.to_hex();
+    let hash: serde_json::Value = item.get("hash")?.clone().into();
+    let hash: MinHash = serde_json::from_value(hash).ok()?;
+    let last_modified = match item.get("last_modified") {
+        Some(Bson::I64(last_modified)) => *last_modified,
+        Some(Bson::I32(last_modified)) => *last_modified as i64,
+        _ => 0,
+    };
+    Some((id, hash, last_modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::oid::ObjectId;
+    use smmdb_db::{CourseStore, MemoryDatabase};
+    use std::env;
+
+    fn hash(perm_gen: &PermGen, data: &[u8]) -> MinHash {
+        let mut hash = MinHash::new(perm_gen);
+        hash.update(perm_gen, data);
+        hash
+    }
+
+    fn hash_doc(hash: &MinHash) -> Bson {
+        Bson::from(serde_json::to_value(hash).unwrap())
+    }
+
+    #[test]
+    fn catches_up_snapshot_with_modified_and_deleted_courses() {
+        let path = env::temp_dir().join(format!(
+            "smmdb-lsh-{}.bin",
+            ObjectId::new().unwrap().to_hex()
+        ));
+        let config = SimilarityConfig {
+            lsh_snapshot_path: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let perm_gen = PermGen::new(config.permutations, config.seed);
+        let database = MemoryDatabase::new();
+        let mut ids = vec![];
+        for (data, last_modified) in [(b"first", 1), (b"other", 2)].iter() {
+            let id = database
+                .insert_course2(doc! {
+                    "hash" => hash_doc(&hash(&perm_gen, *data)),
+                    "last_modified" => *last_modified
+                })
+                .unwrap();
+            ids.push(id);
+        }
+
+        let index = SimilarityIndex::new(&config, &perm_gen);
+        index.load(&database).unwrap();
+        index.snapshot().unwrap();
+        assert!(path.exists());
+
+        let reuploaded = hash(&perm_gen, b"reuploaded");
+        database
+            .update_course2(
+                doc! { "_id" => ids[0].clone() },
+                doc! {
+                    "$set" => {
+                        "hash" => hash_doc(&reuploaded),
+                        "last_modified" => 3
+                    }
+                },
+            )
+            .unwrap();
+        database
+            .delete_course2_meta(doc! { "_id" => ids[1].clone() })
+            .unwrap();
+        let added = database
+            .insert_course2(doc! {
+                "hash" => hash_doc(&hash(&perm_gen, b"added")),
+                "last_modified" => 4
+            })
+            .unwrap();
+
+        let index = SimilarityIndex::new(&config, &perm_gen);
+        index.load(&database).unwrap();
+        let keys: HashSet<String> = index
+            .state
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .index
+            .keys()
+            .keys()
+            .cloned()
+            .collect();
+        let expected: HashSet<String> = vec![ids[0].to_hex(), added.to_hex()].into_iter().collect();
+        assert_eq!(keys, expected);
+        assert!(index
+            .query(&database, &reuploaded)
+            .unwrap()
+            .contains(&ids[0].to_hex()));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
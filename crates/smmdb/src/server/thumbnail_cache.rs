@@ -0,0 +1,95 @@
+use crate::routes::courses2::thumbnail::ThumbnailFormat;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+/// In-memory cache of resized thumbnails, which evicts the least recently used thumbnails once
+/// their total size exceeds the capacity.
+pub struct ThumbnailCache {
+    state: Mutex<CacheState>,
+    /// In bytes.
+    capacity: usize,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ThumbnailKey {
+    pub course_id: String,
+    pub width: u32,
+    pub format: ThumbnailFormat,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<ThumbnailKey, (Vec<u8>, u64)>,
+    /// Keys by the tick of their last access.
+    recency: BTreeMap<u64, ThumbnailKey>,
+    size: usize,
+    tick: u64,
+}
+
+impl ThumbnailCache {
+    pub fn new(capacity: usize) -> Self {
+        ThumbnailCache {
+            state: Mutex::new(CacheState::default()),
+            capacity,
+        }
+    }
+
+    pub fn get(&self, key: &ThumbnailKey) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.tick += 1;
+        let (data, tick) = state.entries.get_mut(key)?;
+        state.recency.remove(tick);
+        *tick = state.tick;
+        state.recency.insert(state.tick, key.clone());
+        Some(data.clone())
+    }
+
+    /// Thumbnails larger than the capacity are not cached.
+    pub fn insert(&self, key: ThumbnailKey, data: Vec<u8>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        state.tick += 1;
+        let tick = state.tick;
+        state.size += data.len();
+        state.recency.insert(tick, key.clone());
+        state.entries.insert(key, (data, tick));
+
+        while state.size > self.capacity {
+            let oldest = match state.recency.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            state.remove(&oldest);
+        }
+    }
+
+    /// Evicts all thumbnails of the course.
+    pub fn remove_course(&self, course_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<ThumbnailKey> = state
+            .entries
+            .keys()
+            .filter(|key| key.course_id == course_id)
+            .cloned()
+            .collect();
+        for key in keys.iter() {
+            state.remove(key);
+        }
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &ThumbnailKey) {
+        if let Some((data, tick)) = self.entries.remove(key) {
+            self.recency.remove(&tick);
+            self.size -= data.len();
+        }
+    }
+}
@@ -1,9 +1,10 @@
-use crate::server::ServerData;
+use crate::server::{block, ServerData};
 
 // use actix_service::{Service, Transform};
 use actix_session::{Session, UserSession};
 use actix_web::{
     dev::{RequestHead, Service, ServiceRequest, ServiceResponse, Transform},
+    error::ErrorInternalServerError,
     http::header,
     web::Data,
     Error,
@@ -11,9 +12,12 @@ use actix_web::{
 use bson::{oid::ObjectId, ordered::OrderedDocument};
 use futures::future::{ok, Future, Ready};
 use smmdb_auth::{AuthSession, Identity};
+use smmdb_db::DatabaseError;
 use std::{
+    cell::RefCell,
     convert::TryFrom,
     pin::Pin,
+    rc::Rc,
     task::{Context, Poll},
 };
 
@@ -21,7 +25,7 @@ pub struct Auth;
 
 impl<S, B> Transform<S> for Auth
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -33,17 +37,19 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(AuthMiddleware { service })
+        ok(AuthMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
     }
 }
 
 pub struct AuthMiddleware<S> {
-    service: S,
+    service: Rc<RefCell<S>>,
 }
 
 impl<S, B> Service for AuthMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -54,29 +60,38 @@ where
     type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>>>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+        self.service.borrow_mut().poll_ready(cx)
     }
 
     fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
-        let session = req.get_session();
-        let data: Option<&Data<ServerData>> = req.app_data();
-        if let Some(data) = data {
-            if let Ok(auth_req) = AuthReq::try_from(session) {
-                let expires_at = auth_req.session.as_ref().unwrap().get_expires_at();
-                if let Some(account) = data.get_account_from_auth(auth_req) {
-                    if !account.is_expired(expires_at) {
-                        Identity::set_identity(account, &mut req);
+        let service = self.service.clone();
+        Box::pin(async move {
+            let data: Option<Data<ServerData>> = req.app_data::<Data<ServerData>>().cloned();
+            if let Some(data) = data {
+                let session = req.get_session();
+                let auth = if let Ok(auth_req) = AuthReq::try_from(session) {
+                    let expires_at = auth_req.session.as_ref().unwrap().get_expires_at();
+                    Some((auth_req, Some(expires_at)))
+                } else if let Ok(auth_req) = AuthReq::try_from(req.head()) {
+                    Some((auth_req, None))
+                } else {
+                    None
+                };
+                if let Some((auth_req, expires_at)) = auth {
+                    let account = block(move || -> Result<_, DatabaseError> {
+                        Ok(data.get_account_from_auth(auth_req))
+                    })
+                    .await
+                    .map_err(ErrorInternalServerError)?;
+                    if let Some(account) = account {
+                        if expires_at.map_or(true, |expires_at| !account.is_expired(expires_at)) {
+                            Identity::set_identity(account, &mut req);
+                        }
                     }
                 }
-            } else if let Ok(auth_req) = AuthReq::try_from(req.head()) {
-                if let Some(account) = data.get_account_from_auth(auth_req) {
-                    Identity::set_identity(account, &mut req);
-                }
             }
-        }
 
-        let fut = self.service.call(req);
-        Box::pin(async move {
+            let fut = service.borrow_mut().call(req);
             let res = fut.await?;
             Ok(res)
         })